@@ -11,6 +11,7 @@ use os_str_bytes::OsStrBytesExt;
 use smallvec::SmallVec;
 
 use crate::path_utils::make_absolute;
+use crate::platform::Platform;
 
 pub struct BuildStaticArchiveInfo {
     pub archive_path: PathBuf,
@@ -40,13 +41,34 @@ impl BuildStaticArchiveInfo {
     }
 }
 
-pub fn make_args_to_build_thin_static_archive(
+/// Creates a thin archive if requested and supported and a regular archive otherwise.
+pub fn make_args_to_build_static_archive(
     archive_path: &Path,
     member_paths: &[impl AsRef<Path>],
+    thin: bool,
+) -> Vec<OsString> {
+    make_args_to_build_static_archive_for_platform(
+        archive_path,
+        member_paths,
+        thin,
+        Platform::current(),
+    )
+}
+
+fn make_args_to_build_static_archive_for_platform(
+    archive_path: &Path,
+    member_paths: &[impl AsRef<Path>],
+    thin: bool,
+    platform: Platform,
 ) -> Vec<OsString> {
     let mut args: Vec<OsString> = vec![];
-    args.push("qc".into());
-    args.push("--thin".into());
+    if thin && platform.supports_thin_archives() {
+        args.push("qc".into());
+        args.push("--thin".into());
+    } else {
+        // The linker may require a symbol table which is not created implicitly.
+        args.push("qcs".into());
+    }
     args.push(archive_path.into());
     for member_path in member_paths {
         args.push(member_path.as_ref().into());
@@ -61,7 +83,7 @@ struct ArArgs<'a> {
     members: SmallVec<[&'a OsStr; 16]>,
 }
 
-fn parse_ar_args(args: &[impl AsRef<OsStr>]) -> Result<ArArgs> {
+fn parse_ar_args(args: &[impl AsRef<OsStr>]) -> Result<ArArgs<'_>> {
     let mut operation = None;
     let mut archive = None;
     let mut members = SmallVec::new();
@@ -90,3 +112,31 @@ fn parse_ar_args(args: &[impl AsRef<OsStr>]) -> Result<ArArgs> {
         members,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive_args(thin: bool, platform: Platform) -> Vec<OsString> {
+        make_args_to_build_static_archive_for_platform(
+            Path::new("/data/x.a"),
+            &["/data/a.o", "/data/b.o"],
+            thin,
+            platform,
+        )
+    }
+
+    #[test]
+    fn mach_o_archives_are_never_thin() {
+        assert_eq!(
+            archive_args(true, Platform::Linux),
+            ["qc", "--thin", "/data/x.a", "/data/a.o", "/data/b.o"].map(OsString::from)
+        );
+        for thin in [true, false] {
+            assert_eq!(
+                archive_args(thin, Platform::MacOs),
+                ["qcs", "/data/x.a", "/data/a.o", "/data/b.o"].map(OsString::from)
+            );
+        }
+    }
+}
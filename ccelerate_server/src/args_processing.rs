@@ -1,4 +1,5 @@
 use std::{
+    collections::HashSet,
     ffi::{OsStr, OsString},
     path::{Path, PathBuf},
 };
@@ -9,20 +10,22 @@ use bstr::BString;
 use ccelerate_shared::WrappedBinary;
 use smallvec::SmallVec;
 
-use crate::{code_language::CodeLanguage, gcc_args, source_file::SourceFile};
+use crate::{code_language::CodeLanguage, gcc_args, msvc_args, source_file::SourceFile};
 
 #[derive(Debug)]
 pub struct BuildObjectFileInfo {
     pub source_path: PathBuf,
     pub source_language: CodeLanguage,
     pub object_path: PathBuf,
-    pub _depfile: Option<DepfileInfo>,
+    pub depfile: Option<DepfileInfo>,
+    // Set when the compiler should write an entry for a compilation database (`-MJ`).
+    pub compilation_database_path: Option<PathBuf>,
 }
 
 #[derive(Debug)]
 pub struct DepfileInfo {
-    pub _path: PathBuf,
-    pub _target: OsString,
+    pub path: PathBuf,
+    pub target: OsString,
 }
 
 impl BuildObjectFileInfo {
@@ -33,6 +36,7 @@ impl BuildObjectFileInfo {
     ) -> Result<Self> {
         match binary {
             binary if binary.is_gcc_compatible() => Self::from_gcc_args(cwd, args),
+            WrappedBinary::ClangCl => Self::from_msvc_args(cwd, args),
             _ => Err(anyhow!(
                 "Cannot extract build object args for binary: {:?}",
                 binary
@@ -41,12 +45,95 @@ impl BuildObjectFileInfo {
     }
 }
 
+/// Compiling multiple sources at once (e.g. `gcc -c a.c b.c`) is handled like separate
+/// commands that compile one source each.
+pub struct BuildObjectFilesInfo {
+    pub single_source_args: Vec<Vec<OsString>>,
+}
+
+impl BuildObjectFilesInfo {
+    pub fn from_args(binary: WrappedBinary, args: &[impl AsRef<OsStr>]) -> Result<Self> {
+        match binary {
+            binary if binary.is_gcc_compatible() => Ok(Self {
+                single_source_args: gcc_args::split_into_single_source_args(args)?,
+            }),
+            WrappedBinary::ClangCl => Ok(Self {
+                single_source_args: msvc_args::split_into_single_source_args(args)?,
+            }),
+            _ => Err(anyhow!(
+                "Cannot extract build object args for binary: {:?}",
+                binary
+            )),
+        }
+    }
+}
+
+pub fn is_build_object_file(binary: WrappedBinary, args: &[impl AsRef<OsStr>]) -> Result<bool> {
+    match binary {
+        binary if binary.is_gcc_compatible() => gcc_args::is_build_object_file(args),
+        WrappedBinary::ClangCl => msvc_args::is_build_object_file(args),
+        _ => Err(anyhow!("Cannot check args for binary: {:?}", binary)),
+    }
+}
+
+/// True if the arguments suppress all warnings. Warnings reported for such objects later on,
+/// e.g. when their chunk is compiled, are not shown either.
+pub fn suppresses_warnings(binary: WrappedBinary, args: &[impl AsRef<OsStr>]) -> Result<bool> {
+    match binary {
+        binary if binary.is_gcc_compatible() => gcc_args::suppresses_warnings(args),
+        WrappedBinary::ClangCl => msvc_args::suppresses_warnings(args),
+        _ => Err(anyhow!("Cannot check args for binary: {:?}", binary)),
+    }
+}
+
+/// Changes the arguments that built an object so that they build `output_path` from the code
+/// in `input_path` instead.
+pub fn update_to_build_object_from_stdin(
+    binary: WrappedBinary,
+    args: &[impl AsRef<OsStr>],
+    input_path: &Path,
+    output_path: &Path,
+) -> Result<Vec<OsString>> {
+    match binary {
+        binary if binary.is_gcc_compatible() => {
+            gcc_args::update_to_build_object_from_stdin(args, input_path, output_path)
+        }
+        WrappedBinary::ClangCl => {
+            msvc_args::update_to_build_object_from_stdin(args, input_path, output_path)
+        }
+        _ => Err(anyhow!("Cannot rewrite args for binary: {:?}", binary)),
+    }
+}
+
 pub fn rewrite_to_extract_local_code(
     binary: WrappedBinary,
     args: &[impl AsRef<OsStr>],
 ) -> Result<Vec<OsString>> {
     match binary {
         binary if binary.is_gcc_compatible() => gcc_args::rewrite_to_extract_local_code(args),
+        WrappedBinary::ClangCl => msvc_args::rewrite_to_extract_local_code(args),
+        _ => Err(anyhow!("Cannot rewrite args for binary: {:?}", binary)),
+    }
+}
+
+pub fn add_diagnostics_color_arg(
+    binary: WrappedBinary,
+    args: &[impl AsRef<OsStr>],
+) -> Result<Vec<OsString>> {
+    match binary {
+        binary if binary.is_gcc_compatible() => gcc_args::add_diagnostics_color_arg(args),
+        WrappedBinary::ClangCl => msvc_args::add_diagnostics_color_arg(args),
+        _ => Err(anyhow!("Cannot rewrite args for binary: {:?}", binary)),
+    }
+}
+
+pub fn remove_compilation_database_arg(
+    binary: WrappedBinary,
+    args: &[impl AsRef<OsStr>],
+) -> Result<Vec<OsString>> {
+    match binary {
+        binary if binary.is_gcc_compatible() => gcc_args::remove_compilation_database_arg(args),
+        WrappedBinary::ClangCl => msvc_args::remove_compilation_database_arg(args),
         _ => Err(anyhow!("Cannot rewrite args for binary: {:?}", binary)),
     }
 }
@@ -61,36 +148,63 @@ pub fn rewrite_to_get_preprocessed_headers(
         binary if binary.is_gcc_compatible() => {
             gcc_args::rewrite_to_get_preprocessed_headers(args, include_code_path, output_path)
         }
+        WrappedBinary::ClangCl => {
+            msvc_args::rewrite_to_get_preprocessed_headers(args, include_code_path, output_path)
+        }
         _ => Err(anyhow!("Cannot rewrite args for binary: {:?}", binary)),
     }
 }
 
+/// `new_sources` contains the replacement for each source of the original command.
 pub fn rewrite_to_link_sources(
     binary: WrappedBinary,
     args: &[impl AsRef<OsStr>],
-    new_sources: &[impl AsRef<Path>],
+    new_sources: &[Vec<SourceFile>],
+    new_whole_archive_sources: &[SourceFile],
+    use_link_group: bool,
 ) -> Result<Vec<OsString>> {
     match binary {
         binary if binary.is_gcc_compatible() => gcc_args::rewrite_to_link_sources(
             args,
-            &new_sources
-                .iter()
-                .map(|s| SourceFile {
-                    path: s.as_ref().to_owned(),
-                    language_override: None,
-                })
-                .collect::<Vec<_>>(),
+            new_sources,
+            new_whole_archive_sources,
+            use_link_group,
         ),
+        // The linker does not need link groups.
+        WrappedBinary::ClangCl => {
+            msvc_args::rewrite_to_link_sources(args, new_sources, new_whole_archive_sources)
+        }
         _ => Err(anyhow!("Cannot rewrite args for binary: {:?}", binary)),
     }
 }
 
 pub struct LinkFileInfo {
     pub sources: SmallVec<[SourceFile; 16]>,
+    // Sources that are between `--whole-archive` and `--no-whole-archive` in the original
+    // command. All archive members of those have to be linked, even if they are not referenced.
+    pub whole_archive_sources: HashSet<PathBuf>,
     pub output: PathBuf,
+    // The output is a shared library built with e.g. `-shared`. Its exported symbols are often
+    // only used by other binaries, not by anything within the link itself.
+    pub is_shared_library: bool,
 }
 
 impl LinkFileInfo {
+    /// Creates a link source for the given path which keeps the language that was specified
+    /// for it in the original command. This is important for source files that are compiled
+    /// and linked in a single step, e.g. `gcc -x c++ main.c -o main`.
+    pub fn to_link_source(&self, path: &Path) -> SourceFile {
+        let language_override = self
+            .sources
+            .iter()
+            .find(|s| s.path == path)
+            .and_then(|s| s.language_override);
+        SourceFile {
+            path: path.to_owned(),
+            language_override,
+        }
+    }
+
     pub fn from_args(
         binary: WrappedBinary,
         cwd: &Path,
@@ -98,6 +212,7 @@ impl LinkFileInfo {
     ) -> Result<Self> {
         match binary {
             binary if binary.is_gcc_compatible() => Self::from_gcc_args(cwd, args),
+            WrappedBinary::ClangCl => Self::from_msvc_args(cwd, args),
             _ => Err(anyhow!(
                 "Cannot extract build object args for binary: {:?}",
                 binary
@@ -109,11 +224,16 @@ impl LinkFileInfo {
 pub fn add_object_compatibility_args_to_key(
     binary: WrappedBinary,
     args: &[impl AsRef<OsStr>],
+    cwd: &Path,
+    resolve_symlinks: bool,
     key: &mut BString,
 ) -> Result<()> {
     match binary {
         binary if binary.is_gcc_compatible() => {
-            gcc_args::add_translation_unit_unspecific_args_to_key(args, key)
+            gcc_args::add_translation_unit_unspecific_args_to_key(args, cwd, resolve_symlinks, key)
+        }
+        WrappedBinary::ClangCl => {
+            msvc_args::add_translation_unit_unspecific_args_to_key(args, cwd, resolve_symlinks, key)
         }
         _ => Err(anyhow!(
             "Cannot add object compatibility args for binary: {:?}",
@@ -121,3 +241,45 @@ pub fn add_object_compatibility_args_to_key(
         )),
     }
 }
+
+pub fn add_preprocess_args_to_key(
+    binary: WrappedBinary,
+    args: &[impl AsRef<OsStr>],
+    key: &mut BString,
+) -> Result<()> {
+    match binary {
+        binary if binary.is_gcc_compatible() => gcc_args::add_preprocess_args_to_key(args, key),
+        WrappedBinary::ClangCl => msvc_args::add_preprocess_args_to_key(args, key),
+        _ => Err(anyhow!(
+            "Cannot add preprocess args for binary: {:?}",
+            binary
+        )),
+    }
+}
+
+pub fn add_link_args_to_key(
+    binary: WrappedBinary,
+    args: &[impl AsRef<OsStr>],
+    key: &mut BString,
+) -> Result<()> {
+    match binary {
+        binary if binary.is_gcc_compatible() => gcc_args::add_link_args_to_key(args, key),
+        WrappedBinary::ClangCl => msvc_args::add_link_args_to_key(args, key),
+        _ => Err(anyhow!("Cannot add link args for binary: {:?}", binary)),
+    }
+}
+
+pub fn get_linker_script_paths(
+    binary: WrappedBinary,
+    cwd: &Path,
+    args: &[impl AsRef<OsStr>],
+) -> Result<Vec<PathBuf>> {
+    match binary {
+        binary if binary.is_gcc_compatible() => gcc_args::get_linker_script_paths(cwd, args),
+        WrappedBinary::ClangCl => msvc_args::get_linker_script_paths(cwd, args),
+        _ => Err(anyhow!(
+            "Cannot get linker script paths for binary: {:?}",
+            binary
+        )),
+    }
+}
@@ -0,0 +1,181 @@
+#![deny(clippy::unwrap_used)]
+
+use std::{path::Path, time::Duration};
+
+use parking_lot::Mutex;
+
+use crate::state_persistent::PersistentState;
+
+/// Requests that arrive after the server was idle for at least this long start a new build.
+const BUILD_IDLE_GAP: Duration = Duration::from_secs(10);
+
+/// The server does not know how much work a build has. Repeated builds of the same project
+/// usually handle a similar number of requests though, so the progress is estimated based on
+/// previous builds. Every handled request counts as a task.
+pub struct BuildProgress {
+    build: Mutex<Option<Build>>,
+}
+
+struct Build {
+    id: String,
+    started: chrono::DateTime<chrono::Utc>,
+    completed_tasks: usize,
+    // Unknown for the first build.
+    expected_tasks: Option<f64>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct BuildProgressSnapshot {
+    pub completed_tasks: usize,
+    pub expected_tasks: Option<f64>,
+    pub percentage: Option<f64>,
+}
+
+impl BuildProgress {
+    pub fn new() -> Self {
+        Self {
+            build: Mutex::new(None),
+        }
+    }
+
+    /// `idle_before` is how long no request was running before this one started.
+    pub fn request_started(&self, idle_before: Option<Duration>, persistent: &PersistentState) {
+        let mut build = self.build.lock();
+        if build.is_some() && idle_before.is_none_or(|idle| idle < BUILD_IDLE_GAP) {
+            return;
+        }
+        *build = Some(Build {
+            id: uuid::Uuid::new_v4().to_string(),
+            started: chrono::Utc::now(),
+            completed_tasks: 0,
+            expected_tasks: persistent.get_expected_build_tasks().unwrap_or_else(|err| {
+                log::warn!("Failed to get expected build tasks: {}", err);
+                None
+            }),
+        });
+    }
+
+    pub fn request_finished(&self) {
+        if let Some(build) = self.build.lock().as_mut() {
+            build.completed_tasks += 1;
+        }
+    }
+
+    /// The task count is stored with every final link, because it's unknown which one is the
+    /// last of the build. The link itself is counted already, because it finishes right after.
+    pub fn link_finished(&self, output: &Path, persistent: &PersistentState) {
+        let build = self.build.lock();
+        let Some(build) = build.as_ref() else {
+            return;
+        };
+        if let Err(err) = persistent.update_build_tasks(
+            &build.id,
+            &output.to_string_lossy(),
+            build.completed_tasks + 1,
+            build.started,
+        ) {
+            log::warn!("Failed to store build tasks: {}", err);
+        }
+    }
+
+    pub fn snapshot(&self) -> BuildProgressSnapshot {
+        let build = self.build.lock();
+        let completed_tasks = build.as_ref().map_or(0, |build| build.completed_tasks);
+        let expected_tasks = build.as_ref().and_then(|build| build.expected_tasks);
+        BuildProgressSnapshot {
+            completed_tasks,
+            expected_tasks,
+            percentage: estimate_percentage(completed_tasks, expected_tasks),
+        }
+    }
+}
+
+/// Builds may have more tasks than expected, but they are never shown as more than done.
+fn estimate_percentage(completed_tasks: usize, expected_tasks: Option<f64>) -> Option<f64> {
+    let expected_tasks = expected_tasks.filter(|expected| *expected >= 1.0)?;
+    Some((completed_tasks as f64 / expected_tasks * 100.0).min(100.0))
+}
+
+impl BuildProgressSnapshot {
+    pub fn summary(&self) -> String {
+        match (self.percentage, self.expected_tasks) {
+            (Some(percentage), Some(expected_tasks)) => format!(
+                "{:.0}% done ({} of ~{:.0} tasks)",
+                percentage, self.completed_tasks, expected_tasks
+            ),
+            _ => format!("{} tasks done", self.completed_tasks),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BuildProgressSnapshot, estimate_percentage};
+    use crate::test_utils::TestEnv;
+
+    #[test]
+    fn percentage_is_estimated_from_expected_tasks() {
+        assert_eq!(estimate_percentage(3, None), None);
+        assert_eq!(estimate_percentage(0, Some(0.0)), None);
+        assert_eq!(estimate_percentage(0, Some(8.0)), Some(0.0));
+        assert_eq!(estimate_percentage(2, Some(8.0)), Some(25.0));
+        assert_eq!(estimate_percentage(8, Some(8.0)), Some(100.0));
+        // A build with more tasks than expected is not shown as more than done.
+        assert_eq!(estimate_percentage(12, Some(8.0)), Some(100.0));
+    }
+
+    #[test]
+    fn first_build_only_shows_the_task_count() {
+        let snapshot = BuildProgressSnapshot {
+            completed_tasks: 3,
+            expected_tasks: None,
+            percentage: None,
+        };
+        assert_eq!(snapshot.summary(), "3 tasks done");
+        let snapshot = BuildProgressSnapshot {
+            completed_tasks: 3,
+            expected_tasks: Some(12.0),
+            percentage: estimate_percentage(3, Some(12.0)),
+        };
+        assert_eq!(snapshot.summary(), "25% done (3 of ~12 tasks)");
+    }
+
+    #[tokio::test]
+    async fn expected_tasks_average_recent_builds_of_the_same_output() {
+        let env = TestEnv::new(&[]).await;
+        let persistent = &env.state.persistent;
+        assert_eq!(
+            persistent
+                .get_expected_build_tasks()
+                .expect("tasks can be read"),
+            None
+        );
+        let start = chrono::Utc::now();
+        let builds = [
+            ("other", 1000),
+            ("main", 100),
+            ("main", 10),
+            ("main", 20),
+            ("main", 30),
+            ("main", 40),
+            ("main", 50),
+        ];
+        for (i, (key, tasks)) in builds.into_iter().enumerate() {
+            persistent
+                .update_build_tasks(
+                    &i.to_string(),
+                    key,
+                    tasks,
+                    start + chrono::Duration::seconds(i as i64),
+                )
+                .expect("tasks can be stored");
+        }
+        // Only the last five builds of the most recent output are used.
+        assert_eq!(
+            persistent
+                .get_expected_build_tasks()
+                .expect("tasks can be read"),
+            Some(30.0)
+        );
+    }
+}
@@ -0,0 +1,273 @@
+#![deny(clippy::unwrap_used)]
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Utc};
+
+use crate::{state::State, state_persistent::PersistentState};
+
+/// Directories in the data directory that are exported unless only referenced files are
+/// exported.
+const EXPORTED_DIRS: &[&str] = &["preprocessed", "objects", "archives"];
+
+/// Writes the database and the files in the data directory into a tar archive, e.g. to restore
+/// a warm cache on a fresh CI runner. Paths in the database are stored relative to the data
+/// directory, so it does not have to be at the same location when importing. With `prune`,
+/// only the local code files that are referenced by the database are exported. With `since`,
+/// only object files built after that time are exported, which keeps incremental exports
+/// small. They are meant to be imported on top of a previous export.
+pub async fn export_cache(
+    state: &State,
+    output_path: &Path,
+    prune: bool,
+    since: Option<DateTime<Utc>>,
+) -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let snapshot_path = temp_dir.path().join("ccelerate.db");
+    state.persistent.write_snapshot(&snapshot_path, since)?;
+
+    let only_referenced = prune || since.is_some();
+    let entries: Vec<PathBuf> = if only_referenced {
+        let mut files = state
+            .persistent
+            .get_snapshot_local_code_files(&snapshot_path)?
+            .into_iter()
+            .filter(|path| path.exists())
+            .filter_map(|path| {
+                path.strip_prefix(&state.data_dir)
+                    .ok()
+                    .map(|path| path.to_owned())
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    } else {
+        EXPORTED_DIRS
+            .iter()
+            .map(PathBuf::from)
+            .filter(|dir| state.data_dir.join(dir).exists())
+            .collect()
+    };
+    let entries_path = temp_dir.path().join("entries.txt");
+    let mut entries_list = String::new();
+    for entry in &entries {
+        entries_list.push_str(&entry.to_string_lossy());
+        entries_list.push('\n');
+    }
+    tokio::fs::write(&entries_path, entries_list).await?;
+
+    let output = tokio::process::Command::new("tar")
+        .arg("-cf")
+        .arg(output_path)
+        .arg("-C")
+        .arg(temp_dir.path())
+        .arg("ccelerate.db")
+        .arg("-C")
+        .arg(&state.data_dir)
+        .arg("-T")
+        .arg(&entries_path)
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Failed to create {}: {}",
+            output_path.display(),
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    println!(
+        "Exported the database and {} {} to {}",
+        entries.len(),
+        if only_referenced {
+            "files"
+        } else {
+            "directories"
+        },
+        output_path.display()
+    );
+    Ok(())
+}
+
+/// The baseline for an incremental export is either a timestamp like `2024-05-01T12:00:00Z` or
+/// a file whose modification time is used, e.g. the previous export.
+pub fn parse_since(cwd: &Path, since: &str) -> Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(since) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    let path = cwd.join(since);
+    match std::fs::metadata(&path).and_then(|metadata| metadata.modified()) {
+        Ok(modified) => Ok(modified.into()),
+        Err(err) => Err(anyhow!(
+            "{} is neither a timestamp nor a readable file: {}",
+            since,
+            err
+        )),
+    }
+}
+
+/// Extracts a cache exported with [`export_cache`] into the data directory. This has to happen
+/// before the database is opened. If there is a database already, the imported records are
+/// merged into it, which is how incremental exports are applied.
+pub async fn import_cache(data_dir: &Path, input_path: &Path) -> Result<()> {
+    // Extract next to the destination, so that the files can be moved instead of copied.
+    let temp_dir = tempfile::tempdir_in(data_dir)?;
+    let output = tokio::process::Command::new("tar")
+        .arg("-xf")
+        .arg(input_path)
+        .arg("-C")
+        .arg(temp_dir.path())
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Failed to extract {}: {}",
+            input_path.display(),
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    let imported_db_path = temp_dir.path().join("ccelerate.db");
+    let db_path = data_dir.join("ccelerate.db");
+    let merged = db_path.exists();
+    if merged {
+        PersistentState::merge_database(&db_path, &imported_db_path)?;
+    } else {
+        tokio::fs::rename(&imported_db_path, &db_path).await?;
+    }
+    for dir in EXPORTED_DIRS {
+        move_files(&temp_dir.path().join(dir), &data_dir.join(dir))?;
+    }
+    println!(
+        "{} {} into {}",
+        if merged { "Merged" } else { "Imported" },
+        input_path.display(),
+        data_dir.display()
+    );
+    Ok(())
+}
+
+/// Moves all files in the source directory into the destination directory, replacing files that
+/// exist already.
+fn move_files(src_dir: &Path, dst_dir: &Path) -> Result<()> {
+    if !src_dir.exists() {
+        return Ok(());
+    }
+    std::fs::create_dir_all(dst_dir)?;
+    for entry in std::fs::read_dir(src_dir)? {
+        let entry = entry?;
+        let dst_path = dst_dir.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            move_files(&entry.path(), &dst_path)?;
+        } else {
+            std::fs::rename(entry.path(), dst_path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use ccelerate_shared::WrappedBinary;
+
+    use super::{export_cache, import_cache};
+    use crate::test_utils::TestEnv;
+
+    #[tokio::test]
+    async fn imported_cache_is_used_for_linking() {
+        let mut env = TestEnv::new(&[]).await;
+        env.write("a.h", "#define VALUE 7\n");
+        env.write("a.c", "#include \"a.h\"\nint a(void) { return VALUE; }\n");
+        env.write(
+            "main.c",
+            "#include <stdio.h>\nint a(void);\nint main(void) { printf(\"%d\", a()); return 0; }\n",
+        );
+        for name in ["a", "main"] {
+            env.run_ok(
+                WrappedBinary::Gcc,
+                &["-c", &format!("{}.c", name), "-o", &format!("{}.o", name)],
+            )
+            .await;
+        }
+        let export_path = env.dir.path().join("cache.tar");
+        export_cache(&env.state, &export_path, false, None)
+            .await
+            .expect("cache can be exported");
+
+        // Start from an empty data directory, like on a fresh CI runner.
+        let data_dir = env.state.data_dir.clone();
+        std::fs::remove_dir_all(&data_dir).expect("data directory can be removed");
+        std::fs::create_dir_all(&data_dir).expect("data directory can be created");
+        import_cache(&data_dir, &export_path)
+            .await
+            .expect("cache can be imported");
+        env.restart(&[]).await;
+
+        // The source can't be preprocessed anymore, so the link only works with the imported
+        // local code. Headers are still included when compiling the chunk.
+        std::fs::remove_file(env.path("a.c")).expect("source can be removed");
+        env.run_ok(WrappedBinary::Gcc, &["main.o", "a.o", "-o", "main"])
+            .await;
+        assert_eq!(env.run_program("main"), "7");
+    }
+
+    #[tokio::test]
+    async fn incremental_export_only_contains_newer_entries() {
+        let env = TestEnv::new(&[]).await;
+        env.write("old.c", "int old(void) { return 1; }\n");
+        env.write("new.c", "int new(void) { return 2; }\n");
+        env.run_ok(WrappedBinary::Gcc, &["-c", "old.c", "-o", "old.o"])
+            .await;
+        tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+        let since = chrono::Utc::now();
+        env.run_ok(WrappedBinary::Gcc, &["-c", "new.c", "-o", "new.o"])
+            .await;
+        let local_code_file = |name: &str| {
+            env.state
+                .persistent
+                .get_object_file(&env.path(name))
+                .expect("object is recorded")
+                .local_code
+                .local_code_file
+                .strip_prefix(&env.state.data_dir)
+                .expect("local code is in the data directory")
+                .to_string_lossy()
+                .to_string()
+        };
+
+        let export_path = env.dir.path().join("cache.tar");
+        export_cache(&env.state, &export_path, false, Some(since))
+            .await
+            .expect("cache can be exported");
+        let output = std::process::Command::new("tar")
+            .arg("-tf")
+            .arg(&export_path)
+            .output()
+            .expect("tar can be started");
+        assert!(output.status.success());
+        let entries = String::from_utf8_lossy(&output.stdout);
+        let entries = entries.lines().collect::<Vec<_>>();
+        assert!(entries.contains(&"ccelerate.db"), "{:?}", entries);
+        assert!(
+            entries.contains(&local_code_file("new.o").as_str()),
+            "{:?}",
+            entries
+        );
+        assert!(
+            !entries.contains(&local_code_file("old.o").as_str()),
+            "{:?}",
+            entries
+        );
+
+        let mut imported = TestEnv::new(&[]).await;
+        let data_dir = imported.state.data_dir.clone();
+        std::fs::remove_dir_all(&data_dir).expect("data directory can be removed");
+        std::fs::create_dir_all(&data_dir).expect("data directory can be created");
+        import_cache(&data_dir, &export_path)
+            .await
+            .expect("cache can be imported");
+        imported.restart(&[]).await;
+        let persistent = &imported.state.persistent;
+        assert!(persistent.get_object_file(&env.path("new.o")).is_some());
+        assert!(persistent.get_object_file(&env.path("old.o")).is_none());
+    }
+}
@@ -0,0 +1,67 @@
+#![deny(clippy::unwrap_used)]
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counts how often preprocessing could be skipped because an earlier result was reused,
+/// either for the same object file or from the preprocess result cache.
+pub struct PreprocessCacheStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    // Size of the local code that did not have to be extracted again.
+    bytes_saved: AtomicU64,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct PreprocessCacheStatsSnapshot {
+    pub hits: u64,
+    pub misses: u64,
+    pub bytes_saved: u64,
+    pub hit_rate: f64,
+}
+
+impl PreprocessCacheStats {
+    pub fn new() -> Self {
+        Self {
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            bytes_saved: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record_hit(&self, bytes_saved: u64) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        self.bytes_saved.fetch_add(bytes_saved, Ordering::Relaxed);
+    }
+
+    pub fn record_miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> PreprocessCacheStatsSnapshot {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        let total = hits + misses;
+        PreprocessCacheStatsSnapshot {
+            hits,
+            misses,
+            bytes_saved: self.bytes_saved.load(Ordering::Relaxed),
+            hit_rate: if total == 0 {
+                0.0
+            } else {
+                hits as f64 / total as f64
+            },
+        }
+    }
+}
+
+impl PreprocessCacheStatsSnapshot {
+    pub fn summary(&self) -> String {
+        format!(
+            "Preprocess cache: {} hits, {} misses ({:.1}% hit rate), {:.1} MiB saved",
+            self.hits,
+            self.misses,
+            self.hit_rate * 100.0,
+            self.bytes_saved as f64 / (1024.0 * 1024.0)
+        )
+    }
+}
@@ -0,0 +1,90 @@
+#![deny(clippy::unwrap_used)]
+
+use std::{
+    ffi::{OsStr, OsString},
+    time::Duration,
+};
+
+use anyhow::Result;
+
+use crate::CommandOutput;
+
+/// Creates the command for a compiler or linker that builds something. With a launcher, i.e. a
+/// program followed by its arguments, the program runs through it, e.g. to measure its resource
+/// usage without changing the build.
+pub fn compiler_command(
+    launcher: &[OsString],
+    program: impl AsRef<OsStr>,
+) -> tokio::process::Command {
+    match launcher.split_first() {
+        Some((launcher, launcher_args)) => {
+            let mut command = tokio::process::Command::new(launcher);
+            command.args(launcher_args).arg(program);
+            command
+        }
+        None => tokio::process::Command::new(program),
+    }
+}
+
+/// Runs the command and collects its output. A child that does not finish within the timeout
+/// (in seconds) is killed, so that e.g. a hanging compiler can't block the build forever. A
+/// timeout of zero disables it. The timeout is reported as a failed output, like any other
+/// failure of the child.
+pub async fn output_with_timeout(
+    command: &mut tokio::process::Command,
+    timeout: u64,
+) -> Result<CommandOutput> {
+    // Compiler drivers start further processes like `cc1`. Those are in the same process
+    // group, so that they can be killed together.
+    #[cfg(unix)]
+    command.process_group(0);
+    // The child is owned by the future below, so it's killed when the future is dropped.
+    let child = command.kill_on_drop(true).spawn()?;
+    let mut process_group = ProcessGroupGuard { id: child.id() };
+    let output = if timeout == 0 {
+        Some(child.wait_with_output().await?)
+    } else {
+        tokio::time::timeout(Duration::from_secs(timeout), child.wait_with_output())
+            .await
+            .ok()
+            .transpose()?
+    };
+    match output {
+        Some(output) => {
+            process_group.id = None;
+            Ok(CommandOutput::from_process_output(output))
+        }
+        None => {
+            drop(process_group);
+            Ok(CommandOutput {
+                stdout: Vec::new(),
+                stderr: format!(
+                    "{} did not finish within {} seconds and was killed\n",
+                    command.as_std().get_program().to_string_lossy(),
+                    timeout
+                )
+                .into_bytes(),
+                status: 1,
+            })
+        }
+    }
+}
+
+/// Kills the process group of a child when dropped, unless the child finished already. This
+/// also happens when the request that waits for the child is cancelled.
+struct ProcessGroupGuard {
+    id: Option<u32>,
+}
+
+impl Drop for ProcessGroupGuard {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some(id) = self.id.and_then(|id| libc::pid_t::try_from(id).ok()) {
+            // SAFETY: `killpg` has no memory safety requirements. The group still exists,
+            // because its leader was not waited for yet.
+            unsafe {
+                libc::killpg(id, libc::SIGKILL);
+            }
+        }
+    }
+}
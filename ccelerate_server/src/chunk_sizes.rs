@@ -0,0 +1,144 @@
+#![deny(clippy::unwrap_used)]
+
+use std::collections::HashMap;
+
+use bstr::{BStr, BString};
+use parking_lot::Mutex;
+
+// Avoid that chunks grow so large that a single failure becomes expensive.
+const MAX_ADAPTIVE_CHUNK_SIZE: usize = 256;
+
+/// Decides how many compatible objects are compiled together before the chunk is split up
+/// because of an error. In adaptive mode, the size is tracked per compatibility key. It grows
+/// while compiling succeeds and shrinks when a chunk has to be split.
+pub struct ChunkSizes {
+    initial: usize,
+    adaptive: bool,
+    sizes: Mutex<HashMap<BString, usize>>,
+}
+
+impl ChunkSizes {
+    pub fn new(initial: usize, adaptive: bool) -> Self {
+        Self {
+            initial: initial.max(1),
+            adaptive,
+            sizes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, key: &BStr) -> usize {
+        if !self.adaptive {
+            return self.initial;
+        }
+        self.sizes.lock().get(key).copied().unwrap_or(self.initial)
+    }
+
+    pub fn compiled_successfully(&self, key: &BStr, chunk_size: usize) {
+        if !self.adaptive {
+            return;
+        }
+        let mut sizes = self.sizes.lock();
+        let size = sizes.entry(key.to_owned()).or_insert(self.initial);
+        // Only grow when the current limit was actually used.
+        if chunk_size >= *size {
+            *size = (*size * 3 / 2).max(*size + 1).min(MAX_ADAPTIVE_CHUNK_SIZE);
+        }
+    }
+
+    pub fn compile_failed(&self, key: &BStr, chunk_size: usize) {
+        if !self.adaptive {
+            return;
+        }
+        let mut sizes = self.sizes.lock();
+        let size = sizes.entry(key.to_owned()).or_insert(self.initial);
+        *size = (*size).min(chunk_size / 2).max(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bstr::ByteSlice;
+    use ccelerate_shared::WrappedBinary;
+
+    use super::*;
+    use crate::test_utils::TestEnv;
+
+    #[test]
+    fn adaptive_sizes() {
+        let sizes = ChunkSizes::new(10, true);
+        let a = b"a".as_bstr();
+        let b = b"b".as_bstr();
+        assert_eq!(sizes.get(a), 10);
+        sizes.compiled_successfully(a, 10);
+        assert_eq!(sizes.get(a), 15);
+        // Smaller chunks don't show that larger ones would work.
+        sizes.compiled_successfully(a, 4);
+        assert_eq!(sizes.get(a), 15);
+        sizes.compile_failed(a, 15);
+        assert_eq!(sizes.get(a), 7);
+        // Keys are independent.
+        assert_eq!(sizes.get(b), 10);
+        for _ in 0..100 {
+            sizes.compiled_successfully(b, MAX_ADAPTIVE_CHUNK_SIZE);
+        }
+        assert_eq!(sizes.get(b), MAX_ADAPTIVE_CHUNK_SIZE);
+        sizes.compile_failed(b, 1);
+        assert_eq!(sizes.get(b), 1);
+        sizes.compiled_successfully(b, 1);
+        assert_eq!(sizes.get(b), 2);
+    }
+
+    #[test]
+    fn fixed_sizes() {
+        let sizes = ChunkSizes::new(10, false);
+        let a = b"a".as_bstr();
+        sizes.compiled_successfully(a, 10);
+        sizes.compile_failed(a, 3);
+        assert_eq!(sizes.get(a), 10);
+        assert_eq!(ChunkSizes::new(0, false).get(a), 1);
+    }
+
+    #[tokio::test]
+    async fn cached_chunks_dont_change_sizes() {
+        let env = TestEnv::new(&["--chunk-size", "2", "--adaptive-chunk-size"]).await;
+        env.write("a.c", "int a(void) { return 1; }");
+        env.write("b.c", "int b(void) { return 2; }");
+        env.write(
+            "main.c",
+            "#include <stdio.h>\nint a(void); int b(void);\nint main(void) { printf(\"%d\", a() + b()); return 0; }",
+        );
+        for name in ["a", "b"] {
+            env.run_ok(
+                WrappedBinary::Gcc,
+                &["-c", &format!("{}.c", name), "-o", &format!("{}.o", name)],
+            )
+            .await;
+        }
+        let recorded_sizes = || {
+            env.state
+                .chunk_sizes
+                .sizes
+                .lock()
+                .values()
+                .copied()
+                .collect::<Vec<_>>()
+        };
+        env.run_ok(WrappedBinary::Gcc, &["main.c", "a.o", "b.o", "-o", "main"])
+            .await;
+        assert_eq!(recorded_sizes(), [3]);
+        env.state
+            .chunk_sizes
+            .sizes
+            .lock()
+            .values_mut()
+            .for_each(|size| *size = 2);
+        // The chunk is reused for a different link, so the size would grow if that was counted.
+        env.run_ok(
+            WrappedBinary::Gcc,
+            &["main.c", "a.o", "b.o", "-Wl,-O1", "-o", "main2"],
+        )
+        .await;
+        assert_eq!(recorded_sizes(), [2]);
+        assert_eq!(env.run_program("main2"), "3");
+    }
+}
@@ -14,29 +14,47 @@ pub enum CodeLanguage {
     I,
     // Preprocessed C++ code.
     II,
+    // Assembly code.
+    Asm,
+    // Assembly code that has to be preprocessed first.
+    AsmCpp,
 }
 
 impl CodeLanguage {
     pub fn from_ext(ext: &str) -> Result<Self> {
-        match ext {
-            "c" => Ok(Self::C),
-            "cc" | "cp" | "cpp" | "cxx" | "c++" => Ok(Self::Cxx),
-            "i" => Ok(Self::I),
-            "ii" => Ok(Self::II),
-            _ => Err(anyhow!("Unknown language extension: {}", ext)),
-        }
+        Self::from_known_ext(ext).ok_or_else(|| anyhow!("Unknown language extension: {}", ext))
     }
 
     pub fn from_path(path: &Path) -> Result<Self> {
         Self::from_ext(path.extension().and_then(|e| e.to_str()).unwrap_or(""))
     }
 
+    /// Like [`Self::from_ext`] but without creating an error. This is used in loops over link
+    /// sources where most paths are objects or archives, which are not a code language.
+    pub fn from_known_ext(ext: &str) -> Option<Self> {
+        match ext {
+            "c" => Some(Self::C),
+            "cc" | "cp" | "cpp" | "cxx" | "c++" => Some(Self::Cxx),
+            "i" => Some(Self::I),
+            "ii" => Some(Self::II),
+            "s" | "asm" => Some(Self::Asm),
+            "S" | "sx" => Some(Self::AsmCpp),
+            _ => None,
+        }
+    }
+
+    pub fn from_known_path(path: &Path) -> Option<Self> {
+        Self::from_known_ext(path.extension().and_then(|e| e.to_str())?)
+    }
+
     pub fn valid_ext(&self) -> &'static str {
         match self {
             Self::C => "c",
             Self::Cxx => "cc",
             Self::I => "i",
             Self::II => "ii",
+            Self::Asm => "s",
+            Self::AsmCpp => "S",
         }
     }
 
@@ -46,6 +64,8 @@ impl CodeLanguage {
             "c++" => Ok(Some(Self::Cxx)),
             "cpp-output" => Ok(Some(Self::I)),
             "c++-cpp-output" => Ok(Some(Self::II)),
+            "assembler" => Ok(Some(Self::Asm)),
+            "assembler-with-cpp" => Ok(Some(Self::AsmCpp)),
             "none" => Ok(None),
             _ => Err(anyhow!("Unknown language {}", arg)),
         }
@@ -57,9 +77,17 @@ impl CodeLanguage {
             Self::Cxx => "c++",
             Self::I => "cpp-output",
             Self::II => "c++-cpp-output",
+            Self::Asm => "assembler",
+            Self::AsmCpp => "assembler-with-cpp",
         }
     }
 
+    /// Assembly has no local code that could be compiled together with other code, so it's
+    /// always assembled as is.
+    pub fn is_asm(self) -> bool {
+        matches!(self, Self::Asm | Self::AsmCpp)
+    }
+
     pub fn to_preprocessed(self) -> Result<CodeLanguage> {
         match self {
             Self::C => Ok(Self::I),
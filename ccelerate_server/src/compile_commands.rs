@@ -0,0 +1,51 @@
+#![deny(clippy::unwrap_used)]
+
+use std::{ffi::OsStr, path::Path};
+
+use anyhow::Result;
+use ccelerate_shared::WrappedBinary;
+
+use crate::args_processing::{self, BuildObjectFileInfo};
+
+/// An entry of a `compile_commands.json` compilation database as used by e.g. clangd.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct CompileCommand {
+    pub directory: String,
+    pub file: String,
+    pub output: String,
+    pub arguments: Vec<String>,
+}
+
+impl CompileCommand {
+    /// Describes the original command, not the commands that ccelerate actually runs.
+    pub fn from_build(
+        binary: WrappedBinary,
+        cwd: &Path,
+        args: &[impl AsRef<OsStr>],
+        args_info: &BuildObjectFileInfo,
+    ) -> Result<Self> {
+        let args = args_processing::remove_compilation_database_arg(binary, args)?;
+        let mut arguments = vec![
+            binary
+                .to_standard_binary_name()
+                .to_string_lossy()
+                .to_string(),
+        ];
+        arguments.extend(args.iter().map(|arg| arg.to_string_lossy().to_string()));
+        Ok(Self {
+            directory: cwd.to_string_lossy().to_string(),
+            file: args_info.source_path.to_string_lossy().to_string(),
+            output: args_info.object_path.to_string_lossy().to_string(),
+            arguments,
+        })
+    }
+
+    /// Writes the entry in the same format as `clang -MJ` does. Multiple of those can be
+    /// concatenated and wrapped in `[` and `]` to get a full compilation database.
+    pub async fn write_fragment(&self, path: &Path) -> Result<()> {
+        let mut fragment = serde_json::to_string(self)?;
+        fragment.push_str(",\n");
+        tokio::fs::write(path, fragment).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,51 @@
+#![deny(clippy::unwrap_used)]
+
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+};
+
+use anyhow::{Result, anyhow};
+
+use crate::child_process;
+
+/// The version of every compiler is only queried once per server run. It's used in cache keys,
+/// so that cached results are not reused after the compiler was updated.
+pub struct CompilerVersions {
+    versions: tokio::sync::Mutex<HashMap<OsString, String>>,
+    // See `--child-timeout`.
+    timeout: u64,
+}
+
+impl CompilerVersions {
+    pub fn new(timeout: u64) -> Self {
+        Self {
+            versions: tokio::sync::Mutex::new(HashMap::new()),
+            timeout,
+        }
+    }
+
+    /// Returns a hash of the `--version` output of the compiler.
+    pub async fn get(&self, program: &OsStr) -> Result<String> {
+        // Keep the lock while running the compiler so that it's only run once.
+        let mut versions = self.versions.lock().await;
+        if let Some(version) = versions.get(program) {
+            return Ok(version.clone());
+        }
+        let output = child_process::output_with_timeout(
+            tokio::process::Command::new(program)
+                .arg("--version")
+                .stdin(std::process::Stdio::null())
+                .stdout(std::process::Stdio::piped())
+                .stderr(std::process::Stdio::piped()),
+            self.timeout,
+        )
+        .await?;
+        if output.status != 0 {
+            return Err(anyhow!("Failed to get version of {:?}", program));
+        }
+        let version = format!("{:032x}", twox_hash::XxHash3_128::oneshot(&output.stdout));
+        versions.insert(program.to_owned(), version.clone());
+        Ok(version)
+    }
+}
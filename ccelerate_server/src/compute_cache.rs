@@ -83,17 +83,24 @@ impl<
         }
     }
 
+    /// The value is computed again on the next access.
+    pub fn remove(&self, key: &Key, time: &KeyTime) {
+        let mut map = self.map.lock();
+        if let Some(values_for_key) = map.get_mut(key) {
+            values_for_key.values_by_key.remove(time);
+        }
+    }
+
     pub fn _for_each_latest<F>(&self, mut f: F)
     where
         F: FnMut(&Key, &KeyTime, &Value),
     {
         for (key, values_for_key) in self.map.lock().iter() {
-            if let Some(max_time) = values_for_key.values_by_key.keys().max() {
-                if let Some(value) = values_for_key.values_by_key.get(max_time) {
-                    if let Some(value) = value.value.borrow().as_ref() {
-                        f(key, max_time, value);
-                    }
-                }
+            if let Some(max_time) = values_for_key.values_by_key.keys().max()
+                && let Some(value) = values_for_key.values_by_key.get(max_time)
+                && let Some(value) = value.value.borrow().as_ref()
+            {
+                f(key, max_time, value);
             }
         }
     }
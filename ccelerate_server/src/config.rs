@@ -1,16 +1,24 @@
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
+    ffi::OsString,
     path::{Path, PathBuf},
     sync::Arc,
 };
 
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use bstr::{BStr, BString};
+use ccelerate_shared::WrappedBinary;
 use parking_lot::Mutex;
 use serde::Deserialize;
 
+use crate::{
+    path_utils::{self, ProjectRoots},
+    platform::Platform,
+};
+
 pub struct ConfigManager {
     state: Mutex<ConfigState>,
+    project_roots: Arc<ProjectRoots>,
 }
 
 struct ConfigState {
@@ -25,6 +33,41 @@ pub struct Config {
     local_header_patterns: Vec<glob::Pattern>,
     include_defines: Vec<BString>,
     pure_c_header_patterns: Vec<glob::Pattern>,
+    object_extensions: Vec<String>,
+    rules: Vec<Rule>,
+    toolchain_paths: HashMap<WrappedBinary, PathBuf>,
+    preprocess_with: Vec<PreprocessWith>,
+    project_roots: Vec<PathBuf>,
+    config_files: Vec<PathBuf>,
+    // Hash of the contents of all config files.
+    fingerprint: u128,
+}
+
+/// What to do with a compile command that matches a rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleAction {
+    /// Run the command as is.
+    Eager,
+    /// Handle the command lazily, even if it matches `eager_patterns`.
+    Chunk,
+    /// Handle the command lazily but never compile the object together with others.
+    Materialize,
+}
+
+/// All conditions that are set have to match.
+struct Rule {
+    source: Option<glob::Pattern>,
+    define: Option<BString>,
+    flag: Option<BString>,
+    action: RuleAction,
+}
+
+/// Sources matching the pattern are preprocessed with another compiler than the one that
+/// compiles them.
+struct PreprocessWith {
+    source: glob::Pattern,
+    binary: WrappedBinary,
 }
 
 #[derive(Debug, Deserialize)]
@@ -33,11 +76,46 @@ struct ConfigFile {
     local_header_patterns: Vec<String>,
     include_defines: Vec<String>,
     pure_c_header_patterns: Vec<String>,
+    #[serde(default)]
+    object_extensions: Vec<String>,
+    // Relative to the directory containing the config file.
+    #[serde(default)]
+    project_root: Option<PathBuf>,
+    #[serde(default)]
+    rules: Vec<RuleFile>,
+    // Executables to run instead of the ones found on `PATH`, e.g. `gcc = "/opt/gcc/bin/gcc"`.
+    // Relative to the directory containing the config file.
+    #[serde(default)]
+    toolchain_paths: HashMap<String, PathBuf>,
+    #[serde(default)]
+    preprocess_with: Vec<PreprocessWithFile>,
+}
+
+/// E.g. `[[rules]]` with `define = "GENERATED_BY_MOC"` and `action = "eager"`.
+#[derive(Debug, Deserialize)]
+struct RuleFile {
+    // Glob pattern for the source path.
+    source: Option<String>,
+    // Name of a macro that is defined with `-D`.
+    define: Option<String>,
+    // Flag starting with `-f`, e.g. `-fno-exceptions`.
+    flag: Option<String>,
+    action: RuleAction,
+}
+
+/// E.g. `[[preprocess_with]]` with `source = "*/legacy/*.c"` and `binary = "gcc"`.
+#[derive(Debug, Deserialize)]
+struct PreprocessWithFile {
+    // Glob pattern for the source path.
+    source: String,
+    // Standard name of a gcc compatible compiler. `toolchain_paths` applies to it as well.
+    binary: String,
 }
 
 impl ConfigManager {
-    pub fn new() -> Self {
+    pub fn new(project_roots: Arc<ProjectRoots>) -> Self {
         Self {
+            project_roots,
             state: Mutex::new(ConfigState {
                 config: Arc::new(Config::new()),
                 config_files: Vec::new(),
@@ -47,6 +125,12 @@ impl ConfigManager {
         }
     }
 
+    /// Config of all config files that have been found so far. This is enough when the config
+    /// for the relevant paths was already loaded before, e.g. while handling the request.
+    pub fn current(&self) -> Arc<Config> {
+        self.state.lock().config.clone()
+    }
+
     pub fn config_for_paths(&self, paths: &[impl AsRef<Path>]) -> Result<Arc<Config>> {
         let mut state = self.state.lock();
         let mut missing_config_dirs = vec![];
@@ -81,6 +165,9 @@ impl ConfigManager {
         let mut config_files = missing_config_files;
         config_files.extend(state.config_files.iter().cloned());
         let new_config = Config::new_from_files(&config_files)?;
+        for project_root in &new_config.project_roots {
+            self.project_roots.add(project_root);
+        }
         *state = ConfigState {
             config: Arc::new(new_config),
             config_files,
@@ -92,19 +179,30 @@ impl ConfigManager {
 }
 
 impl Config {
-    fn new() -> Self {
+    pub fn new() -> Self {
         Self {
             eager_patterns: Vec::new(),
             local_header_patterns: Vec::new(),
             include_defines: Vec::new(),
             pure_c_header_patterns: Vec::new(),
+            object_extensions: vec![Platform::current().default_object_extension().to_string()],
+            rules: Vec::new(),
+            toolchain_paths: HashMap::new(),
+            preprocess_with: Vec::new(),
+            project_roots: Vec::new(),
+            config_files: Vec::new(),
+            fingerprint: 0,
         }
     }
 
     fn new_from_files(config_files: &[impl AsRef<Path>]) -> Result<Self> {
         let mut config = Self::new();
+        let mut hasher = twox_hash::XxHash3_128::new();
         for path in config_files {
+            config.config_files.push(path.as_ref().to_owned());
             let config_file = std::fs::read_to_string(path)?;
+            hasher.write(path.as_ref().as_os_str().as_encoded_bytes());
+            hasher.write(config_file.as_bytes());
             let config_file: ConfigFile = toml::from_str(config_file.as_str())?;
 
             macro_rules! add_patterns {
@@ -122,17 +220,110 @@ impl Config {
             config
                 .include_defines
                 .extend(config_file.include_defines.into_iter().map(BString::from));
+            config
+                .object_extensions
+                .extend(config_file.object_extensions);
+            for rule in config_file.rules {
+                config.rules.push(Rule {
+                    source: rule.source.as_deref().map(glob::Pattern::new).transpose()?,
+                    define: rule.define.map(BString::from),
+                    flag: rule.flag.map(BString::from),
+                    action: rule.action,
+                });
+            }
+            let config_dir = path.as_ref().parent().unwrap_or(Path::new(""));
+            for (name, binary_path) in config_file.toolchain_paths {
+                let binary = WrappedBinary::from_standard_binary_name(name.as_ref())
+                    .ok_or_else(|| anyhow!("Unknown binary in toolchain_paths: {}", name))?;
+                config
+                    .toolchain_paths
+                    .insert(binary, config_dir.join(binary_path));
+            }
+            for preprocess_with in config_file.preprocess_with {
+                let binary =
+                    WrappedBinary::from_standard_binary_name(preprocess_with.binary.as_ref())
+                        .filter(|binary| binary.is_gcc_compatible())
+                        .ok_or_else(|| {
+                            anyhow!(
+                                "Unsupported binary in preprocess_with: {}",
+                                preprocess_with.binary
+                            )
+                        })?;
+                config.preprocess_with.push(PreprocessWith {
+                    source: glob::Pattern::new(&preprocess_with.source)?,
+                    binary,
+                });
+            }
+            if let Some(project_root) = &config_file.project_root {
+                config
+                    .project_roots
+                    .push(path_utils::normalize_path(&config_dir.join(project_root)));
+            }
         }
+        config.fingerprint = hasher.finish_128();
 
         Ok(config)
     }
 
+    pub fn config_files(&self) -> &[PathBuf] {
+        &self.config_files
+    }
+
+    /// Changes when any config file changes. Results that depend on the config can be cached
+    /// with it.
+    pub fn fingerprint(&self) -> u128 {
+        self.fingerprint
+    }
+
+    /// The configured executable or the standard name that is looked up on `PATH`.
+    pub fn binary_path(&self, binary: WrappedBinary) -> OsString {
+        match self.toolchain_path(binary) {
+            Some(path) => path.to_owned().into_os_string(),
+            None => binary.to_standard_binary_name(),
+        }
+    }
+
+    pub fn toolchain_path(&self, binary: WrappedBinary) -> Option<&Path> {
+        self.toolchain_paths.get(&binary).map(|path| path.as_path())
+    }
+
+    /// Compiler that preprocesses the source instead of the one that compiles it, if any.
+    pub fn preprocess_compiler_for(&self, source: &Path) -> Option<WrappedBinary> {
+        self.preprocess_with
+            .iter()
+            .find(|preprocess_with| preprocess_with.source.matches_path(source))
+            .map(|preprocess_with| preprocess_with.binary)
+    }
+
     pub fn is_eager_path(&self, path: &Path) -> bool {
         self.eager_patterns
             .iter()
             .any(|pattern| pattern.matches_path(path))
     }
 
+    /// Action of the first rule that matches the command.
+    pub fn rule_action(
+        &self,
+        sources: &[impl AsRef<Path>],
+        defines: &[BString],
+        f_flags: &[BString],
+    ) -> Option<RuleAction> {
+        self.rules
+            .iter()
+            .find(|rule| {
+                rule.source.as_ref().is_none_or(|pattern| {
+                    sources
+                        .iter()
+                        .any(|source| pattern.matches_path(source.as_ref()))
+                }) && rule
+                    .define
+                    .as_ref()
+                    .is_none_or(|define| defines.contains(define))
+                    && rule.flag.as_ref().is_none_or(|flag| f_flags.contains(flag))
+            })
+            .map(|rule| rule.action)
+    }
+
     pub fn is_local_header(&self, path: &Path) -> bool {
         self.local_header_patterns
             .iter()
@@ -148,4 +339,68 @@ impl Config {
     pub fn is_include_define(&self, name: &BStr) -> bool {
         self.include_defines.iter().any(|define| define == name)
     }
+
+    pub fn is_object_path(&self, path: &Path) -> bool {
+        let Some(extension) = path.extension() else {
+            return false;
+        };
+        self.object_extensions
+            .iter()
+            .any(|object_extension| extension == object_extension.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn object_extensions_are_added_to_the_default() {
+        let dir = tempfile::tempdir().expect("temp dir can be created");
+        let config_path = dir.path().join("ccelerate.toml");
+        std::fs::write(
+            &config_path,
+            r#"
+            eager_patterns = []
+            local_header_patterns = []
+            include_defines = []
+            pure_c_header_patterns = []
+            object_extensions = ["obj", "lo"]
+            "#,
+        )
+        .expect("config can be written");
+        let config = Config::new_from_files(&[config_path]).expect("config can be read");
+        assert!(config.is_object_path(Path::new("/a/foo.obj")));
+        assert!(config.is_object_path(Path::new("/a/foo.lo")));
+        assert!(config.is_object_path(Path::new("/a/foo.pic.o")));
+        assert!(!config.is_object_path(Path::new("/a/foo.c")));
+        assert!(!Config::new().is_object_path(Path::new("/a/foo.obj")));
+    }
+
+    #[test]
+    fn project_roots_are_relative_to_the_config_file() {
+        let dir = tempfile::tempdir().expect("temp dir can be created");
+        let config_dir = dir.path().join("build");
+        std::fs::create_dir_all(&config_dir).expect("dir can be created");
+        std::fs::write(
+            config_dir.join("ccelerate.toml"),
+            r#"
+            eager_patterns = []
+            local_header_patterns = []
+            include_defines = []
+            pure_c_header_patterns = []
+            project_root = "../src"
+            "#,
+        )
+        .expect("config can be written");
+        let project_roots = Arc::new(ProjectRoots::default());
+        let manager = ConfigManager::new(project_roots.clone());
+        manager
+            .config_for_paths(&[config_dir.join("foo.o")])
+            .expect("config can be loaded");
+        assert_eq!(
+            project_roots.shorten(&dir.path().join("src/intern/foo.c")),
+            "intern/foo.c"
+        );
+    }
 }
@@ -0,0 +1,64 @@
+#![deny(clippy::unwrap_used)]
+
+use std::{
+    fs::File,
+    io::{Read, Seek, Write},
+    path::Path,
+};
+
+use anyhow::{Result, anyhow};
+
+/// Makes sure that only one server uses a data directory at a time. Otherwise, they would
+/// overwrite each other's files. The lock is released when this is dropped or the process ends.
+pub struct DataDirLock {
+    _file: File,
+}
+
+impl DataDirLock {
+    pub fn acquire(data_dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(data_dir)?;
+        let lock_path = data_dir.join("ccelerate.lock");
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)?;
+        if file.try_lock().is_err() {
+            let mut pid = String::new();
+            file.read_to_string(&mut pid).ok();
+            return Err(anyhow!(
+                "Another ccelerate_server (pid: {}) is already using {}",
+                pid.trim(),
+                data_dir.display()
+            ));
+        }
+        file.set_len(0)?;
+        file.rewind()?;
+        write!(file, "{}", std::process::id())?;
+        file.flush()?;
+        Ok(Self { _file: file })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_instance_fails() {
+        let dir = tempfile::tempdir().expect("temp dir");
+        let lock = DataDirLock::acquire(dir.path()).expect("first lock");
+        let error = DataDirLock::acquire(dir.path())
+            .err()
+            .expect("second lock must fail");
+        let message = error.to_string();
+        assert!(message.contains("Another ccelerate_server"), "{message}");
+        assert!(
+            message.contains(&std::process::id().to_string()),
+            "{message}"
+        );
+        drop(lock);
+        DataDirLock::acquire(dir.path()).expect("lock after release");
+    }
+}
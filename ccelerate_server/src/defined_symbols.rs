@@ -0,0 +1,178 @@
+#![deny(clippy::unwrap_used)]
+
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use ccelerate_shared::WrappedBinary;
+
+use crate::{
+    CommandOutput, args_processing, child_process, path_utils::ProjectRoots,
+    state_persistent::ObjectData,
+};
+
+/// Symbols that are defined by a translation unit when it's compiled on its own. Those are used
+/// to detect when combining multiple translation units into one would change their meaning,
+/// e.g. because both define the same global variable.
+pub async fn record_defined_symbols(
+    binary: WrappedBinary,
+    program: &OsStr,
+    args: &[impl AsRef<OsStr>],
+    cwd: &Path,
+    source_path: &Path,
+    local_code_file: &Path,
+    launcher: &[OsString],
+) -> Result<()> {
+    let object_file = tempfile::NamedTempFile::with_suffix(".o")?;
+    let build_args = args_processing::update_to_build_object_from_stdin(
+        binary,
+        args,
+        source_path,
+        object_file.path(),
+    )?;
+    let child_output = child_process::compiler_command(launcher, program)
+        .args(build_args)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .current_dir(cwd)
+        .spawn()?
+        .wait_with_output()
+        .await?;
+    if !child_output.status.success() {
+        return Err(CommandOutput::from_process_output(child_output).into());
+    }
+    let symbols = get_strong_defined_symbols(object_file.path()).await?;
+    tokio::fs::write(symbols_file_path(local_code_file), symbols.join("\n")).await?;
+    Ok(())
+}
+
+/// Finds symbols that are defined by more than one of the objects. Objects without recorded
+/// symbols are ignored.
+pub async fn find_duplicate_symbols(
+    objects: &[impl AsRef<ObjectData>],
+    project_roots: &ProjectRoots,
+) -> Result<Vec<String>> {
+    let mut defined_in: HashMap<String, Vec<&Path>> = HashMap::new();
+    for object in objects {
+        let object = object.as_ref();
+        let Ok(symbols) =
+            tokio::fs::read_to_string(symbols_file_path(&object.local_code.local_code_file)).await
+        else {
+            continue;
+        };
+        for symbol in symbols.lines().filter(|s| !s.is_empty()) {
+            defined_in
+                .entry(symbol.to_owned())
+                .or_default()
+                .push(&object.local_code.local_code_file);
+        }
+    }
+    let mut duplicates = defined_in
+        .into_iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .map(|(symbol, paths)| {
+            format!(
+                "{} ({})",
+                symbol,
+                paths
+                    .iter()
+                    .map(|p| project_roots.shorten(p))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+        .collect::<Vec<_>>();
+    duplicates.sort();
+    Ok(duplicates)
+}
+
+fn symbols_file_path(local_code_file: &Path) -> PathBuf {
+    let mut path = OsString::from(local_code_file);
+    path.push(".symbols");
+    path.into()
+}
+
+/// Global symbols that must not be defined more than once. Weak and common symbols are allowed
+/// to be defined multiple times.
+async fn get_strong_defined_symbols(object_path: &Path) -> Result<Vec<String>> {
+    let child_output = tokio::process::Command::new("nm")
+        .args(["--defined-only", "--extern-only", "--format=posix"])
+        .arg(object_path)
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()?
+        .wait_with_output()
+        .await?;
+    if !child_output.status.success() {
+        return Err(CommandOutput::from_process_output(child_output).into());
+    }
+    let mut symbols = vec![];
+    for line in String::from_utf8_lossy(&child_output.stdout).lines() {
+        let mut parts = line.split_whitespace();
+        let (Some(name), Some(kind)) = (parts.next(), parts.next()) else {
+            continue;
+        };
+        if ["T", "D", "B", "R", "G", "S"].contains(&kind) {
+            symbols.push(name.to_owned());
+        }
+    }
+    Ok(symbols)
+}
+
+#[cfg(test)]
+mod tests {
+    use ccelerate_shared::WrappedBinary;
+
+    use super::*;
+    use crate::test_utils::TestEnv;
+
+    #[tokio::test]
+    async fn duplicate_functions_are_found() {
+        let env = TestEnv::new(&["--verify-chunks"]).await;
+        env.write(
+            "a.c",
+            "int dup(void) { return 1; }\nint a(void) { return 2; }",
+        );
+        env.write("b.c", "int dup(void) { return 3; }\nstatic int b(void) { return 4; }\nint c(void) { return b(); }");
+        env.write(
+            "main.c",
+            "int a(void); int c(void);\nint main(void) { return a() + c(); }",
+        );
+        for name in ["a", "b"] {
+            env.run_ok(
+                WrappedBinary::Gcc,
+                &["-c", &format!("{}.c", name), "-o", &format!("{}.o", name)],
+            )
+            .await;
+        }
+        let objects = ["a.o", "b.o"].map(|name| {
+            env.state
+                .persistent
+                .get_object_file(&env.path(name))
+                .expect("object is recorded")
+        });
+        let duplicates = find_duplicate_symbols(&objects, &env.state.project_roots)
+            .await
+            .expect("symbols can be compared");
+        assert_eq!(duplicates.len(), 1);
+        assert!(duplicates[0].starts_with("dup ("), "{:?}", duplicates);
+
+        // The sources are compiled separately, so the linker reports the same error as without
+        // ccelerate, instead of the compiler failing with a redefinition in the chunk.
+        let output = env
+            .run(WrappedBinary::Gcc, &["main.c", "a.o", "b.o", "-o", "main"])
+            .await;
+        assert_ne!(output.status, 0);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("multiple definition of `dup'"),
+            "{}",
+            stderr
+        );
+        assert!(!stderr.contains("redefinition"), "{}", stderr);
+    }
+}
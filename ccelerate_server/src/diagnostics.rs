@@ -0,0 +1,127 @@
+#![deny(clippy::unwrap_used)]
+
+use std::collections::HashSet;
+
+use bstr::ByteSlice;
+
+/// Removes repeated diagnostics from GCC or Clang output. This happens when the same code, e.g.
+/// a shared header, is compiled multiple times and the outputs are combined.
+///
+/// A diagnostic starts with a `file:line:column: severity: message` line. Following notes and
+/// indented lines (source snippets, carets, fix-it hints) belong to it. Diagnostics are compared
+/// by their first line. Context like `In file included from` is only printed together with the
+/// first occurrence of the diagnostic that follows it.
+pub fn dedup_diagnostics(output: Vec<u8>) -> Vec<u8> {
+    let mut seen = HashSet::new();
+    let mut result = vec![];
+    let mut context: Vec<&[u8]> = vec![];
+    let mut diagnostic: Vec<&[u8]> = vec![];
+    let mut flush = |context: &mut Vec<&[u8]>, diagnostic: &mut Vec<&[u8]>| {
+        let Some(first_line) = diagnostic.first() else {
+            return;
+        };
+        if seen.insert(strip_colors(first_line).trim_end().to_vec()) {
+            for line in context.iter().chain(diagnostic.iter()) {
+                result.extend_from_slice(line);
+            }
+        }
+        context.clear();
+        diagnostic.clear();
+    };
+    for line in output.lines_with_terminator() {
+        let plain_line = strip_colors(line);
+        if is_summary_line(&plain_line) {
+            // The counts are wrong after removing diagnostics.
+            continue;
+        }
+        if let Some(severity) = diagnostic_severity(&plain_line) {
+            if severity == b"note" && !diagnostic.is_empty() {
+                diagnostic.push(line);
+            } else {
+                flush(&mut context, &mut diagnostic);
+                diagnostic.push(line);
+            }
+        } else if (line.starts_with(b" ") || line.starts_with(b"\t")) && !diagnostic.is_empty() {
+            diagnostic.push(line);
+        } else {
+            flush(&mut context, &mut diagnostic);
+            context.push(line);
+        }
+    }
+    flush(&mut context, &mut diagnostic);
+    // Remaining lines that don't belong to any diagnostic.
+    for line in context {
+        result.extend_from_slice(line);
+    }
+    result
+}
+
+fn diagnostic_severity(line: &[u8]) -> Option<&[u8]> {
+    static RE: once_cell::sync::Lazy<regex::bytes::Regex> = once_cell::sync::Lazy::new(|| {
+        regex::bytes::Regex::new(
+            r#"^[^\s].*?:\d+:(?:\d+:)? (?P<severity>fatal error|error|warning|note|remark): "#,
+        )
+        .expect("should be valid")
+    });
+    RE.captures(line)
+        .and_then(|c| c.name("severity"))
+        .map(|m| m.as_bytes())
+}
+
+fn is_summary_line(line: &[u8]) -> bool {
+    static RE: once_cell::sync::Lazy<regex::bytes::Regex> = once_cell::sync::Lazy::new(|| {
+        regex::bytes::Regex::new(
+            r#"^\d+ (?:warnings?|errors?)(?: and \d+ (?:warnings?|errors?))? generated\.\s*$"#,
+        )
+        .expect("should be valid")
+    });
+    RE.is_match(line)
+}
+
+fn strip_colors(line: &[u8]) -> std::borrow::Cow<'_, [u8]> {
+    static RE: once_cell::sync::Lazy<regex::bytes::Regex> = once_cell::sync::Lazy::new(|| {
+        regex::bytes::Regex::new(r#"\x1b\[[0-9;]*[mK]"#).expect("should be valid")
+    });
+    RE.replace_all(line, &b""[..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_warning_in_multiple_chunks() {
+        let mut output = String::new();
+        for chunk in ["a.c", "b.c", "c.c"] {
+            output.push_str(&format!(
+                "In file included from <chunk: {chunk}>:1:\n\
+                 /src/shared.h:3:12: warning: 'helper' defined but not used [-Wunused-function]\n\
+                 \x20   3 | static int helper(void) {{ return 0; }}\n\
+                 \x20     |            ^~~~~~\n\
+                 1 warning generated.\n"
+            ));
+        }
+        output.push_str("/src/c.c:2:7: warning: unused variable 'x' [-Wunused-variable]\n");
+        let deduplicated =
+            String::from_utf8(dedup_diagnostics(output.into_bytes())).expect("output is utf8");
+        assert_eq!(
+            deduplicated,
+            "In file included from <chunk: a.c>:1:\n\
+             /src/shared.h:3:12: warning: 'helper' defined but not used [-Wunused-function]\n\
+             \x20   3 | static int helper(void) { return 0; }\n\
+             \x20     |            ^~~~~~\n\
+             /src/c.c:2:7: warning: unused variable 'x' [-Wunused-variable]\n"
+        );
+    }
+
+    #[test]
+    fn colored_duplicates_are_found() {
+        let line =
+            "\x1b[01m\x1b[K/src/a.h:1:1:\x1b[m\x1b[K \x1b[01;35m\x1b[Kwarning: \x1b[m\x1b[Kfoo\n";
+        let output = format!("{line}{line}note without location\n");
+        assert_eq!(
+            dedup_diagnostics(output.into_bytes()),
+            format!("{line}note without location\n").into_bytes()
+        );
+    }
+}
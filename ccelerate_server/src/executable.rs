@@ -0,0 +1,42 @@
+#![deny(clippy::unwrap_used)]
+
+use std::{ffi::OsString, fmt, path::PathBuf};
+
+use ccelerate_shared::WrappedBinary;
+
+use crate::config::Config;
+
+/// A wrapped binary together with the executable that the wrapper stands in for. The wrapper
+/// finds it on the `PATH` of the build, which can differ from the `PATH` of the server.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Executable {
+    pub binary: WrappedBinary,
+    pub resolved_path: Option<PathBuf>,
+}
+
+impl Executable {
+    pub fn new(binary: WrappedBinary, resolved_path: Option<PathBuf>) -> Self {
+        Self {
+            binary,
+            resolved_path,
+        }
+    }
+
+    /// A path configured in `toolchain_paths` is used even if the wrapper resolved another one,
+    /// because it's set explicitly.
+    pub fn program(&self, config: &Config) -> OsString {
+        match config
+            .toolchain_path(self.binary)
+            .or(self.resolved_path.as_deref())
+        {
+            Some(path) => path.as_os_str().to_owned(),
+            None => self.binary.to_standard_binary_name(),
+        }
+    }
+}
+
+impl fmt::Display for Executable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.binary.fmt(f)
+    }
+}
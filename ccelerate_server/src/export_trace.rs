@@ -40,9 +40,17 @@ pub async fn export(
             "name".into(),
             serde_json::Value::String(period.name.clone()),
         );
+        if let Some(request_id) = period.request_id {
+            args.insert(
+                "request_id".into(),
+                serde_json::Value::String(request_id.to_string()),
+            );
+        }
 
         let mut name = period.category.clone();
-        if !period.finished_successfully {
+        if period.queued {
+            name.push_str(" (queued)");
+        } else if !period.finished_successfully {
             name.push_str(" (failed)");
         }
 
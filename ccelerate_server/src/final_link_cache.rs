@@ -0,0 +1,152 @@
+#![deny(clippy::unwrap_used)]
+
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Result;
+use bstr::{BString, ByteVec};
+use parking_lot::Mutex;
+
+use crate::{
+    args_processing,
+    compute_cache::ComputeCache,
+    executable::Executable,
+    link_sources::OriginalLinkSources,
+    state_persistent::InputFileStamp,
+    wrap_compile_object_file::{content_hash, get_input_stamp},
+};
+
+/// Remembers the outputs of final links, so that a link whose inputs did not change does not
+/// have to be done again. Like [`crate::object_by_inputs_cache::ObjectByInputsCache`], entries
+/// are keyed by the inputs and the time of the newest object build.
+pub struct FinalLinkCache {
+    cache: ComputeCache<
+        String,
+        chrono::DateTime<chrono::FixedOffset>,
+        Arc<Mutex<Option<LinkedOutput>>>,
+    >,
+}
+
+#[derive(Debug, Clone)]
+pub struct LinkedOutput {
+    pub path: PathBuf,
+    // Used to detect that the output was changed or removed after it was linked.
+    pub stamp: InputFileStamp,
+    // Files the output was linked from, which are needed to write the manifest again.
+    pub archive_paths: Vec<PathBuf>,
+    pub chunk_object_paths: Vec<PathBuf>,
+}
+
+impl FinalLinkCache {
+    pub fn new() -> Self {
+        Self {
+            cache: ComputeCache::new(),
+        }
+    }
+
+    /// The returned slot contains the output of a previous link with the same inputs. It's
+    /// empty if there is none yet.
+    pub async fn get(
+        &self,
+        key: &str,
+        time: chrono::DateTime<chrono::FixedOffset>,
+    ) -> Arc<Mutex<Option<LinkedOutput>>> {
+        self.cache
+            .get(&key.to_owned(), &time, async || Arc::new(Mutex::new(None)))
+            .await
+    }
+}
+
+/// Identifies a final link by its command (without the output) and inputs. Objects compiled
+/// here are identified by their path, their build time is returned separately. Other inputs
+/// (e.g. prebuilt libraries) are identified by their stamps. Returns `None` if the link should
+/// not be cached, e.g. because no object is compiled here.
+pub async fn create_key(
+    executable: &Executable,
+    args: &[impl AsRef<OsStr>],
+    cwd: &Path,
+    link_sources: &OriginalLinkSources,
+) -> Result<Option<(String, chrono::DateTime<chrono::FixedOffset>)>> {
+    let objects = link_sources
+        .known_object_files
+        .iter()
+        .chain(link_sources.whole_archive_known_object_files.iter());
+    let Some(time) = objects.clone().map(|object| object.last_build).max() else {
+        return Ok(None);
+    };
+    let mut key = BString::default();
+    key.push_str(
+        executable
+            .binary
+            .to_standard_binary_name()
+            .as_encoded_bytes(),
+    );
+    key.push(0);
+    if let Some(resolved_path) = &executable.resolved_path {
+        key.push_str(resolved_path.as_os_str().as_encoded_bytes());
+    }
+    key.push(0);
+    key.push_str(cwd.as_os_str().as_encoded_bytes());
+    key.push(0);
+    // The output is not part of the key, because linking to another path gives the same
+    // result.
+    args_processing::add_link_args_to_key(executable.binary, args, &mut key)?;
+    for object in objects {
+        key.push_str(object.path.as_os_str().as_encoded_bytes());
+        key.push(0);
+    }
+    // Version scripts change the exported symbols without changing the arguments.
+    let linker_scripts = args_processing::get_linker_script_paths(executable.binary, cwd, args)?;
+    for path in link_sources
+        .unknown_sources
+        .iter()
+        .chain(link_sources.whole_archive_unknown_sources.iter())
+        .chain(linker_scripts.iter())
+    {
+        let Ok(stamp) = get_input_stamp(path).await else {
+            return Ok(None);
+        };
+        key.push_str(
+            format!("{}:{}:{}", path.display(), stamp.size, stamp.modified_nanos).as_bytes(),
+        );
+        key.push(0);
+    }
+    Ok(Some((content_hash(&key), time)))
+}
+
+/// Makes the previously linked output available at the given path. Returns the reused output
+/// which replaces the previous one in the cache, or `None` if the previous output was changed
+/// since it was linked.
+pub async fn reuse_linked_output(
+    linked: &LinkedOutput,
+    output: &Path,
+) -> Result<Option<LinkedOutput>> {
+    let Ok(current) = get_input_stamp(&linked.path).await else {
+        return Ok(None);
+    };
+    if current.size != linked.stamp.size || current.modified_nanos != linked.stamp.modified_nanos {
+        return Ok(None);
+    }
+    if linked.path != output {
+        // Copied instead of hard linked, so that changing one of them in place (e.g. with
+        // strip) does not affect the other.
+        tokio::fs::copy(&linked.path, output).await?;
+    }
+    // The build system expects the output to be newer than its inputs, like after linking.
+    let file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(output)
+        .await?
+        .into_std()
+        .await;
+    tokio::task::spawn_blocking(move || file.set_modified(std::time::SystemTime::now())).await??;
+    Ok(Some(LinkedOutput {
+        path: output.to_owned(),
+        stamp: get_input_stamp(output).await?,
+        archive_paths: linked.archive_paths.clone(),
+        chunk_object_paths: linked.chunk_object_paths.clone(),
+    }))
+}
@@ -1,4 +1,5 @@
 use std::{
+    collections::HashSet,
     ffi::{OsStr, OsString},
     path::{Path, PathBuf},
 };
@@ -10,17 +11,20 @@ use os_str_bytes::OsStrBytesExt;
 use smallvec::{SmallVec, smallvec};
 
 use crate::args_processing::{BuildObjectFileInfo, DepfileInfo, LinkFileInfo};
-use crate::{code_language::CodeLanguage, path_utils::make_absolute, source_file::SourceFile};
+use crate::platform::{Platform, WholeArchiveSyntax};
+use crate::{
+    code_language::CodeLanguage,
+    path_utils::{self, make_absolute},
+    source_file::SourceFile,
+};
 
 impl BuildObjectFileInfo {
     pub fn from_gcc_args(cwd: &Path, args: &[impl AsRef<OsStr>]) -> Result<Self> {
         let args = GccArgsInfo::from_args(args)?;
-        let Some(output) = args.get_single_output() else {
-            return Err(anyhow!("There has to be one output"));
-        };
-        if output.extension() != Some(OsStr::new("o")) {
-            return Err(anyhow!("Output must have .o extension"));
+        if args.writes_output_to_stdout() {
+            return Err(anyhow!("The object file is written to stdout"));
         }
+        let object_path = args.get_absolute_single_output(cwd)?;
         let sources = match args.get_sources() {
             Ok(sources) => sources,
             Err(e) => {
@@ -34,7 +38,7 @@ impl BuildObjectFileInfo {
             Some(language) => language,
             None => CodeLanguage::from_path(source.path)?,
         };
-        let depfile = if args.has_single_arg_str("-MD") {
+        let depfile = if args.writes_depfile() {
             let path = args
                 .get_depfile_path()
                 .ok_or_else(|| anyhow!("Failed to get path of depfile"))?;
@@ -42,8 +46,8 @@ impl BuildObjectFileInfo {
                 .get_depfile_target()
                 .ok_or_else(|| anyhow!("Failed to get target of depfile"))?;
             Some(DepfileInfo {
-                _path: make_absolute(cwd, path),
-                _target: target.to_owned(),
+                path: make_absolute(cwd, &path),
+                target,
             })
         } else {
             None
@@ -51,8 +55,11 @@ impl BuildObjectFileInfo {
         Ok(Self {
             source_path: make_absolute(cwd, source.path),
             source_language,
-            object_path: make_absolute(cwd, Path::new(output)),
-            _depfile: depfile,
+            object_path,
+            depfile,
+            compilation_database_path: args
+                .get_compilation_database_path()
+                .map(|path| make_absolute(cwd, path)),
         })
     }
 }
@@ -62,7 +69,9 @@ impl LinkFileInfo {
         let args = GccArgsInfo::from_args(args)?;
         Ok(Self {
             sources: args.get_absolute_sources(cwd)?,
+            whole_archive_sources: args.get_absolute_whole_archive_sources(cwd),
             output: args.get_absolute_single_output(cwd)?,
+            is_shared_library: args.has_single_arg_str("-shared"),
         })
     }
 }
@@ -70,14 +79,68 @@ impl LinkFileInfo {
 pub struct BuildFilesInfo {
     pub sources: SmallVec<[SourceFile; 16]>,
     pub output: Option<PathBuf>,
+    // Objects compiled with link time optimization contain compiler specific intermediate code
+    // instead of machine code. Those can't be reproduced by compiling chunks of local code.
+    pub uses_lto: bool,
+    // The command creates or uses a precompiled header. Its content is not part of the
+    // preprocessed code, so the command can't be split into local code and headers.
+    pub uses_pch: bool,
+    // The command only prints information about the toolchain or the commands it would run,
+    // e.g. `--version` or `-###`. Those have to run as is even if they have an output.
+    pub is_query: bool,
+    // The output is written to stdout with `-o -`. There is no output file in this case.
+    pub output_to_stdout: bool,
+    // Compiling multiple sources with `-c` and without `-o` creates an object file for each
+    // source in the current directory.
+    pub implied_outputs: Vec<PathBuf>,
+    // Directories passed with `-B` where the driver looks for the linker and other tools first.
+    // These are kept in all rewritten commands, because otherwise a different toolchain is used.
+    pub prefix_dirs: Vec<PathBuf>,
+    // Names of macros defined with `-D`, without their values.
+    pub defines: Vec<BString>,
+    // Flags starting with `-f` like `-fno-exceptions`.
+    pub f_flags: Vec<BString>,
 }
 
 impl BuildFilesInfo {
     pub fn from_args(cwd: &Path, args: &[impl AsRef<OsStr>]) -> Result<Self> {
         let args = GccArgsInfo::from_args(args)?;
+        let output_to_stdout = args.writes_output_to_stdout();
         Ok(Self {
             sources: args.get_absolute_sources(cwd)?,
-            output: args.get_absolute_single_output(cwd).ok(),
+            output: if output_to_stdout {
+                None
+            } else {
+                args.get_absolute_single_output(cwd).ok()
+            },
+            uses_lto: args.uses_lto(),
+            uses_pch: args.uses_pch(cwd),
+            is_query: args.is_query(),
+            output_to_stdout,
+            implied_outputs: match args.get_single_output() {
+                Some(_) => vec![],
+                None => args
+                    .get_implied_object_outputs()
+                    .iter()
+                    .map(|output| make_absolute(cwd, output))
+                    .collect(),
+            },
+            prefix_dirs: args
+                .get_prefix_dirs()
+                .iter()
+                .map(|dir| make_absolute(cwd, dir))
+                .collect(),
+            defines: args.get_defines(),
+            f_flags: args
+                .args
+                .iter()
+                .filter_map(|arg| match arg {
+                    GccArg::Single(arg) if arg.starts_with("-f") => {
+                        Some(BString::from(arg.as_encoded_bytes()))
+                    }
+                    _ => None,
+                })
+                .collect(),
         })
     }
 }
@@ -87,33 +150,104 @@ pub fn is_build_object_file(args: &[impl AsRef<OsStr>]) -> Result<bool> {
     Ok(args.has_single_arg_str("-c"))
 }
 
+/// True if the arguments suppress all warnings with `-w`.
+pub fn suppresses_warnings(args: &[impl AsRef<OsStr>]) -> Result<bool> {
+    let args = GccArgsInfo::from_args(args)?;
+    Ok(args.has_single_arg_str("-w"))
+}
+
+/// Splits a command that compiles multiple sources to object files at once into commands that
+/// compile one source each. Arguments like `-x` that apply to following sources are kept, so
+/// every source is still compiled the same way.
+pub fn split_into_single_source_args(args: &[impl AsRef<OsStr>]) -> Result<Vec<Vec<OsString>>> {
+    let args = GccArgsInfo::from_args(args)?;
+    let sources_num = args
+        .args
+        .iter()
+        .filter(|arg| matches!(arg, GccArg::Source(_)))
+        .count();
+    Ok((0..sources_num)
+        .map(|source_index| {
+            let mut single_args = args.clone();
+            let mut current_index = 0;
+            single_args.args.retain(|arg| match arg {
+                GccArg::Source(_) => {
+                    current_index += 1;
+                    current_index - 1 == source_index
+                }
+                _ => true,
+            });
+            single_args.to_args_owned_vec()
+        })
+        .collect())
+}
+
 /// Takes arguments that would build one object file and changes it so that it instead
 /// outputs the preprocessed code for the source file.
 pub fn rewrite_to_extract_local_code(args: &[impl AsRef<OsStr>]) -> Result<Vec<OsString>> {
     let mut args = GccArgsInfo::from_args(args)?;
-    args.args.retain(|arg| match arg {
-        GccArg::Single(arg) => {
-            if *arg == "-c" {
-                // Remove -c, it is replaced by -E below to stop after preprocessing.
-                false
-            } else {
-                true
-            }
+    // Without `-o`, the compiler would derive the depfile path and target from the source name
+    // instead of the object path, so they are passed explicitly.
+    let depfile_args = if args.writes_depfile() {
+        let mut depfile_args = vec![];
+        if !args.has_dual_arg_str("-MF") {
+            let path = args
+                .get_depfile_path()
+                .ok_or_else(|| anyhow!("Failed to get path of depfile"))?;
+            depfile_args.extend([OsString::from("-MF"), path.into_os_string()]);
         }
-        GccArg::Dual(first, _) => {
-            if *first == "-o" {
-                // Remove output file so that output is written to stdout.
-                false
-            } else {
-                true
-            }
+        if !args.has_dual_arg_str("-MT") && !args.has_dual_arg_str("-MQ") {
+            let target = args
+                .get_depfile_target()
+                .ok_or_else(|| anyhow!("Failed to get target of depfile"))?;
+            depfile_args.extend([OsString::from("-MT"), target]);
         }
+        depfile_args
+    } else {
+        vec![]
+    };
+    args.args.retain(|arg| match arg {
+        // Remove -c, it is replaced by -E below to stop after preprocessing.
+        GccArg::Single(arg) if *arg == "-c" => false,
+        // Remove output file so that output is written to stdout.
+        GccArg::Dual(first, _) if *first == "-o" => false,
+        // The compilation database entry is written separately.
+        GccArg::Dual(first, _) if *first == "-MJ" => false,
         _ => true,
     });
     // Stop after preprocessing.
     args.push_single_arg_str("-E");
     // Keep defines in preprocessed output.
     args.push_single_arg_str("-dD");
+    let mut args = args.to_args_owned_vec();
+    args.extend(depfile_args);
+    Ok(args)
+}
+
+/// Makes the compiler color its diagnostics unless the arguments decide that already.
+pub fn add_diagnostics_color_arg(args: &[impl AsRef<OsStr>]) -> Result<Vec<OsString>> {
+    let mut args = GccArgsInfo::from_args(args)?;
+    let has_color_arg = args.args.iter().any(|arg| match arg {
+        GccArg::Single(arg) => {
+            arg.starts_with("-fdiagnostics-color")
+                || arg.starts_with("-fno-diagnostics-color")
+                // Clang specific alternatives.
+                || *arg == "-fcolor-diagnostics"
+                || *arg == "-fno-color-diagnostics"
+        }
+        _ => false,
+    });
+    if !has_color_arg {
+        args.push_single_arg_str("-fdiagnostics-color=always");
+    }
+    Ok(args.to_args_owned_vec())
+}
+
+/// Removes the argument that makes the compiler write a compilation database entry.
+pub fn remove_compilation_database_arg(args: &[impl AsRef<OsStr>]) -> Result<Vec<OsString>> {
+    let mut args = GccArgsInfo::from_args(args)?;
+    args.args
+        .retain(|arg| !matches!(arg, GccArg::Dual(first, _) if *first == "-MJ"));
     Ok(args.to_args_owned_vec())
 }
 
@@ -139,9 +273,15 @@ pub fn rewrite_to_get_preprocessed_headers(
             if *first == "-o" {
                 // Remove output, it's added again below.
                 false
-            } else if *first == "-MT" || *first == "-MF" {
+            } else if *first == "-x" {
+                // Remove language overrides, the language of the new input is set below.
+                false
+            } else if *first == "-MT" || *first == "-MQ" || *first == "-MF" {
                 // Remove some depsfile generation arguments.
                 false
+            } else if *first == "-MJ" {
+                // Don't write compilation database entries for temporary files.
+                false
             } else {
                 true
             }
@@ -154,8 +294,10 @@ pub fn rewrite_to_get_preprocessed_headers(
     // Set output file.
     args.push_dual_arg(OsStr::new("-o"), output_path.as_os_str());
     // Set input file.
-    args.push_source_arg(include_code_path);
-    Ok(args.to_args_owned_vec())
+    args.push_source_with_language_arg(include_code_path);
+    Ok(remove_preprocessor_depfile_options(
+        args.to_args_owned_vec(),
+    ))
 }
 
 pub fn update_to_build_object_from_stdin(
@@ -164,9 +306,12 @@ pub fn update_to_build_object_from_stdin(
     output_path: &Path,
 ) -> Result<Vec<OsString>> {
     let mut args = GccArgsInfo::from_args(args)?;
+    // Warning options like `-w` are kept, so that the compiled chunk reports the same warnings
+    // as the original compilation. Objects with different warning options are never compiled
+    // in the same chunk because all single arguments are part of the compatibility key.
     args.args.retain(|arg| match arg {
         GccArg::Single(arg) => {
-            if *arg == "-MD" {
+            if *arg == "-MD" || *arg == "-MMD" {
                 // Disable depsfile generation.
                 false
             } else {
@@ -177,9 +322,15 @@ pub fn update_to_build_object_from_stdin(
             if *first == "-o" {
                 // Remove output file because it's replaced below.
                 false
-            } else if *first == "-MT" || *first == "-MF" {
+            } else if *first == "-x" {
+                // Remove language overrides, the language of the new input is set below.
+                false
+            } else if *first == "-MT" || *first == "-MQ" || *first == "-MF" {
                 // Remove some depsfile generation arguments.
                 false
+            } else if *first == "-MJ" {
+                // Don't write compilation database entries for temporary files.
+                false
             } else {
                 true
             }
@@ -190,58 +341,231 @@ pub fn update_to_build_object_from_stdin(
     // Set output file.
     args.push_dual_arg(OsStr::new("-o"), output_path.as_os_str());
     // Set input file.
-    args.push_source_arg(input_path);
-    Ok(args.to_args_owned_vec())
+    args.push_source_with_language_arg(input_path);
+    Ok(remove_preprocessor_depfile_options(
+        args.to_args_owned_vec(),
+    ))
 }
 
+/// Every source of the original command is replaced by the corresponding entry in `sources`
+/// at its original position, so that the sources keep their order relative to e.g. `-l`
+/// arguments.
 pub fn rewrite_to_link_sources(
     args: &[impl AsRef<OsStr>],
-    sources: &[SourceFile],
+    sources: &[Vec<SourceFile>],
+    whole_archive_sources: &[SourceFile],
+    use_link_group: bool,
 ) -> Result<Vec<OsString>> {
-    let mut args = GccArgsInfo::from_args(args)?;
-    args.args.retain(|arg| match arg {
-        GccArg::Single(_) => true,
-        GccArg::Dual(_, _) => true,
-        // Remove all sources, they are added again below.
-        GccArg::Source(_) => false,
-    });
+    rewrite_to_link_sources_for_platform(
+        args,
+        sources,
+        whole_archive_sources,
+        use_link_group,
+        Platform::current(),
+    )
+}
 
-    // Add all sources to a link group so that their order does not matter.
-    args.push_single_arg_str("-Wl,--start-group");
-    for source in sources {
-        match source.language() {
-            Ok(language) => {
-                args.push_dual_arg_str("-x", language.to_gcc_x_arg());
+fn rewrite_to_link_sources_for_platform(
+    args: &[impl AsRef<OsStr>],
+    sources: &[Vec<SourceFile>],
+    whole_archive_sources: &[SourceFile],
+    use_link_group: bool,
+    platform: Platform,
+) -> Result<Vec<OsString>> {
+    let old_args = GccArgsInfo::from_args(args)?;
+    let source_count = old_args
+        .args
+        .iter()
+        .filter(|arg| matches!(arg, GccArg::Source(_)))
+        .count();
+    if source_count != sources.len() {
+        return Err(anyhow!(
+            "Expected replacements for {} sources, got {}",
+            source_count,
+            sources.len()
+        ));
+    }
+    let mut writer = LinkSourcesWriter {
+        args: GccArgsInfo { args: smallvec![] },
+        use_link_group: use_link_group && platform.supports_link_groups(),
+        in_link_group: false,
+        last_source: None,
+    };
+    // The whole archive sources are put where the first original `--whole-archive` source
+    // was, or after the last source if there is none.
+    let mut whole_archive_sources = Some(whole_archive_sources).filter(|s| !s.is_empty());
+    let mut in_whole_archive = false;
+    let mut sources_iter = sources.iter();
+    for arg in old_args.args.iter() {
+        match arg {
+            GccArg::Source(_) => {
+                let Some(new_sources) = sources_iter.next() else {
+                    break;
+                };
+                if in_whole_archive
+                    && let Some(whole_archive_sources) = whole_archive_sources.take()
+                {
+                    writer.push_whole_archive_sources(whole_archive_sources, platform)?;
+                }
+                for source in new_sources {
+                    writer.push_source(source);
+                }
+                if sources_iter.len() == 0 {
+                    if let Some(whole_archive_sources) = whole_archive_sources.take() {
+                        writer.push_whole_archive_sources(whole_archive_sources, platform)?;
+                    }
+                    writer.end_link_group();
+                }
             }
-            Err(_) => {
-                args.push_single_arg_str("-x");
-                args.push_single_arg_str("none");
+            GccArg::Single(single) => match LinkerMarker::from_arg(single) {
+                // Remove markers for whole archive regions and groups, they are added again
+                // where necessary. Otherwise, they could apply to different sources now.
+                Some(LinkerMarker::WholeArchive) => in_whole_archive = true,
+                Some(LinkerMarker::NoWholeArchive) => in_whole_archive = false,
+                Some(LinkerMarker::StartGroup | LinkerMarker::EndGroup) => {}
+                None => writer.push_arg(arg.clone()),
+            },
+            GccArg::Dual(_, _) => writer.push_arg(arg.clone()),
+        }
+    }
+    if let Some(whole_archive_sources) = whole_archive_sources.take() {
+        writer.push_whole_archive_sources(whole_archive_sources, platform)?;
+        writer.end_link_group();
+    }
+    Ok(writer.args.to_args_owned_vec())
+}
+
+struct LinkSourcesWriter<'a> {
+    args: GccArgsInfo<'a>,
+    use_link_group: bool,
+    in_link_group: bool,
+    // The previously added source if nothing else was added after it. Repeating it directly
+    // does not change the link, so that is skipped.
+    last_source: Option<&'a SourceFile>,
+}
+
+impl<'a> LinkSourcesWriter<'a> {
+    fn push_arg(&mut self, arg: GccArg<'a>) {
+        self.args.args.push(arg);
+        self.last_source = None;
+    }
+
+    /// Consecutive archives and objects are put into a link group so that their order does
+    /// not matter. Shared libraries are kept outside of groups, because their position affects
+    /// symbol resolution differently, e.g. with `--as-needed`.
+    fn push_source(&mut self, source: &'a SourceFile) {
+        if self.last_source == Some(source) {
+            return;
+        }
+        if source.is_shared_library() {
+            self.end_link_group();
+        } else {
+            self.start_link_group();
+        }
+        self.args.push_link_sources(std::slice::from_ref(source));
+        self.last_source = Some(source);
+    }
+
+    fn push_whole_archive_sources(
+        &mut self,
+        sources: &'a [SourceFile],
+        platform: Platform,
+    ) -> Result<()> {
+        self.start_link_group();
+        self.last_source = None;
+        match platform.whole_archive_syntax() {
+            WholeArchiveSyntax::Region => {
+                self.args.push_single_arg_str("-Wl,--whole-archive");
+                self.args.push_link_sources(sources);
+                self.args.push_single_arg_str("-Wl,--no-whole-archive");
+            }
+            WholeArchiveSyntax::ForceLoad => {
+                // Object files are always loaded entirely anyway.
+                for source in sources {
+                    if source.path.extension() == Some(OsStr::new("a")) {
+                        self.args
+                            .push_dual_arg(OsStr::new("-Xlinker"), OsStr::new("-force_load"));
+                        self.args
+                            .push_dual_arg(OsStr::new("-Xlinker"), source.path.as_os_str());
+                    } else {
+                        self.args.push_link_sources(std::slice::from_ref(source));
+                    }
+                }
+            }
+            WholeArchiveSyntax::Prefix => {
+                return Err(anyhow!(
+                    "Whole archive linking is not supported on this platform yet"
+                ));
             }
         }
-        args.push_source_arg(&source.path);
+        Ok(())
     }
-    args.push_single_arg_str("-Wl,--end-group");
 
-    Ok(args.to_args_owned_vec())
+    fn start_link_group(&mut self) {
+        if self.use_link_group && !self.in_link_group {
+            self.args.push_single_arg_str("-Wl,--start-group");
+            self.in_link_group = true;
+        }
+    }
+
+    fn end_link_group(&mut self) {
+        if self.in_link_group {
+            self.args.push_single_arg_str("-Wl,--end-group");
+            self.in_link_group = false;
+        }
+    }
 }
 
+/// Include paths are normalized, so that differently spelled paths to the same directory
+/// don't prevent objects from being compiled together. Their order is kept because it matters.
 pub fn add_translation_unit_unspecific_args_to_key(
     args: &[impl AsRef<OsStr>],
+    cwd: &Path,
+    resolve_symlinks: bool,
     key: &mut BString,
 ) -> Result<()> {
+    let normalize_include_path = |path: &OsStr| -> OsString {
+        if path == "-" {
+            // `-I-` is a special marker and not a path.
+            return path.to_owned();
+        }
+        let path = make_absolute(cwd, Path::new(path));
+        if resolve_symlinks && let Ok(path) = std::fs::canonicalize(&path) {
+            return path.into_os_string();
+        }
+        path_utils::normalize_path(&path).into_os_string()
+    };
     let args = GccArgsInfo::from_args(args)?;
     for arg in args.args.iter() {
         match arg {
             GccArg::Single(arg) => {
+                if LINK_ONLY_ARGS.iter().any(|link_arg| arg == link_arg) {
+                    // Objects can be compiled together even if they are linked differently.
+                    continue;
+                }
+                if let Some((flag, path)) = split_joined_include_arg(arg) {
+                    key.push_str(flag.as_bytes());
+                    key.push_str(normalize_include_path(path).as_encoded_bytes());
+                    continue;
+                }
                 key.push_str(arg.as_encoded_bytes());
             }
+            GccArg::Dual(first, second) if INCLUDE_PATH_ARGS.iter().any(|a| first == a) => {
+                key.push_str(first.as_encoded_bytes());
+                key.push_str(normalize_include_path(second).as_encoded_bytes());
+            }
             GccArg::Dual(first, second) => {
                 if *first == "-o" {
                     // Don't add output file.
                     continue;
                 }
-                if *first == "-MT" || *first == "-MF" {
-                    // Don't add depsfile generation arguments.
+                if *first == "-MT" || *first == "-MQ" || *first == "-MF" || *first == "-MJ" {
+                    // Don't add depsfile and compilation database generation arguments.
+                    continue;
+                }
+                if *first == "-x" {
+                    // The language of the source is part of the key already, no matter if it
+                    // comes from the extension or from `-x`.
                     continue;
                 }
                 key.push_str(first.as_encoded_bytes());
@@ -254,12 +578,181 @@ pub fn add_translation_unit_unspecific_args_to_key(
     Ok(())
 }
 
+/// Adds all arguments that affect the output of preprocessing the source. The output file is
+/// not added, it only affects the default depfile target which has to be added separately.
+pub fn add_preprocess_args_to_key(args: &[impl AsRef<OsStr>], key: &mut BString) -> Result<()> {
+    let args = GccArgsInfo::from_args(args)?;
+    for arg in args.args.iter() {
+        match arg {
+            GccArg::Single(arg) if *arg == "-c" => {}
+            GccArg::Single(arg) => {
+                if let Some(mut options) = preprocessor_options(arg)
+                    && options.any(|option| option == "-MD" || option == "-MMD")
+                {
+                    // The depfile written this way is not known, so it could not be restored.
+                    return Err(anyhow!("Depfile options passed with -Wp are not supported"));
+                }
+                key.push_str(arg.as_encoded_bytes());
+            }
+            GccArg::Dual(first, _) if *first == "-o" || *first == "-MF" || *first == "-MJ" => {}
+            GccArg::Dual(first, second) => {
+                key.push_str(first.as_encoded_bytes());
+                key.push_str(b" ");
+                key.push_str(second.as_encoded_bytes());
+            }
+            GccArg::Source(_) => {}
+        }
+        // Separate arguments so that different splits don't result in the same key.
+        key.push(0);
+    }
+    Ok(())
+}
+
+/// Adds all arguments of a link except for the output file.
+pub fn add_link_args_to_key(args: &[impl AsRef<OsStr>], key: &mut BString) -> Result<()> {
+    let args = GccArgsInfo::from_args(args)?;
+    for arg in args.args.iter() {
+        match arg {
+            GccArg::Dual(first, _) if *first == "-o" => continue,
+            GccArg::Single(arg) | GccArg::Source(arg) => key.push_str(arg.as_encoded_bytes()),
+            GccArg::Dual(first, second) => {
+                key.push_str(first.as_encoded_bytes());
+                key.push_str(b" ");
+                key.push_str(second.as_encoded_bytes());
+            }
+        }
+        key.push(0);
+    }
+    Ok(())
+}
+
+/// Linker options whose value is a file that affects the linked output, but is not a link
+/// input itself.
+const LINKER_SCRIPT_OPTIONS: &[&str] = &["--version-script", "--dynamic-list"];
+
+/// Returns the files passed with e.g. `-Wl,--version-script=foo.map`. Their content is not
+/// part of the arguments, so they have to be checked separately to know if a link is outdated.
+pub fn get_linker_script_paths(cwd: &Path, args: &[impl AsRef<OsStr>]) -> Result<Vec<PathBuf>> {
+    let args = GccArgsInfo::from_args(args)?;
+    // Options passed with `-Wl,` and `-Xlinker` form a single sequence, because a value may be
+    // passed separately from its option in both cases.
+    let mut linker_options = vec![];
+    for arg in &args.args {
+        match arg {
+            GccArg::Single(arg) => {
+                if let Some(options) = arg.strip_prefix("-Wl,") {
+                    linker_options.extend(options.split(","));
+                }
+            }
+            GccArg::Dual(first, second) if *first == "-Xlinker" => linker_options.push(*second),
+            _ => {}
+        }
+    }
+    let mut paths = vec![];
+    let mut options_iter = linker_options.into_iter();
+    while let Some(option) = options_iter.next() {
+        let Some(option_str) = option.to_str() else {
+            continue;
+        };
+        for script_option in LINKER_SCRIPT_OPTIONS {
+            let Some(rest) = option_str.strip_prefix(script_option) else {
+                continue;
+            };
+            if let Some(path) = rest.strip_prefix('=') {
+                paths.push(make_absolute(cwd, Path::new(path)));
+            } else if rest.is_empty()
+                && let Some(path) = options_iter.next()
+            {
+                paths.push(make_absolute(cwd, Path::new(path)));
+            }
+        }
+    }
+    Ok(paths)
+}
+
+const INCLUDE_PATH_ARGS: &[&str] = &["-I", "-isystem", "-iquote", "-idirafter"];
+
+/// Splits arguments like `-Ifoo` or `-isystem/usr/include/foo`.
+fn split_joined_include_arg(arg: &OsStr) -> Option<(&'static str, &OsStr)> {
+    INCLUDE_PATH_ARGS.iter().find_map(|flag| {
+        arg.strip_prefix(*flag)
+            .filter(|path| !path.is_empty())
+            .map(|path| (*flag, path))
+    })
+}
+
+/// Linker arguments passed through the compiler driver that affect how the following link
+/// inputs are handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LinkerMarker {
+    WholeArchive,
+    NoWholeArchive,
+    StartGroup,
+    EndGroup,
+}
+
+impl LinkerMarker {
+    fn from_arg(arg: &OsStr) -> Option<Self> {
+        let linker_arg = arg.to_str()?.strip_prefix("-Wl,")?;
+        match linker_arg {
+            "--whole-archive" | "-whole-archive" => Some(Self::WholeArchive),
+            "--no-whole-archive" | "-no-whole-archive" => Some(Self::NoWholeArchive),
+            "--start-group" | "-start-group" | "-(" => Some(Self::StartGroup),
+            "--end-group" | "-end-group" | "-)" => Some(Self::EndGroup),
+            _ => None,
+        }
+    }
+}
+
+/// Options that are passed directly to the preprocessor with `-Wp,`. Like with `-Wl,`, multiple
+/// options and their values are separated by commas. These are kept as is in the arguments, so
+/// e.g. `-Wp,-DFOO` is applied whenever the original source is preprocessed.
+fn preprocessor_options(arg: &OsStr) -> Option<impl Iterator<Item = &OsStr>> {
+    Some(arg.strip_prefix("-Wp,")?.split(","))
+}
+
+/// Removes depfile generation options like in `-Wp,-MD,path.d` which is used by some build
+/// systems instead of `-MD -MF`. Other preprocessor options are kept.
+fn remove_preprocessor_depfile_options(args: Vec<OsString>) -> Vec<OsString> {
+    let mut result = Vec::with_capacity(args.len());
+    for arg in args {
+        let Some(mut options) = preprocessor_options(&arg) else {
+            result.push(arg);
+            continue;
+        };
+        let mut kept_options: Vec<&OsStr> = vec![];
+        while let Some(option) = options.next() {
+            if ["-MD", "-MMD", "-MF", "-MT", "-MQ"]
+                .iter()
+                .any(|name| option == *name)
+            {
+                // Skip the value too.
+                options.next();
+            } else if option != "-MP" {
+                kept_options.push(option);
+            }
+        }
+        if kept_options.is_empty() {
+            continue;
+        }
+        let mut new_arg = OsString::from("-Wp");
+        for option in kept_options {
+            new_arg.push(",");
+            new_arg.push(option);
+        }
+        result.push(new_arg);
+    }
+    result
+}
+
+#[derive(Clone)]
 enum GccArg<'a> {
     Single(&'a OsStr),
     Dual(&'a OsStr, &'a OsStr),
     Source(&'a OsStr),
 }
 
+#[derive(Clone)]
 struct GccArgsInfo<'a> {
     args: SmallVec<[GccArg<'a>; 32]>,
 }
@@ -269,6 +762,44 @@ struct SourceArgWithLanguage<'a> {
     language: Option<CodeLanguage>,
 }
 
+/// Arguments that are accepted when compiling but only affect linking. Note that `-pthread`
+/// is not one of them because it also affects the preprocessor.
+const LINK_ONLY_ARGS: &[&str] = &[
+    "-pie",
+    "-no-pie",
+    "-static",
+    "-static-libgcc",
+    "-static-libstdc++",
+    "-rdynamic",
+    "-nostdlib",
+    "-nodefaultlibs",
+    "-nostartfiles",
+];
+
+/// Arguments that take the following argument as their value.
+const DUAL_ARGS: &[&str] = &[
+    "-I",
+    "-isystem",
+    "-iquote",
+    "-idirafter",
+    "-isysroot",
+    "-F",
+    "-include",
+    "-include-pch",
+    "-target",
+    "-arch",
+    "-o",
+    "-MF",
+    "-MT",
+    "-MQ",
+    "-MJ",
+    "-x",
+    "-B",
+    "-D",
+    "-U",
+    "-Xlinker",
+];
+
 impl<'a> GccArgsInfo<'a> {
     fn from_args<S: AsRef<OsStr> + 'a>(args: &'a [S]) -> Result<GccArgsInfo<'a>> {
         let mut result = Self {
@@ -277,19 +808,20 @@ impl<'a> GccArgsInfo<'a> {
         let mut args_iter = args.iter();
         while let Some(arg) = args_iter.next() {
             let arg = arg.as_ref();
-            if arg == "-isystem"
-                || arg == "-include"
-                || arg == "-o"
-                || arg == "-MF"
-                || arg == "-MT"
-                || arg == "-x"
-            {
+            if DUAL_ARGS.iter().any(|dual_arg| arg == *dual_arg) {
                 let next = args_iter
                     .next()
                     .ok_or_else(|| anyhow!("argument after {:?} is missing", arg))?
                     .as_ref();
                 result.args.push(GccArg::Dual(arg, next));
+            } else if let Some(language) = arg.strip_prefix("-x") {
+                // Split joined language arguments like `-xc++` so that they are handled the
+                // same way as `-x c++` everywhere.
+                result.args.push(GccArg::Dual(OsStr::new("-x"), language));
             } else if arg.starts_with("-") {
+                // This includes pass-through arguments like `-Wl,`, `-Wp,` and `-Wa,`. They are
+                // kept as is and are not confused with warning flags because warnings are not
+                // interpreted here at all.
                 result.args.push(GccArg::Single(arg));
             } else {
                 result.args.push(GccArg::Source(arg));
@@ -330,6 +862,32 @@ impl<'a> GccArgsInfo<'a> {
         self.args.push(GccArg::Source(path.as_os_str()));
     }
 
+    /// Adds the source with an explicit language so that it's not affected by any other `-x`
+    /// argument that may still be in the arguments.
+    fn push_source_with_language_arg(&mut self, path: &'a Path) {
+        let language = match CodeLanguage::from_known_path(path) {
+            Some(language) => language.to_gcc_x_arg(),
+            None => "none",
+        };
+        self.push_dual_arg_str("-x", language);
+        self.push_source_arg(path);
+    }
+
+    fn push_link_sources(&mut self, sources: &'a [SourceFile]) {
+        for source in sources {
+            match source.language() {
+                Some(language) => {
+                    self.push_dual_arg_str("-x", language.to_gcc_x_arg());
+                }
+                None => {
+                    self.push_single_arg_str("-x");
+                    self.push_single_arg_str("none");
+                }
+            }
+            self.push_source_arg(&source.path);
+        }
+    }
+
     fn to_args_owned_vec(&self) -> Vec<OsString> {
         self.to_args().iter().map(|s| (*s).to_owned()).collect()
     }
@@ -346,10 +904,46 @@ impl<'a> GccArgsInfo<'a> {
         None
     }
 
-    fn get_depfile_path(&self) -> Option<&'a Path> {
+    /// `-o -` does not refer to a file named `-`.
+    fn writes_output_to_stdout(&self) -> bool {
+        self.get_single_output() == Some(Path::new("-"))
+    }
+
+    fn writes_depfile(&self) -> bool {
+        self.has_single_arg_str("-MD") || self.has_single_arg_str("-MMD")
+    }
+
+    /// Like the compiler, this defaults to the output path with a `.d` extension.
+    fn get_depfile_path(&self) -> Option<PathBuf> {
         for arg in &self.args {
             match arg {
                 GccArg::Dual(first, second) if *first == "-MF" => {
+                    return Some(PathBuf::from(*second));
+                }
+                _ => {}
+            }
+        }
+        self.get_output_or_implied_object_output()
+            .map(|output| output.with_extension("d"))
+    }
+
+    fn is_query(&self) -> bool {
+        self.args.iter().any(|arg| match arg {
+            GccArg::Single(arg) => {
+                ["--version", "-V", "-###", "-dumpversion", "-dumpmachine"]
+                    .iter()
+                    .any(|query| *arg == *query)
+                    || arg.starts_with("--help")
+                    || arg.starts_with("-print-")
+            }
+            _ => false,
+        })
+    }
+
+    fn get_compilation_database_path(&self) -> Option<&'a Path> {
+        for arg in &self.args {
+            match arg {
+                GccArg::Dual(first, second) if *first == "-MJ" => {
                     return Some(Path::new(*second));
                 }
                 _ => {}
@@ -358,18 +952,22 @@ impl<'a> GccArgsInfo<'a> {
         None
     }
 
-    fn get_depfile_target(&self) -> Option<&'a OsStr> {
+    /// Like the compiler, this defaults to the output path as it's passed to `-o`.
+    fn get_depfile_target(&self) -> Option<OsString> {
         for arg in &self.args {
             match arg {
-                GccArg::Dual(first, second) if *first == "-MT" => {
-                    return Some(second);
+                GccArg::Dual(first, second) if *first == "-MT" || *first == "-MQ" => {
+                    return Some((*second).to_owned());
                 }
                 _ => {}
             }
         }
-        None
+        self.get_output_or_implied_object_output()
+            .map(|output| output.into_os_string())
     }
 
+    /// A `-x` argument only affects the sources that come after it until the next `-x`.
+    /// `-x none` resets to detecting the language based on the file extension.
     fn get_sources(&self) -> Result<SmallVec<[SourceArgWithLanguage<'a>; 16]>> {
         let mut sources = smallvec![];
         let mut current_language = None;
@@ -401,11 +999,129 @@ impl<'a> GccArgsInfo<'a> {
             .collect())
     }
 
+    fn get_absolute_whole_archive_sources(&self, cwd: &Path) -> HashSet<PathBuf> {
+        let mut sources = HashSet::new();
+        let mut in_whole_archive = false;
+        for arg in &self.args {
+            match arg {
+                GccArg::Single(arg) => match LinkerMarker::from_arg(arg) {
+                    Some(LinkerMarker::WholeArchive) => in_whole_archive = true,
+                    Some(LinkerMarker::NoWholeArchive) => in_whole_archive = false,
+                    // Groups only change the order in which archives are searched.
+                    Some(LinkerMarker::StartGroup | LinkerMarker::EndGroup) | None => {}
+                },
+                GccArg::Source(path) if in_whole_archive => {
+                    sources.insert(make_absolute(cwd, Path::new(path)));
+                }
+                _ => {}
+            }
+        }
+        sources
+    }
+
     fn get_absolute_single_output(&self, cwd: &Path) -> Result<PathBuf> {
-        let Some(output) = self.get_single_output() else {
-            return Err(anyhow!("There has to be one output"));
+        let output = self
+            .get_output_or_implied_object_output()
+            .ok_or_else(|| anyhow!("There has to be one output"))?;
+        Ok(make_absolute(cwd, &output))
+    }
+
+    /// The output passed with `-o`, or the object file the compiler writes without it.
+    fn get_output_or_implied_object_output(&self) -> Option<PathBuf> {
+        self.get_single_output()
+            .map(Path::to_owned)
+            .or_else(|| self.get_implied_object_output())
+    }
+
+    /// When compiling a single source to an object file without `-o`, the compiler writes the
+    /// object file into the current directory, named like the source.
+    fn get_implied_object_output(&self) -> Option<PathBuf> {
+        let outputs = self.get_implied_object_outputs();
+        let [output] = outputs.as_slice() else {
+            return None;
         };
-        Ok(make_absolute(cwd, output))
+        Some(output.clone())
+    }
+
+    /// Same as [`Self::get_implied_object_output`] but for every source when multiple sources
+    /// are compiled at once.
+    fn get_implied_object_outputs(&self) -> Vec<PathBuf> {
+        if !self.has_single_arg_str("-c") {
+            return vec![];
+        }
+        let Ok(sources) = self.get_sources() else {
+            return vec![];
+        };
+        sources
+            .iter()
+            .filter_map(|source| {
+                let mut name = source.path.file_stem()?.to_owned();
+                name.push(".o");
+                Some(PathBuf::from(name))
+            })
+            .collect()
+    }
+
+    fn get_prefix_dirs(&self) -> Vec<&'a Path> {
+        self.args
+            .iter()
+            .filter_map(|arg| match arg {
+                GccArg::Dual(first, second) if *first == "-B" => Some(Path::new(*second)),
+                GccArg::Single(arg) => arg.strip_prefix("-B").map(Path::new),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn get_defines(&self) -> Vec<BString> {
+        self.args
+            .iter()
+            .filter_map(|arg| match arg {
+                GccArg::Dual(first, second) if *first == "-D" => Some(*second),
+                GccArg::Single(arg) => arg.strip_prefix("-D"),
+                _ => None,
+            })
+            .map(|define| {
+                let define = define.as_encoded_bytes();
+                let name = define.split(|c| *c == b'=').next().unwrap_or_default();
+                BString::from(name)
+            })
+            .collect()
+    }
+
+    fn uses_lto(&self) -> bool {
+        let mut uses_lto = false;
+        for arg in &self.args {
+            if let GccArg::Single(arg) = arg {
+                if *arg == "-flto" || arg.starts_with("-flto=") {
+                    uses_lto = true;
+                } else if *arg == "-fno-lto" {
+                    uses_lto = false;
+                }
+            }
+        }
+        uses_lto
+    }
+
+    /// GCC uses `foo.h.gch` instead of `foo.h` if it exists next to it. This is only detected
+    /// for headers passed with `-include`, because finding other headers would require
+    /// scanning the source.
+    fn uses_pch(&self, cwd: &Path) -> bool {
+        let creates_pch = self.get_single_output().is_some_and(|output| {
+            output
+                .extension()
+                .is_some_and(|ext| ext == "gch" || ext == "pch")
+        });
+        creates_pch
+            || self.args.iter().any(|arg| match arg {
+                GccArg::Dual(first, _) if *first == "-include-pch" => true,
+                GccArg::Dual(first, header) if *first == "-include" => {
+                    let mut pch_path = make_absolute(cwd, Path::new(header)).into_os_string();
+                    pch_path.push(".gch");
+                    Path::new(&pch_path).exists()
+                }
+                _ => false,
+            })
     }
 
     fn has_single_arg(&self, query: &OsStr) -> bool {
@@ -415,7 +1131,643 @@ impl<'a> GccArgsInfo<'a> {
         })
     }
 
+    fn has_dual_arg_str(&self, query: &str) -> bool {
+        self.args
+            .iter()
+            .any(|arg| matches!(arg, GccArg::Dual(first, _) if *first == query))
+    }
+
     fn has_single_arg_str(&self, query: &str) -> bool {
         self.has_single_arg(OsStr::new(query))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ccelerate_shared::WrappedBinary;
+
+    use super::*;
+    use crate::test_utils::TestEnv;
+
+    fn source_languages(args: &[&str]) -> Vec<(PathBuf, Option<CodeLanguage>)> {
+        GccArgsInfo::from_args(args)
+            .expect("args can be parsed")
+            .get_absolute_sources(Path::new("/project"))
+            .expect("sources can be found")
+            .into_iter()
+            .map(|source| (source.path, source.language_override))
+            .collect()
+    }
+
+    #[test]
+    fn language_applies_to_following_sources() {
+        assert_eq!(
+            source_languages(&["-x", "c++", "a.c"]),
+            [(PathBuf::from("/project/a.c"), Some(CodeLanguage::Cxx))]
+        );
+        assert_eq!(
+            source_languages(&["a.c", "-x", "c++"]),
+            [(PathBuf::from("/project/a.c"), None)]
+        );
+        assert_eq!(
+            source_languages(&["-x", "c++", "a.c", "-x", "none", "b.c"]),
+            [
+                (PathBuf::from("/project/a.c"), Some(CodeLanguage::Cxx)),
+                (PathBuf::from("/project/b.c"), None)
+            ]
+        );
+        assert_eq!(
+            source_languages(&["-xc++", "a.c"]),
+            [(PathBuf::from("/project/a.c"), Some(CodeLanguage::Cxx))]
+        );
+    }
+
+    fn chunk_compile_args(args: &[&str]) -> Vec<String> {
+        update_to_build_object_from_stdin(
+            args,
+            Path::new("/tmp/chunk.c"),
+            Path::new("/tmp/chunk.o"),
+        )
+        .expect("args can be rewritten")
+        .into_iter()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect()
+    }
+
+    #[test]
+    fn suppressed_warnings_stay_suppressed() {
+        let args = ["-w", "-Wall", "-c", "a.c", "-o", "a.o"];
+        assert!(suppresses_warnings(&args).expect("args can be parsed"));
+        assert!(chunk_compile_args(&args).iter().any(|arg| arg == "-w"));
+    }
+
+    #[test]
+    fn warnings_are_not_suppressed_without_w() {
+        let args = ["-Wall", "-c", "a.c", "-o", "a.o"];
+        assert!(!suppresses_warnings(&args).expect("args can be parsed"));
+        assert!(!chunk_compile_args(&args).iter().any(|arg| arg == "-w"));
+    }
+
+    fn link_sources(paths: &[&str]) -> Vec<SourceFile> {
+        paths
+            .iter()
+            .map(|path| SourceFile {
+                path: PathBuf::from(path),
+                language_override: None,
+            })
+            .collect()
+    }
+
+    /// The new link sources for each source of the original command.
+    fn replacements(paths: &[&[&str]]) -> Vec<Vec<SourceFile>> {
+        paths.iter().map(|paths| link_sources(paths)).collect()
+    }
+
+    /// Rewritten link arguments without the `-x none` before every source.
+    fn without_language_args(args: &[OsString]) -> Vec<String> {
+        args.iter()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .filter(|arg| arg != "-x" && arg != "none")
+            .collect()
+    }
+
+    #[test]
+    fn link_sources_keep_their_position() {
+        let args = [
+            "a.o",
+            "-lfoo",
+            "b.a",
+            "-Wl,--as-needed",
+            "c.so",
+            "-o",
+            "main",
+        ];
+        let sources = replacements(&[&["/project/all.a"], &["/project/b.a"], &["/project/c.so"]]);
+        let link_args =
+            rewrite_to_link_sources_for_platform(&args, &sources, &[], false, Platform::Linux)
+                .expect("args can be rewritten");
+        assert_eq!(
+            without_language_args(&link_args),
+            [
+                "/project/all.a",
+                "-lfoo",
+                "/project/b.a",
+                "-Wl,--as-needed",
+                "/project/c.so",
+                "-o",
+                "main"
+            ]
+        );
+
+        // The shared library stays outside of the group.
+        let link_args =
+            rewrite_to_link_sources_for_platform(&args, &sources, &[], true, Platform::Linux)
+                .expect("args can be rewritten");
+        assert_eq!(
+            without_language_args(&link_args),
+            [
+                "-Wl,--start-group",
+                "/project/all.a",
+                "-lfoo",
+                "/project/b.a",
+                "-Wl,--as-needed",
+                "-Wl,--end-group",
+                "/project/c.so",
+                "-o",
+                "main"
+            ]
+        );
+    }
+
+    #[test]
+    fn shared_library_link_round_trip() {
+        let args = [
+            "-shared",
+            "-Wl,-soname,libfoo.so.1",
+            "a.o",
+            "-Wl,--version-script=foo.map",
+            "b.o",
+            "-o",
+            "libfoo.so.1.0",
+        ];
+        let info =
+            LinkFileInfo::from_gcc_args(Path::new("/project"), &args).expect("args can be parsed");
+        assert!(info.is_shared_library);
+        assert_eq!(
+            info.sources.iter().map(|s| &s.path).collect::<Vec<_>>(),
+            [Path::new("/project/a.o"), Path::new("/project/b.o")]
+        );
+        assert_eq!(info.output, Path::new("/project/libfoo.so.1.0"));
+        assert_eq!(
+            get_linker_script_paths(Path::new("/project"), &args).expect("args can be parsed"),
+            [PathBuf::from("/project/foo.map")]
+        );
+
+        let sources = replacements(&[&["/project/chunk_a.o"], &["/project/chunk_b.o"]]);
+        let link_args =
+            rewrite_to_link_sources_for_platform(&args, &sources, &[], false, Platform::Linux)
+                .expect("args can be rewritten");
+        assert_eq!(
+            without_language_args(&link_args),
+            [
+                "-shared",
+                "-Wl,-soname,libfoo.so.1",
+                "/project/chunk_a.o",
+                "-Wl,--version-script=foo.map",
+                "/project/chunk_b.o",
+                "-o",
+                "libfoo.so.1.0"
+            ]
+        );
+
+        // The group only wraps the sources, the linker directives keep their order around it.
+        let link_args =
+            rewrite_to_link_sources_for_platform(&args, &sources, &[], true, Platform::Linux)
+                .expect("args can be rewritten");
+        assert_eq!(
+            without_language_args(&link_args),
+            [
+                "-shared",
+                "-Wl,-soname,libfoo.so.1",
+                "-Wl,--start-group",
+                "/project/chunk_a.o",
+                "-Wl,--version-script=foo.map",
+                "/project/chunk_b.o",
+                "-Wl,--end-group",
+                "-o",
+                "libfoo.so.1.0"
+            ]
+        );
+    }
+
+    #[test]
+    fn whole_archive_sources_keep_their_position() {
+        let args = [
+            "main.o",
+            "-Wl,--whole-archive",
+            "libctor.a",
+            "-Wl,--no-whole-archive",
+            "-lfoo",
+            "-o",
+            "main",
+        ];
+        let link_args = rewrite_to_link_sources_for_platform(
+            &args,
+            &replacements(&[&["/project/all.a"], &[]]),
+            &link_sources(&["/project/libctor.a"]),
+            false,
+            Platform::Linux,
+        )
+        .expect("args can be rewritten");
+        assert_eq!(
+            without_language_args(&link_args),
+            [
+                "/project/all.a",
+                "-Wl,--whole-archive",
+                "/project/libctor.a",
+                "-Wl,--no-whole-archive",
+                "-lfoo",
+                "-o",
+                "main"
+            ]
+        );
+    }
+
+    #[test]
+    fn link_group_markers_are_optional() {
+        let args = [
+            "-Wl,--start-group",
+            "a.o",
+            "b.a",
+            "-Wl,--end-group",
+            "-o",
+            "main",
+        ];
+        let sources = replacements(&[&["/project/all.a"], &["/project/b.a"]]);
+        let is_group_marker = |arg: &OsString| arg.to_string_lossy().contains("-group");
+
+        let args_without_group =
+            rewrite_to_link_sources(&args, &sources, &[], false).expect("args can be rewritten");
+        assert!(!args_without_group.iter().any(is_group_marker));
+        assert!(args_without_group.iter().any(|arg| arg == "/project/all.a"));
+
+        let args_with_group =
+            rewrite_to_link_sources(&args, &sources, &[], true).expect("args can be rewritten");
+        assert_eq!(
+            args_with_group.iter().any(is_group_marker),
+            Platform::current().supports_link_groups()
+        );
+    }
+
+    #[test]
+    fn mach_o_link_args() {
+        let args = [
+            "main.o",
+            "-Wl,--whole-archive",
+            "libctor.a",
+            "-Wl,--no-whole-archive",
+            "-o",
+            "main",
+        ];
+        let sources = replacements(&[&["/project/all.a"], &[]]);
+        let whole_archive_sources = link_sources(&["/project/libctor.a", "/project/ctor.o"]);
+        let link_args = rewrite_to_link_sources_for_platform(
+            &args,
+            &sources,
+            &whole_archive_sources,
+            true,
+            Platform::MacOs,
+        )
+        .expect("args can be rewritten");
+        let link_args = without_language_args(&link_args);
+        assert_eq!(
+            link_args,
+            [
+                "/project/all.a",
+                "-Xlinker",
+                "-force_load",
+                "-Xlinker",
+                "/project/libctor.a",
+                "/project/ctor.o",
+                "-o",
+                "main"
+            ]
+        );
+    }
+
+    fn compatibility_key(args: &[&str]) -> BString {
+        let mut key = BString::default();
+        add_translation_unit_unspecific_args_to_key(args, Path::new("/project"), false, &mut key)
+            .expect("key can be created");
+        key
+    }
+
+    #[test]
+    fn cross_compile_round_trip() {
+        let args = [
+            "-target",
+            "aarch64-linux-gnu",
+            "-arch",
+            "arm64",
+            "-arch",
+            "x86_64",
+            "--sysroot=/opt/sysroot",
+            "-c",
+            "foo.c",
+            "-o",
+            "foo.o",
+        ];
+        assert_eq!(
+            GccArgsInfo::from_args(&args)
+                .expect("args can be parsed")
+                .to_args_owned_vec(),
+            args.map(OsString::from)
+        );
+        let info =
+            BuildFilesInfo::from_args(Path::new("/project"), &args).expect("args can be parsed");
+        assert_eq!(
+            info.sources.iter().map(|s| &s.path).collect::<Vec<_>>(),
+            [Path::new("/project/foo.c")]
+        );
+
+        let chunk_args = chunk_compile_args(&args);
+        assert!(
+            chunk_args
+                .windows(2)
+                .any(|pair| pair == ["-target", "aarch64-linux-gnu"])
+        );
+        assert_eq!(
+            chunk_args.iter().filter(|arg| *arg == "-arch").count(),
+            2,
+            "{:?}",
+            chunk_args
+        );
+
+        // Objects for different targets must not be compiled together.
+        assert_ne!(
+            compatibility_key(&["-target", "aarch64-linux-gnu"]),
+            compatibility_key(&["-target", "x86_64-linux-gnu"])
+        );
+        assert_ne!(
+            compatibility_key(&["-arch", "arm64"]),
+            compatibility_key(&["-arch", "arm64", "-arch", "x86_64"])
+        );
+        assert_ne!(
+            compatibility_key(&["--target=aarch64-linux-gnu"]),
+            compatibility_key(&[] as &[&str])
+        );
+    }
+
+    #[test]
+    fn pthread_round_trip() {
+        let compile_args = ["-pthread", "-c", "foo.c", "-o", "foo.o"];
+        assert_eq!(
+            GccArgsInfo::from_args(&compile_args)
+                .expect("args can be parsed")
+                .to_args_owned_vec(),
+            compile_args.map(OsString::from)
+        );
+        assert!(
+            chunk_compile_args(&compile_args)
+                .iter()
+                .any(|arg| arg == "-pthread")
+        );
+        // `-pthread` defines macros, so it has to match for objects compiled together.
+        assert_ne!(
+            compatibility_key(&["-pthread"]),
+            compatibility_key(&[] as &[&str])
+        );
+        assert_eq!(
+            compatibility_key(&["-pthread", "-static", "-rdynamic"]),
+            compatibility_key(&["-pthread"])
+        );
+
+        let link_args = ["main.o", "-pthread", "-static", "-o", "main"];
+        let link_args = rewrite_to_link_sources(
+            &link_args,
+            &replacements(&[&["/project/all.a"]]),
+            &[],
+            false,
+        )
+        .expect("args can be rewritten");
+        assert!(link_args.iter().any(|arg| arg == "-pthread"));
+        assert!(link_args.iter().any(|arg| arg == "-static"));
+        assert!(link_args.iter().any(|arg| arg == "/project/all.a"));
+    }
+
+    #[test]
+    fn linker_selection_round_trip() {
+        let args = [
+            "-fuse-ld=lld",
+            "-B/opt/custom/bin",
+            "-B",
+            "tools",
+            "--ld-path=/opt/custom/bin/ld.lld",
+            "main.o",
+            "a.o",
+            "-o",
+            "main",
+        ];
+        let info =
+            LinkFileInfo::from_gcc_args(Path::new("/project"), &args).expect("args can be parsed");
+        assert_eq!(
+            info.sources.iter().map(|s| &s.path).collect::<Vec<_>>(),
+            [Path::new("/project/main.o"), Path::new("/project/a.o")]
+        );
+        let files =
+            BuildFilesInfo::from_args(Path::new("/project"), &args).expect("args can be parsed");
+        assert_eq!(
+            files.prefix_dirs,
+            [
+                PathBuf::from("/opt/custom/bin"),
+                PathBuf::from("/project/tools")
+            ]
+        );
+
+        // Both objects are in the same archive, which only has to be linked once.
+        let link_args = rewrite_to_link_sources(
+            &args,
+            &replacements(&[&["/project/all.a"], &["/project/all.a"]]),
+            &[],
+            false,
+        )
+        .expect("args can be rewritten");
+        let link_args = without_language_args(&link_args);
+        assert_eq!(
+            link_args,
+            [
+                "-fuse-ld=lld",
+                "-B/opt/custom/bin",
+                "-B",
+                "tools",
+                "--ld-path=/opt/custom/bin/ld.lld",
+                "/project/all.a",
+                "-o",
+                "main"
+            ]
+        );
+    }
+
+    #[test]
+    fn include_flags_round_trip() {
+        let args = [
+            "-iquote",
+            "inc",
+            "-iquoteinc2",
+            "-idirafter",
+            "after",
+            "-isysroot",
+            "/sdk",
+            "-F",
+            "/frameworks",
+            "-c",
+            "foo.c",
+            "-o",
+            "foo.o",
+        ];
+        assert_eq!(
+            GccArgsInfo::from_args(&args)
+                .expect("args can be parsed")
+                .to_args_owned_vec(),
+            args.map(OsString::from)
+        );
+        let info = BuildObjectFileInfo::from_gcc_args(Path::new("/project"), &args)
+            .expect("args can be parsed");
+        assert_eq!(info.source_path, Path::new("/project/foo.c"));
+
+        // Include paths are compared independent of how they are spelled.
+        assert_eq!(
+            compatibility_key(&["-iquote", "inc", "-idirafter", "./after"]),
+            compatibility_key(&["-iquote/project/inc", "-idirafter", "/project/after"])
+        );
+        for (a, b) in [
+            (["-isysroot", "/sdk1"], ["-isysroot", "/sdk2"]),
+            (["-F", "/frameworks1"], ["-F", "/frameworks2"]),
+            (["-iquote", "inc"], ["-idirafter", "inc"]),
+        ] {
+            assert_ne!(compatibility_key(&a), compatibility_key(&b));
+        }
+    }
+
+    #[test]
+    fn include_paths_are_normalized_in_key() {
+        let key = compatibility_key(&["-Ia/b", "-isystem", "sys"]);
+        for args in [
+            ["-Ia/./b", "-isystem", "sys"],
+            ["-Ia/b/", "-isystem", "./sys/"],
+            ["-Ia/c/../b", "-isystem", "/project/sys"],
+        ] {
+            assert_eq!(compatibility_key(&args), key, "{:?}", args);
+        }
+        // The order of include paths is kept, because it decides which header is found.
+        assert_ne!(
+            compatibility_key(&["-Ia", "-Ib"]),
+            compatibility_key(&["-Ib", "-Ia"])
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn symlinked_include_paths_are_resolved_optionally() {
+        let dir = tempfile::tempdir().expect("temp dir can be created");
+        std::fs::create_dir(dir.path().join("real")).expect("dir can be created");
+        std::os::unix::fs::symlink(dir.path().join("real"), dir.path().join("link"))
+            .expect("symlink can be created");
+        let key = |include: &str, resolve_symlinks: bool| {
+            let mut key = BString::default();
+            add_translation_unit_unspecific_args_to_key(
+                &["-I", include],
+                dir.path(),
+                resolve_symlinks,
+                &mut key,
+            )
+            .expect("key can be created");
+            key
+        };
+        assert_ne!(key("link", false), key("real", false));
+        assert_eq!(key("link", true), key("real", true));
+    }
+
+    #[test]
+    fn preprocessor_options_are_split() {
+        assert_eq!(
+            preprocessor_options(OsStr::new("-Wp,-D,FOO,-UBAR"))
+                .expect("is a preprocessor option")
+                .collect::<Vec<_>>(),
+            ["-D", "FOO", "-UBAR"]
+        );
+        assert!(preprocessor_options(OsStr::new("-Wall")).is_none());
+        assert!(preprocessor_options(OsStr::new("-Wl,-DFOO")).is_none());
+        assert_eq!(
+            remove_preprocessor_depfile_options(
+                [
+                    "-Wp,-DFOO",
+                    "-Wp,-MD,foo.d",
+                    "-Wp,-MMD,foo.d,-DBAR,-MP",
+                    "-Wall"
+                ]
+                .map(OsString::from)
+                .to_vec()
+            ),
+            ["-Wp,-DFOO", "-Wp,-DBAR", "-Wall"].map(OsString::from)
+        );
+    }
+
+    #[tokio::test]
+    async fn preprocessor_options_define_macros() {
+        let env = TestEnv::new(&[]).await;
+        env.write(
+            "foo.c",
+            "#ifndef FOO\n#error FOO is not defined\n#endif\nint foo(void) { return FOO; }",
+        );
+        env.write(
+            "main.c",
+            "#include <stdio.h>\nint foo(void);\nint main(void) { printf(\"%d\", foo()); return 0; }",
+        );
+        let output = env
+            .run(WrappedBinary::Gcc, &["-c", "foo.c", "-o", "foo.o"])
+            .await;
+        assert_ne!(output.status, 0);
+        env.run_ok(
+            WrappedBinary::Gcc,
+            &["-Wp,-DFOO=7", "-c", "foo.c", "-o", "foo.o"],
+        )
+        .await;
+        env.run_ok(WrappedBinary::Gcc, &["main.c", "foo.o", "-o", "main"])
+            .await;
+        assert_eq!(env.run_program("main"), "7");
+    }
+
+    #[test]
+    fn pass_through_args_are_not_warnings() {
+        let args = [
+            "-Wall",
+            "-Wl,-rpath,/lib",
+            "a.c",
+            "-Wp,-DFOO",
+            "b.c",
+            "-Wa,--noexecstack",
+            "c.c",
+        ];
+        let info = GccArgsInfo::from_args(&args).expect("args can be parsed");
+        let kinds = info
+            .args
+            .iter()
+            .map(|arg| match arg {
+                GccArg::Single(arg) => format!("single {}", arg.to_string_lossy()),
+                GccArg::Dual(first, second) => format!(
+                    "dual {} {}",
+                    first.to_string_lossy(),
+                    second.to_string_lossy()
+                ),
+                GccArg::Source(arg) => format!("source {}", arg.to_string_lossy()),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                "single -Wall",
+                "single -Wl,-rpath,/lib",
+                "source a.c",
+                "single -Wp,-DFOO",
+                "source b.c",
+                "single -Wa,--noexecstack",
+                "source c.c",
+            ]
+        );
+        assert_eq!(
+            LinkerMarker::from_arg(OsStr::new("-Wl,--whole-archive")),
+            Some(LinkerMarker::WholeArchive)
+        );
+        for arg in [
+            "-Wp,--whole-archive",
+            "-Wa,--whole-archive",
+            "-Wwhole-archive",
+        ] {
+            assert_eq!(LinkerMarker::from_arg(OsStr::new(arg)), None);
+        }
+        for arg in ["-Wl,-DFOO", "-Wa,-DFOO", "-WDFOO"] {
+            assert!(preprocessor_options(OsStr::new(arg)).is_none());
+        }
+    }
+}
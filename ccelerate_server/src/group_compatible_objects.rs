@@ -4,11 +4,13 @@ use anyhow::Result;
 use bstr::{BString, ByteVec};
 
 use crate::{
-    args_processing, state::State, state_persistent::ObjectData, task_periods::TaskPeriodInfo,
+    args_processing, path_utils::ProjectRoots, state::State, state_persistent::ObjectData,
+    task_periods::TaskPeriodInfo,
 };
 
 #[derive(Debug, Clone)]
 pub struct CompatibleObjects {
+    pub key: BString,
     pub objects: nunny::Vec<Arc<ObjectData>>,
 }
 
@@ -21,11 +23,17 @@ pub fn group_compatible_objects(
         .start(GroupObjectsToChunksTaskInfo { num: objects.len() });
     let mut chunks: HashMap<BString, CompatibleObjects> = HashMap::new();
     for object in objects {
-        let key = create_object_compatibility_key(object)?;
+        let mut key =
+            create_object_compatibility_key(object, state.cli.canonicalize_include_paths)?;
+        if object.pinned_individual {
+            // Makes the key unique so that the object ends up in a chunk on its own.
+            key.push_str(object.path.as_os_str().as_encoded_bytes());
+        }
         chunks
-            .entry(key)
+            .entry(key.clone())
             .and_modify(|chunk| chunk.objects.push(object.clone()))
             .or_insert_with(|| CompatibleObjects {
+                key,
                 objects: nunny::Vec::of(object.clone()),
             });
     }
@@ -33,7 +41,7 @@ pub fn group_compatible_objects(
     Ok(chunks.into_values().collect())
 }
 
-fn create_object_compatibility_key(object: &ObjectData) -> Result<BString> {
+fn create_object_compatibility_key(object: &ObjectData, resolve_symlinks: bool) -> Result<BString> {
     let info = args_processing::BuildObjectFileInfo::from_args(
         object.create.binary,
         &object.create.cwd,
@@ -48,6 +56,10 @@ fn create_object_compatibility_key(object: &ObjectData) -> Result<BString> {
             .to_standard_binary_name()
             .as_encoded_bytes(),
     );
+    // Objects built by different compilers with the same name must not be combined.
+    if let Some(resolved_binary) = &object.create.resolved_binary {
+        key.push_str(resolved_binary.as_os_str().as_encoded_bytes());
+    }
     key.push_str(info.source_language.valid_ext());
     key.push_str(object.create.cwd.as_os_str().as_encoded_bytes());
     for include_define in &object.local_code.include_defines {
@@ -56,6 +68,8 @@ fn create_object_compatibility_key(object: &ObjectData) -> Result<BString> {
     args_processing::add_object_compatibility_args_to_key(
         object.create.binary,
         &object.create.args,
+        &object.create.cwd,
+        resolve_symlinks,
         &mut key,
     )?;
     Ok(key)
@@ -70,11 +84,125 @@ impl TaskPeriodInfo for GroupObjectsToChunksTaskInfo {
         "Group Chunks".to_string()
     }
 
-    fn terminal_one_liner(&self) -> String {
+    fn terminal_one_liner(&self, _project_roots: &ProjectRoots) -> String {
         format!("Objects: {}", self.num)
     }
 
     fn log_detailed(&self) {
-        log::info!("Group objects to chunks");
+        log::debug!("Group objects to chunks");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ccelerate_shared::WrappedBinary;
+
+    use super::*;
+    use crate::test_utils::TestEnv;
+
+    #[tokio::test]
+    async fn language_override_is_used_for_grouping() {
+        let env = TestEnv::new(&[]).await;
+        // `new` is not a valid name in C++, so this only compiles as C.
+        env.write("a.cpp", "int a(void) { int new = 1; return new; }");
+        env.write("b.c", "int b(void) { return 2; }");
+        env.write(
+            "main.c",
+            "#include <stdio.h>\nint a(void); int b(void);\nint main(void) { printf(\"%d\", a() + b()); return 0; }",
+        );
+        env.run_ok(WrappedBinary::Gcc, &["-x", "c", "-c", "a.cpp", "-o", "a.o"])
+            .await;
+        env.run_ok(WrappedBinary::Gcc, &["-c", "b.c", "-o", "b.o"])
+            .await;
+        let objects = ["a.o", "b.o"].map(|name| {
+            env.state
+                .persistent
+                .get_object_file(&env.path(name))
+                .expect("object is recorded")
+        });
+        assert_eq!(
+            objects[0].local_code.local_code_file.extension(),
+            Some(std::ffi::OsStr::new("i"))
+        );
+        assert_eq!(
+            create_object_compatibility_key(&objects[0], false).expect("key can be created"),
+            create_object_compatibility_key(&objects[1], false).expect("key can be created"),
+        );
+        env.run_ok(WrappedBinary::Gcc, &["main.c", "a.o", "b.o", "-o", "main"])
+            .await;
+        assert_eq!(env.run_program("main"), "3");
+    }
+
+    #[tokio::test]
+    async fn pinned_object_is_compiled_alone() {
+        let env = TestEnv::new(&[]).await;
+        let names = ["a", "b", "c"];
+        for name in names {
+            env.write(
+                &format!("{}.c", name),
+                &format!("int {name}(void) {{ return 1; }}\n"),
+            );
+            env.run_ok(
+                WrappedBinary::Gcc,
+                &["-c", &format!("{}.c", name), "-o", &format!("{}.o", name)],
+            )
+            .await;
+        }
+        assert!(
+            env.state
+                .persistent
+                .set_object_file_pinned_individual(&env.path("b.o"), true)
+                .expect("pin can be set")
+        );
+        // The pin is kept when the object is compiled again.
+        env.run_ok(WrappedBinary::Gcc, &["-c", "b.c", "-o", "b.o"])
+            .await;
+
+        let objects = names.map(|name| {
+            env.state
+                .persistent
+                .get_object_file(&env.path(&format!("{}.o", name)))
+                .expect("object is recorded")
+        });
+        let chunks =
+            group_compatible_objects(&objects, &env.state).expect("objects can be grouped");
+        assert_eq!(chunks.len(), 2);
+        let pinned_chunk = chunks
+            .iter()
+            .find(|chunk| chunk.objects.iter().any(|o| o.path == env.path("b.o")))
+            .expect("pinned object is in a chunk");
+        assert_eq!(pinned_chunk.objects.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn equivalent_include_paths_are_grouped() {
+        let env = TestEnv::new(&[]).await;
+        env.write("a/b/shared.h", "#define VALUE 1\n");
+        for (name, include) in [("x", "-Ia/./b"), ("y", "-Ia/b"), ("z", "-Ia/b/")] {
+            env.write(
+                &format!("{}.c", name),
+                &format!("#include \"shared.h\"\nint {name}(void) {{ return VALUE; }}\n"),
+            );
+            env.run_ok(
+                WrappedBinary::Gcc,
+                &[
+                    include,
+                    "-c",
+                    &format!("{}.c", name),
+                    "-o",
+                    &format!("{}.o", name),
+                ],
+            )
+            .await;
+        }
+        let objects = ["x.o", "y.o", "z.o"].map(|name| {
+            env.state
+                .persistent
+                .get_object_file(&env.path(name))
+                .expect("object is recorded")
+        });
+        let chunks =
+            group_compatible_objects(&objects, &env.state).expect("objects can be grouped");
+        assert_eq!(chunks.len(), 1);
     }
 }
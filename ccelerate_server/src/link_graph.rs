@@ -0,0 +1,186 @@
+#![deny(clippy::unwrap_used)]
+
+use std::{collections::HashSet, fmt::Write, path::Path, sync::Arc};
+
+use anyhow::{Result, anyhow};
+
+use crate::{
+    args_processing, group_compatible_objects::group_compatible_objects,
+    link_sources::find_link_sources, path_utils::ProjectRoots, state::State,
+    state_persistent::ObjectData,
+};
+
+/// Creates a DOT graph that shows which archives and objects end up in a previously linked
+/// binary. Objects that can be compiled together are grouped in clusters. Nothing is compiled.
+pub fn create_link_graph(output: &Path, state: &Arc<State>) -> Result<String> {
+    let record = state
+        .persistent
+        .get_linked_file(output)
+        .ok_or_else(|| anyhow!("No link is recorded for {}", output.display()))?;
+    let args_info =
+        args_processing::LinkFileInfo::from_args(record.binary, &record.cwd, &record.args)?;
+    let config = state.config_manager.config_for_paths(&[&record.cwd])?;
+    let link_sources = find_link_sources(&args_info, state, &config)?;
+
+    let project_roots = &state.project_roots;
+    let mut dot = String::new();
+    writeln!(dot, "digraph link {{")?;
+    writeln!(dot, "  rankdir=LR;")?;
+    writeln!(dot, "  node [shape=box];")?;
+    write_node(&mut dot, project_roots, output, "shape=doubleoctagon")?;
+    for source in &args_info.sources {
+        write_edge(&mut dot, output, &source.path)?;
+    }
+    for (archive, members) in &link_sources.archive_members {
+        write_node(&mut dot, project_roots, archive, "shape=folder")?;
+        for member in members {
+            write_edge(&mut dot, archive, member)?;
+        }
+    }
+    let unknown_sources = link_sources
+        .unknown_sources
+        .iter()
+        .chain(link_sources.whole_archive_unknown_sources.iter())
+        .collect::<HashSet<_>>();
+    for path in unknown_sources {
+        write_node(&mut dot, project_roots, path, "style=dashed")?;
+    }
+    let mut cluster_index = 0;
+    for (objects, label_suffix) in [
+        (&link_sources.known_object_files, ""),
+        (
+            &link_sources.whole_archive_known_object_files,
+            ", whole archive",
+        ),
+    ] {
+        for group in group_compatible_objects(objects, state)? {
+            write_cluster(
+                &mut dot,
+                project_roots,
+                cluster_index,
+                &group.objects,
+                label_suffix,
+            )?;
+            cluster_index += 1;
+        }
+    }
+    writeln!(dot, "}}")?;
+    Ok(dot)
+}
+
+fn write_cluster(
+    dot: &mut String,
+    project_roots: &ProjectRoots,
+    index: usize,
+    objects: &[Arc<ObjectData>],
+    label_suffix: &str,
+) -> Result<()> {
+    writeln!(dot, "  subgraph cluster_{} {{", index)?;
+    writeln!(
+        dot,
+        "    label={};",
+        quote(&format!(
+            "Group {} ({} objects{})",
+            index,
+            objects.len(),
+            label_suffix
+        ))
+    )?;
+    for object in objects {
+        let attributes = if object.pinned_individual {
+            "style=bold"
+        } else {
+            ""
+        };
+        write!(dot, "  ")?;
+        write_node(dot, project_roots, &object.path, attributes)?;
+    }
+    writeln!(dot, "  }}")?;
+    Ok(())
+}
+
+fn write_node(
+    dot: &mut String,
+    project_roots: &ProjectRoots,
+    path: &Path,
+    attributes: &str,
+) -> Result<()> {
+    let mut all_attributes = format!("label={}", quote(&project_roots.shorten(path)));
+    if !attributes.is_empty() {
+        all_attributes.push_str(", ");
+        all_attributes.push_str(attributes);
+    }
+    writeln!(dot, "  {} [{}];", node_id(path), all_attributes)?;
+    Ok(())
+}
+
+fn write_edge(dot: &mut String, from: &Path, to: &Path) -> Result<()> {
+    writeln!(dot, "  {} -> {};", node_id(from), node_id(to))?;
+    Ok(())
+}
+
+fn node_id(path: &Path) -> String {
+    quote(&path.to_string_lossy())
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use ccelerate_shared::WrappedBinary;
+
+    use super::*;
+    use crate::test_utils::TestEnv;
+
+    #[tokio::test]
+    async fn graph_contains_archives_and_objects() {
+        let env = TestEnv::new(&[]).await;
+        for name in ["a", "b", "c"] {
+            env.write(
+                &format!("{}.c", name),
+                &format!("int {name}(void) {{ return 1; }}\n"),
+            );
+            env.run_ok(
+                WrappedBinary::Gcc,
+                &["-c", &format!("{}.c", name), "-o", &format!("{}.o", name)],
+            )
+            .await;
+        }
+        env.run_ok(WrappedBinary::Ar, &["rcs", "libab.a", "a.o", "b.o"])
+            .await;
+        env.write(
+            "main.c",
+            "int a(void); int b(void); int c(void);\n\
+             int main(void) { return a() + b() + c() - 3; }\n",
+        );
+        env.run_ok(
+            WrappedBinary::Gcc,
+            &["main.c", "libab.a", "c.o", "-o", "main"],
+        )
+        .await;
+
+        let dot = create_link_graph(&env.path("main"), &env.state).expect("graph can be created");
+        let id = |name: &str| node_id(&env.path(name));
+        assert!(dot.starts_with("digraph link {"), "{}", dot);
+        for (from, to) in [
+            ("main", "main.c"),
+            ("main", "libab.a"),
+            ("main", "c.o"),
+            ("libab.a", "a.o"),
+            ("libab.a", "b.o"),
+        ] {
+            let edge = format!("{} -> {};", id(from), id(to));
+            assert!(dot.contains(&edge), "{} not in {}", edge, dot);
+        }
+        assert!(
+            dot.contains(&format!("{} [label=", id("libab.a"))),
+            "{}",
+            dot
+        );
+        // All objects are compiled the same way, so they are in one cluster.
+        assert_eq!(dot.matches("subgraph cluster_").count(), 1, "{}", dot);
+        assert!(dot.contains("Group 0 (3 objects)"), "{}", dot);
+    }
+}
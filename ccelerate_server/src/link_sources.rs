@@ -1,75 +1,150 @@
-use std::{
-    collections::HashSet,
-    path::{Path, PathBuf},
-    sync::Arc,
-};
+use std::{collections::HashSet, path::PathBuf, sync::Arc};
 
 use crate::{
-    ar_args, args_processing, path_utils::shorten_path, state::State, state_persistent::ObjectData,
-    task_periods::TaskPeriodInfo,
+    ar_args, args_processing, config::Config, path_utils::ProjectRoots, placeholders, state::State,
+    state_persistent::ObjectData, task_periods::TaskPeriodInfo,
 };
 
 use anyhow::Result;
+use ccelerate_shared::WrappedBinary;
 
 #[derive(Debug, Default)]
 pub struct OriginalLinkSources {
     // These are link sources that were not compiled here, so they were probably
-    // precompiled using a different system.
+    // precompiled using a different system. Every path is only contained once, at the
+    // position where it was first found, because that is what matters for symbol resolution.
     pub unknown_sources: Vec<PathBuf>,
     // Those object files are compiled from source here, so we know how they are
     // compiled exactly and can optimize that process.
     pub known_object_files: Vec<Arc<ObjectData>>,
+    // Same as above, but for sources that were found in a `--whole-archive` region of the
+    // original link command. Those have to stay in such a region so that e.g. static
+    // constructors are not dropped by the linker.
+    pub whole_archive_unknown_sources: Vec<PathBuf>,
+    // This also contains objects that are passed to a shared library link directly.
+    pub whole_archive_known_object_files: Vec<Arc<ObjectData>>,
+    // Link sources (outside of `--whole-archive` regions) for each source of the original
+    // command, including repetitions. They replace the original source at its position, so
+    // that they keep their order relative to e.g. `-l` arguments and shared libraries.
+    pub ordered_sources: Vec<Vec<OrderedLinkSource>>,
+    // Archives known here with their members, in the order they were found.
+    pub archive_members: Vec<(PathBuf, Vec<PathBuf>)>,
 
+    // Same paths as in `unknown_sources` for fast lookup.
+    unknown_sources_set: HashSet<PathBuf>,
+    // All paths that have been visited already, independent of their type. This is used to
+    // deduplicate repeated link inputs and to avoid running into cycles.
     handled_paths: HashSet<PathBuf>,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OrderedLinkSource {
+    // Placeholder for the position of one or more known object files.
+    KnownObjects,
+    Unknown(PathBuf),
+}
+
+struct PendingLinkSource {
+    path: PathBuf,
+    whole_archive: bool,
+    // Objects that are passed to a shared library link directly are always linked entirely,
+    // even though nothing in the link references their symbols. Putting them into an archive
+    // would drop the exported symbols.
+    link_entirely: bool,
+    // Index of the source in the original command that this was found through.
+    origin: usize,
+}
+
 pub fn find_link_sources(
     args_info: &args_processing::LinkFileInfo,
     state: &Arc<State>,
+    config: &Config,
 ) -> Result<OriginalLinkSources> {
     let task_period = state.task_periods.start(FindLinkSourcesTaskInfo {
         output: args_info.output.clone(),
     });
 
-    let mut link_sources = OriginalLinkSources::default();
-    for source in args_info.sources.iter() {
-        find_link_sources_for_file(&source.path, &mut link_sources, state)?;
+    let mut link_sources = OriginalLinkSources {
+        ordered_sources: vec![vec![]; args_info.sources.len()],
+        ..Default::default()
+    };
+    // Use an explicit stack instead of recursion because archives may reference other archives
+    // arbitrarily deep. Paths are pushed in reverse so that they are popped in discovery order.
+    let mut pending: Vec<PendingLinkSource> = args_info
+        .sources
+        .iter()
+        .enumerate()
+        .rev()
+        .map(|(origin, s)| PendingLinkSource {
+            path: s.path.clone(),
+            whole_archive: args_info.whole_archive_sources.contains(&s.path),
+            link_entirely: args_info.is_shared_library,
+            origin,
+        })
+        .collect();
+    while let Some(source) = pending.pop() {
+        if !link_sources.handled_paths.insert(source.path.clone()) {
+            if !source.whole_archive {
+                let is_unknown = link_sources.unknown_sources_set.contains(&source.path);
+                link_sources.add_ordered_source(
+                    source.origin,
+                    match is_unknown {
+                        true => OrderedLinkSource::Unknown(source.path),
+                        false => OrderedLinkSource::KnownObjects,
+                    },
+                );
+            }
+            continue;
+        }
+        find_link_sources_for_file(&source, &mut link_sources, &mut pending, state, config)?;
     }
     task_period.finished_successfully();
     Ok(link_sources)
 }
 
 fn find_link_sources_for_file(
-    path: &Path,
+    source: &PendingLinkSource,
     link_sources: &mut OriginalLinkSources,
+    pending: &mut Vec<PendingLinkSource>,
     state: &Arc<State>,
+    config: &Config,
 ) -> Result<()> {
+    let path = source.path.as_path();
     match path.extension() {
         Some(extension) if extension == "a" => {
-            find_link_sources_for_static_library(path, link_sources, state)?;
+            find_link_sources_for_static_library(source, link_sources, pending, state)?;
         }
-        Some(extension) if extension == "o" => {
-            find_link_sources_for_object_file(path, link_sources, state)?;
+        Some(_) if config.is_object_path(path) => {
+            find_link_sources_for_object_file(source, link_sources, state)?;
         }
         _ => {
-            link_sources.unknown_sources.push(path.to_owned());
+            link_sources.add_unknown_source(source);
         }
     }
     Ok(())
 }
 
 fn find_link_sources_for_static_library(
-    library_path: &Path,
+    source: &PendingLinkSource,
     link_sources: &mut OriginalLinkSources,
+    pending: &mut Vec<PendingLinkSource>,
     state: &Arc<State>,
 ) -> Result<()> {
-    if !link_sources.handled_paths.insert(library_path.to_owned()) {
-        return Ok(());
-    }
+    let library_path = source.path.as_path();
     let Some(record) = state.persistent.get_archive_file(library_path) else {
-        link_sources.unknown_sources.push(library_path.to_owned());
+        link_sources.add_unknown_source(source);
         return Ok(());
     };
+    if state.cli.hybrid
+        && !placeholders::is_placeholder_file(library_path, placeholders::dummy_archive())
+    {
+        log::debug!(
+            "Archive was rebuilt without ccelerate: {}",
+            library_path.display()
+        );
+        link_sources.add_unknown_source(source);
+        return Ok(());
+    }
     if !record.binary.is_ar_compatible() {
         return Err(anyhow::anyhow!(
             "Archive not created by ar: {}",
@@ -77,34 +152,85 @@ fn find_link_sources_for_static_library(
         ));
     }
     let ar_args = ar_args::BuildStaticArchiveInfo::from_args(&record.cwd, &record.args)?;
-    for source in ar_args.member_paths {
-        find_link_sources_for_file(&source, link_sources, state)?;
-    }
+    link_sources
+        .archive_members
+        .push((library_path.to_owned(), ar_args.member_paths.to_vec()));
+    pending.extend(
+        ar_args
+            .member_paths
+            .into_iter()
+            .rev()
+            .map(|path| PendingLinkSource {
+                path,
+                whole_archive: source.whole_archive,
+                link_entirely: false,
+                origin: source.origin,
+            }),
+    );
     Ok(())
 }
 
 fn find_link_sources_for_object_file(
-    object_path: &Path,
+    source: &PendingLinkSource,
     link_sources: &mut OriginalLinkSources,
     state: &Arc<State>,
 ) -> Result<()> {
-    if !link_sources.handled_paths.insert(object_path.to_owned()) {
-        return Ok(());
-    }
+    let object_path = source.path.as_path();
     let Some(record) = state.persistent.get_object_file(object_path) else {
-        link_sources.unknown_sources.push(object_path.to_owned());
+        link_sources.add_unknown_source(source);
         return Ok(());
     };
-    if !record.create.binary.is_gcc_compatible() {
+    // The record may be stale, e.g. when the object was rebuilt outside of ccelerate later.
+    // Then the object on disk is real and has to be linked as is.
+    if !placeholders::is_placeholder_file(object_path, placeholders::dummy_object()) {
+        log::debug!(
+            "Object was rebuilt without ccelerate: {}",
+            object_path.display()
+        );
+        link_sources.add_unknown_source(source);
+        return Ok(());
+    }
+    if !record.create.binary.is_gcc_compatible() && record.create.binary != WrappedBinary::ClangCl {
         return Err(anyhow::anyhow!(
-            "Object file not created by gcc compatible: {}",
+            "Object file not created by a supported compiler: {}",
             object_path.display()
         ));
     }
-    link_sources.known_object_files.push(record);
+    if source.whole_archive || source.link_entirely {
+        link_sources.whole_archive_known_object_files.push(record);
+    } else {
+        link_sources.known_object_files.push(record);
+        link_sources.add_ordered_source(source.origin, OrderedLinkSource::KnownObjects);
+    }
     Ok(())
 }
 
+impl OriginalLinkSources {
+    fn add_unknown_source(&mut self, source: &PendingLinkSource) {
+        if source.whole_archive {
+            self.whole_archive_unknown_sources.push(source.path.clone());
+        } else {
+            self.unknown_sources.push(source.path.clone());
+            self.unknown_sources_set.insert(source.path.clone());
+            self.add_ordered_source(
+                source.origin,
+                OrderedLinkSource::Unknown(source.path.clone()),
+            );
+        }
+    }
+
+    fn add_ordered_source(&mut self, origin: usize, source: OrderedLinkSource) {
+        let ordered_sources = &mut self.ordered_sources[origin];
+        if source == OrderedLinkSource::KnownObjects
+            && ordered_sources.last() == Some(&OrderedLinkSource::KnownObjects)
+        {
+            // All known objects end up in the same archive, so it does not have to be repeated.
+            return;
+        }
+        ordered_sources.push(source);
+    }
+}
+
 struct FindLinkSourcesTaskInfo {
     output: PathBuf,
 }
@@ -114,11 +240,443 @@ impl TaskPeriodInfo for FindLinkSourcesTaskInfo {
         "Find Sources".to_string()
     }
 
-    fn terminal_one_liner(&self) -> String {
-        shorten_path(&self.output)
+    fn terminal_one_liner(&self, project_roots: &ProjectRoots) -> String {
+        project_roots.shorten(&self.output)
     }
 
     fn log_detailed(&self) {
-        log::info!("Find link sources for {}", self.output.to_string_lossy());
+        log::debug!("Find link sources for {}", self.output.to_string_lossy());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use ccelerate_shared::WrappedBinary;
+
+    use super::*;
+    use crate::{config::Config, test_utils::TestEnv};
+
+    #[tokio::test]
+    async fn deep_archive_chain_does_not_overflow() {
+        let env = TestEnv::new(&[]).await;
+        let cwd = env.path("");
+        let depth = 10_000;
+        // Inserting all records in one transaction is much faster.
+        let execute = |sql: &str| {
+            env.state
+                .persistent
+                .conn
+                .lock()
+                .execute_batch(sql)
+                .expect("statement can be executed")
+        };
+        execute("BEGIN");
+        for i in 0..depth {
+            let archive = cwd.join(format!("lib{}.a", i));
+            let member = cwd.join(format!("lib{}.a", i + 1));
+            env.state
+                .persistent
+                .update_archive_file(
+                    &archive,
+                    WrappedBinary::Ar,
+                    &cwd,
+                    [OsStr::new("rcs"), archive.as_os_str(), member.as_os_str()],
+                )
+                .expect("record can be stored");
+        }
+        execute("COMMIT");
+        let link_info =
+            args_processing::LinkFileInfo::from_gcc_args(&cwd, &["lib0.a", "-o", "main"])
+                .expect("args can be parsed");
+        let link_sources = find_link_sources(&link_info, &env.state, &Config::new())
+            .expect("link sources can be found");
+        assert_eq!(link_sources.archive_members.len(), depth);
+        // The last archive in the chain has no record, so it's linked as is.
+        assert_eq!(
+            link_sources.unknown_sources,
+            vec![cwd.join(format!("lib{}.a", depth))]
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_sources_keep_first_position() {
+        let env = TestEnv::new(&[]).await;
+        let cwd = env.path("");
+        let link_info = args_processing::LinkFileInfo::from_gcc_args(
+            &cwd,
+            &[
+                "x.a", "y.so", "x.a", "z.a", "y.so", "w.a", "z.a", "-o", "main",
+            ],
+        )
+        .expect("args can be parsed");
+        let link_sources = find_link_sources(&link_info, &env.state, &Config::new())
+            .expect("link sources can be found");
+        assert_eq!(
+            link_sources.unknown_sources,
+            ["x.a", "y.so", "z.a", "w.a"]
+                .iter()
+                .map(|name| cwd.join(name))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn repeated_archives_are_expanded_once() {
+        let env = TestEnv::new(&[]).await;
+        let cwd = env.path("");
+        // Like in Blender, the archives depend on each other and are listed multiple times.
+        env.write(
+            "kernel.c",
+            "int blenlib(void);\nint kernel(void) { return blenlib() + 1; }",
+        );
+        env.write("blenlib.c", "int kernel(void);\nint blenlib(void) { return 1; }\nint blenlib_kernel(void) { return kernel(); }");
+        env.write(
+            "main.c",
+            "#include <stdio.h>\nint blenlib_kernel(void);\nint main(void) { printf(\"%d\", blenlib_kernel()); return 0; }",
+        );
+        for name in ["kernel", "blenlib", "main"] {
+            env.run_ok(
+                WrappedBinary::Gcc,
+                &["-c", &format!("{}.c", name), "-o", &format!("{}.o", name)],
+            )
+            .await;
+        }
+        env.run_ok(WrappedBinary::Ar, &["rcs", "libkernel.a", "kernel.o"])
+            .await;
+        env.run_ok(WrappedBinary::Ar, &["rcs", "libblenlib.a", "blenlib.o"])
+            .await;
+        let args = [
+            "main.o",
+            "libkernel.a",
+            "libblenlib.a",
+            "libkernel.a",
+            "libblenlib.a",
+            "libkernel.a",
+            "-o",
+            "main",
+        ];
+
+        let link_info =
+            args_processing::LinkFileInfo::from_gcc_args(&cwd, &args).expect("args can be parsed");
+        let link_sources = find_link_sources(&link_info, &env.state, &Config::new())
+            .expect("link sources can be found");
+        assert_eq!(
+            link_sources
+                .archive_members
+                .iter()
+                .map(|(archive, _)| archive.clone())
+                .collect::<Vec<_>>(),
+            vec![cwd.join("libkernel.a"), cwd.join("libblenlib.a")]
+        );
+        assert_eq!(link_sources.known_object_files.len(), 3);
+        assert!(link_sources.unknown_sources.is_empty());
+        // Every original source is replaced by the archive of known objects. The rewritten
+        // command skips the consecutive repetitions.
+        assert_eq!(
+            link_sources.ordered_sources,
+            vec![vec![OrderedLinkSource::KnownObjects]; 6]
+        );
+
+        env.run_ok(WrappedBinary::Gcc, &args).await;
+        assert_eq!(env.run_program("main"), "2");
+    }
+
+    #[tokio::test]
+    async fn grouped_and_ungrouped_links_resolve_repeated_archives() {
+        for mode in ["always", "never"] {
+            let env = TestEnv::new(&["--link-group", mode]).await;
+            let cwd = env.path("");
+            // `libext.a` is built without ccelerate and is needed before and after the known
+            // objects, so without a link group, its repetition has to be preserved.
+            env.write("ext.c", "int ext(void) { return 1; }");
+            env.write(
+                "ext2.c",
+                "int kernel(void);\nint ext2(void) { return kernel() + 1; }",
+            );
+            env.write(
+                "kernel.c",
+                "int ext(void);\nint kernel(void) { return ext() + 1; }",
+            );
+            env.write(
+                "main.c",
+                "#include <stdio.h>\nint ext2(void);\nint main(void) { printf(\"%d\", ext2()); return 0; }",
+            );
+            for name in ["ext", "ext2"] {
+                let status = std::process::Command::new("gcc")
+                    .args(["-c", &format!("{}.c", name)])
+                    .current_dir(&cwd)
+                    .status()
+                    .expect("gcc can be started");
+                assert!(status.success());
+            }
+            let status = std::process::Command::new("ar")
+                .args(["rcs", "libext.a", "ext.o", "ext2.o"])
+                .current_dir(&cwd)
+                .status()
+                .expect("ar can be started");
+            assert!(status.success());
+            for name in ["kernel", "main"] {
+                env.run_ok(
+                    WrappedBinary::Gcc,
+                    &["-c", &format!("{}.c", name), "-o", &format!("{}.o", name)],
+                )
+                .await;
+            }
+            env.run_ok(WrappedBinary::Ar, &["rcs", "libkernel.a", "kernel.o"])
+                .await;
+            let args = [
+                "main.o",
+                "libext.a",
+                "libkernel.a",
+                "libext.a",
+                "-o",
+                "main",
+            ];
+
+            let link_info = args_processing::LinkFileInfo::from_gcc_args(&cwd, &args)
+                .expect("args can be parsed");
+            let link_sources = find_link_sources(&link_info, &env.state, &Config::new())
+                .expect("link sources can be found");
+            assert_eq!(
+                link_sources.ordered_sources,
+                vec![
+                    vec![OrderedLinkSource::KnownObjects],
+                    vec![OrderedLinkSource::Unknown(cwd.join("libext.a"))],
+                    vec![OrderedLinkSource::KnownObjects],
+                    vec![OrderedLinkSource::Unknown(cwd.join("libext.a"))],
+                ]
+            );
+
+            env.run_ok(WrappedBinary::Gcc, &args).await;
+            assert_eq!(env.run_program("main"), "3", "--link-group {}", mode);
+        }
+    }
+
+    #[tokio::test]
+    async fn blender_style_link_partition() {
+        let env = TestEnv::new(&[]).await;
+        let cwd = env.path("build");
+        std::fs::create_dir_all(cwd.join("lib")).expect("directory can be created");
+        let persistent = &env.state.persistent;
+        let record_object = |name: &str| {
+            let path = cwd.join(name);
+            std::fs::write(&path, placeholders::dummy_object()).expect("file can be written");
+            persistent
+                .update_object_file(
+                    &path,
+                    &crate::executable::Executable::new(WrappedBinary::Gxx, None),
+                    &cwd,
+                    [
+                        OsStr::new("-c"),
+                        OsStr::new("x.cc"),
+                        OsStr::new("-o"),
+                        path.as_os_str(),
+                    ],
+                )
+                .expect("record can be stored");
+            persistent
+                .update_object_file_local_code(
+                    &path,
+                    &env.state.data_dir.join(format!("{}.ii", name)),
+                    name,
+                    Vec::<PathBuf>::new(),
+                    Vec::<&bstr::BStr>::new(),
+                    None,
+                )
+                .expect("record can be stored");
+        };
+        let record_archive = |name: &str, members: &[&str]| {
+            let path = cwd.join(name);
+            persistent
+                .update_archive_file(
+                    &path,
+                    WrappedBinary::Ar,
+                    &cwd,
+                    ["qc", name].into_iter().chain(members.iter().copied()),
+                )
+                .expect("record can be stored");
+        };
+        for name in [
+            "creator.cc.o",
+            "kernel_main.cc.o",
+            "kernel_mesh.cc.o",
+            "blenlib_math.cc.o",
+            "blenlib_string.cc.o",
+        ] {
+            record_object(name);
+        }
+        record_archive(
+            "lib/libbf_blenkernel.a",
+            &["kernel_main.cc.o", "kernel_mesh.cc.o"],
+        );
+        record_archive(
+            "lib/libbf_blenlib.a",
+            &["blenlib_math.cc.o", "blenlib_string.cc.o"],
+        );
+
+        // Shortened version of the final link of Blender.
+        let args = [
+            "-Wl,--gc-sections",
+            "-rdynamic",
+            "creator.cc.o",
+            "-o",
+            "bin/blender",
+            "lib/libbf_blenkernel.a",
+            "lib/libbf_blenlib.a",
+            "/usr/lib/x86_64-linux-gnu/libpython3.11.so",
+            "lib/libbf_blenkernel.a",
+            "lib/libextern_glog.a",
+            "/usr/lib/x86_64-linux-gnu/libz.a",
+            "lib/libbf_blenlib.a",
+            "-lpthread",
+            "/usr/lib/x86_64-linux-gnu/libpython3.11.so",
+            "lib/libbf_blenkernel.a",
+        ];
+        let link_info =
+            args_processing::LinkFileInfo::from_gcc_args(&cwd, &args).expect("args can be parsed");
+        let link_sources = find_link_sources(&link_info, &env.state, &Config::new())
+            .expect("link sources can be found");
+
+        assert_eq!(
+            link_sources
+                .known_object_files
+                .iter()
+                .map(|object| object.path.clone())
+                .collect::<Vec<_>>(),
+            [
+                "creator.cc.o",
+                "kernel_main.cc.o",
+                "kernel_mesh.cc.o",
+                "blenlib_math.cc.o",
+                "blenlib_string.cc.o",
+            ]
+            .map(|name| cwd.join(name))
+        );
+        assert_eq!(
+            link_sources.unknown_sources,
+            vec![
+                PathBuf::from("/usr/lib/x86_64-linux-gnu/libpython3.11.so"),
+                cwd.join("lib/libextern_glog.a"),
+                PathBuf::from("/usr/lib/x86_64-linux-gnu/libz.a"),
+            ]
+        );
+        assert_eq!(
+            link_sources
+                .archive_members
+                .iter()
+                .map(|(archive, _)| archive.clone())
+                .collect::<Vec<_>>(),
+            [
+                cwd.join("lib/libbf_blenkernel.a"),
+                cwd.join("lib/libbf_blenlib.a")
+            ]
+        );
+        assert!(link_sources.whole_archive_known_object_files.is_empty());
+        assert!(link_sources.whole_archive_unknown_sources.is_empty());
+    }
+
+    #[tokio::test]
+    async fn hybrid_link_mixes_real_and_ccelerate_objects() {
+        let env = TestEnv::new(&["--hybrid"]).await;
+        let cwd = env.path("");
+        env.write("real.c", "int real(void) { return 1; }\n");
+        env.write("lib.c", "int lib(void) { return 2; }\n");
+        env.write("other.c", "int other(void) { return 3; }\n");
+        env.write(
+            "main.c",
+            "#include <stdio.h>\nint real(void);\nint lib(void);\nint other(void);\n\
+             int main(void) { printf(\"%d\", real() + lib() + other()); return 0; }\n",
+        );
+        for name in ["main", "lib", "other"] {
+            env.run_ok(
+                WrappedBinary::Gcc,
+                &["-c", &format!("{}.c", name), "-o", &format!("{}.o", name)],
+            )
+            .await;
+        }
+        env.run_ok(WrappedBinary::Ar, &["rcs", "liblib.a", "lib.o"])
+            .await;
+        env.run_ok(WrappedBinary::Ar, &["rcs", "libother.a", "other.o"])
+            .await;
+        // `real.o` and `libother.a` are built by targets that bypass ccelerate. The archive
+        // still has a record from before.
+        for args in [
+            &["gcc", "-c", "real.c"][..],
+            &["gcc", "-c", "other.c"],
+            &["ar", "rcs", "libother.a", "other.o"],
+        ] {
+            let status = std::process::Command::new(args[0])
+                .args(&args[1..])
+                .current_dir(&cwd)
+                .status()
+                .expect("tool can be started");
+            assert!(status.success());
+        }
+        let args = ["main.o", "real.o", "liblib.a", "libother.a", "-o", "main"];
+
+        let link_info =
+            args_processing::LinkFileInfo::from_gcc_args(&cwd, &args).expect("args can be parsed");
+        let link_sources = find_link_sources(&link_info, &env.state, &Config::new())
+            .expect("link sources can be found");
+        assert_eq!(
+            link_sources
+                .known_object_files
+                .iter()
+                .map(|object| object.path.clone())
+                .collect::<Vec<_>>(),
+            vec![cwd.join("main.o"), cwd.join("lib.o")]
+        );
+        assert_eq!(
+            link_sources.unknown_sources,
+            vec![cwd.join("real.o"), cwd.join("libother.a")]
+        );
+
+        env.run_ok(WrappedBinary::Gcc, &args).await;
+        assert_eq!(env.run_program("main"), "6");
+    }
+
+    #[tokio::test]
+    async fn stale_record_of_real_object_is_not_expanded() {
+        let env = TestEnv::new(&[]).await;
+        let cwd = env.path("");
+        env.write("a.c", "int a(void) { return 1; }\n");
+        env.write(
+            "main.c",
+            "#include <stdio.h>\nint a(void);\nint main(void) { printf(\"%d\", a()); return 0; }\n",
+        );
+        for name in ["main", "a"] {
+            env.run_ok(
+                WrappedBinary::Gcc,
+                &["-c", &format!("{}.c", name), "-o", &format!("{}.o", name)],
+            )
+            .await;
+        }
+        // The object is rebuilt outside of ccelerate afterwards, so its record is stale.
+        env.write("a.c", "int a(void) { return 5; }\n");
+        let status = std::process::Command::new("gcc")
+            .args(["-c", "a.c", "-o", "a.o"])
+            .current_dir(&cwd)
+            .status()
+            .expect("gcc can be started");
+        assert!(status.success());
+        assert!(
+            env.state
+                .persistent
+                .get_object_file(&cwd.join("a.o"))
+                .is_some()
+        );
+        let args = ["main.o", "a.o", "-o", "main"];
+
+        let link_info =
+            args_processing::LinkFileInfo::from_gcc_args(&cwd, &args).expect("args can be parsed");
+        let link_sources = find_link_sources(&link_info, &env.state, &Config::new())
+            .expect("link sources can be found");
+        assert_eq!(link_sources.known_object_files.len(), 1);
+        assert_eq!(link_sources.unknown_sources, vec![cwd.join("a.o")]);
+
+        env.run_ok(WrappedBinary::Gcc, &args).await;
+        assert_eq!(env.run_program("main"), "5");
     }
 }
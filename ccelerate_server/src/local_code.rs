@@ -8,8 +8,12 @@ use std::{
 
 use anyhow::Result;
 use bstr::{BStr, BString, ByteSlice};
+use ccelerate_shared::WrappedBinary;
 
-use crate::{config::Config, path_utils::make_absolute};
+use crate::{
+    config::Config,
+    path_utils::{make_absolute, normalize_path},
+};
 
 #[derive(Debug, Default)]
 pub struct LocalCode {
@@ -26,11 +30,31 @@ pub struct LocalCode {
     pub include_defines: Vec<BString>,
 }
 
+/// Compilers differ slightly in how they write line markers into preprocessed code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinemarkerFlavor {
+    Gcc,
+    // Clang marks entering and leaving pseudo files like `<built-in>` explicitly and may also
+    // use `#line` directives (e.g. with `-fuse-line-directives`).
+    Clang,
+}
+
+impl LinemarkerFlavor {
+    pub fn from_binary(binary: WrappedBinary) -> Self {
+        match binary {
+            // clang-cl writes `#line` directives like MSVC.
+            WrappedBinary::Clang | WrappedBinary::Clangxx | WrappedBinary::ClangCl => Self::Clang,
+            _ => Self::Gcc,
+        }
+    }
+}
+
 impl LocalCode {
     pub async fn from_preprocessed_code(
         code: &BStr,
         source_file_path: &Path,
         config: &Config,
+        flavor: LinemarkerFlavor,
     ) -> Result<LocalCode> {
         let Some(source_dir) = source_file_path.parent() else {
             return Err(anyhow::anyhow!(
@@ -48,34 +72,57 @@ impl LocalCode {
 
         let mut revertable_previous_line_start = None;
         let write_line_markers = true;
+        // Headers that are included from a pseudo file are passed with `-include`.
+        let mut in_pseudo_file = false;
 
         for line in code.split(|&b| b == b'\n') {
             let is_local = header_stack.len() == local_depth;
             let line = line.as_bstr();
             if line.starts_with(b"#define ") {
-                if is_local {
-                    if let Ok(macro_def) = MacroDefinition::parse(line) {
-                        if config.is_include_define(macro_def.name) {
-                            result.include_defines.push(line.to_owned());
-                        }
-                    }
+                if is_local
+                    && let Ok(macro_def) = MacroDefinition::parse(line)
+                    && config.is_include_define(macro_def.name)
+                {
+                    result.include_defines.push(line.to_owned());
                 }
             } else if let Some(_undef) = line.strip_prefix(b"#undef ") {
                 continue;
-            } else if line.starts_with(b"# ") {
+            } else if line.starts_with(b"# ")
+                || (flavor == LinemarkerFlavor::Clang && line.starts_with(b"#line "))
+            {
                 let Ok(line_marker) = GccLinemarker::parse(line) else {
                     continue;
                 };
                 let header_path = Path::new(line_marker.header_name);
+                let is_forced_include = in_pseudo_file;
+                in_pseudo_file = line_marker.is_pseudo_file();
                 if line_marker.is_start_of_new_file {
-                    if is_local {
-                        if config.is_local_header(header_path) {
+                    // Pseudo files like `<built-in>` contain e.g. predefined macros. They are
+                    // not local code, but they are also not headers that could be included.
+                    let is_pseudo_file = line_marker.is_pseudo_file();
+                    // All objects in a chunk have the same `-include` arguments, so forced
+                    // includes are already part of the shared preprocessed headers. They are
+                    // neither added to the local code nor included a second time.
+                    if is_local && !is_pseudo_file && !is_forced_include {
+                        // Classify based on the absolute path so that the same header is treated
+                        // the same in every translation unit, independent of how it's included.
+                        // Otherwise, a header may end up in the shared header block and in the
+                        // local code of another file at the same time. Include guards and
+                        // `#pragma once` don't protect against that because the local code does
+                        // not contain the header anymore. The path is normalized because e.g.
+                        // `local/../common.h` would match a pattern like `**/local/*.h`.
+                        if config.is_local_header(&normalize_path(&make_absolute(
+                            source_dir,
+                            header_path,
+                        ))) {
                             local_depth += 1;
                         } else {
                             result.direct_includes.push(header_path.to_owned());
                         }
                     }
-                    all_includes.insert(header_path);
+                    if !is_pseudo_file {
+                        all_includes.insert(header_path);
+                    }
                     header_stack.push(header_path);
                 } else if line_marker.is_return_to_file {
                     header_stack.pop();
@@ -165,7 +212,7 @@ impl<'a> GccLinemarker<'a> {
         let line = std::str::from_utf8(line)?;
         let err = || anyhow::anyhow!("Failed to parse line: {:?}", line);
         static RE: once_cell::sync::Lazy<regex::Regex> = once_cell::sync::Lazy::new(|| {
-            regex::Regex::new(r#"# (\d+) "(.*)"\s*(\d?)\s*(\d?)\s*(\d?)\s*(\d?)"#)
+            regex::Regex::new(r#"#(?:line)? (\d+) "(.*)"\s*(\d?)\s*(\d?)\s*(\d?)\s*(\d?)"#)
                 .expect("should be valid")
         });
         let Some(captures) = RE.captures(line) else {
@@ -202,4 +249,209 @@ impl<'a> GccLinemarker<'a> {
             _next_is_extern_c: numbers.contains(&4),
         })
     }
+
+    fn is_pseudo_file(&self) -> bool {
+        self.header_name.starts_with('<') && self.header_name.ends_with('>')
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ccelerate_shared::WrappedBinary;
+
+    use super::*;
+    use crate::test_utils::TestEnv;
+
+    // What `clang -E` writes for the same files as in the test below.
+    const CLANG_OUTPUT: &str = r#"# 1 "a.c"
+# 1 "<built-in>" 1
+# 1 "<built-in>" 3
+# 384 "<built-in>" 3
+# 1 "<command line>" 1
+# 1 "<built-in>" 2
+# 1 "a.c" 2
+# 1 "./b.h" 1
+# 1 "./c.h" 1
+int c;
+# 2 "./b.h" 2
+int b;
+# 2 "a.c" 2
+int a;
+"#;
+
+    async fn extract(code: &[u8], source: &Path, flavor: LinemarkerFlavor) -> LocalCode {
+        LocalCode::from_preprocessed_code(code.as_bstr(), source, &Config::new(), flavor)
+            .await
+            .expect("local code can be extracted")
+    }
+
+    fn code_lines(local_code: &LocalCode) -> Vec<&BStr> {
+        local_code
+            .local_code
+            .lines()
+            .map(|line| line.as_bstr())
+            .filter(|line| !line.starts_with(b"#") && !line.trim_ascii().is_empty())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn gcc_and_clang_find_the_same_includes() {
+        let dir = tempfile::tempdir().expect("temp dir can be created");
+        std::fs::write(dir.path().join("a.c"), "#include \"b.h\"\nint a;\n")
+            .expect("file can be written");
+        std::fs::write(dir.path().join("b.h"), "#include \"c.h\"\nint b;\n")
+            .expect("file can be written");
+        std::fs::write(dir.path().join("c.h"), "int c;\n").expect("file can be written");
+        let gcc_output = std::process::Command::new("gcc")
+            .args(["-E", "a.c"])
+            .current_dir(dir.path())
+            .output()
+            .expect("gcc can be started");
+        assert!(gcc_output.status.success());
+
+        let source = dir.path().join("a.c");
+        let gcc = extract(&gcc_output.stdout, &source, LinemarkerFlavor::Gcc).await;
+        let clang = extract(CLANG_OUTPUT.as_bytes(), &source, LinemarkerFlavor::Clang).await;
+
+        assert_eq!(gcc.direct_includes, vec![dir.path().join("b.h")]);
+        assert_eq!(clang.direct_includes, gcc.direct_includes);
+        let project_includes = |local_code: &LocalCode| {
+            let mut includes = local_code
+                .all_includes
+                .iter()
+                .filter(|path| path.starts_with(dir.path()))
+                .cloned()
+                .collect::<Vec<_>>();
+            includes.sort();
+            includes
+        };
+        assert_eq!(
+            project_includes(&gcc),
+            vec![dir.path().join("b.h"), dir.path().join("c.h")]
+        );
+        assert_eq!(project_includes(&clang), project_includes(&gcc));
+        assert_eq!(code_lines(&gcc), vec!["int a;"]);
+        assert_eq!(code_lines(&clang), code_lines(&gcc));
+    }
+
+    #[tokio::test]
+    async fn clang_line_directives() {
+        let code = "#line 1 \"a.c\"\n#line 1 \"b.h\" 1\nint b;\n#line 2 \"a.c\" 2\nint a;\n";
+        let source = Path::new("/project/a.c");
+        let clang = extract(code.as_bytes(), source, LinemarkerFlavor::Clang).await;
+        assert_eq!(clang.direct_includes, vec![PathBuf::from("/project/b.h")]);
+        assert_eq!(code_lines(&clang), vec!["int a;"]);
+    }
+
+    #[tokio::test]
+    async fn pragma_once_header_included_directly_and_transitively() {
+        let env = TestEnv::new(&[]).await;
+        env.write(
+            "ccelerate.toml",
+            r#"
+eager_patterns = []
+local_header_patterns = ["**/local/*.h"]
+include_defines = []
+pure_c_header_patterns = []
+"#,
+        );
+        // Defining the struct twice in the chunk would fail.
+        let common = env.write("common.h", "#pragma once\nstruct Common { int value; };\n");
+        env.write("local/wrapper.h", "#include \"../common.h\"\n");
+        env.write(
+            "a.c",
+            "#include \"local/wrapper.h\"\n#include \"common.h\"\nint a(void) { struct Common c = {1}; return c.value; }",
+        );
+        env.write(
+            "b.c",
+            "#include \"common.h\"\nint b(void) { struct Common c = {2}; return c.value; }",
+        );
+        env.write(
+            "main.c",
+            "#include <stdio.h>\nint a(void); int b(void);\nint main(void) { printf(\"%d\", a() + b()); return 0; }",
+        );
+        for name in ["a", "b"] {
+            env.run_ok(
+                WrappedBinary::Gcc,
+                &["-c", &format!("{}.c", name), "-o", &format!("{}.o", name)],
+            )
+            .await;
+            let object = env
+                .state
+                .persistent
+                .get_object_file(&env.path(&format!("{}.o", name)))
+                .expect("object is recorded");
+            // The header is global in both files, even though `a.c` also includes it through a
+            // local header.
+            assert_eq!(
+                object
+                    .local_code
+                    .direct_includes
+                    .iter()
+                    .map(|path| crate::path_utils::normalize_path(path))
+                    .collect::<Vec<_>>(),
+                vec![common.clone()]
+            );
+        }
+        env.run_ok(WrappedBinary::Gcc, &["main.c", "a.o", "b.o", "-o", "main"])
+            .await;
+        assert_eq!(env.run_program("main"), "3");
+    }
+
+    #[tokio::test]
+    async fn common_forced_include_is_preprocessed_once() {
+        let env = TestEnv::new(&[]).await;
+        env.write(
+            "ccelerate.toml",
+            r#"
+eager_patterns = []
+local_header_patterns = ["**/local/*.h"]
+include_defines = []
+pure_c_header_patterns = []
+"#,
+        );
+        // Defining the function twice in the chunk would fail.
+        env.write(
+            "local/prefix.h",
+            "static int prefix_value(void) { return 3; }\n",
+        );
+        for name in ["a", "b"] {
+            env.write(
+                &format!("{}.c", name),
+                &format!("int {name}(void) {{ return prefix_value(); }}\n"),
+            );
+            env.run_ok(
+                WrappedBinary::Gcc,
+                &[
+                    "-include",
+                    "local/prefix.h",
+                    "-c",
+                    &format!("{}.c", name),
+                    "-o",
+                    &format!("{}.o", name),
+                ],
+            )
+            .await;
+            let object = env
+                .state
+                .persistent
+                .get_object_file(&env.path(&format!("{}.o", name)))
+                .expect("object is recorded");
+            let local_code = std::fs::read_to_string(&object.local_code.local_code_file)
+                .expect("local code exists");
+            assert!(
+                !local_code.contains("prefix_value(void) {"),
+                "{}",
+                local_code
+            );
+            assert!(object.local_code.direct_includes.is_empty());
+        }
+        env.write(
+            "main.c",
+            "#include <stdio.h>\nint a(void); int b(void);\nint main(void) { printf(\"%d\", a() + b()); return 0; }",
+        );
+        env.run_ok(WrappedBinary::Gcc, &["main.c", "a.o", "b.o", "-o", "main"])
+            .await;
+        assert_eq!(env.run_program("main"), "6");
+    }
 }
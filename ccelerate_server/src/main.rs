@@ -1,49 +1,79 @@
 use std::{
-    ffi::OsStr,
+    ffi::{OsStr, OsString},
     io::Write,
     num::NonZeroUsize,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{Arc, atomic::Ordering},
     time::Duration,
 };
 
 use actix_web::{HttpResponse, web::Data};
-use anyhow::Result;
-use ccelerate_shared::{RunRequestData, RunRequestDataWire, RunResponseData, WrappedBinary};
-use config::ConfigManager;
-use object_by_inputs_cache::ObjectByInputsCache;
+use anyhow::{Result, anyhow};
+use bstr::ByteSlice;
+use ccelerate_shared::{
+    CompileChunkRequestData, CompileChunkRequestWire, RunBatchRequestWire, RunLocallyResponseData,
+    RunRequestData, RunRequestDataWire, RunResponseData, RunResponseDataWire, WrappedBinary,
+};
+use config::{Config, RuleAction};
+use executable::Executable;
+use manifest::Manifest;
 use os_str_bytes::OsStrBytesExt;
-use parallel_pool::ParallelPool;
-use parking_lot::Mutex;
 use path_utils::make_absolute;
-use ratatui::widgets::TableState;
+use platform::Platform;
+use request_id::RequestId;
 use state::State;
-use task_periods::TaskPeriods;
 
 mod ar_args;
 mod args_processing;
+mod build_progress;
+mod cache_archive;
+mod cache_stats;
+mod child_process;
+mod chunk_sizes;
 mod code_language;
+mod compile_commands;
+mod compiler_versions;
 mod compute_cache;
 mod config;
+mod data_dir_lock;
+mod defined_symbols;
+mod diagnostics;
+mod executable;
 mod export_trace;
+mod final_link_cache;
 mod gcc_args;
 mod group_compatible_objects;
+mod link_graph;
 mod link_sources;
 mod local_code;
+mod manifest;
+mod memory_budget;
+mod msvc_args;
 mod object_by_inputs_cache;
+mod object_tool_args;
 mod parallel_pool;
 mod path_utils;
+mod placeholders;
+mod platform;
 mod preprocess_headers;
 mod preprocessor_directives;
+mod remote_workers;
+mod repair;
+mod request_activity;
+mod request_id;
+mod request_slots;
 mod source_file;
 mod state;
 mod state_persistent;
 mod task_periods;
+#[cfg(test)]
+mod test_utils;
 mod tui;
 mod wrap_compile_object_file;
 mod wrap_create_static_archive;
 mod wrap_eager;
 mod wrap_final_link;
+mod wrap_object_tool;
 
 static ASSETS_DIR: include_dir::Dir = include_dir::include_dir!("$CARGO_MANIFEST_DIR/src/assets");
 
@@ -54,8 +84,13 @@ struct WebState {
 #[derive(clap::Parser, Debug)]
 #[command(name = "ccelerate_server")]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
     #[arg(long, default_value_t = ccelerate_shared::DEFAULT_PORT)]
     port: u16,
+    /// Address to listen on. Use e.g. `0.0.0.0` so that other servers can use this one as worker.
+    #[arg(long, default_value_t = std::net::Ipv4Addr::LOCALHOST.into())]
+    bind: std::net::IpAddr,
     #[arg(long)]
     no_tui: bool,
     #[arg(short, long)]
@@ -64,6 +99,176 @@ struct Cli {
     data_dir: Option<PathBuf>,
     #[arg(long)]
     log_files: bool,
+    /// Compile commands with more sources than this are handled eagerly.
+    #[arg(long)]
+    max_sources: Option<usize>,
+    /// Whether consecutive archives and objects are put into link groups in the final link, so
+    /// that their order does not matter. Shared libraries are never part of a link group.
+    #[arg(long, value_enum, default_value_t = LinkGroupMode::Auto)]
+    link_group: LinkGroupMode,
+    /// Number of object file records that are kept in memory.
+    #[arg(long, default_value_t = NonZeroUsize::new(100_000).expect("not zero"))]
+    object_cache_size: NonZeroUsize,
+    /// Start with an empty database if the existing one can't be opened. The old database is
+    /// kept next to it.
+    #[arg(long)]
+    auto_reset_db: bool,
+    /// Check that translation units that are compiled together don't define the same symbols.
+    /// This requires compiling every translation unit on its own once.
+    #[arg(long)]
+    verify_chunks: bool,
+    /// Maximum number of compatible objects that are compiled together. Chunks are split up
+    /// when compiling them fails.
+    #[arg(long, default_value_t = 10)]
+    chunk_size: usize,
+    /// Grow the chunk size while compiling succeeds and shrink it when chunks have to be split.
+    #[arg(long)]
+    adaptive_chunk_size: bool,
+    /// Name preprocessed local code files only by the hash of their content. Identical code from
+    /// differently named sources is stored once then, which helps when sharing the cache.
+    #[arg(long)]
+    content_addressed_local_code: bool,
+    /// Write a JSON manifest into this directory for every final link. It lists the output, the
+    /// created archives and the compiled objects with the hashes of their content.
+    #[arg(long)]
+    write_manifest: Option<PathBuf>,
+    /// Kill compiler and linker processes that run longer than this many seconds. 0 disables
+    /// the timeout.
+    #[arg(long, default_value_t = 0)]
+    child_timeout: u64,
+    /// Run compilers and linkers through this program and its arguments, e.g.
+    /// `--compiler-launcher /usr/bin/time -v \;` to measure their resource usage. All values up
+    /// to a `;` or the end of the command line belong to the launcher.
+    #[arg(long, num_args = 1.., allow_hyphen_values = true, value_terminator = ";")]
+    compiler_launcher: Vec<OsString>,
+    /// Shut down the server after no requests have been handled for this many seconds.
+    #[arg(long)]
+    idle_timeout: Option<u64>,
+    /// URL of another ccelerate server that can compile chunks when this machine is busy,
+    /// e.g. `http://build-2:6235`. Can be passed multiple times.
+    #[arg(long = "worker")]
+    workers: Vec<String>,
+    /// Shared secret between servers. Other servers can only compile chunks on this one if they
+    /// pass the same token, and it's sent to the workers passed with `--worker`.
+    #[arg(long)]
+    worker_token: Option<String>,
+    /// Approximate limit for the size of inputs that are compiled at the same time in megabytes.
+    #[arg(long)]
+    memory_budget: Option<u32>,
+    /// Maximum number of requests that are handled at the same time. What happens to further
+    /// requests is decided by `--when-overloaded`.
+    #[arg(long)]
+    max_running_requests: Option<usize>,
+    #[arg(long, value_enum, default_value_t = OverloadResponse::Wait)]
+    when_overloaded: OverloadResponse,
+    /// Paths in the terminal and logs are shown relative to this directory when possible.
+    /// A `project_root` in a `ccelerate.toml` has the same effect.
+    #[arg(long)]
+    project_root: Option<PathBuf>,
+    /// Also resolve symlinks in include paths when deciding which objects can be compiled
+    /// together. `.` and `..` are always resolved.
+    #[arg(long)]
+    canonicalize_include_paths: bool,
+    /// Link against regular archives that contain copies of the compiled objects instead of
+    /// thin archives that only reference them. Those stay valid when the objects are removed
+    /// or the data directory is moved.
+    #[arg(long)]
+    regular_archives: bool,
+    /// Some objects and archives of the build are created without ccelerate. A file that has a
+    /// database record is only treated as a placeholder if it still has the content that
+    /// ccelerate wrote. Otherwise, it was rebuilt by the real tool and is used as is. Object
+    /// files linked by a final link are always checked like this.
+    #[arg(long)]
+    hybrid: bool,
+    /// How much is logged without the terminal ui.
+    #[arg(long, value_enum, default_value_t = LogLevel::Info)]
+    log_level: LogLevel,
+}
+
+#[derive(clap::Subcommand, Debug, Clone, PartialEq, Eq)]
+enum Command {
+    /// Recreate missing or corrupted local code files in the data directory and exit.
+    Repair,
+    /// Always compile the given object file on its own instead of together with other objects,
+    /// e.g. because it's known to conflict with them. This is stored in the database.
+    PinIndividual {
+        object_path: PathBuf,
+        /// Remove the pin again.
+        #[arg(long)]
+        unpin: bool,
+    },
+    /// Print a DOT graph of the archives and objects that a previously linked binary consists
+    /// of, with objects that can be compiled together grouped in clusters.
+    Graph { output_path: PathBuf },
+    /// Check that the files listed in a manifest written with `--write-manifest` still exist
+    /// with the same content.
+    VerifyManifest { manifest_path: PathBuf },
+    /// Write the database and the cached files into a tar archive, e.g. to share a warm cache
+    /// with CI runners.
+    ExportCache {
+        output_path: PathBuf,
+        /// Only export local code files that are referenced by the database.
+        #[arg(long)]
+        prune: bool,
+        /// Only export object files built after this time. Either a timestamp like
+        /// `2024-05-01T12:00:00Z` or a file whose modification time is used, e.g. the previous
+        /// export.
+        #[arg(long)]
+        since: Option<String>,
+    },
+    /// Extract a tar archive written by `export-cache` into the data directory. Records are
+    /// merged into an existing database.
+    ImportCache { input_path: PathBuf },
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum LinkGroupMode {
+    /// Use a link group unless the platform linker does not support it.
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum OverloadResponse {
+    /// Wait until another request is done.
+    Wait,
+    /// Let the wrapper run commands on the client if that gives the same result, e.g. when
+    /// compiling an object file. Other commands like final links still wait.
+    RunLocally,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum LogLevel {
+    /// Only errors.
+    Error,
+    /// Also failed tasks.
+    Warn,
+    /// Also the category and short name of every task.
+    Info,
+    /// Also details of every task and the full arguments of every request.
+    Debug,
+}
+
+impl LogLevel {
+    fn to_level_filter(self) -> log::LevelFilter {
+        match self {
+            LogLevel::Error => log::LevelFilter::Error,
+            LogLevel::Warn => log::LevelFilter::Warn,
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Debug => log::LevelFilter::Debug,
+        }
+    }
+}
+
+impl LinkGroupMode {
+    fn use_link_group(&self) -> bool {
+        match self {
+            LinkGroupMode::Auto => Platform::current().supports_link_groups(),
+            LinkGroupMode::Always => true,
+            LinkGroupMode::Never => false,
+        }
+    }
 }
 
 #[actix_web::get("/")]
@@ -143,21 +348,82 @@ impl CommandOutput {
             status: child.status.code().unwrap_or(1),
         }
     }
+
+    /// Replaces references to a temporary file in the output with something the user can
+    /// make sense of.
+    pub fn with_replaced_path(mut self, path: &Path, label: &str) -> Self {
+        let path = path.as_os_str().as_encoded_bytes();
+        self.stdout = self.stdout.replace(path, label);
+        self.stderr = self.stderr.replace(path, label);
+        self
+    }
 }
 
-async fn handle_request(request: &RunRequestData, state: &Arc<State>) -> Result<CommandOutput> {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+enum RequestHandling {
+    CreateStaticArchive,
+    ObjectTool,
+    Probe,
+    OutputToStdout,
+    Eager,
+    Assemble,
+    CompileObjectFile,
+    CompileObjectFiles,
+    FinalLink,
+}
+
+struct ClassifiedRequest {
+    handling: RequestHandling,
+    config: Arc<Config>,
+    files: Result<gcc_args::BuildFilesInfo>,
+    rule_action: Option<RuleAction>,
+}
+
+/// Decides how a request is handled without actually handling it yet.
+fn classify_request(request: &RunRequestData, state: &State) -> Result<ClassifiedRequest> {
     match request.binary {
-        WrappedBinary::Ar => {
-            return wrap_create_static_archive::wrap_create_static_archive(
-                request.binary,
-                &request.args,
-                &request.cwd,
-                state,
-            )
-            .await;
-        }
-        WrappedBinary::Gcc | WrappedBinary::Gxx | WrappedBinary::Clang | WrappedBinary::Clangxx => {
-            let files = gcc_args::BuildFilesInfo::from_args(&request.cwd, &request.args);
+        WrappedBinary::Ar => Ok(ClassifiedRequest {
+            handling: RequestHandling::CreateStaticArchive,
+            config: state.config_manager.config_for_paths(&[&request.cwd])?,
+            files: Err(anyhow::anyhow!("Not a gcc compatible command")),
+            rule_action: None,
+        }),
+        WrappedBinary::Objcopy | WrappedBinary::Strip => Ok(ClassifiedRequest {
+            handling: RequestHandling::ObjectTool,
+            config: state.config_manager.config_for_paths(&[&request.cwd])?,
+            files: Err(anyhow::anyhow!("Not a gcc compatible command")),
+            rule_action: None,
+        }),
+        WrappedBinary::Gcc
+        | WrappedBinary::Gxx
+        | WrappedBinary::Clang
+        | WrappedBinary::Clangxx
+        | WrappedBinary::ClangCl => {
+            let files = match request.binary {
+                WrappedBinary::ClangCl => {
+                    gcc_args::BuildFilesInfo::from_msvc_args(&request.cwd, &request.args)
+                }
+                _ => gcc_args::BuildFilesInfo::from_args(&request.cwd, &request.args),
+            };
+            if files.as_ref().is_ok_and(|files| files.is_query) {
+                // Probes run as is, so there is no need to look up the config.
+                return Ok(ClassifiedRequest {
+                    handling: RequestHandling::Probe,
+                    config: Arc::new(Config::new()),
+                    files,
+                    rule_action: None,
+                });
+            }
+            if files.as_ref().is_ok_and(|files| files.output_to_stdout) {
+                // Commands like `gcc -c -o - foo.c` are usually probes too. There is no output
+                // file that could be created lazily, so they always run as is.
+                return Ok(ClassifiedRequest {
+                    handling: RequestHandling::OutputToStdout,
+                    config: Arc::new(Config::new()),
+                    files,
+                    rule_action: None,
+                });
+            }
 
             let known_sources = match &files {
                 Ok(files) => files.sources.as_slice(),
@@ -166,45 +432,219 @@ async fn handle_request(request: &RunRequestData, state: &Arc<State>) -> Result<
             let mut paths_for_config: Vec<&Path> = vec![request.cwd.as_ref()];
             paths_for_config.extend(known_sources.iter().map(|s| s.path.as_path()));
 
-            let has_output = match &files {
-                Ok(files) => files.output.is_some(),
+            let output = match &files {
+                Ok(files) => files.output.as_deref(),
+                Err(_) => None,
+            };
+            let implied_outputs = match &files {
+                Ok(files) => files.implied_outputs.as_slice(),
+                Err(_) => &[],
+            };
+            let has_output = output.is_some() || !implied_outputs.is_empty();
+            let uses_lto = match &files {
+                Ok(files) => files.uses_lto,
                 Err(_) => false,
             };
+            let uses_pch = match &files {
+                Ok(files) => files.uses_pch,
+                Err(_) => false,
+            };
+            let prints_included_files = request.binary == WrappedBinary::ClangCl
+                && msvc_args::prints_included_files(&request.args).unwrap_or(false);
             let config = state.config_manager.config_for_paths(&paths_for_config)?;
-            if is_gcc_cmakescratch(&request.args, &request.cwd)
+            let rule_action = match &files {
+                Ok(files) => config.rule_action(
+                    &files.sources.iter().map(|s| &s.path).collect::<Vec<_>>(),
+                    &files.defines,
+                    &files.f_flags,
+                ),
+                Err(_) => None,
+            };
+            let is_eager_path = match rule_action {
+                Some(RuleAction::Eager) => true,
+                Some(RuleAction::Chunk | RuleAction::Materialize) => false,
+                None => known_sources.iter().any(|p| config.is_eager_path(&p.path)),
+            };
+            let handling = if is_gcc_cmakescratch(&request.args, &request.cwd)
                 || is_gcc_compiler_id_check(&request.args, &request.cwd)
                 || !has_output
-                || known_sources.iter().any(|p| config.is_eager_path(&p.path))
             {
-                return wrap_eager::wrap_eager(request.binary, &request.args, &request.cwd, state)
-                    .await;
-            }
-            match gcc_args::is_build_object_file(&request.args)? {
-                true => {
-                    wrap_compile_object_file::wrap_compile_object_file(
-                        request.binary,
-                        &request.args,
-                        &request.cwd,
-                        state,
-                        &config,
-                    )
-                    .await
-                }
-                false => {
-                    wrap_final_link::wrap_final_link(
-                        request.binary,
-                        &request.args,
-                        &request.cwd,
-                        state,
-                        &config,
-                    )
-                    .await
+                RequestHandling::Eager
+            } else if args_processing::is_build_object_file(request.binary, &request.args)? {
+                // These only apply to compiles. Links always have to go through the final link,
+                // because the real linker can't use placeholder objects.
+                if uses_lto || uses_pch || prints_included_files || is_eager_path {
+                    RequestHandling::Eager
+                } else if known_sources
+                    .iter()
+                    .any(|source| source.language().is_some_and(|l| l.is_asm()))
+                {
+                    RequestHandling::Assemble
+                } else if state
+                    .cli
+                    .max_sources
+                    .is_some_and(|max_sources| known_sources.len() > max_sources)
+                {
+                    RequestHandling::Eager
+                } else if output.is_some_and(|output| config.is_object_path(output)) {
+                    RequestHandling::CompileObjectFile
+                } else if implied_outputs.len() > 1
+                    && implied_outputs
+                        .iter()
+                        .all(|output| config.is_object_path(output))
+                {
+                    RequestHandling::CompileObjectFiles
+                } else {
+                    RequestHandling::Eager
                 }
+            } else {
+                RequestHandling::FinalLink
+            };
+            Ok(ClassifiedRequest {
+                handling,
+                config,
+                files,
+                rule_action,
+            })
+        }
+    }
+}
+
+/// The compiler does not run in a terminal, so it would not color diagnostics on its own even
+/// if the client can show them.
+fn args_for_client_terminal(request: &RunRequestData) -> Result<Vec<OsString>> {
+    if request.client_is_tty {
+        args_processing::add_diagnostics_color_arg(request.binary, &request.args)
+    } else {
+        Ok(request.args.clone())
+    }
+}
+
+async fn handle_request(request: &RunRequestData, state: &Arc<State>) -> Result<CommandOutput> {
+    let classified = classify_request(request, state)?;
+    let executable = Executable::new(request.binary, request.resolved_binary.clone());
+    match classified.handling {
+        RequestHandling::CreateStaticArchive => {
+            wrap_create_static_archive::wrap_create_static_archive(
+                request.binary,
+                &request.args,
+                &request.cwd,
+                state,
+            )
+            .await
+        }
+        RequestHandling::ObjectTool => {
+            wrap_object_tool::wrap_object_tool(&executable, &request.args, &request.cwd, state)
+                .await
+        }
+        RequestHandling::Probe => {
+            wrap_eager::wrap_probe(&executable, &request.args, &request.cwd, state).await
+        }
+        RequestHandling::OutputToStdout => {
+            wrap_eager::wrap_output_to_stdout(&executable, &request.args, &request.cwd, state).await
+        }
+        RequestHandling::Eager => {
+            let args = args_for_client_terminal(request)?;
+            wrap_eager::wrap_eager(&executable, &args, &request.cwd, state).await
+        }
+        RequestHandling::Assemble => {
+            let args = args_for_client_terminal(request)?;
+            wrap_eager::wrap_assemble(&executable, &args, &request.cwd, state).await
+        }
+        RequestHandling::CompileObjectFile => {
+            let output = wrap_compile_object_file::wrap_compile_object_file(
+                &executable,
+                &request.args,
+                &request.cwd,
+                state,
+                &classified.config,
+            )
+            .await?;
+            pin_materialized_objects(&classified, state)?;
+            Ok(output)
+        }
+        RequestHandling::CompileObjectFiles => {
+            let output = wrap_compile_object_file::wrap_compile_object_files(
+                &executable,
+                &request.args,
+                &request.cwd,
+                state,
+                &classified.config,
+            )
+            .await?;
+            pin_materialized_objects(&classified, state)?;
+            Ok(output)
+        }
+        RequestHandling::FinalLink => {
+            let args = args_for_client_terminal(request)?;
+            let output = wrap_final_link::wrap_final_link(
+                &executable,
+                &args,
+                &request.cwd,
+                state,
+                &classified.config,
+            )
+            .await?;
+            if let Ok(files) = &classified.files
+                && let Some(link_output) = &files.output
+            {
+                state
+                    .build_progress
+                    .link_finished(link_output, &state.persistent);
             }
+            Ok(output)
         }
     }
 }
 
+/// Objects matching a `materialize` rule are always compiled on their own, like pinned ones.
+/// The pin is removed again once the rule does not match anymore.
+fn pin_materialized_objects(classified: &ClassifiedRequest, state: &State) -> Result<()> {
+    let Ok(files) = &classified.files else {
+        return Ok(());
+    };
+    let pinned = classified.rule_action == Some(RuleAction::Materialize);
+    for output in files.output.iter().chain(files.implied_outputs.iter()) {
+        state
+            .persistent
+            .set_object_file_pinned_by_rule(output, pinned)?;
+    }
+    Ok(())
+}
+
+async fn handle_request_to_wire(
+    run_request: &RunRequestData,
+    state: &Arc<State>,
+) -> RunResponseDataWire {
+    let running_request = state.request_activity.start();
+    state
+        .build_progress
+        .request_started(running_request.idle_before(), &state.persistent);
+    log::debug!(
+        "Run {} in {}: {:?}",
+        run_request.binary,
+        run_request.cwd.display(),
+        run_request.args
+    );
+    // Make already runs that many commands in parallel, so running more tasks at the same
+    // time would oversubscribe the machine.
+    let _pool_limit = run_request
+        .make_jobs
+        .map(|make_jobs| state.pool.limit_scope(make_jobs));
+    let output = CommandOutput::from_result(
+        RequestId::new()
+            .scope(handle_request(run_request, state))
+            .await,
+    );
+    state.build_progress.request_finished();
+    RunResponseData {
+        stdout: output.stdout,
+        stderr: output.stderr,
+        status: output.status,
+    }
+    .to_wire(run_request.accepts_compression)
+}
+
 #[actix_web::post("/run")]
 async fn route_run(
     run_request: actix_web::web::Json<RunRequestDataWire>,
@@ -214,32 +654,320 @@ async fn route_run(
         log::error!("Could not parse: {:#?}", run_request);
         return HttpResponse::InternalServerError().body("Failed to parse request");
     };
-    let output = CommandOutput::from_result(handle_request(&run_request, &web_state.state).await);
+    let state = &web_state.state;
+    let _slot = match state.request_slots.try_acquire() {
+        Ok(slot) => slot,
+        Err(()) => {
+            if state.cli.when_overloaded == OverloadResponse::RunLocally
+                && let Some(program) = program_to_run_locally(&run_request, state)
+            {
+                log::debug!("Busy, running {} on the client", run_request.binary);
+                return HttpResponse::ServiceUnavailable()
+                    .json(RunLocallyResponseData { program }.to_wire());
+            }
+            state.request_slots.acquire().await
+        }
+    };
+    HttpResponse::Ok().json(handle_request_to_wire(&run_request, state).await)
+}
+
+/// Returns the program that the client can run instead of sending the request, if running it
+/// there has the same result. Only compiles qualify. Links and archives may contain placeholder
+/// objects, so only the server can create them, even when they are handled eagerly.
+fn program_to_run_locally(request: &RunRequestData, state: &State) -> Option<OsString> {
+    let classified = classify_request(request, state).ok()?;
+    match classified.handling {
+        RequestHandling::Eager
+        | RequestHandling::Assemble
+        | RequestHandling::CompileObjectFile
+        | RequestHandling::CompileObjectFiles => {}
+        RequestHandling::Probe
+        | RequestHandling::OutputToStdout
+        | RequestHandling::CreateStaticArchive
+        | RequestHandling::ObjectTool
+        | RequestHandling::FinalLink => return None,
+    }
+    if !args_processing::is_build_object_file(request.binary, &request.args).unwrap_or(false) {
+        return None;
+    }
+    let program = Executable::new(request.binary, request.resolved_binary.clone())
+        .program(&classified.config);
+    // Otherwise, the client might find the wrapper itself on its `PATH`.
+    Path::new(&program).is_absolute().then_some(program)
+}
+
+/// Handles multiple requests at once to avoid the overhead of sending many small requests.
+/// The requests are handled concurrently and the responses are returned in the same order.
+#[actix_web::post("/run_batch")]
+async fn route_run_batch(
+    batch_request: actix_web::web::Json<RunBatchRequestWire>,
+    web_state: Data<WebState>,
+) -> impl actix_web::Responder {
+    let Ok(run_requests) = batch_request
+        .requests
+        .iter()
+        .map(RunRequestData::from_wire)
+        .collect::<Result<Vec<_>, _>>()
+    else {
+        log::error!("Could not parse: {:#?}", batch_request);
+        return HttpResponse::InternalServerError().body("Failed to parse request");
+    };
+    // The responses can't tell the client to run single commands locally, so these always wait
+    // when the server is busy.
+    let responses = futures::future::join_all(run_requests.iter().map(async |run_request| {
+        let _slot = web_state.state.request_slots.acquire().await;
+        handle_request_to_wire(run_request, &web_state.state).await
+    }))
+    .await;
+    HttpResponse::Ok().json(responses)
+}
+
+#[derive(serde::Serialize)]
+struct DebugParseResponse {
+    handling: RequestHandling,
+    parse_error: Option<String>,
+    sources: Vec<DebugParseSource>,
+    output: Option<String>,
+    uses_lto: bool,
+    uses_pch: bool,
+    prefix_dirs: Vec<String>,
+    config_files: Vec<String>,
+}
+
+#[derive(serde::Serialize)]
+struct DebugParseSource {
+    path: String,
+    language_override: Option<String>,
+}
+
+/// Used by other ccelerate servers to offload compiling chunks to this machine.
+#[actix_web::post("/compile_chunk")]
+async fn route_compile_chunk(
+    http_request: actix_web::HttpRequest,
+    request: actix_web::web::Json<CompileChunkRequestWire>,
+    web_state: Data<WebState>,
+) -> impl actix_web::Responder {
+    // Compiling chunks runs the compiler with arguments from the request, so this must only be
+    // possible for the servers that are meant to use this one as worker.
+    let Some(worker_token) = &web_state.state.cli.worker_token else {
+        return HttpResponse::Forbidden()
+            .body("Compiling chunks for other servers requires --worker-token");
+    };
+    let request_token = http_request
+        .headers()
+        .get(ccelerate_shared::WORKER_TOKEN_HEADER)
+        .map(|value| value.as_bytes());
+    if request_token != Some(worker_token.as_bytes()) {
+        return HttpResponse::Forbidden().body("Invalid worker token");
+    }
+    let Ok(request) = CompileChunkRequestData::from_wire(&request) else {
+        return HttpResponse::InternalServerError().body("Failed to parse request");
+    };
+    let _running_request = web_state.state.request_activity.start();
+    let output = CommandOutput::from_result(
+        RequestId::new()
+            .scope(wrap_final_link::compile_chunk_for_remote(
+                &request,
+                &web_state.state,
+            ))
+            .await,
+    );
     HttpResponse::Ok().json(
         RunResponseData {
             stdout: output.stdout,
             stderr: output.stderr,
             status: output.status,
         }
-        .to_wire(),
+        .to_wire(true),
     )
 }
 
+/// Shows how a request would be handled without running it. This helps to find out why a
+/// command is e.g. handled eagerly.
+#[actix_web::post("/debug/parse")]
+async fn route_debug_parse(
+    run_request: actix_web::web::Json<RunRequestDataWire>,
+    web_state: Data<WebState>,
+) -> impl actix_web::Responder {
+    let Ok(run_request) = RunRequestData::from_wire(&run_request) else {
+        log::error!("Could not parse: {:#?}", run_request);
+        return HttpResponse::InternalServerError().body("Failed to parse request");
+    };
+    let classified = match classify_request(&run_request, &web_state.state) {
+        Ok(classified) => classified,
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    let mut response = DebugParseResponse {
+        handling: classified.handling,
+        parse_error: None,
+        sources: vec![],
+        output: None,
+        uses_lto: false,
+        uses_pch: false,
+        prefix_dirs: vec![],
+        config_files: classified
+            .config
+            .config_files()
+            .iter()
+            .map(|p| p.to_string_lossy().to_string())
+            .collect(),
+    };
+    match &classified.files {
+        Ok(files) => {
+            response.sources = files
+                .sources
+                .iter()
+                .map(|s| DebugParseSource {
+                    path: s.path.to_string_lossy().to_string(),
+                    language_override: s.language_override.map(|l| l.to_gcc_x_arg().to_string()),
+                })
+                .collect();
+            response.output = files
+                .output
+                .as_ref()
+                .map(|p| p.to_string_lossy().to_string());
+            response.uses_lto = files.uses_lto;
+            response.uses_pch = files.uses_pch;
+            response.prefix_dirs = files
+                .prefix_dirs
+                .iter()
+                .map(|p| p.to_string_lossy().to_string())
+                .collect();
+        }
+        Err(err) => response.parse_error = Some(err.to_string()),
+    }
+    HttpResponse::Ok().json(response)
+}
+
+/// Compilation database for all object files that have been built through ccelerate. This
+/// allows IDE tooling to work with the original commands.
+#[actix_web::get("/compile_commands.json")]
+async fn route_compile_commands(web_state: Data<WebState>) -> impl actix_web::Responder {
+    let records = match web_state.state.persistent.get_all_object_file_records() {
+        Ok(records) => records,
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    let mut commands = Vec::new();
+    for (path, record) in records {
+        let command = args_processing::BuildObjectFileInfo::from_args(
+            record.binary,
+            &record.cwd,
+            &record.args,
+        )
+        .and_then(|args_info| {
+            compile_commands::CompileCommand::from_build(
+                record.binary,
+                &record.cwd,
+                &record.args,
+                &args_info,
+            )
+        });
+        match command {
+            Ok(command) => commands.push(command),
+            Err(err) => log::warn!(
+                "Skipping {} in compilation database: {}",
+                path.display(),
+                err
+            ),
+        }
+    }
+    HttpResponse::Ok().json(commands)
+}
+
+#[derive(serde::Serialize)]
+struct StatsResponse {
+    preprocess_cache: cache_stats::PreprocessCacheStatsSnapshot,
+    build_progress: build_progress::BuildProgressSnapshot,
+    pool: parallel_pool::PoolStats,
+}
+
+#[actix_web::get("/stats")]
+async fn route_stats(web_state: Data<WebState>) -> impl actix_web::Responder {
+    HttpResponse::Ok().json(StatsResponse {
+        preprocess_cache: web_state.state.preprocess_cache_stats.snapshot(),
+        build_progress: web_state.state.build_progress.snapshot(),
+        pool: web_state.state.pool.stats(),
+    })
+}
+
+/// Streams task start and finish events as server-sent events while they happen. Every event
+/// is a JSON encoded [`task_periods::TaskEvent`].
+#[actix_web::get("/events")]
+async fn route_events(web_state: Data<WebState>) -> impl actix_web::Responder {
+    let receiver = web_state.state.task_periods.subscribe();
+    let stream = futures::stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    let json = serde_json::to_string(&event).unwrap_or_default();
+                    let data = actix_web::web::Bytes::from(format!("data: {}\n\n", json));
+                    return Some((Ok::<_, actix_web::Error>(data), receiver));
+                }
+                // The client was too slow and missed some events.
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(stream)
+}
+
 async fn server_thread(state: Arc<State>) {
     let web_state = actix_web::web::Data::new(WebState { state });
     let web_state_clone = web_state.clone();
-    actix_web::HttpServer::new(move || {
+    let server = actix_web::HttpServer::new(move || {
         actix_web::App::new()
             .app_data(web_state.clone())
+            // Chunks of preprocessed code sent by other servers can be large.
+            .app_data(actix_web::web::JsonConfig::default().limit(1 << 30))
             .service(route_index)
             .service(route_run)
+            .service(route_run_batch)
+            .service(route_debug_parse)
+            .service(route_compile_commands)
+            .service(route_compile_chunk)
+            .service(route_events)
+            .service(route_stats)
     })
     .client_request_timeout(Duration::from_secs(0))
     .bind(web_state_clone.state.address.clone())
     .unwrap()
-    .run()
-    .await
-    .unwrap();
+    .run();
+    if let Some(idle_timeout) = web_state_clone.state.cli.idle_timeout {
+        tokio::spawn(stop_when_idle(
+            web_state_clone.state.clone(),
+            server.handle(),
+            Duration::from_secs(idle_timeout),
+        ));
+    }
+    server.await.unwrap();
+    web_state_clone
+        .state
+        .server_stopped
+        .store(true, Ordering::Relaxed);
+}
+
+async fn stop_when_idle(
+    state: Arc<State>,
+    server_handle: actix_web::dev::ServerHandle,
+    idle_timeout: Duration,
+) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        if state
+            .request_activity
+            .idle_duration()
+            .is_some_and(|idle_duration| idle_duration >= idle_timeout)
+        {
+            log::info!("Stopping server after being idle for {:?}", idle_timeout);
+            // Requests that came in in the meantime are still handled.
+            server_handle.stop(true).await;
+            return;
+        }
+    }
 }
 
 struct NoTuiLogger {}
@@ -250,7 +978,12 @@ impl log::Log for NoTuiLogger {
     }
 
     fn log(&self, record: &log::Record) {
-        println!("{} - {}", record.level(), record.args());
+        match RequestId::current() {
+            Some(request_id) => {
+                println!("{} [{}] - {}", record.level(), request_id, record.args())
+            }
+            None => println!("{} - {}", record.level(), record.args()),
+        }
     }
 
     fn flush(&self) {
@@ -269,31 +1002,76 @@ async fn main() -> Result<()> {
             .clone()
             .unwrap_or_else(|| PathBuf::from("./ccelerate_data")),
     );
-    let db_path = data_dir.join("ccelerate.db");
-    let addr = format!("127.0.0.1:{}", cli.port);
-    let state = Arc::new(State {
-        address: addr.clone(),
-        persistent: state_persistent::PersistentState::new(&db_path).await?,
-        task_periods: TaskPeriods::new(),
-        tasks_table_state: Arc::new(Mutex::new(TableState::default())),
-        auto_scroll: Arc::new(Mutex::new(true)),
-        pool: ParallelPool::new(cli.jobs.unwrap_or_else(|| {
-            std::thread::available_parallelism()
-                .unwrap_or(NonZeroUsize::new(1).unwrap())
-                .get()
-        })),
-        cli,
-        data_dir,
-        config_manager: ConfigManager::new(),
-        objects_cache: ObjectByInputsCache::new(),
-    });
+    let _data_dir_lock = match &cli.command {
+        // These only read the data directory, so they can run while a server is using it.
+        Some(Command::Graph { .. } | Command::VerifyManifest { .. }) => None,
+        _ => Some(data_dir_lock::DataDirLock::acquire(&data_dir)?),
+    };
+    if let Some(Command::ImportCache { input_path }) = &cli.command {
+        // The database must not be open yet.
+        return cache_archive::import_cache(&data_dir, &make_absolute(&cwd, input_path)).await;
+    }
+    let state = Arc::new(State::new(cli, data_dir).await?);
+    let addr = state.address.clone();
 
+    match &state.cli.command {
+        Some(Command::Repair) => {
+            log::set_logger(&NoTuiLogger {})
+                .map(|()| log::set_max_level(state.cli.log_level.to_level_filter()))
+                .unwrap();
+            return repair::repair(&state).await;
+        }
+        Some(Command::PinIndividual { object_path, unpin }) => {
+            let object_path = make_absolute(&cwd, object_path);
+            if !state
+                .persistent
+                .set_object_file_pinned_individual(&object_path, !unpin)?
+            {
+                return Err(anyhow!(
+                    "No object file is recorded for {}",
+                    object_path.display()
+                ));
+            }
+            return Ok(());
+        }
+        Some(Command::Graph { output_path }) => {
+            let output_path = make_absolute(&cwd, output_path);
+            print!("{}", link_graph::create_link_graph(&output_path, &state)?);
+            return Ok(());
+        }
+        Some(Command::ExportCache {
+            output_path,
+            prune,
+            since,
+        }) => {
+            let output_path = make_absolute(&cwd, output_path);
+            let since = since
+                .as_deref()
+                .map(|since| cache_archive::parse_since(&cwd, since))
+                .transpose()?;
+            return cache_archive::export_cache(&state, &output_path, *prune, since).await;
+        }
+        Some(Command::ImportCache { .. }) => unreachable!("handled before opening the database"),
+        Some(Command::VerifyManifest { manifest_path }) => {
+            let manifest = Manifest::read(&make_absolute(&cwd, manifest_path)).await?;
+            let problems = manifest.verify().await;
+            for problem in &problems {
+                println!("{}", problem);
+            }
+            if !problems.is_empty() {
+                return Err(anyhow!("{} files don't match the manifest", problems.len()));
+            }
+            return Ok(());
+        }
+        None => {}
+    }
     if state.cli.no_tui {
         log::set_logger(&NoTuiLogger {})
-            .map(|()| log::set_max_level(log::LevelFilter::Info))
+            .map(|()| log::set_max_level(state.cli.log_level.to_level_filter()))
             .unwrap();
         log::info!("Listening on http://{}", addr);
         server_thread(state.clone()).await;
+        log::info!("{}", state.preprocess_cache_stats.snapshot().summary());
         return Ok(());
     }
     // Run the server in the background and the tui on the main thread.
@@ -304,5 +1082,1160 @@ async fn main() -> Result<()> {
             log::error!("Error running tui: {e}");
         }
     };
+    println!("{}", state.preprocess_cache_stats.snapshot().summary());
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsString, time::Duration};
+
+    use actix_web::{body::MessageBody, web::Data};
+    use ccelerate_shared::{
+        RunBatchRequestWire, RunLocallyResponseData, RunLocallyResponseWire, RunRequestData,
+        RunResponseData, RunResponseDataWire, WrappedBinary,
+    };
+
+    use crate::{
+        CommandOutput, RequestHandling, WebState, args_for_client_terminal, classify_request,
+        handle_request, handle_request_to_wire, path_utils::ProjectRoots, placeholders,
+        remote_workers::RemoteWorkers, route_compile_chunk, route_compile_commands,
+        route_debug_parse, route_events, route_run, route_run_batch, route_stats, stop_when_idle,
+        task_periods::TaskPeriodInfo, test_utils::TestEnv,
+    };
+
+    fn classify(env: &TestEnv, args: &[&str]) -> RequestHandling {
+        classify_request(&env.request(WrappedBinary::Gcc, args), &env.state)
+            .expect("request can be classified")
+            .handling
+    }
+
+    async fn call_json<R: serde::de::DeserializeOwned>(
+        env: &TestEnv,
+        request: actix_web::test::TestRequest,
+    ) -> R {
+        let app = actix_web::test::init_service(
+            actix_web::App::new()
+                .app_data(Data::new(WebState {
+                    state: env.state.clone(),
+                }))
+                .service(route_run_batch)
+                .service(route_debug_parse)
+                .service(route_compile_commands)
+                .service(route_stats),
+        )
+        .await;
+        actix_web::test::call_and_read_body_json(&app, request.to_request()).await
+    }
+
+    async fn post_json<R: serde::de::DeserializeOwned>(
+        env: &TestEnv,
+        uri: &str,
+        body: &impl serde::Serialize,
+    ) -> R {
+        call_json(
+            env,
+            actix_web::test::TestRequest::post().uri(uri).set_json(body),
+        )
+        .await
+    }
+
+    async fn get_json<R: serde::de::DeserializeOwned>(env: &TestEnv, uri: &str) -> R {
+        call_json(env, actix_web::test::TestRequest::get().uri(uri)).await
+    }
+
+    const CONFIG: &str = r#"
+eager_patterns = []
+local_header_patterns = []
+include_defines = []
+pure_c_header_patterns = []
+"#;
+
+    #[tokio::test]
+    async fn overloaded_server_only_lets_clients_compile() {
+        let env = TestEnv::new(&[
+            "--max-running-requests",
+            "1",
+            "--when-overloaded",
+            "run-locally",
+        ])
+        .await;
+        let compiler = env.write_executable("bin/gcc", "#!/bin/sh\nexec gcc \"$@\"\n");
+        env.write("main.c", "int main(void) { return 0; }\n");
+        let app = actix_web::test::init_service(
+            actix_web::App::new()
+                .app_data(Data::new(WebState {
+                    state: env.state.clone(),
+                }))
+                .service(route_run),
+        )
+        .await;
+        let post = |args: &[&str]| {
+            let mut request = env.request(WrappedBinary::Gcc, args);
+            request.resolved_binary = Some(compiler.clone());
+            actix_web::test::TestRequest::post()
+                .uri("/run")
+                .set_json(request.to_wire())
+                .to_request()
+        };
+
+        let slot = env.state.request_slots.acquire().await;
+        let compile_args = ["-c", "main.c", "-o", "main.o"];
+        let response = actix_web::test::call_service(&app, post(&compile_args)).await;
+        assert_eq!(
+            response.status(),
+            actix_web::http::StatusCode::SERVICE_UNAVAILABLE
+        );
+        let wire: RunLocallyResponseWire = actix_web::test::read_body_json(response).await;
+        let program = RunLocallyResponseData::from_wire(&wire)
+            .expect("response can be decoded")
+            .program;
+        assert_eq!(program, compiler.as_os_str());
+
+        // Links have to wait for a slot, also when they are handled eagerly.
+        for link_args in [&["main.o", "-o", "main"][..], &["main.c", "-o", "main"]] {
+            let waiting = actix_web::test::call_service(&app, post(link_args));
+            assert!(
+                tokio::time::timeout(Duration::from_millis(200), waiting)
+                    .await
+                    .is_err()
+            );
+        }
+
+        // The wrapper runs the compiler itself and the build continues with the real object.
+        let status = std::process::Command::new(&program)
+            .args(compile_args)
+            .current_dir(env.path(""))
+            .status()
+            .expect("compiler can run");
+        assert!(status.success());
+        drop(slot);
+        env.run_ok(WrappedBinary::Gcc, &["main.o", "-o", "main"])
+            .await;
+        env.run_program("main");
+    }
+
+    #[tokio::test]
+    async fn preprocess_cache_counters_are_in_stats() {
+        let env = TestEnv::new(&[]).await;
+        env.write("a.c", "int a(void) { return 1; }\n");
+        let preprocess_cache = async || {
+            get_json::<serde_json::Value>(&env, "/stats").await["preprocess_cache"].clone()
+        };
+        assert_eq!(preprocess_cache().await["misses"], 0);
+
+        env.run_ok(WrappedBinary::Gcc, &["-c", "a.c", "-o", "a.o"])
+            .await;
+        let stats = preprocess_cache().await;
+        assert_eq!((&stats["hits"], &stats["misses"]), (&0.into(), &1.into()));
+        assert_eq!(stats["bytes_saved"], 0);
+
+        env.run_ok(WrappedBinary::Gcc, &["-c", "a.c", "-o", "a.o"])
+            .await;
+        let stats = preprocess_cache().await;
+        assert_eq!((&stats["hits"], &stats["misses"]), (&1.into(), &1.into()));
+        assert_eq!(stats["hit_rate"], 0.5);
+        assert!(stats["bytes_saved"].as_u64().expect("is a number") > 0);
+    }
+
+    #[tokio::test]
+    async fn make_job_count_only_applies_during_the_request() {
+        let env = TestEnv::new(&["--jobs", "4"]).await;
+        env.write("a.c", "int a(void) { return 1; }\n");
+        let mut request = env.request(WrappedBinary::Gcc, &["-c", "a.c", "-o", "a.o"]);
+        request.make_jobs = Some(1);
+        handle_request_to_wire(&request, &env.state).await;
+        assert_eq!(env.state.pool.limit(), 4);
+    }
+
+    #[tokio::test]
+    async fn precompiled_headers_are_compiled_eagerly() {
+        let env = TestEnv::new(&[]).await;
+        env.write("pch.h", "static int from_pch(void) { return 4; }\n");
+        env.write("a.c", "int a(void) { return from_pch(); }\n");
+        assert_eq!(
+            classify(&env, &["-include", "pch.h", "-c", "a.c", "-o", "a.o"]),
+            RequestHandling::CompileObjectFile
+        );
+        assert_eq!(
+            classify(
+                &env,
+                &["-include-pch", "pch.h.pch", "-c", "a.c", "-o", "a.o"]
+            ),
+            RequestHandling::Eager
+        );
+
+        // Creating a `.gch` file.
+        let create_args = ["-x", "c-header", "-c", "pch.h", "-o", "pch.h.gch"];
+        assert_eq!(classify(&env, &create_args), RequestHandling::Eager);
+        env.run_ok(WrappedBinary::Gcc, &create_args).await;
+        assert!(env.path("pch.h.gch").exists());
+
+        // Using it implicitly because it's next to the header.
+        let use_args = ["-include", "pch.h", "-c", "a.c", "-o", "a.o"];
+        assert_eq!(classify(&env, &use_args), RequestHandling::Eager);
+        env.run_ok(WrappedBinary::Gcc, &use_args).await;
+        assert!(!placeholders::is_placeholder_file(
+            &env.path("a.o"),
+            placeholders::dummy_object()
+        ));
+    }
+
+    #[tokio::test]
+    async fn define_rule_forces_eager() {
+        let env = TestEnv::new(&[]).await;
+        env.write(
+            "ccelerate.toml",
+            &format!(
+                "{}[[rules]]\ndefine = \"GENERATED_BY_MOC\"\naction = \"eager\"\n",
+                CONFIG
+            ),
+        );
+        env.write("a.c", "int a(void) { return 1; }");
+        assert_eq!(
+            classify(&env, &["-DGENERATED_BY_MOC=1", "-c", "a.c", "-o", "a.o"]),
+            RequestHandling::Eager
+        );
+        assert_eq!(
+            classify(&env, &["-DOTHER", "-c", "a.c", "-o", "a.o"]),
+            RequestHandling::CompileObjectFile
+        );
+        env.run_ok(
+            WrappedBinary::Gcc,
+            &["-DGENERATED_BY_MOC", "-c", "a.c", "-o", "a.o"],
+        )
+        .await;
+        assert!(!placeholders::is_placeholder_file(
+            &env.path("a.o"),
+            placeholders::dummy_object()
+        ));
+    }
+
+    #[tokio::test]
+    async fn materialize_pin_is_removed_with_the_rule() {
+        let env = TestEnv::new(&[]).await;
+        env.write(
+            "ccelerate.toml",
+            &format!(
+                "{}[[rules]]\ndefine = \"ISOLATED\"\naction = \"materialize\"\n",
+                CONFIG
+            ),
+        );
+        env.write("a.c", "int a(void) { return 1; }");
+        env.write("b.c", "int b(void) { return 2; }");
+        let is_pinned = |name: &str| {
+            env.state
+                .persistent
+                .get_object_file(&env.path(name))
+                .expect("object is recorded")
+                .pinned_individual
+        };
+        env.run_ok(
+            WrappedBinary::Gcc,
+            &["-DISOLATED", "-c", "a.c", "-o", "a.o"],
+        )
+        .await;
+        assert!(is_pinned("a.o"));
+        env.run_ok(WrappedBinary::Gcc, &["-c", "a.c", "-o", "a.o"])
+            .await;
+        assert!(!is_pinned("a.o"));
+
+        // Explicit pins are independent of the rules.
+        env.run_ok(WrappedBinary::Gcc, &["-c", "b.c", "-o", "b.o"])
+            .await;
+        env.state
+            .persistent
+            .set_object_file_pinned_individual(&env.path("b.o"), true)
+            .expect("pin can be set");
+        env.run_ok(WrappedBinary::Gcc, &["-c", "b.c", "-o", "b.o"])
+            .await;
+        assert!(is_pinned("b.o"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn configured_toolchain_path_is_used() {
+        let env = TestEnv::new(&[]).await;
+        let log_path = env.path("gcc.log");
+        env.write_executable(
+            "toolchain/gcc",
+            &format!(
+                "#!/bin/sh\necho \"$@\" >> '{}'\nexec gcc \"$@\"\n",
+                log_path.display()
+            ),
+        );
+        env.write(
+            "ccelerate.toml",
+            &format!("{}[toolchain_paths]\ngcc = \"toolchain/gcc\"\n", CONFIG),
+        );
+        env.write("a.c", "int a(void) { return 1; }");
+        env.write(
+            "main.c",
+            "#include <stdio.h>\nint a(void);\nint main(void) { printf(\"%d\", a()); return 0; }",
+        );
+        env.run_ok(WrappedBinary::Gcc, &["-c", "a.c", "-o", "a.o"])
+            .await;
+        env.run_ok(WrappedBinary::Gcc, &["main.c", "a.o", "-o", "main"])
+            .await;
+        assert_eq!(env.run_program("main"), "1");
+
+        let log = std::fs::read_to_string(&log_path).expect("configured compiler was run");
+        // The source is preprocessed when it's compiled and the final link runs it again.
+        assert!(log.lines().any(|line| line.contains("a.c")), "{}", log);
+        assert!(log.lines().any(|line| line.ends_with("-o main")), "{}", log);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn resolved_binary_is_used() {
+        let env = TestEnv::new(&[]).await;
+        let log_path = env.path("gcc.log");
+        let resolved = env.write_executable(
+            "toolchain/gcc",
+            &format!(
+                "#!/bin/sh\necho \"$@\" >> '{}'\nexec gcc \"$@\"\n",
+                log_path.display()
+            ),
+        );
+        env.write("a.c", "int a(void) { return 1; }");
+        env.write(
+            "main.c",
+            "#include <stdio.h>\nint a(void);\nint main(void) { printf(\"%d\", a()); return 0; }",
+        );
+        for args in [
+            &["-c", "a.c", "-o", "a.o"][..],
+            &["main.c", "a.o", "-o", "main"],
+        ] {
+            let mut request = env.request(WrappedBinary::Gcc, args);
+            request.resolved_binary = Some(resolved.clone());
+            let output = CommandOutput::from_result(handle_request(&request, &env.state).await);
+            assert_eq!(output.status, 0, "{}", output);
+        }
+        assert_eq!(env.run_program("main"), "1");
+
+        let log = std::fs::read_to_string(&log_path).expect("resolved compiler was run");
+        assert!(log.lines().any(|line| line.contains("a.c")), "{}", log);
+        assert!(log.lines().any(|line| line.ends_with("-o main")), "{}", log);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn matching_sources_are_preprocessed_with_override_compiler() {
+        let env = TestEnv::new(&[]).await;
+        let log_path = env.path("clang.log");
+        // Stands in for a different compiler, only used to see when it's run.
+        env.write_executable(
+            "toolchain/clang",
+            &format!(
+                "#!/bin/sh\necho \"$@\" >> '{}'\nexec gcc \"$@\"\n",
+                log_path.display()
+            ),
+        );
+        env.write(
+            "ccelerate.toml",
+            &format!(
+                "{}[toolchain_paths]\nclang = \"toolchain/clang\"\n\n\
+                 [[preprocess_with]]\nsource = \"*/legacy/*.c\"\nbinary = \"clang\"\n",
+                CONFIG
+            ),
+        );
+        env.write("legacy/a.c", "int a(void) { return 1; }");
+        env.write("b.c", "int b(void) { return 2; }");
+        env.write(
+            "main.c",
+            "#include <stdio.h>\nint a(void); int b(void);\nint main(void) { printf(\"%d\", a() + b()); return 0; }",
+        );
+        env.run_ok(WrappedBinary::Gcc, &["-c", "legacy/a.c", "-o", "a.o"])
+            .await;
+        env.run_ok(WrappedBinary::Gcc, &["-c", "b.c", "-o", "b.o"])
+            .await;
+        env.run_ok(WrappedBinary::Gcc, &["main.c", "a.o", "b.o", "-o", "main"])
+            .await;
+        assert_eq!(env.run_program("main"), "3");
+
+        let log = std::fs::read_to_string(&log_path).expect("override compiler was run");
+        assert!(
+            log.lines().any(|line| line.contains("legacy/a.c")),
+            "{}",
+            log
+        );
+        // Other sources and the chunks themselves are handled by the normal compiler.
+        assert!(!log.lines().any(|line| line.contains("b.c")), "{}", log);
+        assert!(
+            !log.lines().any(|line| line.ends_with("-o main")),
+            "{}",
+            log
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn hanging_compiler_is_killed_after_timeout() {
+        let env = TestEnv::new(&["--child-timeout", "1"]).await;
+        let marker_path = env.path("marker");
+        // The sleep runs in a separate process, like `cc1` for a compiler driver.
+        let compiler = env.write_executable(
+            "toolchain/gcc",
+            &format!(
+                "#!/bin/sh\n(sleep 2; touch '{}') &\nwait\n",
+                marker_path.display()
+            ),
+        );
+        let mut request = env.request(WrappedBinary::Gcc, &["--version"]);
+        request.resolved_binary = Some(compiler);
+        let output = CommandOutput::from_result(handle_request(&request, &env.state).await);
+        assert_ne!(output.status, 0);
+        assert!(
+            String::from_utf8_lossy(&output.stderr).contains("did not finish within 1 seconds"),
+            "{}",
+            output
+        );
+        assert_eq!(env.state.pool.stats().in_use, 0);
+
+        // The whole process group is killed, not only the script.
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        assert!(!marker_path.exists());
+    }
+
+    #[tokio::test]
+    async fn compilers_run_through_launcher() {
+        let mut env = TestEnv::new(&[]).await;
+        let log_path = env.path("launcher.log");
+        // Logs its own argument and runs the compiler with the remaining ones.
+        let launcher = env.write_executable(
+            "launcher",
+            &format!(
+                "#!/bin/sh\nprintf '%s\\n' \"$1\" >> '{}'\nshift\nexec \"$@\"\n",
+                log_path.display()
+            ),
+        );
+        let launcher = launcher.to_str().expect("path is valid unicode");
+        // Values of the launcher are neither split nor taken as options of the server.
+        env.restart(&[
+            "--compiler-launcher",
+            launcher,
+            "-v --mark",
+            ";",
+            "--child-timeout",
+            "30",
+        ])
+        .await;
+        assert_eq!(
+            env.state.cli.compiler_launcher,
+            [launcher, "-v --mark"].map(OsString::from)
+        );
+        assert_eq!(env.state.cli.child_timeout, 30);
+
+        env.write("main.c", "int main(void) { return 0; }\n");
+        env.run_ok(WrappedBinary::Gcc, &["-c", "main.c", "-o", "main.o"])
+            .await;
+        env.run_ok(WrappedBinary::Gcc, &["main.o", "-o", "main"])
+            .await;
+        env.run_program("main");
+        let log = std::fs::read_to_string(&log_path).expect("launcher was used");
+        assert!(!log.is_empty());
+        assert!(log.lines().all(|line| line == "-v --mark"), "{}", log);
+    }
+
+    /// Runs gcc with the clang-cl options that ccelerate uses, so that clang-cl commands can
+    /// be tested without it.
+    const FAKE_CLANG_CL: &str = r#"#!/bin/sh
+for arg do
+  shift
+  case "$arg" in
+    --) ;;
+    /c) set -- "$@" -c ;;
+    /E|/P) set -- "$@" -E ;;
+    /w) set -- "$@" -w ;;
+    /Tc) set -- "$@" -x c ;;
+    /Tp) set -- "$@" -x c++ ;;
+    /Fi*) set -- "$@" -o "${arg#/Fi}" ;;
+    /Fo*) set -- "$@" -o "${arg#/Fo}" ;;
+    /Fe*) set -- "$@" -o "${arg#/Fe}" ;;
+    /I*) set -- "$@" "-I${arg#/I}" ;;
+    /D*) set -- "$@" "-D${arg#/D}" ;;
+    /clang:*) set -- "$@" "${arg#/clang:}" ;;
+    *) set -- "$@" "$arg" ;;
+  esac
+done
+exec gcc "$@"
+"#;
+
+    #[tokio::test]
+    async fn clang_cl_commands_are_classified() {
+        let env = TestEnv::new(&[]).await;
+        env.write(
+            "ccelerate.toml",
+            &format!("{}object_extensions = [\"obj\"]\n", CONFIG),
+        );
+        let classify_cl = |args: &[&str]| {
+            classify_request(&env.request(WrappedBinary::ClangCl, args), &env.state)
+                .expect("request can be classified")
+                .handling
+        };
+        assert_eq!(
+            classify_cl(&["/c", "/Fobar.obj", "/Iinc", "foo.cpp"]),
+            RequestHandling::CompileObjectFile
+        );
+        assert_eq!(
+            classify_cl(&["/c", "a.c", "b.c"]),
+            RequestHandling::CompileObjectFiles
+        );
+        assert_eq!(
+            classify_cl(&["main.obj", "util.lib", "/Feapp.exe"]),
+            RequestHandling::FinalLink
+        );
+        assert_eq!(classify_cl(&["/?"]), RequestHandling::Probe);
+        assert_eq!(
+            classify_cl(&["/E", "foo.cpp"]),
+            RequestHandling::OutputToStdout
+        );
+        // The build system reads the dependencies from the output.
+        assert_eq!(
+            classify_cl(&["/c", "/showIncludes", "/Fobar.obj", "foo.cpp"]),
+            RequestHandling::Eager
+        );
+        assert_eq!(
+            classify_cl(&["/c", "/GL", "/Fobar.obj", "foo.cpp"]),
+            RequestHandling::Eager
+        );
+    }
+
+    #[tokio::test]
+    async fn clang_cl_objects_are_compiled_in_chunks() {
+        let env = TestEnv::new(&[]).await;
+        env.write_executable("toolchain/clang-cl", FAKE_CLANG_CL);
+        env.write(
+            "ccelerate.toml",
+            &format!(
+                "{}object_extensions = [\"obj\"]\n[toolchain_paths]\nclang-cl = \"toolchain/clang-cl\"\n",
+                CONFIG
+            ),
+        );
+        env.write("inc/value.h", "#define VALUE 2\n");
+        env.write(
+            "util.c",
+            "#include \"value.h\"\nint util(void) { return VALUE; }\n",
+        );
+        env.write(
+            "main.c",
+            "#include <stdio.h>\nint util(void);\nint main(void) { printf(\"%d\", util() + ANSWER); return 0; }\n",
+        );
+        env.run_ok(
+            WrappedBinary::ClangCl,
+            &["/c", "/Iinc", "/Foutil.obj", "util.c"],
+        )
+        .await;
+        env.run_ok(
+            WrappedBinary::ClangCl,
+            &["/c", "/DANSWER=40", "/Fomain.obj", "main.c"],
+        )
+        .await;
+        // Nothing has been compiled yet.
+        for object in ["util.obj", "main.obj"] {
+            assert!(placeholders::is_placeholder_file(
+                &env.path(object),
+                placeholders::dummy_object()
+            ));
+        }
+
+        env.run_ok(
+            WrappedBinary::ClangCl,
+            &["main.obj", "util.obj", "/Feapp.exe"],
+        )
+        .await;
+        assert_eq!(env.run_program("app.exe"), "42");
+    }
+
+    #[tokio::test]
+    async fn assembly_sources_are_assembled_eagerly() {
+        let env = TestEnv::new(&[]).await;
+        // Needs the preprocessor, but contains no instructions so that it works on every
+        // architecture.
+        env.write(
+            "marker.S",
+            "#define NAME asm_marker\n.globl NAME\n.data\nNAME:\n.byte 7\n",
+        );
+        env.write(
+            "main.c",
+            "#include <stdio.h>\nextern char asm_marker[];\nint main(void) { printf(\"%d\", asm_marker[0]); return 0; }",
+        );
+        let args = ["-c", "marker.S", "-o", "marker.o"];
+        assert_eq!(classify(&env, &args), RequestHandling::Assemble);
+        let output = env.run_ok(WrappedBinary::Gcc, &args).await;
+        assert!(output.stderr.is_empty(), "{}", output);
+        assert!(!placeholders::is_placeholder_file(
+            &env.path("marker.o"),
+            placeholders::dummy_object()
+        ));
+
+        env.run_ok(WrappedBinary::Gcc, &["-c", "main.c", "-o", "main.o"])
+            .await;
+        env.run_ok(WrappedBinary::Gcc, &["main.o", "marker.o", "-o", "main"])
+            .await;
+        assert_eq!(env.run_program("main"), "7");
+    }
+
+    #[tokio::test]
+    async fn configured_object_extensions_are_compiled_lazily() {
+        let env = TestEnv::new(&[]).await;
+        env.write(
+            "ccelerate.toml",
+            &format!("{}object_extensions = [\"obj\", \"lo\"]", CONFIG),
+        );
+        env.write("a.c", "int a(void) { return 1; }");
+        env.write("b.c", "int b(void) { return 2; }");
+        env.write(
+            "main.c",
+            "#include <stdio.h>\nint a(void); int b(void);\nint main(void) { printf(\"%d\", a() + b()); return 0; }",
+        );
+        env.run_ok(WrappedBinary::Gcc, &["-c", "a.c", "-o", "a.obj"])
+            .await;
+        env.run_ok(WrappedBinary::Gcc, &["-c", "b.c", "-o", "b.lo"])
+            .await;
+        for object in ["a.obj", "b.lo"] {
+            assert!(placeholders::is_placeholder_file(
+                &env.path(object),
+                placeholders::dummy_object()
+            ));
+        }
+        env.run_ok(
+            WrappedBinary::Gcc,
+            &["main.c", "a.obj", "b.lo", "-o", "main"],
+        )
+        .await;
+        assert_eq!(env.run_program("main"), "3");
+    }
+
+    #[tokio::test]
+    async fn whole_archive_keeps_constructors() {
+        let env = TestEnv::new(&[]).await;
+        env.write(
+            "ctor.c",
+            "#include <stdio.h>\n__attribute__((constructor)) static void ctor(void) { printf(\"ctor\"); }",
+        );
+        env.write("main.c", "int main(void) { return 0; }");
+        env.run_ok(WrappedBinary::Gcc, &["-c", "ctor.c", "-o", "ctor.o"])
+            .await;
+        env.run_ok(WrappedBinary::Ar, &["rcs", "libctor.a", "ctor.o"])
+            .await;
+        env.run_ok(
+            WrappedBinary::Gcc,
+            &[
+                "main.c",
+                "-Wl,--whole-archive",
+                "libctor.a",
+                "-Wl,--no-whole-archive",
+                "-o",
+                "main",
+            ],
+        )
+        .await;
+        assert_eq!(env.run_program("main"), "ctor");
+    }
+
+    #[tokio::test]
+    async fn lto_compiles_run_eagerly() {
+        let env = TestEnv::new(&[]).await;
+        env.write("a.c", "int a(void) { return 1; }");
+        env.write("b.c", "int b(void) { return 2; }");
+        env.write(
+            "main.c",
+            "#include <stdio.h>\nint a(void); int b(void);\nint main(void) { printf(\"%d\", a() + b()); return 0; }",
+        );
+        assert_eq!(
+            classify(&env, &["-flto", "-c", "a.c", "-o", "a.o"]),
+            RequestHandling::Eager
+        );
+        assert_eq!(
+            classify(&env, &["-flto=auto", "-c", "a.c", "-o", "a.o"]),
+            RequestHandling::Eager
+        );
+        assert_eq!(
+            classify(&env, &["-flto", "a.o", "b.o", "-o", "main"]),
+            RequestHandling::FinalLink
+        );
+        env.run_ok(WrappedBinary::Gcc, &["-flto", "-c", "a.c", "-o", "a.o"])
+            .await;
+        assert!(!placeholders::is_placeholder_file(
+            &env.path("a.o"),
+            placeholders::dummy_object()
+        ));
+        // Objects that are compiled lazily can still be linked with LTO objects.
+        env.run_ok(WrappedBinary::Gcc, &["-c", "b.c", "-o", "b.o"])
+            .await;
+        env.run_ok(
+            WrappedBinary::Gcc,
+            &["-flto", "main.c", "a.o", "b.o", "-o", "main"],
+        )
+        .await;
+        assert_eq!(env.run_program("main"), "3");
+    }
+
+    #[tokio::test]
+    async fn compile_and_link_in_one_step() {
+        let env = TestEnv::new(&[]).await;
+        env.write(
+            "main.c",
+            "#include <stdio.h>\nint main(void) { printf(\"hello\"); return 0; }",
+        );
+        assert_eq!(
+            classify(&env, &["main.c", "-o", "main"]),
+            RequestHandling::FinalLink
+        );
+        env.run_ok(WrappedBinary::Gcc, &["main.c", "-o", "main"])
+            .await;
+        assert_eq!(env.run_program("main"), "hello");
+    }
+
+    #[tokio::test]
+    async fn compile_without_output_creates_object_in_cwd() {
+        let env = TestEnv::new(&[]).await;
+        env.write("foo.c", "int foo(void) { return 1; }");
+        assert_eq!(
+            classify(&env, &["-c", "foo.c"]),
+            RequestHandling::CompileObjectFile
+        );
+        env.run_ok(WrappedBinary::Gcc, &["-c", "foo.c"]).await;
+        assert!(placeholders::is_placeholder_file(
+            &env.path("foo.o"),
+            placeholders::dummy_object()
+        ));
+    }
+
+    #[tokio::test]
+    async fn batch_handles_compiles_and_archives() {
+        let env = TestEnv::new(&[]).await;
+        env.write("a.c", "int a(void) { return 1; }");
+        env.write("b.c", "int b(void) { return 2; }");
+        // Compiles are lazy, so only preprocessing errors are reported right away.
+        env.write("broken.c", "#include \"missing.h\"");
+        // Archive requests in the same batch can't rely on the compiles being done, so the
+        // objects are compiled before.
+        env.run_ok(WrappedBinary::Gcc, &["-c", "b.c", "-o", "b.o"])
+            .await;
+        let requests = [
+            (WrappedBinary::Gcc, vec!["-c", "a.c", "-o", "a.o"]),
+            (WrappedBinary::Ar, vec!["rcs", "libb.a", "b.o"]),
+            (WrappedBinary::Gcc, vec!["-c", "broken.c", "-o", "broken.o"]),
+        ];
+        let batch = RunBatchRequestWire {
+            requests: requests
+                .iter()
+                .map(|(binary, args)| env.request(*binary, args).to_wire())
+                .collect(),
+        };
+        let responses: Vec<RunResponseDataWire> = post_json(&env, "/run_batch", &batch).await;
+        let responses = responses
+            .into_iter()
+            .map(|response| RunResponseData::from_wire(response).expect("response is valid"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            responses
+                .iter()
+                .map(|response| response.status)
+                .collect::<Vec<_>>(),
+            [0, 0, 1]
+        );
+        assert!(String::from_utf8_lossy(&responses[2].stderr).contains("broken.c"));
+        assert!(env.path("a.o").exists());
+        assert!(env.path("libb.a").exists());
+    }
+
+    #[tokio::test]
+    async fn preprocessor_warnings_are_in_the_response() {
+        let env = TestEnv::new(&[]).await;
+        env.write(
+            "foo.c",
+            "#warning \"foo is deprecated\"\nint foo(void) { return 1; }",
+        );
+        // The warning has to be forwarded even though the object is not compiled yet.
+        assert_eq!(
+            classify(&env, &["-c", "foo.c", "-o", "foo.o"]),
+            RequestHandling::CompileObjectFile
+        );
+        let batch = RunBatchRequestWire {
+            requests: vec![
+                env.request(WrappedBinary::Gcc, &["-c", "foo.c", "-o", "foo.o"])
+                    .to_wire(),
+            ],
+        };
+        let responses: Vec<RunResponseDataWire> = post_json(&env, "/run_batch", &batch).await;
+        let [response] = responses.as_slice() else {
+            panic!("expected one response");
+        };
+        let response = RunResponseData::from_wire(response.clone()).expect("response is valid");
+        assert_eq!(response.status, 0);
+        let stderr = String::from_utf8_lossy(&response.stderr);
+        assert!(stderr.contains("foo is deprecated"), "{}", stderr);
+        assert!(stderr.contains("-Wcpp"), "{}", stderr);
+    }
+
+    #[tokio::test]
+    async fn debug_parse_shows_handling() {
+        let env = TestEnv::new(&[]).await;
+        let config_path = env.write("ccelerate.toml", CONFIG);
+        env.write("foo.c", "int foo(void) { return 1; }");
+        let request = env
+            .request(WrappedBinary::Gcc, &["-flto", "-c", "foo.c", "-o", "foo.o"])
+            .to_wire();
+        let response: serde_json::Value = post_json(&env, "/debug/parse", &request).await;
+        assert_eq!(response["handling"], "Eager");
+        assert_eq!(response["uses_lto"], true);
+        assert_eq!(
+            response["output"],
+            env.path("foo.o").to_string_lossy().as_ref()
+        );
+        assert_eq!(
+            response["sources"][0]["path"],
+            env.path("foo.c").to_string_lossy().as_ref()
+        );
+        assert_eq!(
+            response["config_files"][0],
+            config_path.to_string_lossy().as_ref()
+        );
+        // Nothing was run.
+        assert!(!env.path("foo.o").exists());
+    }
+
+    #[tokio::test]
+    async fn too_many_sources_are_compiled_eagerly() {
+        let env = TestEnv::new(&["--max-sources", "2"]).await;
+        env.write("a.c", "int a(void) { return 1; }");
+        env.write("b.c", "int b(void) { return 2; }");
+        env.write("c.c", "int c(void) { return 3; }");
+        assert_eq!(
+            classify(&env, &["-c", "a.c", "b.c"]),
+            RequestHandling::CompileObjectFiles
+        );
+        assert_eq!(
+            classify(&env, &["-c", "a.c", "b.c", "c.c"]),
+            RequestHandling::Eager
+        );
+        env.run_ok(WrappedBinary::Gcc, &["-c", "a.c", "b.c", "c.c"])
+            .await;
+        for object in ["a.o", "b.o", "c.o"] {
+            assert!(!placeholders::is_placeholder_file(
+                &env.path(object),
+                placeholders::dummy_object()
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn compile_commands_describe_original_commands() {
+        let env = TestEnv::new(&[]).await;
+        env.write("foo.c", "int foo(void) { return 1; }");
+        let args = ["-O2", "-c", "foo.c", "-o", "foo.o", "-MJ", "foo.o.json"];
+        env.run_ok(WrappedBinary::Gcc, &args).await;
+        let expected = serde_json::json!({
+            "directory": env.path("").to_string_lossy(),
+            "file": env.path("foo.c").to_string_lossy(),
+            "output": env.path("foo.o").to_string_lossy(),
+            "arguments": ["gcc", "-O2", "-c", "foo.c", "-o", "foo.o"],
+        });
+
+        // The fragment is written like by `clang -MJ`.
+        let fragment =
+            std::fs::read_to_string(env.path("foo.o.json")).expect("fragment is written");
+        let fragment: serde_json::Value = serde_json::from_str(
+            fragment
+                .trim_end()
+                .strip_suffix(',')
+                .expect("fragment ends with a comma"),
+        )
+        .expect("fragment is valid json");
+        assert_eq!(fragment, expected);
+
+        let commands: serde_json::Value = get_json(&env, "/compile_commands.json").await;
+        assert_eq!(commands, serde_json::json!([expected]));
+    }
+
+    struct TestTaskInfo {}
+
+    impl TaskPeriodInfo for TestTaskInfo {
+        fn category(&self) -> String {
+            "Test".to_string()
+        }
+
+        fn terminal_one_liner(&self, _project_roots: &ProjectRoots) -> String {
+            "test task".to_string()
+        }
+
+        fn log_detailed(&self) {}
+    }
+
+    #[actix_web::test]
+    async fn events_are_streamed() {
+        let env = TestEnv::new(&[]).await;
+        let app = actix_web::test::init_service(
+            actix_web::App::new()
+                .app_data(Data::new(WebState {
+                    state: env.state.clone(),
+                }))
+                .service(route_events),
+        )
+        .await;
+        let response = actix_web::test::call_service(
+            &app,
+            actix_web::test::TestRequest::get()
+                .uri("/events")
+                .to_request(),
+        )
+        .await;
+        assert!(response.status().is_success());
+        let mut body = Box::pin(response.into_body());
+        let mut next_event = async || {
+            let chunk = tokio::time::timeout(
+                Duration::from_secs(10),
+                std::future::poll_fn(|cx| body.as_mut().poll_next(cx)),
+            )
+            .await
+            .expect("event arrives")
+            .expect("stream is not closed")
+            .expect("chunk can be read");
+            let chunk = String::from_utf8_lossy(&chunk).to_string();
+            let json = chunk
+                .strip_prefix("data: ")
+                .and_then(|chunk| chunk.strip_suffix("\n\n"))
+                .expect("event is formatted as server-sent event");
+            serde_json::from_str::<serde_json::Value>(json).expect("event is json")
+        };
+
+        let task = env.state.task_periods.start(TestTaskInfo {});
+        let start = next_event().await;
+        assert_eq!(start["kind"], "start");
+        assert_eq!(start["category"], "Test");
+        assert_eq!(start["name"], "test task");
+        assert!(start["duration"].is_null());
+        task.finished_successfully();
+        drop(task);
+        let finish = next_event().await;
+        assert_eq!(finish["kind"], "finish");
+        assert_eq!(finish["id"], start["id"]);
+        assert_eq!(finish["success"], true);
+        assert!(finish["duration"].is_number());
+    }
+
+    #[tokio::test]
+    async fn color_arg_is_added_for_terminals() {
+        let env = TestEnv::new(&[]).await;
+        let args_for = |args: &[&str], client_is_tty: bool| {
+            let request = RunRequestData {
+                client_is_tty,
+                ..env.request(WrappedBinary::Gcc, args)
+            };
+            args_for_client_terminal(&request).expect("args can be parsed")
+        };
+        let link_args = ["main.o", "-o", "main"];
+        assert_eq!(args_for(&link_args, false), link_args);
+        assert_eq!(
+            args_for(&link_args, true),
+            ["main.o", "-o", "main", "-fdiagnostics-color=always"]
+        );
+        for color_arg in [
+            "-fdiagnostics-color",
+            "-fdiagnostics-color=never",
+            "-fno-diagnostics-color",
+            "-fcolor-diagnostics",
+            "-fno-color-diagnostics",
+        ] {
+            let args = ["main.o", color_arg, "-o", "main"];
+            assert_eq!(args_for(&args, true), args, "{}", color_arg);
+        }
+    }
+
+    #[tokio::test]
+    async fn object_output_to_stdout() {
+        let env = TestEnv::new(&[]).await;
+        env.write("foo.c", "int foo(void) { return 1; }");
+        assert_eq!(
+            classify(&env, &["-c", "-o", "-", "foo.c"]),
+            RequestHandling::OutputToStdout
+        );
+        // It's run as is, even though the GNU assembler can't actually write to stdout.
+        let args = ["-c", "-o", "-", "foo.c"];
+        let output = env.run(WrappedBinary::Gcc, &args).await;
+        let expected = std::process::Command::new("gcc")
+            .args(args)
+            .current_dir(env.path(""))
+            .output()
+            .expect("gcc can be started");
+        assert_eq!(Some(output.status), expected.status.code());
+        assert_eq!(output.stdout, expected.stdout);
+        // `-` is not a relative file name.
+        assert!(!env.path("-").exists());
+        assert!(
+            env.state
+                .persistent
+                .get_object_file(&env.path("-"))
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn version_queries_are_probes() {
+        let env = TestEnv::new(&[]).await;
+        for args in [
+            &["--version"][..],
+            &["-V"],
+            &["-dumpversion"],
+            &["--version", "-c", "foo.c", "-o", "foo.o"],
+        ] {
+            assert_eq!(classify(&env, args), RequestHandling::Probe, "{:?}", args);
+        }
+        let output = env.run_ok(WrappedBinary::Gcc, &["--version"]).await;
+        let expected = std::process::Command::new("gcc")
+            .arg("--version")
+            .output()
+            .expect("gcc can be started");
+        assert_eq!(output.stdout, expected.stdout);
+    }
+
+    #[tokio::test]
+    async fn dry_run_is_proxied() {
+        let env = TestEnv::new(&[]).await;
+        env.write("foo.c", "int main(void) { return 0; }");
+        let args = ["-###", "foo.c", "-o", "foo"];
+        assert_eq!(classify(&env, &args), RequestHandling::Probe);
+        let output = env.run(WrappedBinary::Gcc, &args).await;
+        let expected = std::process::Command::new("gcc")
+            .args(args)
+            .current_dir(env.path(""))
+            .output()
+            .expect("gcc can be started");
+        assert_eq!(Some(output.status), expected.status.code());
+        assert_eq!(output.stdout, expected.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("cc1"), "{}", stderr);
+        assert!(!env.path("foo").exists());
+    }
+
+    #[actix_web::test]
+    async fn server_stops_when_idle() {
+        let env = TestEnv::new(&[]).await;
+        let server = actix_web::HttpServer::new(actix_web::App::new)
+            .workers(1)
+            .bind("127.0.0.1:0")
+            .expect("server can be bound")
+            .run();
+        let running_request = env.state.request_activity.start();
+        actix_web::rt::spawn(stop_when_idle(
+            env.state.clone(),
+            server.handle(),
+            Duration::from_millis(100),
+        ));
+        let server = actix_web::rt::spawn(server);
+        // The server keeps running while requests are handled.
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(!server.is_finished());
+        drop(running_request);
+        tokio::time::timeout(Duration::from_secs(10), server)
+            .await
+            .expect("server stops")
+            .expect("server task does not panic")
+            .expect("server stops without error");
+    }
+
+    async fn compile_on_worker(worker_args: &[&str], token: Option<&str>) -> Result<i32, String> {
+        let worker = TestEnv::new(worker_args).await;
+        let web_state = Data::new(WebState {
+            state: worker.state.clone(),
+        });
+        let server = actix_web::HttpServer::new(move || {
+            actix_web::App::new()
+                .app_data(web_state.clone())
+                .service(route_compile_chunk)
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .expect("server can be bound");
+        let url = format!("http://{}", server.addrs()[0]);
+        let server = server.run();
+        let server_handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let workers = RemoteWorkers::new(
+            &[url],
+            token.map(str::to_owned),
+            1,
+            RemoteWorkers::request_timeout(0),
+        )
+        .expect("workers can be created");
+        let result = workers
+            .acquire()
+            .expect("worker is available")
+            .compile(
+                WrappedBinary::Gcc,
+                &["-O2", "-c", "foo.c", "-o", "foo.o"].map(Into::into),
+                "i",
+                b"int foo(void) { return 1; }".to_vec(),
+            )
+            .await
+            .map(|response| response.status)
+            .map_err(|err| err.to_string());
+        server_handle.stop(false).await;
+        result
+    }
+
+    #[actix_web::test]
+    async fn compiling_chunks_requires_token() {
+        let worker_args = ["--worker-token", "secret"];
+        assert_eq!(compile_on_worker(&worker_args, Some("secret")).await, Ok(0));
+        for token in [None, Some("wrong")] {
+            let err = compile_on_worker(&worker_args, token)
+                .await
+                .expect_err("compiling is forbidden");
+            assert!(err.contains("403"), "{}", err);
+        }
+        // Without a token, the server does not compile for others at all.
+        let err = compile_on_worker(&[], Some("secret"))
+            .await
+            .expect_err("compiling is forbidden");
+        assert!(err.contains("403"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn unresponsive_worker_fails_after_timeout() {
+        // Accepts connections but never answers.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("listener can be bound");
+        let url = format!(
+            "http://{}",
+            listener.local_addr().expect("listener has an address")
+        );
+        tokio::spawn(async move {
+            let mut connections = vec![];
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.push(stream);
+            }
+        });
+
+        let workers = RemoteWorkers::new(&[url], None, 1, Duration::from_millis(500))
+            .expect("workers can be created");
+        let worker = workers.acquire().expect("worker is available");
+        let args = ["-c", "foo.c", "-o", "foo.o"].map(Into::into);
+        let compile = worker.compile(
+            WrappedBinary::Gcc,
+            &args,
+            "i",
+            b"int foo(void) { return 1; }".to_vec(),
+        );
+        // The error makes the final link compile the chunk locally instead.
+        let result = tokio::time::timeout(Duration::from_secs(10), compile)
+            .await
+            .expect("request does not hang");
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn bind_address() {
+        let env = TestEnv::new(&["--bind", "0.0.0.0", "--port", "1234"]).await;
+        assert_eq!(env.state.address, "0.0.0.0:1234");
+        let env = TestEnv::new(&["--bind", "::1", "--port", "1234"]).await;
+        assert_eq!(env.state.address, "[::1]:1234");
+        let env = TestEnv::new(&["--port", "1234"]).await;
+        assert_eq!(env.state.address, "127.0.0.1:1234");
+    }
+}
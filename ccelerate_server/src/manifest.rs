@@ -0,0 +1,139 @@
+#![deny(clippy::unwrap_used)]
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+use crate::{path_utils, wrap_compile_object_file::content_hash};
+
+/// Lists the files that a final link produced and used, so that they can be checked or
+/// transferred later.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    pub output: ManifestFile,
+    // Archives that were created for the compiled objects.
+    pub archives: Vec<ManifestFile>,
+    // Objects that were compiled from chunks of local code. These are the archive members.
+    pub objects: Vec<ManifestFile>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ManifestFile {
+    pub path: PathBuf,
+    pub hash: String,
+}
+
+impl Manifest {
+    pub async fn new(output: &Path, archives: &[PathBuf], objects: &[PathBuf]) -> Result<Self> {
+        let mut manifest = Self {
+            output: ManifestFile::new(output).await?,
+            archives: vec![],
+            objects: vec![],
+        };
+        for archive in archives {
+            manifest.archives.push(ManifestFile::new(archive).await?);
+        }
+        for object in objects {
+            manifest.objects.push(ManifestFile::new(object).await?);
+        }
+        Ok(manifest)
+    }
+
+    /// Every linked output gets its own file in the directory. The name contains a hash of the
+    /// output path, because outputs in different directories may have the same name.
+    pub async fn write_to_dir(&self, manifest_dir: &Path) -> Result<()> {
+        let path_hash =
+            twox_hash::XxHash64::oneshot(0, self.output.path.as_os_str().as_encoded_bytes());
+        let file_name = self
+            .output
+            .path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let manifest_path = manifest_dir.join(format!("{}-{:016x}.json", file_name, path_hash));
+        path_utils::ensure_directory_and_write(
+            &manifest_path,
+            serde_json::to_string_pretty(self)?.as_bytes(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn read(path: &Path) -> Result<Self> {
+        Ok(serde_json::from_slice(&tokio::fs::read(path).await?)?)
+    }
+
+    /// Returns a description of every file that is missing or has changed.
+    pub async fn verify(&self) -> Vec<String> {
+        let mut problems = vec![];
+        for file in std::iter::once(&self.output)
+            .chain(self.archives.iter())
+            .chain(self.objects.iter())
+        {
+            match tokio::fs::read(&file.path).await {
+                Ok(content) => {
+                    if content_hash(&content) != file.hash {
+                        problems.push(format!("Changed: {}", file.path.display()));
+                    }
+                }
+                Err(_) => problems.push(format!("Missing: {}", file.path.display())),
+            }
+        }
+        problems
+    }
+}
+
+impl ManifestFile {
+    async fn new(path: &Path) -> Result<Self> {
+        Ok(Self {
+            path: path.to_owned(),
+            hash: content_hash(&tokio::fs::read(path).await?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ccelerate_shared::WrappedBinary;
+
+    use super::Manifest;
+    use crate::test_utils::TestEnv;
+
+    #[tokio::test]
+    async fn written_manifest_can_be_verified() {
+        let manifest_dir = tempfile::tempdir().expect("temp dir can be created");
+        let manifest_dir_arg = manifest_dir.path().to_string_lossy().to_string();
+        let env = TestEnv::new(&["--write-manifest", &manifest_dir_arg]).await;
+        env.write("a.c", "int a(void) { return 1; }");
+        env.write("main.c", "int a(void);\nint main(void) { return a() - 1; }");
+        env.run_ok(WrappedBinary::Gcc, &["-c", "a.c", "-o", "a.o"])
+            .await;
+        env.run_ok(WrappedBinary::Gcc, &["main.c", "a.o", "-o", "main"])
+            .await;
+
+        let manifest_paths = std::fs::read_dir(manifest_dir.path())
+            .expect("manifest directory exists")
+            .map(|entry| entry.expect("entry can be read").path())
+            .collect::<Vec<_>>();
+        let [manifest_path] = manifest_paths.as_slice() else {
+            panic!("expected one manifest: {:?}", manifest_paths);
+        };
+        let manifest = Manifest::read(manifest_path)
+            .await
+            .expect("manifest can be read");
+        assert_eq!(manifest.output.path, env.path("main"));
+        assert!(!manifest.archives.is_empty());
+        assert!(!manifest.objects.is_empty());
+        assert!(manifest.verify().await.is_empty());
+
+        std::fs::write(env.path("main"), "changed").expect("output can be written");
+        std::fs::remove_file(&manifest.objects[0].path).expect("object can be removed");
+        assert_eq!(
+            manifest.verify().await,
+            [
+                format!("Changed: {}", env.path("main").display()),
+                format!("Missing: {}", manifest.objects[0].path.display()),
+            ]
+        );
+    }
+}
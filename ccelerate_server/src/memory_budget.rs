@@ -0,0 +1,62 @@
+#![deny(clippy::unwrap_used)]
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Limits how much input is processed at the same time. Compiling large chunks needs a lot of
+/// memory, so limiting only the number of parallel jobs is not always enough.
+pub struct MemoryBudget {
+    // Permits are kilobytes because the number of permits that can be acquired at once is limited.
+    semaphore: Option<Semaphore>,
+    total_kb: u32,
+}
+
+impl MemoryBudget {
+    pub fn new(budget_mb: Option<u32>) -> Self {
+        let total_kb = budget_mb.unwrap_or(0).saturating_mul(1024).max(1);
+        Self {
+            semaphore: budget_mb.map(|_| Semaphore::new(total_kb as usize)),
+            total_kb,
+        }
+    }
+
+    /// Waits until enough of the budget is available. Inputs that are larger than the entire
+    /// budget still run, just not in parallel with anything else.
+    pub async fn acquire(&self, bytes: u64) -> Option<SemaphorePermit<'_>> {
+        let semaphore = self.semaphore.as_ref()?;
+        let kb = u32::try_from(bytes.div_ceil(1024))
+            .unwrap_or(u32::MAX)
+            .clamp(1, self.total_kb);
+        Some(
+            semaphore
+                .acquire_many(kb)
+                .await
+                .expect("semaphore is never closed"),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn oversized_inputs_run_alone() {
+        let budget = MemoryBudget::new(Some(1));
+        // Much larger than the entire budget, but it must not wait forever.
+        let large = budget.acquire(100 * 1024 * 1024).await;
+        assert!(large.is_some());
+        let small =
+            tokio::time::timeout(std::time::Duration::from_millis(50), budget.acquire(1024)).await;
+        assert!(small.is_err(), "must wait until the large input is done");
+        drop(large);
+        let first = budget.acquire(512 * 1024).await;
+        let second = budget.acquire(512 * 1024).await;
+        assert!(first.is_some() && second.is_some());
+    }
+
+    #[tokio::test]
+    async fn no_budget_never_waits() {
+        let budget = MemoryBudget::new(None);
+        assert!(budget.acquire(u64::MAX).await.is_none());
+    }
+}
@@ -0,0 +1,1089 @@
+#![deny(clippy::unwrap_used)]
+
+use std::{
+    collections::HashSet,
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Result, anyhow};
+use bstr::{BString, ByteVec};
+use smallvec::{SmallVec, smallvec};
+
+use crate::{
+    args_processing::{BuildObjectFileInfo, LinkFileInfo},
+    code_language::CodeLanguage,
+    gcc_args::BuildFilesInfo,
+    path_utils::{self, make_absolute},
+    source_file::SourceFile,
+};
+
+impl BuildObjectFileInfo {
+    pub fn from_msvc_args(cwd: &Path, args: &[impl AsRef<OsStr>]) -> Result<Self> {
+        let args = MsvcArgsInfo::from_args(args)?;
+        if args.writes_output_to_stdout() {
+            return Err(anyhow!("The preprocessed code is written to stdout"));
+        }
+        let sources = args.get_sources();
+        let [source] = sources.as_slice() else {
+            return Err(anyhow!("There has to be exactly one source"));
+        };
+        let source_language = match source.language {
+            Some(language) => language,
+            None => CodeLanguage::from_path(source.path)?,
+        };
+        let object_path = args
+            .get_object_output(source.path)
+            .ok_or_else(|| anyhow!("Failed to get object path"))?;
+        Ok(Self {
+            source_path: make_absolute(cwd, source.path),
+            source_language,
+            object_path: make_absolute(cwd, &object_path),
+            // Dependencies are printed with `/showIncludes` instead.
+            depfile: None,
+            compilation_database_path: None,
+        })
+    }
+}
+
+impl LinkFileInfo {
+    pub fn from_msvc_args(cwd: &Path, args: &[impl AsRef<OsStr>]) -> Result<Self> {
+        let args = MsvcArgsInfo::from_args(args)?;
+        let output = args
+            .get_link_output()
+            .ok_or_else(|| anyhow!("There has to be one output"))?;
+        Ok(Self {
+            sources: args.get_absolute_sources(cwd),
+            whole_archive_sources: args.get_absolute_whole_archive_sources(cwd),
+            output: make_absolute(cwd, &output),
+            is_shared_library: args.has_option("LD") || args.has_option("LDd"),
+        })
+    }
+}
+
+impl BuildFilesInfo {
+    pub fn from_msvc_args(cwd: &Path, args: &[impl AsRef<OsStr>]) -> Result<Self> {
+        let args = MsvcArgsInfo::from_args(args)?;
+        let output_to_stdout = args.writes_output_to_stdout();
+        let object_outputs: Vec<PathBuf> = if args.has_option("c") {
+            args.get_sources()
+                .iter()
+                .filter_map(|source| args.get_object_output(source.path))
+                .map(|output| make_absolute(cwd, &output))
+                .collect()
+        } else {
+            vec![]
+        };
+        let output = if output_to_stdout {
+            None
+        } else if args.has_option("c") {
+            match object_outputs.as_slice() {
+                [output] => Some(output.clone()),
+                _ => None,
+            }
+        } else {
+            args.get_link_output()
+                .map(|output| make_absolute(cwd, &output))
+        };
+        Ok(Self {
+            sources: args.get_absolute_sources(cwd),
+            implied_outputs: if object_outputs.len() > 1 {
+                object_outputs
+            } else {
+                vec![]
+            },
+            output,
+            uses_lto: args.uses_lto(),
+            uses_pch: args.uses_pch(),
+            is_query: args.is_query(),
+            output_to_stdout,
+            prefix_dirs: vec![],
+            defines: args.get_defines(),
+            f_flags: args
+                .args
+                .iter()
+                .filter_map(|arg| match arg {
+                    MsvcArg::Single(arg) if arg.to_str().is_some_and(|a| a.starts_with("-f")) => {
+                        Some(BString::from(arg.as_encoded_bytes()))
+                    }
+                    _ => None,
+                })
+                .collect(),
+        })
+    }
+}
+
+pub fn is_build_object_file(args: &[impl AsRef<OsStr>]) -> Result<bool> {
+    let args = MsvcArgsInfo::from_args(args)?;
+    Ok(args.has_option("c"))
+}
+
+/// True if the arguments suppress all warnings with `/w`.
+pub fn suppresses_warnings(args: &[impl AsRef<OsStr>]) -> Result<bool> {
+    let args = MsvcArgsInfo::from_args(args)?;
+    Ok(args.has_option("w"))
+}
+
+/// True if the compiler prints the included files with `/showIncludes`. Build systems like
+/// Ninja get the dependencies of an object from that output, so it can't be created lazily.
+pub fn prints_included_files(args: &[impl AsRef<OsStr>]) -> Result<bool> {
+    let args = MsvcArgsInfo::from_args(args)?;
+    Ok(args.args.iter().any(|arg| {
+        arg.option_name()
+            .is_some_and(|name| name.starts_with("showIncludes"))
+    }))
+}
+
+/// Like [`crate::gcc_args::split_into_single_source_args`]. Options apply to all sources, so
+/// only the other sources have to be removed.
+pub fn split_into_single_source_args(args: &[impl AsRef<OsStr>]) -> Result<Vec<Vec<OsString>>> {
+    let args = MsvcArgsInfo::from_args(args)?;
+    let sources_num = args
+        .args
+        .iter()
+        .filter(|arg| matches!(arg, MsvcArg::Source { .. }))
+        .count();
+    Ok((0..sources_num)
+        .map(|source_index| {
+            let mut single_args = args.clone();
+            let mut current_index = 0;
+            single_args.args.retain(|arg| match arg {
+                MsvcArg::Source { .. } => {
+                    current_index += 1;
+                    current_index - 1 == source_index
+                }
+                _ => true,
+            });
+            single_args.to_args_owned_vec()
+        })
+        .collect())
+}
+
+/// Takes arguments that would build one object file and changes it so that it instead
+/// outputs the preprocessed code for the source file.
+pub fn rewrite_to_extract_local_code(args: &[impl AsRef<OsStr>]) -> Result<Vec<OsString>> {
+    let mut args = MsvcArgsInfo::from_args(args)?;
+    args.args.retain(|arg| match arg.option_name() {
+        // Remove /c, it is replaced by /E below to stop after preprocessing.
+        Some("c") => false,
+        // Remove output file so that output is written to stdout.
+        Some(name) if is_object_output_option(name) => false,
+        // The included headers would be printed again.
+        Some(name) if name.starts_with("showIncludes") => false,
+        _ => true,
+    });
+    // Stop after preprocessing.
+    args.push_option("/E");
+    // Keep defines in preprocessed output.
+    args.push_option("/clang:-dD");
+    Ok(args.to_args_owned_vec())
+}
+
+/// Like [`crate::gcc_args::update_to_build_object_from_stdin`]. The original sources are
+/// replaced by the input file and the object is written to the output path.
+pub fn update_to_build_object_from_stdin(
+    args: &[impl AsRef<OsStr>],
+    input_path: &Path,
+    output_path: &Path,
+) -> Result<Vec<OsString>> {
+    let mut args = MsvcArgsInfo::from_args(args)?;
+    args.args.retain(|arg| match arg {
+        // Remove all sources, the input file is used instead.
+        MsvcArg::Source { .. } => false,
+        _ => match arg.option_name() {
+            // The included headers are part of the preprocessed input already.
+            Some(name) if name.starts_with("showIncludes") => false,
+            // Remove language overrides, the language of the new input is set below.
+            Some("TC") | Some("TP") => false,
+            // Remove output file because it's replaced below.
+            Some(name) if is_object_output_option(name) => false,
+            _ => true,
+        },
+    });
+    let mut output_arg = OsString::from("/Fo");
+    output_arg.push(output_path);
+    args.push_owned_option(output_arg);
+    args.push_source_with_language(input_path);
+    Ok(args.to_args_owned_vec())
+}
+
+/// Makes the compiler color its diagnostics unless the arguments decide that already.
+pub fn add_diagnostics_color_arg(args: &[impl AsRef<OsStr>]) -> Result<Vec<OsString>> {
+    let mut args = MsvcArgsInfo::from_args(args)?;
+    let has_color_arg = args.args.iter().any(|arg| match arg {
+        MsvcArg::Single(arg) => arg.to_str().is_some_and(|arg| {
+            arg.starts_with("-fdiagnostics-color")
+                || arg.starts_with("-fno-diagnostics-color")
+                || arg == "-fcolor-diagnostics"
+                || arg == "-fno-color-diagnostics"
+        }),
+        _ => false,
+    });
+    if !has_color_arg {
+        args.push_option("-fcolor-diagnostics");
+    }
+    Ok(args.to_args_owned_vec())
+}
+
+/// clang-cl does not write compilation database entries, so there is nothing to remove.
+pub fn remove_compilation_database_arg(args: &[impl AsRef<OsStr>]) -> Result<Vec<OsString>> {
+    Ok(MsvcArgsInfo::from_args(args)?.to_args_owned_vec())
+}
+
+pub fn rewrite_to_get_preprocessed_headers(
+    args: &[impl AsRef<OsStr>],
+    include_code_path: &Path,
+    output_path: &Path,
+) -> Result<Vec<OsString>> {
+    let mut args = MsvcArgsInfo::from_args(args)?;
+    args.args.retain(|arg| match arg {
+        // Remove all sources, the include code is used instead.
+        MsvcArg::Source { .. } => false,
+        _ => match arg.option_name() {
+            // Remove /c, it is replaced by /P below to stop after preprocessing.
+            Some("c") => false,
+            Some(name) if name.starts_with("showIncludes") => false,
+            // Remove language overrides, the language of the new input is set below.
+            Some("TC") | Some("TP") => false,
+            Some(name) if is_object_output_option(name) => false,
+            _ => true,
+        },
+    });
+    // Write the preprocessed code to the output file.
+    let mut output_arg = OsString::from("/Fi");
+    output_arg.push(output_path);
+    args.push_option("/P");
+    args.push_owned_option(output_arg);
+    args.push_source_with_language(include_code_path);
+    Ok(args.to_args_owned_vec())
+}
+
+/// The linker searches all archives for undefined symbols regardless of their order, so link
+/// groups are never necessary.
+pub fn rewrite_to_link_sources(
+    args: &[impl AsRef<OsStr>],
+    sources: &[Vec<SourceFile>],
+    whole_archive_sources: &[SourceFile],
+) -> Result<Vec<OsString>> {
+    let mut args = MsvcArgsInfo::from_args(args)?;
+    args.args
+        .retain(|arg| !matches!(arg, MsvcArg::Source { .. }));
+    // They are added again below where necessary.
+    args.linker_args
+        .retain(|arg| whole_archive_linker_arg(arg).is_none());
+    for source in sources.iter().flatten().chain(whole_archive_sources) {
+        args.push_link_source(source);
+    }
+    for source in whole_archive_sources {
+        let mut linker_arg = OsString::from("/WHOLEARCHIVE:");
+        linker_arg.push(&source.path);
+        args.owned_linker_args.push(linker_arg);
+    }
+    Ok(args.to_args_owned_vec())
+}
+
+/// Include paths are normalized like in
+/// [`crate::gcc_args::add_translation_unit_unspecific_args_to_key`].
+pub fn add_translation_unit_unspecific_args_to_key(
+    args: &[impl AsRef<OsStr>],
+    cwd: &Path,
+    resolve_symlinks: bool,
+    key: &mut BString,
+) -> Result<()> {
+    let normalize_include_path = |path: &OsStr| -> OsString {
+        let path = make_absolute(cwd, Path::new(path));
+        if resolve_symlinks && let Ok(path) = std::fs::canonicalize(&path) {
+            return path.into_os_string();
+        }
+        path_utils::normalize_path(&path).into_os_string()
+    };
+    let args = MsvcArgsInfo::from_args(args)?;
+    for arg in args.args.iter() {
+        match arg {
+            // Don't add the source file, but its language.
+            MsvcArg::Source { marker, .. } => {
+                if let Some(marker) = marker {
+                    key.push_str(marker.as_encoded_bytes());
+                }
+            }
+            MsvcArg::Single(arg) => {
+                let name = arg.to_str().and_then(option_name);
+                if name.is_some_and(|name| {
+                    is_object_output_option(name) || name.starts_with("showIncludes")
+                }) {
+                    continue;
+                }
+                if let Some((flag, path)) = name.and_then(split_joined_include_arg) {
+                    key.push_str(flag.as_bytes());
+                    key.push_str(normalize_include_path(path).as_encoded_bytes());
+                } else {
+                    key.push_str(arg.as_encoded_bytes());
+                }
+            }
+            MsvcArg::Dual(first, second) => {
+                let name = first.to_str().and_then(option_name);
+                if name.is_some_and(is_object_output_option) {
+                    continue;
+                }
+                match name.filter(|name| INCLUDE_PATH_OPTIONS.contains(name)) {
+                    // Without the prefix, so that joined and separate values match.
+                    Some(name) => {
+                        key.push_str(name.as_bytes());
+                        key.push_str(normalize_include_path(second).as_encoded_bytes());
+                    }
+                    None => {
+                        key.push_str(first.as_encoded_bytes());
+                        key.push_str(second.as_encoded_bytes());
+                    }
+                }
+            }
+        }
+        key.push(0);
+    }
+    // Arguments after `/link` only affect linking.
+    Ok(())
+}
+
+/// Adds all arguments that affect the output of preprocessing the source.
+pub fn add_preprocess_args_to_key(args: &[impl AsRef<OsStr>], key: &mut BString) -> Result<()> {
+    let args = MsvcArgsInfo::from_args(args)?;
+    for arg in args.args.iter() {
+        match arg {
+            MsvcArg::Source { marker, .. } => {
+                if let Some(marker) = marker {
+                    key.push_str(marker.as_encoded_bytes());
+                }
+            }
+            MsvcArg::Single(_) | MsvcArg::Dual(_, _)
+                if arg
+                    .option_name()
+                    .is_some_and(|name| name == "c" || is_object_output_option(name)) => {}
+            MsvcArg::Single(arg) => key.push_str(arg.as_encoded_bytes()),
+            MsvcArg::Dual(first, second) => {
+                key.push_str(first.as_encoded_bytes());
+                key.push_str(b" ");
+                key.push_str(second.as_encoded_bytes());
+            }
+        }
+        // Separate arguments so that different splits don't result in the same key.
+        key.push(0);
+    }
+    Ok(())
+}
+
+/// Adds all arguments of a link except for the output file.
+pub fn add_link_args_to_key(args: &[impl AsRef<OsStr>], key: &mut BString) -> Result<()> {
+    let args = MsvcArgsInfo::from_args(args)?;
+    for arg in args.args.iter() {
+        match arg {
+            MsvcArg::Single(_) | MsvcArg::Dual(_, _)
+                if arg.option_name().is_some_and(is_link_output_option) =>
+            {
+                continue;
+            }
+            MsvcArg::Single(arg)
+            | MsvcArg::Source {
+                marker: None,
+                path: arg,
+            } => {
+                key.push_str(arg.as_encoded_bytes());
+            }
+            MsvcArg::Dual(first, second)
+            | MsvcArg::Source {
+                marker: Some(first),
+                path: second,
+            } => {
+                key.push_str(first.as_encoded_bytes());
+                key.push_str(b" ");
+                key.push_str(second.as_encoded_bytes());
+            }
+        }
+        key.push(0);
+    }
+    for arg in &args.linker_args {
+        key.push_str(arg.as_encoded_bytes());
+        key.push(0);
+    }
+    Ok(())
+}
+
+/// Returns the module definition files passed to the linker with `/DEF:foo.def`. Like version
+/// scripts, they control which symbols are exported.
+pub fn get_linker_script_paths(cwd: &Path, args: &[impl AsRef<OsStr>]) -> Result<Vec<PathBuf>> {
+    let args = MsvcArgsInfo::from_args(args)?;
+    Ok(args
+        .linker_args
+        .iter()
+        .filter_map(|arg| {
+            let (option, path) = option_name(arg.to_str()?)?.split_once(':')?;
+            option
+                .eq_ignore_ascii_case("DEF")
+                .then(|| make_absolute(cwd, Path::new(path)))
+        })
+        .collect())
+}
+
+/// clang-cl accepts options with a `/` or a `-` prefix. This returns the option without it.
+fn option_name(arg: &str) -> Option<&str> {
+    arg.strip_prefix('/')
+        .or_else(|| arg.strip_prefix('-'))
+        .filter(|name| !name.is_empty())
+}
+
+fn is_object_output_option(name: &str) -> bool {
+    name.starts_with("Fo")
+}
+
+fn is_link_output_option(name: &str) -> bool {
+    name.starts_with("Fe")
+}
+
+const INCLUDE_PATH_OPTIONS: &[&str] = &["I", "imsvc", "external:I"];
+
+/// Splits options like `/Ifoo` into the option and the path.
+fn split_joined_include_arg(name: &str) -> Option<(&'static str, &OsStr)> {
+    ["external:I", "imsvc", "I"].into_iter().find_map(|option| {
+        name.strip_prefix(option)
+            .filter(|path| !path.is_empty())
+            .map(|path| (option, OsStr::new(path)))
+    })
+}
+
+/// Returns the archive of a `/WHOLEARCHIVE:foo.lib` linker argument. A `/WHOLEARCHIVE` without
+/// a library applies to all of them and is kept as is.
+fn whole_archive_linker_arg(arg: &OsStr) -> Option<&OsStr> {
+    let name = option_name(arg.to_str()?)?;
+    let (option, library) = name.split_once(':')?;
+    option
+        .eq_ignore_ascii_case("WHOLEARCHIVE")
+        .then_some(OsStr::new(library))
+}
+
+/// Options that take the following argument as their value when it's not joined.
+const DUAL_OPTIONS: &[&str] = &[
+    "I",
+    "imsvc",
+    "external:I",
+    "D",
+    "U",
+    "FI",
+    "Fo:",
+    "Fe:",
+    "Tc",
+    "Tp",
+    "Xclang",
+    "mllvm",
+    "target",
+];
+
+#[derive(Clone)]
+enum MsvcArg<'a> {
+    Single(&'a OsStr),
+    Dual(&'a OsStr, &'a OsStr),
+    // `/Tc` and `/Tp` mark the following source as C or C++ code regardless of its extension.
+    Source {
+        marker: Option<&'a OsStr>,
+        path: &'a OsStr,
+    },
+}
+
+impl MsvcArg<'_> {
+    fn option_name(&self) -> Option<&str> {
+        match self {
+            MsvcArg::Single(arg) | MsvcArg::Dual(arg, _) => option_name(arg.to_str()?),
+            MsvcArg::Source { .. } => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct MsvcArgsInfo<'a> {
+    args: SmallVec<[MsvcArg<'a>; 32]>,
+    // Arguments after `/link` are passed to the linker as is.
+    linker_args: Vec<&'a OsStr>,
+    // Added linker arguments that don't refer to the original arguments.
+    owned_linker_args: Vec<OsString>,
+    // Added options that don't refer to the original arguments.
+    owned_options: Vec<OsString>,
+}
+
+struct SourceArgWithLanguage<'a> {
+    path: &'a Path,
+    language: Option<CodeLanguage>,
+}
+
+impl<'a> MsvcArgsInfo<'a> {
+    fn from_args<S: AsRef<OsStr> + 'a>(args: &'a [S]) -> Result<MsvcArgsInfo<'a>> {
+        let mut result = Self {
+            args: SmallVec::with_capacity(args.len()),
+            linker_args: vec![],
+            owned_linker_args: vec![],
+            owned_options: vec![],
+        };
+        let mut args_iter = args.iter();
+        let mut only_sources = false;
+        while let Some(arg) = args_iter.next() {
+            let arg = arg.as_ref();
+            let Some((arg_str, name)) = arg
+                .to_str()
+                .and_then(|arg_str| Some((arg_str, option_name(arg_str)?)))
+                .filter(|_| !only_sources)
+            else {
+                result.args.push(MsvcArg::Source {
+                    marker: None,
+                    path: arg,
+                });
+                continue;
+            };
+            if arg_str == "--" {
+                // Absolute paths start with `/` too, so they can only be passed after `--`.
+                only_sources = true;
+            } else if name == "link" {
+                result
+                    .linker_args
+                    .extend(args_iter.by_ref().map(|a| a.as_ref()));
+            } else if DUAL_OPTIONS.contains(&name) {
+                let next = args_iter
+                    .next()
+                    .ok_or_else(|| anyhow!("argument after {:?} is missing", arg))?
+                    .as_ref();
+                if name == "Tc" || name == "Tp" {
+                    result.args.push(MsvcArg::Source {
+                        marker: Some(arg),
+                        path: next,
+                    });
+                } else {
+                    result.args.push(MsvcArg::Dual(arg, next));
+                }
+            } else if (name.starts_with("Tc") || name.starts_with("Tp")) && name.len() > 2 {
+                // Split joined sources like `/Tcfoo.c`, the prefix is ASCII.
+                result.args.push(MsvcArg::Source {
+                    marker: Some(OsStr::new(&arg_str[..3])),
+                    path: OsStr::new(&arg_str[3..]),
+                });
+            } else {
+                result.args.push(MsvcArg::Single(arg));
+            }
+        }
+        Ok(result)
+    }
+
+    /// Options come first, then the sources and then the linker arguments. Unlike with gcc, the
+    /// order of options and sources does not matter.
+    fn to_args_owned_vec(&self) -> Vec<OsString> {
+        let mut result = vec![];
+        let mut plain_sources = vec![];
+        for arg in &self.args {
+            match arg {
+                MsvcArg::Single(arg) => result.push((*arg).to_owned()),
+                MsvcArg::Dual(first, second) => {
+                    result.push((*first).to_owned());
+                    result.push((*second).to_owned());
+                }
+                MsvcArg::Source {
+                    marker: Some(marker),
+                    path,
+                } => {
+                    result.push((*marker).to_owned());
+                    result.push((*path).to_owned());
+                }
+                MsvcArg::Source { marker: None, path } => plain_sources.push((*path).to_owned()),
+            }
+        }
+        result.extend(self.owned_options.iter().cloned());
+        if plain_sources
+            .iter()
+            .any(|source| source.as_encoded_bytes().starts_with(b"/"))
+        {
+            result.push("--".into());
+        }
+        result.extend(plain_sources);
+        if !self.linker_args.is_empty() || !self.owned_linker_args.is_empty() {
+            result.push("/link".into());
+            result.extend(self.linker_args.iter().map(|arg| (*arg).to_owned()));
+            result.extend(self.owned_linker_args.iter().cloned());
+        }
+        result
+    }
+
+    fn push_option(&mut self, option: &'a str) {
+        self.args.push(MsvcArg::Single(OsStr::new(option)));
+    }
+
+    fn push_owned_option(&mut self, option: OsString) {
+        self.owned_options.push(option);
+    }
+
+    /// Code is added with an explicit language so that it's not affected by `/TC` or `/TP`.
+    fn push_source_with_language(&mut self, path: &'a Path) {
+        let marker = match CodeLanguage::from_known_path(path) {
+            Some(CodeLanguage::C) => Some(OsStr::new("/Tc")),
+            Some(CodeLanguage::Cxx) => Some(OsStr::new("/Tp")),
+            _ => None,
+        };
+        self.args.push(MsvcArg::Source {
+            marker,
+            path: path.as_os_str(),
+        });
+    }
+
+    fn push_link_source(&mut self, source: &'a SourceFile) {
+        let marker = match source.language_override {
+            Some(CodeLanguage::C) => Some(OsStr::new("/Tc")),
+            Some(CodeLanguage::Cxx) => Some(OsStr::new("/Tp")),
+            _ => None,
+        };
+        self.args.push(MsvcArg::Source {
+            marker,
+            path: source.path.as_os_str(),
+        });
+    }
+
+    fn has_option(&self, query: &str) -> bool {
+        self.args.iter().any(|arg| match arg {
+            MsvcArg::Single(_) => arg.option_name() == Some(query),
+            _ => false,
+        })
+    }
+
+    /// Returns the value of the last of the given options, which may be joined like `/Fofoo.obj`
+    /// or separate like `/Fo: foo.obj`. Options are checked in the given order, so longer ones
+    /// have to come first.
+    fn get_value(&self, names: &[&str]) -> Option<&'a OsStr> {
+        let mut value = None;
+        for arg in &self.args {
+            match arg {
+                MsvcArg::Dual(first, second) => {
+                    if first
+                        .to_str()
+                        .and_then(option_name)
+                        .is_some_and(|name| names.contains(&name))
+                    {
+                        value = Some(*second);
+                    }
+                }
+                MsvcArg::Single(arg) => {
+                    let Some(name) = arg.to_str().and_then(option_name) else {
+                        continue;
+                    };
+                    if let Some(joined) = names.iter().find_map(|option| {
+                        name.strip_prefix(option).filter(|value| !value.is_empty())
+                    }) {
+                        value = Some(OsStr::new(joined));
+                    }
+                }
+                MsvcArg::Source { .. } => {}
+            }
+        }
+        value
+    }
+
+    /// `/E` and `/EP` write the preprocessed code to stdout.
+    fn writes_output_to_stdout(&self) -> bool {
+        self.has_option("E") || self.has_option("EP")
+    }
+
+    fn is_query(&self) -> bool {
+        ["-version", "###", "?", "help"]
+            .iter()
+            .any(|query| self.has_option(query))
+    }
+
+    /// `/TC` and `/TP` set the language of all sources without `/Tc` or `/Tp`. Objects and
+    /// archives are not affected.
+    fn get_sources(&self) -> SmallVec<[SourceArgWithLanguage<'a>; 16]> {
+        let all_language = if self.has_option("TC") {
+            Some(CodeLanguage::C)
+        } else if self.has_option("TP") {
+            Some(CodeLanguage::Cxx)
+        } else {
+            None
+        };
+        let mut sources = smallvec![];
+        for arg in &self.args {
+            let MsvcArg::Source { marker, path } = arg else {
+                continue;
+            };
+            let path = Path::new(*path);
+            let language = match marker.and_then(|marker| option_name(marker.to_str()?)) {
+                Some("Tc") => Some(CodeLanguage::C),
+                Some("Tp") => Some(CodeLanguage::Cxx),
+                _ if is_link_input(path) => None,
+                _ => all_language,
+            };
+            sources.push(SourceArgWithLanguage { path, language });
+        }
+        sources
+    }
+
+    fn get_absolute_sources(&self, cwd: &Path) -> SmallVec<[SourceFile; 16]> {
+        self.get_sources()
+            .iter()
+            .map(|s| SourceFile {
+                path: make_absolute(cwd, s.path),
+                language_override: s.language,
+            })
+            .collect()
+    }
+
+    /// Archives passed to the linker with `/WHOLEARCHIVE:foo.lib`, or all archives if it's
+    /// passed without a library.
+    fn get_absolute_whole_archive_sources(&self, cwd: &Path) -> HashSet<PathBuf> {
+        let mut sources = HashSet::new();
+        for arg in &self.linker_args {
+            if let Some(library) = whole_archive_linker_arg(arg) {
+                sources.insert(make_absolute(cwd, Path::new(library)));
+            } else if arg
+                .to_str()
+                .and_then(option_name)
+                .is_some_and(|name| name.eq_ignore_ascii_case("WHOLEARCHIVE"))
+            {
+                sources.extend(
+                    self.get_sources()
+                        .iter()
+                        .filter(|source| source.path.extension() == Some(OsStr::new("lib")))
+                        .map(|source| make_absolute(cwd, source.path)),
+                );
+            }
+        }
+        sources
+    }
+
+    /// Like the compiler, this names the object like the source if `/Fo` is not given or if it's
+    /// a directory.
+    fn get_object_output(&self, source: &Path) -> Option<PathBuf> {
+        let mut default_name = source.file_stem()?.to_owned();
+        default_name.push(".obj");
+        Some(match self.get_value(&["Fo:", "Fo"]) {
+            Some(output) if is_directory_arg(output) => Path::new(output).join(default_name),
+            Some(output) if Path::new(output).extension().is_none() => {
+                Path::new(output).with_extension("obj")
+            }
+            Some(output) => PathBuf::from(output),
+            None => PathBuf::from(default_name),
+        })
+    }
+
+    /// Like the compiler, this names the output like the first source if `/Fe` is not given or
+    /// if it's a directory. `/LD` creates a DLL instead of an executable.
+    fn get_link_output(&self) -> Option<PathBuf> {
+        let extension = if self.has_option("LD") || self.has_option("LDd") {
+            "dll"
+        } else {
+            "exe"
+        };
+        let default_name = || -> Option<PathBuf> {
+            let sources = self.get_sources();
+            let first_source = sources.first()?;
+            Some(Path::new(first_source.path.file_name()?).with_extension(extension))
+        };
+        match self.get_value(&["Fe:", "Fe"]) {
+            Some(output) if is_directory_arg(output) => {
+                Some(Path::new(output).join(default_name()?))
+            }
+            Some(output) if Path::new(output).extension().is_none() => {
+                Some(Path::new(output).with_extension(extension))
+            }
+            Some(output) => Some(PathBuf::from(output)),
+            None => default_name(),
+        }
+    }
+
+    /// Defines may have a value after `=` or `#`, e.g. `/DFOO=1` or `/DFOO#1`.
+    fn get_defines(&self) -> Vec<BString> {
+        self.args
+            .iter()
+            .filter_map(|arg| match arg {
+                MsvcArg::Dual(_, second) if arg.option_name() == Some("D") => Some(*second),
+                MsvcArg::Single(_) => arg
+                    .option_name()?
+                    .strip_prefix('D')
+                    .filter(|define| !define.is_empty())
+                    .map(OsStr::new),
+                _ => None,
+            })
+            .map(|define| {
+                let define = define.as_encoded_bytes();
+                let name = define
+                    .split(|c| *c == b'=' || *c == b'#')
+                    .next()
+                    .unwrap_or_default();
+                BString::from(name)
+            })
+            .collect()
+    }
+
+    /// `/GL` enables whole program optimization, which is link time optimization.
+    fn uses_lto(&self) -> bool {
+        let mut uses_lto = false;
+        for arg in &self.args {
+            match arg.option_name() {
+                Some("GL") | Some("flto") => uses_lto = true,
+                Some(name) if name.starts_with("flto=") => uses_lto = true,
+                Some("GL-") | Some("fno-lto") => uses_lto = false,
+                _ => {}
+            }
+        }
+        uses_lto
+    }
+
+    /// `/Yc` creates and `/Yu` uses a precompiled header.
+    fn uses_pch(&self) -> bool {
+        self.args.iter().any(|arg| {
+            matches!(arg, MsvcArg::Single(_))
+                && arg
+                    .option_name()
+                    .is_some_and(|name| name.starts_with("Yc") || name.starts_with("Yu"))
+        })
+    }
+}
+
+/// Outputs ending with a path separator name a directory.
+fn is_directory_arg(arg: &OsStr) -> bool {
+    arg.as_encoded_bytes()
+        .last()
+        .is_some_and(|c| *c == b'/' || *c == b'\\')
+}
+
+fn is_link_input(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ["obj", "o", "lib", "a", "res", "def"].contains(&ext))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::OsString,
+        path::{Path, PathBuf},
+    };
+
+    use bstr::BString;
+
+    use super::*;
+
+    fn strings(args: &[OsString]) -> Vec<String> {
+        args.iter()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn source(path: &str) -> SourceFile {
+        SourceFile {
+            path: PathBuf::from(path),
+            language_override: None,
+        }
+    }
+
+    #[test]
+    fn compile_args_are_parsed() {
+        let cwd = Path::new("/project");
+        for args in [
+            &["/c", "/Fobar.obj", "/Iinc", "foo.cpp"][..],
+            &["-c", "/Fo:", "bar.obj", "/I", "inc", "foo.cpp"],
+        ] {
+            assert!(is_build_object_file(args).expect("args can be parsed"));
+            let info = BuildObjectFileInfo::from_msvc_args(cwd, args).expect("args can be parsed");
+            assert_eq!(info.source_path, Path::new("/project/foo.cpp"));
+            assert_eq!(info.source_language, CodeLanguage::Cxx);
+            assert_eq!(info.object_path, Path::new("/project/bar.obj"));
+            assert!(info.depfile.is_none());
+
+            let files = BuildFilesInfo::from_msvc_args(cwd, args).expect("args can be parsed");
+            assert_eq!(
+                files.sources.iter().map(|s| &s.path).collect::<Vec<_>>(),
+                [Path::new("/project/foo.cpp")]
+            );
+            assert_eq!(files.output, Some(PathBuf::from("/project/bar.obj")));
+            assert!(!files.is_query);
+            assert!(!files.output_to_stdout);
+        }
+    }
+
+    #[test]
+    fn object_output_defaults_to_source_name() {
+        let info =
+            BuildObjectFileInfo::from_msvc_args(Path::new("/project"), &["/c", "/TP", "src/foo.c"])
+                .expect("args can be parsed");
+        assert_eq!(info.source_language, CodeLanguage::Cxx);
+        assert_eq!(info.object_path, Path::new("/project/foo.obj"));
+    }
+
+    #[test]
+    fn link_args_are_parsed() {
+        let args = [
+            "main.obj",
+            "util.lib",
+            "/Feapp.exe",
+            "/link",
+            "/WHOLEARCHIVE:util.lib",
+        ];
+        assert!(!is_build_object_file(&args).expect("args can be parsed"));
+        let info =
+            LinkFileInfo::from_msvc_args(Path::new("/project"), &args).expect("args can be parsed");
+        assert_eq!(
+            info.sources.iter().map(|s| &s.path).collect::<Vec<_>>(),
+            [
+                Path::new("/project/main.obj"),
+                Path::new("/project/util.lib")
+            ]
+        );
+        assert_eq!(info.output, Path::new("/project/app.exe"));
+        assert!(
+            info.whole_archive_sources
+                .contains(Path::new("/project/util.lib"))
+        );
+        assert!(!info.is_shared_library);
+    }
+
+    #[test]
+    fn multiple_sources_are_split() {
+        let args = ["/c", "/Iinc", "a.c", "/Tpb.c"];
+        let single_args = split_into_single_source_args(&args).expect("args can be split");
+        assert_eq!(
+            single_args
+                .iter()
+                .map(|args| strings(args))
+                .collect::<Vec<_>>(),
+            [
+                vec!["/c", "/Iinc", "a.c"],
+                vec!["/c", "/Iinc", "/Tp", "b.c"]
+            ]
+        );
+    }
+
+    #[test]
+    fn local_code_is_extracted_to_stdout() {
+        let args = ["/c", "/showIncludes", "/Fobar.obj", "/Iinc", "foo.cpp"];
+        assert_eq!(
+            strings(&rewrite_to_extract_local_code(&args).expect("args can be rewritten")),
+            ["/Iinc", "/E", "/clang:-dD", "foo.cpp"]
+        );
+    }
+
+    #[test]
+    fn headers_are_preprocessed_into_file() {
+        let args = ["/c", "/TP", "/Fo:", "bar.obj", "/DFOO", "foo.c"];
+        let rewritten = rewrite_to_get_preprocessed_headers(
+            &args,
+            Path::new("/tmp/headers.cc"),
+            Path::new("/tmp/headers.ii"),
+        )
+        .expect("args can be rewritten");
+        assert_eq!(
+            strings(&rewritten),
+            [
+                "/DFOO",
+                "/P",
+                "/Tp",
+                "/tmp/headers.cc",
+                "/Fi/tmp/headers.ii"
+            ]
+        );
+    }
+
+    #[test]
+    fn chunk_is_built_from_input_file() {
+        let args = ["/c", "/TC", "/showIncludes", "/Fobar.obj", "/w", "foo.c"];
+        assert!(suppresses_warnings(&args).expect("args can be parsed"));
+        assert!(prints_included_files(&args).expect("args can be parsed"));
+        let rewritten = update_to_build_object_from_stdin(
+            &args,
+            Path::new("/tmp/chunk.i"),
+            Path::new("/tmp/chunk.o"),
+        )
+        .expect("args can be rewritten");
+        // Absolute paths look like options, so they are passed after `--`.
+        assert_eq!(
+            strings(&rewritten),
+            ["/c", "/w", "/Fo/tmp/chunk.o", "--", "/tmp/chunk.i"]
+        );
+        assert!(!prints_included_files(&rewritten).expect("args can be parsed"));
+    }
+
+    #[test]
+    fn link_sources_are_replaced() {
+        let args = [
+            "main.obj",
+            "util.lib",
+            "/Feapp.exe",
+            "/link",
+            "/WHOLEARCHIVE:util.lib",
+            "/DEF:app.def",
+        ];
+        let rewritten = rewrite_to_link_sources(
+            &args,
+            &[vec![source("/tmp/chunks.a")], vec![]],
+            &[source("/tmp/util_chunks.a")],
+        )
+        .expect("args can be rewritten");
+        assert_eq!(
+            strings(&rewritten),
+            [
+                "/Feapp.exe",
+                "--",
+                "/tmp/chunks.a",
+                "/tmp/util_chunks.a",
+                "/link",
+                "/DEF:app.def",
+                "/WHOLEARCHIVE:/tmp/util_chunks.a"
+            ]
+        );
+        assert_eq!(
+            get_linker_script_paths(Path::new("/project"), &args).expect("args can be parsed"),
+            [PathBuf::from("/project/app.def")]
+        );
+    }
+
+    #[test]
+    fn keys_ignore_outputs() {
+        let compatibility_key = |args: &[&str]| {
+            let mut key = BString::default();
+            add_translation_unit_unspecific_args_to_key(
+                args,
+                Path::new("/project"),
+                false,
+                &mut key,
+            )
+            .expect("key can be created");
+            key
+        };
+        // Joined and separate include paths are the same.
+        assert_eq!(
+            compatibility_key(&["/c", "/Fofoo.obj", "/Iinc", "foo.c"]),
+            compatibility_key(&["/c", "/Fo:", "bar.obj", "/I", "inc", "bar.c"])
+        );
+        assert_ne!(
+            compatibility_key(&["/c", "/Iinc", "foo.c"]),
+            compatibility_key(&["/c", "/Iinc", "/DFOO", "foo.c"])
+        );
+
+        let preprocess_key = |args: &[&str]| {
+            let mut key = BString::default();
+            add_preprocess_args_to_key(args, &mut key).expect("key can be created");
+            key
+        };
+        assert_eq!(
+            preprocess_key(&["/c", "/Fofoo.obj", "/DFOO", "foo.c"]),
+            preprocess_key(&["/c", "/Fobar.obj", "/DFOO", "foo.c"])
+        );
+        assert_ne!(
+            preprocess_key(&["/c", "/DFOO", "foo.c"]),
+            preprocess_key(&["/c", "/DBAR", "foo.c"])
+        );
+
+        let link_key = |args: &[&str]| {
+            let mut key = BString::default();
+            add_link_args_to_key(args, &mut key).expect("key can be created");
+            key
+        };
+        assert_eq!(
+            link_key(&["main.obj", "/Feapp.exe"]),
+            link_key(&["main.obj", "/Feother.exe"])
+        );
+        assert_ne!(
+            link_key(&["main.obj", "/link", "/DEBUG"]),
+            link_key(&["main.obj"])
+        );
+    }
+}
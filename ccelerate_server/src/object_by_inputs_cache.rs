@@ -7,10 +7,14 @@ use std::{
 
 use anyhow::Result;
 
-use crate::compute_cache::ComputeCache;
+use crate::{compute_cache::ComputeCache, wrap_final_link::CompiledChunk};
 
 pub struct ObjectByInputsCache {
-    cache: ComputeCache<Vec<PathBuf>, chrono::DateTime<chrono::FixedOffset>, Arc<Result<PathBuf>>>,
+    cache: ComputeCache<
+        Vec<PathBuf>,
+        chrono::DateTime<chrono::FixedOffset>,
+        Arc<Result<CompiledChunk>>,
+    >,
 }
 
 impl ObjectByInputsCache {
@@ -25,10 +29,10 @@ impl ObjectByInputsCache {
         inputs: &[impl AsRef<Path>],
         time: chrono::DateTime<chrono::FixedOffset>,
         build_object: F,
-    ) -> Arc<Result<PathBuf>>
+    ) -> Arc<Result<CompiledChunk>>
     where
         F: FnOnce() -> Fut,
-        Fut: Future<Output = Result<PathBuf>>,
+        Fut: Future<Output = Result<CompiledChunk>>,
     {
         self.cache
             .get(
@@ -38,4 +42,11 @@ impl ObjectByInputsCache {
             )
             .await
     }
+
+    pub fn remove(&self, inputs: &[impl AsRef<Path>], time: chrono::DateTime<chrono::FixedOffset>) {
+        self.cache.remove(
+            &inputs.iter().map(|p| p.as_ref().to_owned()).collect(),
+            &time,
+        );
+    }
 }
@@ -0,0 +1,162 @@
+#![deny(clippy::unwrap_used)]
+
+use std::{
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use anyhow::anyhow;
+use ccelerate_shared::WrappedBinary;
+
+use crate::path_utils::make_absolute;
+
+/// Files that are modified by `objcopy` or `strip`.
+pub struct ObjectToolInfo {
+    pub files: Vec<ObjectToolFile>,
+}
+
+pub struct ObjectToolFile {
+    pub input: PathBuf,
+    // Same as the input when the file is modified in place.
+    pub output: PathBuf,
+    // Position of the input in the original arguments.
+    arg_index: usize,
+}
+
+const OBJCOPY_DUAL_ARGS: &[&str] = &[
+    "-I", "-O", "-B", "-F", "-j", "-R", "-K", "-N", "-G", "-L", "-W", "-b", "-i",
+];
+const STRIP_DUAL_ARGS: &[&str] = &["-F", "-I", "-O", "-R", "-K", "-N", "-o"];
+
+impl ObjectToolInfo {
+    pub fn from_args(
+        binary: WrappedBinary,
+        cwd: &Path,
+        args: &[impl AsRef<OsStr>],
+    ) -> Result<Self> {
+        let dual_args = match binary {
+            WrappedBinary::Objcopy => OBJCOPY_DUAL_ARGS,
+            WrappedBinary::Strip => STRIP_DUAL_ARGS,
+            _ => return Err(anyhow!("{} is not an object tool", binary)),
+        };
+        // Positional arguments with their index in the arguments.
+        let mut positional: Vec<(usize, &Path)> = vec![];
+        let mut explicit_output = None;
+        let mut args_iter = args.iter().map(|s| s.as_ref()).enumerate();
+        while let Some((index, arg)) = args_iter.next() {
+            if dual_args.iter().any(|dual_arg| arg == *dual_arg) {
+                let (_, next) = args_iter
+                    .next()
+                    .ok_or_else(|| anyhow!("argument after {:?} is missing", arg))?;
+                if arg == "-o" {
+                    explicit_output = Some(Path::new(next));
+                }
+            } else if arg.as_encoded_bytes().starts_with(b"@") {
+                return Err(anyhow!("Response files are not supported"));
+            } else if !arg.as_encoded_bytes().starts_with(b"-") {
+                positional.push((index, Path::new(arg)));
+            }
+        }
+        let files = match binary {
+            WrappedBinary::Objcopy => match positional.as_slice() {
+                [(index, input)] => vec![ObjectToolFile::new(cwd, *index, input, input)],
+                [(index, input), (_, output)] => {
+                    vec![ObjectToolFile::new(cwd, *index, input, output)]
+                }
+                _ => return Err(anyhow!("Expected an input and optional output file")),
+            },
+            _ => match (positional.as_slice(), explicit_output) {
+                ([(index, input)], Some(output)) => {
+                    vec![ObjectToolFile::new(cwd, *index, input, output)]
+                }
+                (_, Some(_)) => return Err(anyhow!("-o requires exactly one input file")),
+                (inputs, None) => inputs
+                    .iter()
+                    .map(|(index, input)| ObjectToolFile::new(cwd, *index, input, input))
+                    .collect(),
+            },
+        };
+        Ok(Self { files })
+    }
+}
+
+impl ObjectToolFile {
+    fn new(cwd: &Path, arg_index: usize, input: &Path, output: &Path) -> Self {
+        Self {
+            input: make_absolute(cwd, input),
+            output: make_absolute(cwd, output),
+            arg_index,
+        }
+    }
+}
+
+/// Removes the inputs of the given files from the arguments so that the tool only processes
+/// the remaining files. This only works when the files are modified in place.
+pub fn remove_files_from_args<'a>(
+    args: &[impl AsRef<OsStr>],
+    files: impl IntoIterator<Item = &'a ObjectToolFile>,
+) -> Vec<OsString> {
+    let removed_indices = files.into_iter().map(|f| f.arg_index).collect::<Vec<_>>();
+    args.iter()
+        .enumerate()
+        .filter(|(i, _)| !removed_indices.contains(i))
+        .map(|(_, arg)| arg.as_ref().to_owned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn files(binary: WrappedBinary, args: &[&str]) -> Vec<(PathBuf, PathBuf)> {
+        ObjectToolInfo::from_args(binary, Path::new("/project"), args)
+            .expect("args can be parsed")
+            .files
+            .into_iter()
+            .map(|file| (file.input, file.output))
+            .collect()
+    }
+
+    #[test]
+    fn objcopy_files() {
+        assert_eq!(
+            files(WrappedBinary::Objcopy, &["-O", "binary", "a.o"]),
+            [("/project/a.o".into(), "/project/a.o".into())]
+        );
+        assert_eq!(
+            files(WrappedBinary::Objcopy, &["-g", "a.o", "/out/b.o"]),
+            [("/project/a.o".into(), "/out/b.o".into())]
+        );
+        assert!(
+            ObjectToolInfo::from_args(WrappedBinary::Objcopy, Path::new("/"), &["a", "b", "c"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn strip_files() {
+        assert_eq!(
+            files(WrappedBinary::Strip, &["-R", ".comment", "a.o", "b.o"]),
+            [
+                ("/project/a.o".into(), "/project/a.o".into()),
+                ("/project/b.o".into(), "/project/b.o".into())
+            ]
+        );
+        assert_eq!(
+            files(WrappedBinary::Strip, &["-o", "b.o", "a.o"]),
+            [("/project/a.o".into(), "/project/b.o".into())]
+        );
+    }
+
+    #[test]
+    fn placeholder_inputs_are_removed_from_args() {
+        let args = ["--strip-debug", "a.o", "real.o"];
+        let info = ObjectToolInfo::from_args(WrappedBinary::Strip, Path::new("/project"), &args)
+            .expect("args can be parsed");
+        assert_eq!(
+            remove_files_from_args(&args, &info.files[..1]),
+            ["--strip-debug", "real.o"]
+        );
+    }
+}
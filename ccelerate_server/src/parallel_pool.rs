@@ -1,29 +1,212 @@
 #![deny(clippy::unwrap_used)]
 
-use std::sync::Arc;
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+};
 
+use parking_lot::Mutex;
 use tokio::task::JoinHandle;
 
+use crate::request_id;
+
+/// Tasks get permits in the order in which they started waiting for them, because the
+/// semaphore is fair.
 pub struct ParallelPool {
+    limits: Arc<PoolLimits>,
+    in_use: Arc<AtomicUsize>,
+    waiting: AtomicUsize,
+}
+
+/// All changes of the limit happen while `state` is locked, so that permits that are added and
+/// removed concurrently can't get mixed up.
+struct PoolLimits {
     semaphore: Arc<tokio::sync::Semaphore>,
+    max_num: usize,
+    state: Mutex<PoolLimitsState>,
+}
+
+struct PoolLimitsState {
+    // Number of tasks that may run at the same time, at most `max_num`.
+    limit: usize,
+    // Limits of all requests that are currently handled. The smallest one is used.
+    requested: Vec<usize>,
+    // Permits that were in use when the limit was lowered. They are removed from the semaphore
+    // once they are released.
+    permits_to_remove: usize,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct PoolStats {
+    // Number of tasks that may run in parallel, which may be lowered by make's job count.
+    pub limit: usize,
+    pub in_use: usize,
+    pub available: usize,
+    pub waiting: usize,
+}
+
+/// Keeps the number of used permits up to date.
+struct PoolPermit {
+    permit: Option<tokio::sync::OwnedSemaphorePermit>,
+    limits: Arc<PoolLimits>,
+    in_use: Arc<AtomicUsize>,
+}
+
+impl Drop for PoolPermit {
+    fn drop(&mut self) {
+        self.in_use.fetch_sub(1, Ordering::Relaxed);
+        if let Some(permit) = self.permit.take() {
+            self.limits.release(permit);
+        }
+    }
+}
+
+/// Lowers the limit of the pool until it is dropped.
+pub struct PoolLimitScope {
+    limits: Arc<PoolLimits>,
+    limit: usize,
+}
+
+impl Drop for PoolLimitScope {
+    fn drop(&mut self) {
+        let mut state = self.limits.state.lock();
+        if let Some(index) = state.requested.iter().position(|l| *l == self.limit) {
+            state.requested.swap_remove(index);
+        }
+        self.limits.update_limit(&mut state);
+    }
+}
+
+impl PoolLimits {
+    fn release(&self, permit: tokio::sync::OwnedSemaphorePermit) {
+        let mut state = self.state.lock();
+        if state.permits_to_remove > 0 {
+            state.permits_to_remove -= 1;
+            permit.forget();
+        }
+    }
+
+    fn update_limit(&self, state: &mut PoolLimitsState) {
+        let limit = state
+            .requested
+            .iter()
+            .min()
+            .copied()
+            .unwrap_or(self.max_num)
+            .clamp(1, self.max_num);
+        if limit < state.limit {
+            let to_remove = state.limit - limit;
+            // Permits that are in use are removed once they are released.
+            let removed = self.semaphore.forget_permits(to_remove);
+            state.permits_to_remove += to_remove - removed;
+        } else if limit > state.limit {
+            let to_add = limit - state.limit;
+            let not_removed = to_add.min(state.permits_to_remove);
+            state.permits_to_remove -= not_removed;
+            self.semaphore.add_permits(to_add - not_removed);
+        }
+        state.limit = limit;
+    }
 }
 
 impl ParallelPool {
     pub fn new(num: usize) -> Self {
         Self {
-            semaphore: Arc::new(tokio::sync::Semaphore::new(num)),
+            limits: Arc::new(PoolLimits {
+                semaphore: Arc::new(tokio::sync::Semaphore::new(num)),
+                max_num: num,
+                state: Mutex::new(PoolLimitsState {
+                    limit: num,
+                    requested: Vec::new(),
+                    permits_to_remove: 0,
+                }),
+            }),
+            in_use: Arc::new(AtomicUsize::new(0)),
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let limit = self.limit();
+        let in_use = self.in_use.load(Ordering::Relaxed);
+        PoolStats {
+            limit,
+            in_use,
+            // More permits may be in use than the limit allows right after lowering it.
+            available: limit.saturating_sub(in_use),
+            waiting: self.waiting.load(Ordering::Relaxed),
+        }
+    }
+
+    fn make_permit(&self, permit: tokio::sync::OwnedSemaphorePermit) -> PoolPermit {
+        self.in_use.fetch_add(1, Ordering::Relaxed);
+        PoolPermit {
+            permit: Some(permit),
+            limits: self.limits.clone(),
+            in_use: self.in_use.clone(),
+        }
+    }
+
+    async fn acquire(&self) -> PoolPermit {
+        self.waiting.fetch_add(1, Ordering::Relaxed);
+        let permit = self
+            .limits
+            .semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("should always succeed eventually");
+        self.waiting.fetch_sub(1, Ordering::Relaxed);
+        self.make_permit(permit)
+    }
+
+    /// Lowers how many tasks may run at the same time while the returned scope is alive, but
+    /// never below one. When multiple scopes are alive, the smallest limit is used. Once all
+    /// are dropped, the pool uses the number of tasks it was created with again. Running tasks
+    /// are not interrupted when the limit is lowered.
+    pub fn limit_scope(&self, limit: usize) -> PoolLimitScope {
+        let mut state = self.limits.state.lock();
+        state.requested.push(limit);
+        self.limits.update_limit(&mut state);
+        PoolLimitScope {
+            limits: self.limits.clone(),
+            limit,
         }
     }
 
-    pub fn run_spawned<F, Fut, Out>(&self, f: F) -> JoinHandle<Out>
+    pub fn limit(&self) -> usize {
+        self.limits.state.lock().limit
+    }
+
+    /// Like [`Self::run_spawned`] but only if the task can start right away. Otherwise, the
+    /// task is given back.
+    pub fn try_run_spawned<F, Fut, Out>(&self, f: F) -> Result<JoinHandle<Out>, F>
     where
         F: FnOnce() -> Fut + Send + 'static,
         Fut: Future<Output = Out> + Send + 'static,
         Out: Send + 'static,
     {
-        let permit = self.semaphore.clone().acquire_owned();
-        tokio::task::spawn(async move {
-            let _permit = permit.await.expect("should be valid");
+        let Ok(permit) = self.limits.semaphore.clone().try_acquire_owned() else {
+            return Err(f);
+        };
+        let permit = self.make_permit(permit);
+        Ok(request_id::spawn(async move {
+            let _permit = permit;
+            f().await
+        }))
+    }
+
+    /// Waits for a permit before spawning the task. Waiting inside of the spawned task would
+    /// make the order depend on when the runtime first polls it.
+    pub async fn run_spawned<F, Fut, Out>(&self, f: F) -> JoinHandle<Out>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Out> + Send + 'static,
+        Out: Send + 'static,
+    {
+        let permit = self.acquire().await;
+        request_id::spawn(async move {
+            let _permit = permit;
             f().await
         })
     }
@@ -33,11 +216,156 @@ impl ParallelPool {
         F: FnOnce() -> Fut,
         Fut: Future<Output = Out>,
     {
-        let _permit = self
-            .semaphore
-            .acquire()
-            .await
-            .expect("should always succeed eventually");
+        let _permit = self.acquire().await;
         f().await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Runs more tasks than the pool allows and returns how many ran at the same time.
+    async fn max_concurrent_tasks(pool: &ParallelPool) -> usize {
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let mut handles = vec![];
+        for _ in 0..8 {
+            let running = running.clone();
+            let max_running = max_running.clone();
+            handles.push(
+                pool.run_spawned(async move || {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                })
+                .await,
+            );
+        }
+        for handle in handles {
+            handle.await.expect("task succeeds");
+        }
+        max_running.load(Ordering::SeqCst)
+    }
+
+    #[tokio::test]
+    async fn job_count_limits_concurrency() {
+        let pool = ParallelPool::new(4);
+        assert_eq!(max_concurrent_tasks(&pool).await, 4);
+        {
+            let _scope = pool.limit_scope(2);
+            let _other_scope = pool.limit_scope(3);
+            assert_eq!(pool.limit(), 2);
+            assert_eq!(max_concurrent_tasks(&pool).await, 2);
+        }
+        // The limit only applies while a scope is alive.
+        assert_eq!(pool.limit(), 4);
+        assert_eq!(max_concurrent_tasks(&pool).await, 4);
+        // More jobs than the pool was created with don't raise the limit.
+        let _scope = pool.limit_scope(16);
+        assert_eq!(pool.limit(), 4);
+    }
+
+    #[tokio::test]
+    async fn permits_in_use_are_not_lost_when_the_limit_changes() {
+        let pool = ParallelPool::new(4);
+        let (release, released) = tokio::sync::watch::channel(false);
+        let mut handles = vec![];
+        for _ in 0..3 {
+            let mut released = released.clone();
+            handles.push(
+                pool.run_spawned(async move || {
+                    let _ = released.wait_for(|released| *released).await;
+                })
+                .await,
+            );
+        }
+        // Two of the removed permits are still in use.
+        let scope = pool.limit_scope(1);
+        assert_eq!(pool.stats().available, 0);
+        drop(scope);
+        let scope = pool.limit_scope(2);
+        drop(scope);
+        release.send(true).expect("tasks are waiting");
+        for handle in handles {
+            handle.await.expect("task succeeds");
+        }
+        assert_eq!(pool.limit(), 4);
+        assert_eq!(max_concurrent_tasks(&pool).await, 4);
+        let permits = (0..4)
+            .map(|_| pool.limits.semaphore.clone().try_acquire_owned())
+            .collect::<Vec<_>>();
+        assert!(permits.iter().all(|permit| permit.is_ok()));
+        assert!(pool.limits.semaphore.clone().try_acquire_owned().is_err());
+    }
+
+    #[tokio::test]
+    async fn bounded_pool_never_exceeds_jobs() {
+        let pool = Arc::new(ParallelPool::new(3));
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let mut handles = vec![];
+        for i in 0..32 {
+            let pool = pool.clone();
+            let running = running.clone();
+            let max_running = max_running.clone();
+            handles.push(tokio::spawn(async move {
+                let body_pool = pool.clone();
+                let body = async move || {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now, Ordering::SeqCst);
+                    assert!(body_pool.stats().in_use <= 3);
+                    tokio::time::sleep(Duration::from_millis(i % 4)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                };
+                if i % 2 == 0 {
+                    pool.run_local(body).await;
+                } else {
+                    pool.run_spawned(body).await.await.expect("task succeeds");
+                }
+            }));
+        }
+        for handle in handles {
+            handle.await.expect("task succeeds");
+        }
+        assert_eq!(max_running.load(Ordering::SeqCst), 3);
+        let stats = pool.stats();
+        assert_eq!((stats.in_use, stats.available, stats.waiting), (0, 3, 0));
+    }
+
+    #[tokio::test]
+    async fn permits_are_acquired_in_order() {
+        let pool = Arc::new(ParallelPool::new(1));
+        let (release, released) = tokio::sync::watch::channel(false);
+        let mut released = released.clone();
+        let blocker = pool
+            .run_spawned(async move || {
+                let _ = released.wait_for(|released| *released).await;
+            })
+            .await;
+        let order = Arc::new(Mutex::new(vec![]));
+        let waiting = tokio::spawn({
+            let pool = pool.clone();
+            let order = order.clone();
+            async move {
+                // The futures are polled in order the first time, so they wait in that order.
+                futures::future::join_all((0..5).map(|i| {
+                    let order = order.clone();
+                    pool.run_local(async move || order.lock().push(i))
+                }))
+                .await;
+            }
+        });
+        while pool.stats().waiting < 5 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(pool.stats().in_use, 1);
+        release.send(true).expect("task is waiting");
+        blocker.await.expect("task succeeds");
+        waiting.await.expect("tasks succeed");
+        assert_eq!(*order.lock(), [0, 1, 2, 3, 4]);
+    }
+}
@@ -1,6 +1,8 @@
 #![deny(clippy::unwrap_used)]
 
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
+
+use parking_lot::RwLock;
 
 pub fn make_absolute(base: &Path, path: &Path) -> PathBuf {
     if path.is_absolute() {
@@ -9,11 +11,61 @@ pub fn make_absolute(base: &Path, path: &Path) -> PathBuf {
     base.join(path)
 }
 
-pub fn shorten_path(path: &Path) -> String {
-    if let Some(path_name) = path.file_name() {
-        path_name.to_string_lossy().to_string()
-    } else {
-        path.to_string_lossy().to_string()
+/// Removes `.` components, resolves `..` components and trailing slashes without accessing the
+/// file system. Note that this can change the meaning of paths that contain symlinks.
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if matches!(result.components().next_back(), Some(Component::Normal(_))) {
+                    result.pop();
+                } else {
+                    result.push(component);
+                }
+            }
+            _ => result.push(component),
+        }
+    }
+    result
+}
+
+/// Paths are displayed relative to these when possible. They are registered from the command
+/// line and from config files.
+#[derive(Default)]
+pub struct ProjectRoots {
+    roots: RwLock<Vec<PathBuf>>,
+}
+
+impl ProjectRoots {
+    /// The root has to be absolute. Components like `..` are removed so that paths starting
+    /// with the root are found.
+    pub fn add(&self, root: &Path) {
+        let root = normalize_path(root);
+        let mut roots = self.roots.write();
+        if !roots.contains(&root) {
+            roots.push(root);
+        }
+    }
+
+    pub fn shorten(&self, path: &Path) -> String {
+        // The innermost root results in the shortest path.
+        if let Some(relative_path) = self
+            .roots
+            .read()
+            .iter()
+            .filter_map(|root| path.strip_prefix(root).ok())
+            .min_by_key(|relative_path| relative_path.components().count())
+            .filter(|relative_path| !relative_path.as_os_str().is_empty())
+        {
+            return relative_path.to_string_lossy().to_string();
+        }
+        if let Some(path_name) = path.file_name() {
+            path_name.to_string_lossy().to_string()
+        } else {
+            path.to_string_lossy().to_string()
+        }
     }
 }
 
@@ -29,3 +81,53 @@ pub async fn ensure_directory_and_write(path: &Path, content: &[u8]) -> Result<(
     tokio::fs::write(path, content).await?;
     Ok(())
 }
+
+/// Like [`ensure_directory_and_write`], but readers never see a partially written file. This is
+/// necessary when multiple tasks may write the same file at the same time.
+pub async fn ensure_directory_and_write_atomically(
+    path: &Path,
+    content: &[u8],
+) -> Result<(), std::io::Error> {
+    ensure_directory_for_file(path).await?;
+    let mut temp_name = path.file_name().unwrap_or_default().to_owned();
+    temp_name.push(format!(".{}.tmp", uuid::Uuid::new_v4()));
+    let temp_path = path.with_file_name(temp_name);
+    tokio::fs::write(&temp_path, content).await?;
+    if let Err(err) = tokio::fs::rename(&temp_path, path).await {
+        tokio::fs::remove_file(&temp_path).await.ok();
+        return Err(err);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_are_shortened_relative_to_the_innermost_root() {
+        let roots = ProjectRoots::default();
+        roots.add(Path::new("/repo"));
+        roots.add(Path::new("/repo/extern/lib"));
+        assert_eq!(
+            roots.shorten(Path::new("/repo/source/intern/foo.cc")),
+            "source/intern/foo.cc"
+        );
+        assert_eq!(
+            roots.shorten(Path::new("/repo/extern/lib/src/bar.c")),
+            "src/bar.c"
+        );
+        // Paths outside of all roots fall back to the file name.
+        assert_eq!(roots.shorten(Path::new("/other/baz.c")), "baz.c");
+        assert_eq!(roots.shorten(Path::new("/repo")), "repo");
+    }
+
+    #[test]
+    fn roots_are_normalized() {
+        let roots = ProjectRoots::default();
+        roots.add(Path::new("/repo/build/../src/"));
+        roots.add(Path::new("/repo/./src"));
+        assert_eq!(*roots.roots.read(), [PathBuf::from("/repo/src")]);
+        assert_eq!(roots.shorten(Path::new("/repo/src/a/foo.c")), "a/foo.c");
+    }
+}
@@ -0,0 +1,33 @@
+#![deny(clippy::unwrap_used)]
+
+use std::path::Path;
+
+use crate::platform::Platform;
+
+/// Written in place of object files whose compilation is deferred.
+pub fn dummy_object() -> &'static [u8] {
+    crate::ASSETS_DIR
+        .get_file(Platform::current().dummy_object_asset_name())
+        .expect("file should exist")
+        .contents()
+}
+
+/// Written in place of static archives whose creation is deferred.
+pub fn dummy_archive() -> &'static [u8] {
+    crate::ASSETS_DIR
+        .get_file("dummy_archive.a")
+        .expect("file should exist")
+        .contents()
+}
+
+/// Placeholders always have the same content, so a file that was created by the real tool at
+/// the same path can be detected. The size is checked first to avoid reading most such files.
+pub fn is_placeholder_file(path: &Path, placeholder: &[u8]) -> bool {
+    let Ok(metadata) = std::fs::metadata(path) else {
+        return false;
+    };
+    if metadata.len() != placeholder.len() as u64 {
+        return false;
+    }
+    std::fs::read(path).is_ok_and(|content| content == placeholder)
+}
@@ -0,0 +1,115 @@
+#![deny(clippy::unwrap_used)]
+
+/// Differences between the toolchains of the platforms that are relevant when rewriting commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    Linux,
+    MacOs,
+    Windows,
+}
+
+/// How the linker is told to include all members of an archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WholeArchiveSyntax {
+    // `--whole-archive` ... `--no-whole-archive` around the archives.
+    Region,
+    // `-force_load` before every archive.
+    ForceLoad,
+    // `/WHOLEARCHIVE:` with every archive.
+    Prefix,
+}
+
+impl Platform {
+    pub fn current() -> Self {
+        if cfg!(target_os = "macos") {
+            Self::MacOs
+        } else if cfg!(target_os = "windows") {
+            Self::Windows
+        } else {
+            Self::Linux
+        }
+    }
+
+    pub fn supports_link_groups(&self) -> bool {
+        match self {
+            Self::Linux => true,
+            // The Mach-O linker resolves symbols in archives independent of their order anyway.
+            Self::MacOs => false,
+            Self::Windows => false,
+        }
+    }
+
+    pub fn supports_thin_archives(&self) -> bool {
+        match self {
+            Self::Linux => true,
+            // Apple's `ar` and `ld` don't support thin archives well.
+            Self::MacOs => false,
+            Self::Windows => false,
+        }
+    }
+
+    pub fn whole_archive_syntax(&self) -> WholeArchiveSyntax {
+        match self {
+            Self::Linux => WholeArchiveSyntax::Region,
+            Self::MacOs => WholeArchiveSyntax::ForceLoad,
+            Self::Windows => WholeArchiveSyntax::Prefix,
+        }
+    }
+
+    pub fn default_object_extension(&self) -> &'static str {
+        match self {
+            Self::Linux | Self::MacOs => "o",
+            Self::Windows => "obj",
+        }
+    }
+
+    /// Name of the asset that is written in place of object files whose compilation is deferred.
+    /// Its content is never linked, so the format does not have to match the platform for now.
+    pub fn dummy_object_asset_name(&self) -> &'static str {
+        "dummy_object.o"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flag_choices() {
+        assert!(Platform::Linux.supports_link_groups());
+        assert!(Platform::Linux.supports_thin_archives());
+        assert_eq!(
+            Platform::Linux.whole_archive_syntax(),
+            WholeArchiveSyntax::Region
+        );
+        assert_eq!(Platform::Linux.default_object_extension(), "o");
+
+        assert!(!Platform::MacOs.supports_link_groups());
+        assert!(!Platform::MacOs.supports_thin_archives());
+        assert_eq!(
+            Platform::MacOs.whole_archive_syntax(),
+            WholeArchiveSyntax::ForceLoad
+        );
+        assert_eq!(Platform::MacOs.default_object_extension(), "o");
+
+        assert!(!Platform::Windows.supports_link_groups());
+        assert!(!Platform::Windows.supports_thin_archives());
+        assert_eq!(
+            Platform::Windows.whole_archive_syntax(),
+            WholeArchiveSyntax::Prefix
+        );
+        assert_eq!(Platform::Windows.default_object_extension(), "obj");
+    }
+
+    #[test]
+    fn current_platform() {
+        let expected = if cfg!(target_os = "macos") {
+            Platform::MacOs
+        } else if cfg!(target_os = "windows") {
+            Platform::Windows
+        } else {
+            Platform::Linux
+        };
+        assert_eq!(Platform::current(), expected);
+    }
+}
@@ -5,8 +5,14 @@ use bstr::{BStr, BString, ByteSlice};
 use nunny::NonEmpty;
 
 use crate::{
-    CommandOutput, args_processing, code_language::CodeLanguage, config::Config, path_utils,
-    state::State, state_persistent::ObjectData, task_periods::TaskPeriodInfo,
+    args_processing, child_process,
+    code_language::CodeLanguage,
+    config::Config,
+    path_utils::{self, ProjectRoots},
+    state::State,
+    state_persistent::ObjectData,
+    task_periods::TaskPeriodInfo,
+    wrap_final_link::chunk_label,
 };
 
 pub async fn get_preprocessed_headers(
@@ -29,15 +35,36 @@ pub async fn get_preprocessed_headers(
         include_code_file.path(),
         output_path,
     )?;
-    let child = tokio::process::Command::new(any_object.create.binary.to_standard_binary_name())
+    let output = child_process::output_with_timeout(
+        child_process::compiler_command(
+            &state.cli.compiler_launcher,
+            any_object.create.executable().program(config),
+        )
         .args(preprocess_args)
+        // Arguments like `-include` may contain paths relative to the original directory.
+        .current_dir(&any_object.create.cwd)
         .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .spawn()?;
-    let child_output = child.wait_with_output().await?;
-    if !child_output.status.success() {
-        return Err(CommandOutput::from_process_output(child_output).into());
+        .stderr(std::process::Stdio::piped()),
+        state.cli.child_timeout,
+    )
+    .await?;
+    let label = chunk_label(objects, &state.project_roots);
+    if output.status != 0 {
+        let output = output.with_replaced_path(include_code_file.path(), &label);
+        task_period.failed_with_output(&output);
+        return Err(output.into());
     }
+    // The line markers refer to the temporary file, which would show up in diagnostics when
+    // compiling the chunk later, e.g. in "In file included from ...".
+    let preprocessed_headers = tokio::fs::read(output_path).await?;
+    tokio::fs::write(
+        output_path,
+        preprocessed_headers.replace(
+            include_code_file.path().as_os_str().as_encoded_bytes(),
+            &label,
+        ),
+    )
+    .await?;
     task_period.finished_successfully();
     Ok(())
 }
@@ -112,11 +139,11 @@ impl TaskPeriodInfo for GetPreprocessedHeadersTaskInfo {
         "Headers".to_string()
     }
 
-    fn terminal_one_liner(&self) -> String {
+    fn terminal_one_liner(&self, _project_roots: &ProjectRoots) -> String {
         "Headers".into()
     }
 
     fn log_detailed(&self) {
-        log::info!("Get preprocessed headers");
+        log::debug!("Get preprocessed headers");
     }
 }
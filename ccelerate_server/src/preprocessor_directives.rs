@@ -1,6 +1,8 @@
 #![allow(dead_code)]
 
+use std::ffi::{OsStr, OsString};
 use std::io::Write;
+use std::path::Component;
 use std::path::Path;
 use std::path::PathBuf;
 
@@ -16,6 +18,9 @@ pub enum DirectivesUpdate {
     Removed,
 }
 
+/// The absolute path is stored below the directives directory. On Windows, the path prefix
+/// (e.g. `C:` or `\\server\share`) becomes the first directory, so that paths on different
+/// drives don't collide.
 pub fn get_corresponding_directives_path(
     directives_dir: &Path,
     original: &Path,
@@ -23,24 +28,74 @@ pub fn get_corresponding_directives_path(
     if !original.is_absolute() {
         return Err(anyhow!("Path must be absolute"));
     }
-    // TODO: Generalize making the path relative to root.
-    let relative = original.strip_prefix("/")?;
-    let derived_path = directives_dir.join(relative);
+    let mut derived_path = directives_dir.to_owned();
+    for component in original.components() {
+        match component {
+            Component::Prefix(prefix) => derived_path.push(encode_path_prefix(prefix.as_os_str())),
+            Component::RootDir => {}
+            component => derived_path.push(component),
+        }
+    }
     Ok(derived_path)
 }
 
 pub fn get_original_path(directives_dir: &Path, derived: &Path) -> PathBuf {
-    if let Ok(relative) = derived.strip_prefix(directives_dir) {
-        // TODO: Generalize path root.
-        PathBuf::from("/").join(relative)
-    } else {
-        derived.to_owned()
+    let Ok(relative) = derived.strip_prefix(directives_dir) else {
+        return derived.to_owned();
+    };
+    let mut components = relative.components();
+    let mut original = PathBuf::new();
+    if cfg!(windows) {
+        // Absolute paths always have a prefix on Windows.
+        if let Some(prefix) = components.next() {
+            original.push(decode_path_prefix(prefix.as_os_str()));
+        }
+    }
+    original.push(std::path::MAIN_SEPARATOR_STR);
+    original.push(components.as_path());
+    original
+}
+
+/// Path prefixes contain characters that are not allowed in file names.
+fn encode_path_prefix(prefix: &OsStr) -> OsString {
+    let mut encoded = String::new();
+    for c in prefix.to_string_lossy().chars() {
+        match c {
+            '%' | ':' | '\\' | '/' | '?' => encoded.push_str(&format!("%{:02X}", c as u32)),
+            c => encoded.push(c),
+        }
     }
+    encoded.into()
+}
+
+fn decode_path_prefix(encoded: &OsStr) -> OsString {
+    let encoded = encoded.to_string_lossy();
+    let mut decoded = String::new();
+    let mut rest = encoded.as_ref();
+    while let Some(index) = rest.find('%') {
+        decoded.push_str(&rest[..index]);
+        let code = rest
+            .get(index + 1..index + 3)
+            .and_then(|code| u8::from_str_radix(code, 16).ok());
+        match code {
+            Some(code) => {
+                decoded.push(code as char);
+                rest = &rest[index + 3..];
+            }
+            None => {
+                decoded.push('%');
+                rest = &rest[index + 1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded.into()
 }
 
 pub async fn update_directives_file(
     directives_dir: &Path,
     original: &Path,
+    trigraphs: bool,
 ) -> Result<DirectivesUpdate> {
     let derived_path = get_corresponding_directives_path(directives_dir, original)?;
 
@@ -54,7 +109,7 @@ pub async fn update_directives_file(
         return Ok(DirectivesUpdate::Unchanged);
     }
     let original_code = tokio::fs::read(original).await?;
-    let updated_derived_code = extract_preprocessor_directives(original_code.as_bstr())?;
+    let updated_derived_code = extract_preprocessor_directives(original_code.as_bstr(), trigraphs)?;
 
     if derived_exists {
         let old_derived_code = tokio::fs::read(&derived_path).await?;
@@ -66,12 +121,22 @@ pub async fn update_directives_file(
     Ok(DirectivesUpdate::Changed)
 }
 
-pub fn extract_preprocessor_directives(code: &BStr) -> Result<BString> {
+/// `trigraphs` should be set when the code is compiled with `-trigraphs`. Then e.g. `??=` is
+/// handled like `#`.
+pub fn extract_preprocessor_directives(code: &BStr, trigraphs: bool) -> Result<BString> {
+    let replaced_code;
+    let code = if trigraphs {
+        replaced_code = replace_trigraphs(code);
+        replaced_code.as_bstr()
+    } else {
+        code
+    };
     let mut result = BString::new(vec![]);
     let mut remaining = code;
 
     // Need to find any of the following:
-    // - # at beginning of line (potentially with whitespace before it)
+    // - # or %: at beginning of line (potentially with whitespace or line continuations
+    //   before it)
     // - //
     // - /*
     // - "
@@ -79,7 +144,7 @@ pub fn extract_preprocessor_directives(code: &BStr) -> Result<BString> {
     static RE_FIND_START: once_cell::sync::Lazy<regex::bytes::Regex> = once_cell::sync::Lazy::new(
         || {
             regex::bytes::Regex::new(
-            r#"(?m)(?P<preproc>^[ \t]*#)|(?P<line_comment>//)|(?P<block_comment>/\*)|(?P<string>")|(?P<char>')|(?P<raw>R"[^(\r\n]*\()"#,
+            r#"(?m)(?P<preproc>^(?:[ \t]|\\\r?\n)*(?:#|%:))|(?P<line_comment>//)|(?P<block_comment>/\*)|(?P<string>")|(?P<char>')|(?P<raw>R"[^(\r\n]*\()"#,
         )
         .expect("should be valid")
         },
@@ -87,6 +152,12 @@ pub fn extract_preprocessor_directives(code: &BStr) -> Result<BString> {
 
     while let Some(capture) = RE_FIND_START.captures(remaining) {
         if let Some(m) = capture.name("preproc") {
+            let offset = code.len() - remaining.len() + m.start();
+            if ends_with_line_continuation(&code[..offset]) {
+                // The line is joined with the previous one, so this does not start a directive.
+                remaining = remaining[m.end()..].as_bstr();
+                continue;
+            }
             let end = m.start() + find_directive_length(remaining[m.start()..].as_bstr())?;
             let part = &remaining[m.start()..end];
             write!(result, "{}", part)?;
@@ -153,8 +224,7 @@ fn find_directive_length(code: &BStr) -> Result<usize> {
     while let Some(capture) = RE_FIND_NEXT.captures(&code[current..]) {
         if let Some(m) = capture.name("newline") {
             let i = current + m.start();
-            let before = &code[..i];
-            if before.ends_with(b"\\") {
+            if ends_with_line_continuation(&code[..i + 1]) {
                 current = i + 1;
                 continue;
             }
@@ -184,6 +254,44 @@ fn find_directive_length(code: &BStr) -> Result<usize> {
     Ok(code.len())
 }
 
+fn ends_with_line_continuation(code: &[u8]) -> bool {
+    code.ends_with(b"\\\n") || code.ends_with(b"\\\r\n")
+}
+
+/// Trigraphs are replaced before anything else, so e.g. `??/` can also continue a line.
+fn replace_trigraphs(code: &BStr) -> BString {
+    let mut result = BString::new(Vec::with_capacity(code.len()));
+    let mut remaining = code.as_bytes();
+    while let Some(index) = remaining.find(b"??") {
+        let replacement = match remaining.get(index + 2) {
+            Some(b'=') => Some(b'#'),
+            Some(b'/') => Some(b'\\'),
+            Some(b'\'') => Some(b'^'),
+            Some(b'(') => Some(b'['),
+            Some(b')') => Some(b']'),
+            Some(b'!') => Some(b'|'),
+            Some(b'<') => Some(b'{'),
+            Some(b'>') => Some(b'}'),
+            Some(b'-') => Some(b'~'),
+            _ => None,
+        };
+        match replacement {
+            Some(replacement) => {
+                result.extend_from_slice(&remaining[..index]);
+                result.push(replacement);
+                remaining = &remaining[index + 3..];
+            }
+            None => {
+                // Only skip one `?`, because it may start a trigraph with the next one.
+                result.extend_from_slice(&remaining[..index + 1]);
+                remaining = &remaining[index + 1..];
+            }
+        }
+    }
+    result.extend_from_slice(remaining);
+    result
+}
+
 fn find_block_comment_length(code: &BStr) -> Result<usize> {
     match code.find(b"*/") {
         Some(end) => Ok(end + 2),
@@ -237,3 +345,104 @@ fn find_raw_string_length(code: &BStr) -> Result<usize> {
         None => Err(anyhow!("Failed to find end of raw string")),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn unix_paths_round_trip() {
+        let directives_dir = Path::new("/data/directives");
+        let derived = get_corresponding_directives_path(directives_dir, Path::new("/a/b"))
+            .expect("path is absolute");
+        assert_eq!(derived, Path::new("/data/directives/a/b"));
+        assert_eq!(
+            get_original_path(directives_dir, &derived),
+            Path::new("/a/b")
+        );
+        assert!(get_corresponding_directives_path(directives_dir, Path::new("a/b")).is_err());
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn windows_paths_round_trip() {
+        let directives_dir = Path::new(r"D:\data\directives");
+        for (original, expected) in [
+            (r"C:\a\b", r"D:\data\directives\C%3A\a\b"),
+            (
+                r"\\server\share\a\b",
+                r"D:\data\directives\%5C%5Cserver%5Cshare\a\b",
+            ),
+        ] {
+            let derived = get_corresponding_directives_path(directives_dir, Path::new(original))
+                .expect("path is absolute");
+            assert_eq!(derived, Path::new(expected));
+            assert_eq!(
+                get_original_path(directives_dir, &derived),
+                Path::new(original)
+            );
+        }
+    }
+
+    #[test]
+    fn path_prefixes_are_valid_file_names() {
+        for prefix in ["C:", r"\\server\share", r"\\?\C:", "100%"] {
+            let encoded = encode_path_prefix(OsStr::new(prefix));
+            assert!(
+                !encoded.to_string_lossy().contains([':', '\\', '/', '?']),
+                "{:?}",
+                encoded
+            );
+            assert_eq!(decode_path_prefix(&encoded), prefix);
+        }
+    }
+
+    fn directives(code: &str, trigraphs: bool) -> String {
+        extract_preprocessor_directives(code.as_bytes().as_bstr(), trigraphs)
+            .expect("directives can be extracted")
+            .to_string()
+    }
+
+    #[test]
+    fn digraph_directives() {
+        assert_eq!(
+            directives("%:define A 1\nint a;\n  %:include <b.h>\n", false),
+            "%:define A 1\n  %:include <b.h>\n"
+        );
+    }
+
+    #[test]
+    fn trigraph_directives() {
+        let code = "??=define A 1\nint a;\n";
+        assert_eq!(directives(code, false), "");
+        assert_eq!(directives(code, true), "#define A 1\n");
+    }
+
+    #[test]
+    fn continuation_joined_directives() {
+        // A directive that continues on the following lines.
+        assert_eq!(
+            directives("#define SUM \\\n  1 + \\\r\n  2\nint a = SUM;\n", false),
+            "#define SUM \\\n  1 + \\\r\n  2\n"
+        );
+        // The line with the `#` is joined with a line of regular code before it.
+        assert_eq!(
+            directives("int a = 1 + \\\n#define NOT_A_DIRECTIVE\n2;\n", false),
+            ""
+        );
+        // Only line continuations before the `#` still start a directive.
+        assert_eq!(
+            directives("int a;\n\\\n  #define B 2\n", false),
+            "\\\n  #define B 2\n"
+        );
+        // Continuations within comments and strings don't end the directive early.
+        assert_eq!(
+            directives(
+                "#define S \"a\\\nb\" /* x\n y */ + 1\nint s;\n// c \\\n#define C\n",
+                false
+            ),
+            "#define S \"a\\\nb\" /* x\n y */ + 1\n"
+        );
+    }
+}
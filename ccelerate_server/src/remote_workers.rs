@@ -0,0 +1,137 @@
+#![deny(clippy::unwrap_used)]
+
+use std::{
+    ffi::OsString,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use anyhow::Result;
+use ccelerate_shared::{
+    CompileChunkRequestData, RunResponseData, RunResponseDataWire, WrappedBinary,
+};
+
+/// A worker that can't be connected to within this time is treated as unavailable.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Limit for a whole request when compilers have no timeout. Otherwise a worker that accepts
+/// the connection but never answers would block the final link forever.
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(600);
+/// Time for sending the chunk and receiving the object in addition to compiling it.
+const TRANSFER_MARGIN: Duration = Duration::from_secs(30);
+
+/// Other ccelerate servers that chunks can be compiled on when the local pool is busy.
+pub struct RemoteWorkers {
+    workers: Vec<RemoteWorker>,
+    client: reqwest::Client,
+    token: Option<String>,
+    // Remote workers are assumed to be about as powerful as the local machine.
+    max_active_per_worker: usize,
+}
+
+struct RemoteWorker {
+    url: String,
+    active: AtomicUsize,
+}
+
+/// A remote worker that has been reserved for one compilation.
+pub struct RemoteWorkerGuard<'a> {
+    workers: &'a RemoteWorkers,
+    worker: &'a RemoteWorker,
+}
+
+impl RemoteWorkers {
+    /// Requests that take longer than `request_timeout` fail, so that the chunk is compiled
+    /// locally instead.
+    pub fn new(
+        urls: &[String],
+        token: Option<String>,
+        max_active_per_worker: usize,
+        request_timeout: Duration,
+    ) -> Result<Self> {
+        Ok(Self {
+            workers: urls
+                .iter()
+                .map(|url| RemoteWorker {
+                    url: url.trim_end_matches('/').to_owned(),
+                    active: AtomicUsize::new(0),
+                })
+                .collect(),
+            client: reqwest::Client::builder()
+                .connect_timeout(CONNECT_TIMEOUT.min(request_timeout))
+                .timeout(request_timeout)
+                .build()?,
+            token,
+            max_active_per_worker,
+        })
+    }
+
+    /// Compiling a chunk on a worker takes about as long as compiling it here, which is limited
+    /// by `--child-timeout` (in seconds).
+    pub fn request_timeout(child_timeout: u64) -> Duration {
+        match child_timeout {
+            0 => DEFAULT_REQUEST_TIMEOUT,
+            _ => Duration::from_secs(child_timeout) + TRANSFER_MARGIN,
+        }
+    }
+
+    /// Reserves the least busy worker if any of them can take more work.
+    pub fn acquire(&self) -> Option<RemoteWorkerGuard<'_>> {
+        let worker = self
+            .workers
+            .iter()
+            .min_by_key(|worker| worker.active.load(Ordering::Relaxed))?;
+        let previous_active = worker.active.fetch_add(1, Ordering::Relaxed);
+        let guard = RemoteWorkerGuard {
+            workers: self,
+            worker,
+        };
+        if previous_active >= self.max_active_per_worker {
+            return None;
+        }
+        Some(guard)
+    }
+}
+
+impl RemoteWorkerGuard<'_> {
+    pub fn url(&self) -> &str {
+        &self.worker.url
+    }
+
+    /// Compiles preprocessed code on the worker. Errors are only returned if the worker could
+    /// not be used. Compile errors are part of the response.
+    pub async fn compile(
+        &self,
+        binary: WrappedBinary,
+        args: &[OsString],
+        source_extension: &str,
+        source: Vec<u8>,
+    ) -> Result<RunResponseData> {
+        let request = CompileChunkRequestData {
+            binary,
+            args: args.to_vec(),
+            source_extension: source_extension.to_owned(),
+            source,
+        };
+        let mut http_request = self
+            .workers
+            .client
+            .post(format!("{}/compile_chunk", self.worker.url));
+        if let Some(token) = &self.workers.token {
+            http_request = http_request.header(ccelerate_shared::WORKER_TOKEN_HEADER, token);
+        }
+        let response = http_request
+            .json(&request.to_wire())
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<RunResponseDataWire>()
+            .await?;
+        Ok(RunResponseData::from_wire(response)?)
+    }
+}
+
+impl Drop for RemoteWorkerGuard<'_> {
+    fn drop(&mut self) {
+        self.worker.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
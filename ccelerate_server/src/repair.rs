@@ -0,0 +1,109 @@
+#![deny(clippy::unwrap_used)]
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use futures::stream::{FuturesUnordered, StreamExt};
+
+use crate::{args_processing::BuildObjectFileInfo, state::State, wrap_compile_object_file};
+
+/// Extracts the local code again for all recorded object files whose local code file is missing
+/// or corrupted, e.g. because parts of the data directory were deleted.
+pub async fn repair(state: &Arc<State>) -> Result<()> {
+    let records = state.persistent.get_all_object_file_records()?;
+    let mut checked_num = 0;
+    let mut repaired_num = 0;
+    let mut failed_num = 0;
+    let mut tasks = FuturesUnordered::new();
+    for (object_path, _) in records {
+        // Records without local code can't be used for linking anyway.
+        let Some(object) = state.persistent.get_object_file(&object_path) else {
+            continue;
+        };
+        checked_num += 1;
+        if wrap_compile_object_file::local_code_file_is_intact(&object.local_code.local_code_file)
+            .await?
+        {
+            continue;
+        }
+        tasks.push(async move {
+            let result = state
+                .pool
+                .run_local(async || {
+                    let create = &object.create;
+                    let args_info =
+                        BuildObjectFileInfo::from_args(create.binary, &create.cwd, &create.args)?;
+                    let config = state
+                        .config_manager
+                        .config_for_paths(&[&create.cwd, &args_info.source_path])?;
+                    wrap_compile_object_file::recompute_local_code(&object, state, &config).await
+                })
+                .await;
+            (object, result)
+        });
+    }
+    while let Some((object, result)) = tasks.next().await {
+        match result {
+            Ok(()) => {
+                repaired_num += 1;
+                log::info!("Repaired {}", state.project_roots.shorten(&object.path));
+            }
+            Err(err) => {
+                failed_num += 1;
+                log::error!(
+                    "Failed to repair {}: {}",
+                    state.project_roots.shorten(&object.path),
+                    err
+                );
+            }
+        }
+    }
+    println!(
+        "Checked {} object files, repaired {}, failed to repair {}",
+        checked_num, repaired_num, failed_num
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use ccelerate_shared::WrappedBinary;
+
+    use crate::{test_utils::TestEnv, wrap_compile_object_file};
+
+    #[tokio::test]
+    async fn deleted_local_code_is_regenerated() {
+        let env = TestEnv::new(&["--content-addressed-local-code"]).await;
+        env.write("a.c", "int a(void) { return 1; }\n");
+        env.write("b.c", "int b(void) { return 2; }\n");
+        for name in ["a", "b"] {
+            env.run_ok(
+                WrappedBinary::Gcc,
+                &["-c", &format!("{}.c", name), "-o", &format!("{}.o", name)],
+            )
+            .await;
+        }
+        let local_code_file = |name: &str| {
+            env.state
+                .persistent
+                .get_object_file(&env.path(name))
+                .expect("object is recorded")
+                .local_code
+                .local_code_file
+                .clone()
+        };
+        let deleted = local_code_file("a.o");
+        let content = std::fs::read(&deleted).expect("file exists");
+        std::fs::remove_file(&deleted).expect("file can be deleted");
+
+        super::repair(&env.state).await.expect("repair succeeds");
+        assert_eq!(std::fs::read(&deleted).expect("file exists"), content);
+        for name in ["a.o", "b.o"] {
+            assert!(
+                wrap_compile_object_file::local_code_file_is_intact(&local_code_file(name))
+                    .await
+                    .expect("file can be checked")
+            );
+        }
+    }
+}
@@ -0,0 +1,68 @@
+#![deny(clippy::unwrap_used)]
+
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// Keeps track of whether requests are being handled and since when the server is idle.
+pub struct RequestActivity {
+    inner: Mutex<RequestActivityInner>,
+}
+
+struct RequestActivityInner {
+    running: usize,
+    last_change: Instant,
+}
+
+pub struct RunningRequest<'a> {
+    activity: &'a RequestActivity,
+    idle_before: Option<Duration>,
+}
+
+impl RequestActivity {
+    pub fn new() -> Self {
+        Self {
+            inner: Mutex::new(RequestActivityInner {
+                running: 0,
+                last_change: Instant::now(),
+            }),
+        }
+    }
+
+    /// The request counts as running until the returned value is dropped.
+    pub fn start(&self) -> RunningRequest<'_> {
+        let mut inner = self.inner.lock();
+        let idle_before = (inner.running == 0).then(|| inner.last_change.elapsed());
+        inner.running += 1;
+        inner.last_change = Instant::now();
+        RunningRequest {
+            activity: self,
+            idle_before,
+        }
+    }
+
+    /// Time since the last request finished, or none if requests are still running.
+    pub fn idle_duration(&self) -> Option<Duration> {
+        let inner = self.inner.lock();
+        if inner.running > 0 {
+            return None;
+        }
+        Some(inner.last_change.elapsed())
+    }
+}
+
+impl RunningRequest<'_> {
+    /// How long the server was idle before this request started, or none if other requests
+    /// were running.
+    pub fn idle_before(&self) -> Option<Duration> {
+        self.idle_before
+    }
+}
+
+impl Drop for RunningRequest<'_> {
+    fn drop(&mut self) {
+        let mut inner = self.activity.inner.lock();
+        inner.running -= 1;
+        inner.last_change = Instant::now();
+    }
+}
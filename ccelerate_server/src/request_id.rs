@@ -0,0 +1,47 @@
+#![deny(clippy::unwrap_used)]
+
+use std::fmt;
+
+use tokio::task::JoinHandle;
+
+/// Identifies a single incoming request so that all the work done for it can be correlated,
+/// even when multiple requests are handled concurrently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RequestId(uuid::Uuid);
+
+tokio::task_local! {
+    static CURRENT_REQUEST_ID: RequestId;
+}
+
+impl RequestId {
+    pub fn new() -> Self {
+        Self(uuid::Uuid::new_v4())
+    }
+
+    /// The id of the request that the current task is working on, if any.
+    pub fn current() -> Option<Self> {
+        CURRENT_REQUEST_ID.try_with(|id| *id).ok()
+    }
+
+    pub async fn scope<Fut: Future>(self, f: Fut) -> Fut::Output {
+        CURRENT_REQUEST_ID.scope(self, f).await
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Same as [`tokio::task::spawn`] but the spawned task keeps the request id of the caller.
+pub fn spawn<Fut>(f: Fut) -> JoinHandle<Fut::Output>
+where
+    Fut: Future + Send + 'static,
+    Fut::Output: Send + 'static,
+{
+    match RequestId::current() {
+        Some(id) => tokio::task::spawn(id.scope(f)),
+        None => tokio::task::spawn(f),
+    }
+}
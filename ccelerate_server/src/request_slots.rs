@@ -0,0 +1,36 @@
+#![deny(clippy::unwrap_used)]
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Limits how many requests are handled at the same time. Without a limit, every request that
+/// the build system sends is accepted right away.
+pub struct RequestSlots {
+    semaphore: Option<Semaphore>,
+}
+
+impl RequestSlots {
+    pub fn new(max_running_requests: Option<usize>) -> Self {
+        Self {
+            semaphore: max_running_requests.map(|max| Semaphore::new(max.max(1))),
+        }
+    }
+
+    /// Waits until a slot is free.
+    pub async fn acquire(&self) -> Option<SemaphorePermit<'_>> {
+        let semaphore = self.semaphore.as_ref()?;
+        Some(
+            semaphore
+                .acquire()
+                .await
+                .expect("semaphore is never closed"),
+        )
+    }
+
+    /// Like [`Self::acquire`] but gives up if all slots are taken.
+    pub fn try_acquire(&self) -> Result<Option<SemaphorePermit<'_>>, ()> {
+        let Some(semaphore) = self.semaphore.as_ref() else {
+            return Ok(None);
+        };
+        semaphore.try_acquire().map(Some).map_err(|_| ())
+    }
+}
@@ -1,7 +1,6 @@
 use std::path::PathBuf;
 
 use crate::code_language::CodeLanguage;
-use anyhow::Result;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct SourceFile {
@@ -10,15 +9,18 @@ pub struct SourceFile {
 }
 
 impl SourceFile {
-    pub fn language(&self) -> Result<CodeLanguage> {
-        if let Some(language) = self.language_override {
-            return Ok(language);
-        }
-        let ext = self
-            .path
-            .extension()
-            .and_then(|e| e.to_str())
-            .ok_or_else(|| anyhow::anyhow!("Failed to get extension"))?;
-        CodeLanguage::from_ext(ext)
+    /// None if the language is not known, e.g. for objects and archives.
+    pub fn language(&self) -> Option<CodeLanguage> {
+        self.language_override
+            .or_else(|| CodeLanguage::from_known_path(&self.path))
+    }
+
+    /// E.g. `libfoo.so` or `libfoo.so.1`.
+    pub fn is_shared_library(&self) -> bool {
+        let Some(name) = self.path.file_name() else {
+            return false;
+        };
+        let name = name.to_string_lossy();
+        name.ends_with(".so") || name.contains(".so.")
     }
 }
@@ -1,12 +1,32 @@
 #![deny(clippy::unwrap_used)]
 
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    num::NonZeroUsize,
+    path::PathBuf,
+    sync::{Arc, atomic::AtomicBool},
+};
 
+use anyhow::Result;
 use parking_lot::Mutex;
+use ratatui::widgets::TableState;
 
 use crate::{
-    Cli, config::ConfigManager, object_by_inputs_cache::ObjectByInputsCache,
-    parallel_pool::ParallelPool, state_persistent::PersistentState, task_periods::TaskPeriods,
+    Cli,
+    build_progress::BuildProgress,
+    cache_stats::PreprocessCacheStats,
+    chunk_sizes::ChunkSizes,
+    compiler_versions::CompilerVersions,
+    config::ConfigManager,
+    final_link_cache::FinalLinkCache,
+    memory_budget::MemoryBudget,
+    object_by_inputs_cache::ObjectByInputsCache,
+    parallel_pool::ParallelPool,
+    path_utils::{ProjectRoots, make_absolute},
+    remote_workers::RemoteWorkers,
+    request_activity::RequestActivity,
+    request_slots::RequestSlots,
+    state_persistent::PersistentState,
+    task_periods::TaskPeriods,
 };
 
 pub struct State {
@@ -19,5 +39,67 @@ pub struct State {
     pub cli: Cli,
     pub data_dir: PathBuf,
     pub config_manager: ConfigManager,
+    pub project_roots: Arc<ProjectRoots>,
     pub objects_cache: ObjectByInputsCache,
+    pub final_link_cache: FinalLinkCache,
+    pub chunk_sizes: ChunkSizes,
+    pub compiler_versions: CompilerVersions,
+    pub preprocess_cache_stats: PreprocessCacheStats,
+    pub request_activity: RequestActivity,
+    pub request_slots: RequestSlots,
+    pub build_progress: BuildProgress,
+    pub remote_workers: RemoteWorkers,
+    pub memory_budget: MemoryBudget,
+    // Set when the server stopped e.g. because of the idle timeout.
+    pub server_stopped: AtomicBool,
+}
+
+impl State {
+    pub async fn new(cli: Cli, data_dir: PathBuf) -> Result<Self> {
+        let db_path = data_dir.join("ccelerate.db");
+        let address = std::net::SocketAddr::new(cli.bind, cli.port).to_string();
+        let jobs = cli.jobs.unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(NonZeroUsize::get)
+                .unwrap_or(1)
+        });
+        let project_roots = Arc::new(ProjectRoots::default());
+        if let Some(project_root) = &cli.project_root {
+            project_roots.add(&make_absolute(&std::env::current_dir()?, project_root));
+        }
+        Ok(Self {
+            address,
+            persistent: PersistentState::new(
+                &db_path,
+                &data_dir,
+                cli.object_cache_size,
+                cli.auto_reset_db,
+            )
+            .await?,
+            task_periods: TaskPeriods::new(project_roots.clone()),
+            tasks_table_state: Arc::new(Mutex::new(TableState::default())),
+            auto_scroll: Arc::new(Mutex::new(true)),
+            pool: ParallelPool::new(jobs),
+            data_dir,
+            config_manager: ConfigManager::new(project_roots.clone()),
+            project_roots,
+            objects_cache: ObjectByInputsCache::new(),
+            final_link_cache: FinalLinkCache::new(),
+            chunk_sizes: ChunkSizes::new(cli.chunk_size, cli.adaptive_chunk_size),
+            compiler_versions: CompilerVersions::new(cli.child_timeout),
+            preprocess_cache_stats: PreprocessCacheStats::new(),
+            request_activity: RequestActivity::new(),
+            request_slots: RequestSlots::new(cli.max_running_requests),
+            build_progress: BuildProgress::new(),
+            remote_workers: RemoteWorkers::new(
+                &cli.workers,
+                cli.worker_token.clone(),
+                jobs,
+                RemoteWorkers::request_timeout(cli.child_timeout),
+            )?,
+            memory_budget: MemoryBudget::new(cli.memory_budget),
+            server_stopped: AtomicBool::new(false),
+            cli,
+        })
+    }
 }
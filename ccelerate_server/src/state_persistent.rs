@@ -1,5 +1,7 @@
 use std::{
+    collections::HashSet,
     ffi::{OsStr, OsString},
+    num::NonZeroUsize,
     path::{Path, PathBuf},
     sync::Arc,
 };
@@ -10,61 +12,212 @@ use ccelerate_shared::WrappedBinary;
 use chrono::Utc;
 use parking_lot::Mutex;
 
-use crate::path_utils;
+use crate::{args_processing, executable::Executable, path_utils};
 
 pub struct PersistentState {
     pub conn: Arc<Mutex<rusqlite::Connection>>,
+    // Files in the data directory are stored relative to it, so that it can be moved.
+    data_dir: PathBuf,
+    // Final links look up the same object files many times. This avoids querying and parsing
+    // them again every time.
+    object_files_cache: Mutex<lru::LruCache<PathBuf, Arc<ObjectData>>>,
+}
+
+/// Whether opening the database failed because the file is not a valid database (anymore).
+fn is_corruption_error(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        let sqlite_err = match cause.downcast_ref::<rusqlite_migration::Error>() {
+            Some(rusqlite_migration::Error::RusqliteError { err, .. }) => Some(err),
+            _ => cause.downcast_ref::<rusqlite::Error>(),
+        };
+        matches!(
+            sqlite_err,
+            Some(rusqlite::Error::SqliteFailure(err, _))
+                if matches!(
+                    err.code,
+                    rusqlite::ErrorCode::DatabaseCorrupt | rusqlite::ErrorCode::NotADatabase
+                )
+        )
+    })
 }
 
 impl PersistentState {
-    pub async fn new(path: &Path) -> Result<Self> {
+    pub async fn new(
+        path: &Path,
+        data_dir: &Path,
+        object_files_cache_size: NonZeroUsize,
+        auto_reset: bool,
+    ) -> Result<Self> {
         path_utils::ensure_directory_for_file(path).await?;
-        let db_migrations = rusqlite_migration::Migrations::new(vec![rusqlite_migration::M::up(
-            "
-            CREATE TABLE ObjectFiles(
-                path TEXT NOT NULL PRIMARY KEY,
-                build TEXT NOT NULL,
-                build_debug TEXT NOT NULL,
-                local_code TEXT,
-                local_code_debug TEXT,
-                last_build TEXT NOT NULL
-            );
-            CREATE TABLE ArchiveFiles(
-                path TEXT NOT NULL PRIMARY KEY,
-                build TEXT NOT NULL,
-                build_debug TEXT NOT NULL
-            );
-            ",
-        )]);
         let mut conn = rusqlite::Connection::open(path)?;
-        conn.pragma_update(None, "journal_mode", "WAL")?;
-        db_migrations.to_latest(&mut conn)?;
+        let conn = match Self::prepare_connection(&mut conn) {
+            Ok(()) => conn,
+            Err(err) if auto_reset && is_corruption_error(&err) => {
+                // The database may be corrupted e.g. after a power loss. Keep the old files
+                // around instead of deleting them in case they are still needed. Other errors,
+                // like missing permissions, don't warrant resetting the database.
+                let corrupt_path = path.with_file_name(format!(
+                    "{}.corrupt-{}",
+                    path.file_name().unwrap_or_default().to_string_lossy(),
+                    Utc::now().format("%Y%m%d-%H%M%S")
+                ));
+                eprintln!(
+                    "Failed to open database ({}), moving it to {}",
+                    err,
+                    corrupt_path.display()
+                );
+                tokio::fs::rename(path, &corrupt_path).await?;
+                for suffix in ["-wal", "-shm"] {
+                    let with_suffix = |path: &Path| {
+                        let mut path = path.as_os_str().to_owned();
+                        path.push(suffix);
+                        PathBuf::from(path)
+                    };
+                    let side_file = with_suffix(path);
+                    if tokio::fs::try_exists(&side_file).await? {
+                        tokio::fs::rename(&side_file, with_suffix(&corrupt_path)).await?;
+                    }
+                }
+                // Only close the connection now, because SQLite removes the WAL file of the
+                // database when its last connection is closed.
+                drop(conn);
+                Self::open_connection(path)?
+            }
+            Err(err) => {
+                return Err(err.context(format!(
+                    "Failed to open database at {}, use --auto-reset-db to move it aside and start with an empty one",
+                    path.display()
+                )));
+            }
+        };
         Ok(Self {
             conn: Arc::new(Mutex::new(conn)),
+            data_dir: data_dir.to_owned(),
+            object_files_cache: Mutex::new(lru::LruCache::new(object_files_cache_size)),
         })
     }
 
+    fn open_connection(path: &Path) -> Result<rusqlite::Connection> {
+        let mut conn = rusqlite::Connection::open(path)?;
+        Self::prepare_connection(&mut conn)?;
+        Ok(conn)
+    }
+
+    fn prepare_connection(conn: &mut rusqlite::Connection) -> Result<()> {
+        let db_migrations = rusqlite_migration::Migrations::new(vec![
+            rusqlite_migration::M::up(
+                "
+                CREATE TABLE ObjectFiles(
+                    path TEXT NOT NULL PRIMARY KEY,
+                    build TEXT NOT NULL,
+                    build_debug TEXT NOT NULL,
+                    local_code TEXT,
+                    local_code_debug TEXT,
+                    last_build TEXT NOT NULL
+                );
+                CREATE TABLE ArchiveFiles(
+                    path TEXT NOT NULL PRIMARY KEY,
+                    build TEXT NOT NULL,
+                    build_debug TEXT NOT NULL
+                );
+                ",
+            ),
+            // The machine-read columns store binary records now. Existing rows keep their JSON
+            // text which can still be read.
+            rusqlite_migration::M::up(
+                "
+                CREATE TABLE ObjectFilesNew(
+                    path TEXT NOT NULL PRIMARY KEY,
+                    build BLOB NOT NULL,
+                    build_debug TEXT NOT NULL,
+                    local_code BLOB,
+                    local_code_debug TEXT,
+                    last_build TEXT NOT NULL
+                );
+                INSERT INTO ObjectFilesNew SELECT * FROM ObjectFiles;
+                DROP TABLE ObjectFiles;
+                ALTER TABLE ObjectFilesNew RENAME TO ObjectFiles;
+                ",
+            ),
+            rusqlite_migration::M::up(
+                "CREATE INDEX ObjectFilesLastBuild ON ObjectFiles(last_build);",
+            ),
+            // Local code files may be named only by their content, so the source name is kept
+            // separately for display.
+            rusqlite_migration::M::up("ALTER TABLE ObjectFiles ADD COLUMN source_name TEXT;"),
+            rusqlite_migration::M::up(
+                "ALTER TABLE ObjectFiles ADD COLUMN pinned_individual INTEGER NOT NULL DEFAULT 0;",
+            ),
+            rusqlite_migration::M::up(
+                "
+                CREATE TABLE LinkedFiles(
+                    path TEXT NOT NULL PRIMARY KEY,
+                    build TEXT NOT NULL,
+                    build_debug TEXT NOT NULL
+                );
+                ",
+            ),
+            rusqlite_migration::M::up("ALTER TABLE ObjectFiles ADD COLUMN input_stamps BLOB;"),
+            rusqlite_migration::M::up(
+                "
+                CREATE TABLE PreprocessResults(
+                    key TEXT NOT NULL PRIMARY KEY,
+                    result BLOB NOT NULL
+                );
+                ",
+            ),
+            // Kept outside of the build record, because adding a field there would make
+            // existing records undecodable.
+            rusqlite_migration::M::up("ALTER TABLE ObjectFiles ADD COLUMN resolved_binary TEXT;"),
+            rusqlite_migration::M::up(
+                "
+                CREATE TABLE Builds(
+                    id TEXT NOT NULL PRIMARY KEY,
+                    key TEXT NOT NULL,
+                    tasks INTEGER NOT NULL,
+                    started TEXT NOT NULL
+                );
+                ",
+            ),
+            // Separate from `pinned_individual`, so that pins set by config rules can be removed
+            // again without touching the ones that were set explicitly.
+            rusqlite_migration::M::up(
+                "ALTER TABLE ObjectFiles ADD COLUMN pinned_by_rule INTEGER NOT NULL DEFAULT 0;",
+            ),
+        ]);
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        db_migrations.to_latest(conn)?;
+        Ok(())
+    }
+
     pub fn update_object_file(
         &self,
         object_path: &Path,
-        binary: WrappedBinary,
+        executable: &Executable,
         cwd: &Path,
         args: impl IntoIterator<Item = impl AsRef<OsStr>>,
     ) -> Result<()> {
         let data = CompileObjectRecord {
-            binary,
+            binary: executable.binary,
+            resolved_binary: executable.resolved_path.clone(),
             cwd: cwd.to_path_buf(),
             args: args.into_iter().map(|s| s.as_ref().to_owned()).collect(),
         };
         self.conn.lock().execute(
-            "INSERT OR REPLACE INTO ObjectFiles (path, build, build_debug, local_code, local_code_debug, last_build) VALUES (?1, ?2, ?3, NULL, NULL, ?4)",
+            // Upsert instead of replacing the row to keep flags like `pinned_individual`.
+            "INSERT INTO ObjectFiles (path, build, build_debug, local_code, local_code_debug, last_build, resolved_binary) VALUES (?1, ?2, ?3, NULL, NULL, ?4, ?5)
+             ON CONFLICT(path) DO UPDATE SET build = excluded.build, build_debug = excluded.build_debug, local_code = NULL, local_code_debug = NULL, last_build = excluded.last_build, input_stamps = NULL, resolved_binary = excluded.resolved_binary",
             rusqlite::params![
                 object_path.to_string_lossy(),
-                serde_json::to_string(&data.to_raw())?,
+                encode_record(&data.to_raw())?,
                 serde_json::to_string_pretty(&data.to_debug())?,
                 Utc::now().to_rfc3339(),
+                data.resolved_binary
+                    .as_ref()
+                    .map(|path| path.to_string_lossy()),
             ],
         )?;
+        self.object_files_cache.lock().pop(object_path);
         Ok(())
     }
 
@@ -72,8 +225,10 @@ impl PersistentState {
         &self,
         object_path: &Path,
         local_code_file: &Path,
+        source_name: &str,
         direct_includes: impl IntoIterator<Item = impl AsRef<Path>>,
         include_defines: impl IntoIterator<Item = impl AsRef<BStr>>,
+        input_stamps: Option<&[InputFileStamp]>,
     ) -> Result<()> {
         let data = ObjectLocalCodeRecord {
             local_code_file: local_code_file.to_path_buf(),
@@ -87,16 +242,77 @@ impl PersistentState {
                 .collect(),
         };
         self.conn.lock().execute(
-            "UPDATE ObjectFiles SET local_code = ?1, local_code_debug = ?2 WHERE path = ?3",
+            "UPDATE ObjectFiles SET local_code = ?1, local_code_debug = ?2, source_name = ?3, input_stamps = ?4 WHERE path = ?5",
             rusqlite::params![
-                serde_json::to_string(&data.to_raw())?,
+                encode_record(&data.to_raw(&self.data_dir))?,
                 serde_json::to_string_pretty(&data.to_debug())?,
+                source_name,
+                input_stamps.map(|stamps| encode_record(&stamps)).transpose()?,
                 object_path.to_string_lossy(),
             ],
         )?;
+        self.object_files_cache.lock().pop(object_path);
+        Ok(())
+    }
+
+    /// Remembers the result of preprocessing, so that it can be reused for identical inputs.
+    pub fn update_preprocess_result(
+        &self,
+        key: &str,
+        result: &PreprocessResultRecord,
+    ) -> Result<()> {
+        self.conn.lock().execute(
+            "INSERT OR REPLACE INTO PreprocessResults (key, result) VALUES (?1, ?2)",
+            rusqlite::params![key, encode_record(&result.to_raw(&self.data_dir))?],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_preprocess_result(&self, key: &str) -> Option<PreprocessResultRecord> {
+        self.conn
+            .lock()
+            .query_row(
+                "SELECT result FROM PreprocessResults WHERE key = ?",
+                rusqlite::params!(key),
+                |row| {
+                    let result = decode_record::<PreprocessResultRecordRaw>(row.get_ref(0)?)
+                        .map_err(|_| rusqlite::Error::InvalidQuery)?;
+                    Ok(PreprocessResultRecord::from_raw(&result, &self.data_dir))
+                },
+            )
+            .ok()
+    }
+
+    /// Remembers how many tasks a build had so far. The key identifies builds of the same
+    /// project, e.g. by their linked output.
+    pub fn update_build_tasks(
+        &self,
+        build_id: &str,
+        key: &str,
+        tasks: usize,
+        started: chrono::DateTime<Utc>,
+    ) -> Result<()> {
+        self.conn.lock().execute(
+            "INSERT OR REPLACE INTO Builds (id, key, tasks, started) VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![build_id, key, tasks, started.to_rfc3339()],
+        )?;
         Ok(())
     }
 
+    /// Average number of tasks of the last builds with the same key as the most recent build.
+    /// The next build is expected to be of the same project.
+    pub fn get_expected_build_tasks(&self) -> Result<Option<f64>> {
+        Ok(self.conn.lock().query_row(
+            "SELECT AVG(tasks) FROM (
+                SELECT tasks FROM Builds
+                WHERE key = (SELECT key FROM Builds ORDER BY started DESC LIMIT 1)
+                ORDER BY started DESC LIMIT 5
+            )",
+            [],
+            |row| row.get(0),
+        )?)
+    }
+
     pub fn update_archive_file(
         &self,
         archive_path: &Path,
@@ -120,36 +336,249 @@ impl PersistentState {
         Ok(())
     }
 
+    /// Remembers how a binary is linked, e.g. to inspect its link sources later.
+    pub fn update_linked_file(
+        &self,
+        output_path: &Path,
+        binary: WrappedBinary,
+        cwd: &Path,
+        args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    ) -> Result<()> {
+        let data = FinalLinkRecord {
+            binary,
+            cwd: cwd.to_path_buf(),
+            args: args.into_iter().map(|s| s.as_ref().to_owned()).collect(),
+        };
+        self.conn.lock().execute(
+            "INSERT OR REPLACE INTO LinkedFiles (path, build, build_debug) VALUES (?1, ?2, ?3)",
+            rusqlite::params![
+                output_path.to_string_lossy(),
+                serde_json::to_string(&data.to_raw())?,
+                serde_json::to_string_pretty(&data.to_debug())?,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Records the same build for another path, e.g. when an object file was copied.
+    pub fn copy_object_file(&self, from: &Path, to: &Path) -> Result<()> {
+        self.conn.lock().execute(
+            "INSERT OR REPLACE INTO ObjectFiles (path, build, build_debug, local_code, local_code_debug, last_build, source_name, pinned_individual, pinned_by_rule, input_stamps, resolved_binary)
+             SELECT ?2, build, build_debug, local_code, local_code_debug, ?3, source_name, pinned_individual, pinned_by_rule, input_stamps, resolved_binary FROM ObjectFiles WHERE path = ?1",
+            rusqlite::params![
+                from.to_string_lossy(),
+                to.to_string_lossy(),
+                Utc::now().to_rfc3339(),
+            ],
+        )?;
+        self.object_files_cache.lock().pop(to);
+        Ok(())
+    }
+
+    pub fn update_object_file_input_stamps(
+        &self,
+        object_path: &Path,
+        input_stamps: &[InputFileStamp],
+    ) -> Result<()> {
+        self.conn.lock().execute(
+            "UPDATE ObjectFiles SET input_stamps = ?1 WHERE path = ?2",
+            rusqlite::params![encode_record(&input_stamps)?, object_path.to_string_lossy()],
+        )?;
+        self.object_files_cache.lock().pop(object_path);
+        Ok(())
+    }
+
+    /// Pinned objects are always compiled on their own instead of together with other objects.
+    /// Returns false if there is no record for the object file.
+    pub fn set_object_file_pinned_individual(&self, path: &Path, pinned: bool) -> Result<bool> {
+        let changed_rows = self.conn.lock().execute(
+            "UPDATE ObjectFiles SET pinned_individual = ?1 WHERE path = ?2",
+            rusqlite::params![pinned, path.to_string_lossy()],
+        )?;
+        self.object_files_cache.lock().pop(path);
+        Ok(changed_rows > 0)
+    }
+
+    /// Like [`Self::set_object_file_pinned_individual`], but for pins that follow from config
+    /// rules. Those are updated whenever the object is compiled.
+    pub fn set_object_file_pinned_by_rule(&self, path: &Path, pinned: bool) -> Result<()> {
+        self.conn.lock().execute(
+            "UPDATE ObjectFiles SET pinned_by_rule = ?1 WHERE path = ?2",
+            rusqlite::params![pinned, path.to_string_lossy()],
+        )?;
+        self.object_files_cache.lock().pop(path);
+        Ok(())
+    }
+
+    pub fn copy_archive_file(&self, from: &Path, to: &Path) -> Result<()> {
+        self.conn.lock().execute(
+            "INSERT OR REPLACE INTO ArchiveFiles (path, build, build_debug)
+             SELECT ?2, build, build_debug FROM ArchiveFiles WHERE path = ?1",
+            rusqlite::params![from.to_string_lossy(), to.to_string_lossy()],
+        )?;
+        Ok(())
+    }
+
     pub fn get_object_file(&self, path: &Path) -> Option<Arc<ObjectData>> {
+        // Keep the cache locked while reading from the database so that a concurrent update
+        // can't be overwritten with outdated data.
+        let mut cache = self.object_files_cache.lock();
+        if let Some(object) = cache.get(path) {
+            return Some(object.clone());
+        }
+        let object = self.get_object_file_uncached(path)?;
+        cache.put(path.to_owned(), object.clone());
+        Some(object)
+    }
+
+    fn get_object_file_uncached(&self, path: &Path) -> Option<Arc<ObjectData>> {
         self.conn
             .lock()
             .query_row(
-                "SELECT build, local_code, last_build FROM ObjectFiles WHERE path = ?",
+                "SELECT build, local_code, last_build, source_name, pinned_individual OR pinned_by_rule, input_stamps, resolved_binary FROM ObjectFiles WHERE path = ?",
                 rusqlite::params!(path.to_string_lossy()),
                 |row| {
-                    let build: String = row.get(0)?;
-                    let local_code: Option<String> = row.get(1)?;
-                    let build = serde_json::from_str::<CompileObjectRecordRaw>(&build)
+                    let build = decode_record::<CompileObjectRecordRaw>(row.get_ref(0)?)
                         .map_err(|_| rusqlite::Error::InvalidQuery)?;
-                    let local_code = serde_json::from_str::<ObjectLocalCodeRecordRaw>(
-                        &local_code.unwrap_or_default(),
-                    )
-                    .map_err(|_| rusqlite::Error::InvalidQuery)
-                    .map(|c| ObjectLocalCodeRecord::from_raw(&c))?;
+                    let local_code = decode_record::<ObjectLocalCodeRecordRaw>(row.get_ref(1)?)
+                        .map_err(|_| rusqlite::Error::InvalidQuery)
+                        .map(|c| ObjectLocalCodeRecord::from_raw(&c, &self.data_dir))?;
                     let last_build: String = row.get(2)?;
                     let last_build = chrono::DateTime::parse_from_rfc3339(&last_build)
                         .map_err(|_| rusqlite::Error::InvalidQuery)?;
+                    let source_name: Option<String> = row.get(3)?;
+                    let pinned_individual: bool = row.get(4)?;
+                    // Stamps are only used to skip work, so ones that can't be decoded (e.g.
+                    // written by an older version) are just ignored.
+                    let input_stamps = match row.get_ref(5)? {
+                        rusqlite::types::ValueRef::Null => None,
+                        value => decode_record::<Vec<InputFileStamp>>(value).ok(),
+                    };
+                    let resolved_binary: Option<String> = row.get(6)?;
                     Ok(Arc::new(ObjectData {
                         path: path.to_owned(),
-                        create: CompileObjectRecord::from_raw(&build),
+                        create: CompileObjectRecord::from_raw(
+                            &build,
+                            resolved_binary.map(PathBuf::from),
+                        ),
                         local_code,
                         last_build,
+                        source_name,
+                        pinned_individual,
+                        input_stamps,
                     }))
                 },
             )
             .ok()
     }
 
+    pub fn get_all_object_file_records(&self) -> Result<Vec<(PathBuf, CompileObjectRecord)>> {
+        let conn = self.conn.lock();
+        let mut statement = conn.prepare("SELECT path, build, resolved_binary FROM ObjectFiles")?;
+        let rows = statement.query_map([], |row| {
+            let path: String = row.get(0)?;
+            let build = decode_record::<CompileObjectRecordRaw>(row.get_ref(1)?)
+                .map_err(|_| rusqlite::Error::InvalidQuery)?;
+            let resolved_binary: Option<String> = row.get(2)?;
+            Ok((
+                PathBuf::from(path),
+                CompileObjectRecord::from_raw(&build, resolved_binary.map(PathBuf::from)),
+            ))
+        })?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// Local code files that are referenced by a snapshot written with [`Self::write_snapshot`].
+    pub fn get_snapshot_local_code_files(&self, snapshot_path: &Path) -> Result<HashSet<PathBuf>> {
+        let conn = rusqlite::Connection::open(snapshot_path)?;
+        let mut files = HashSet::new();
+        let mut statement =
+            conn.prepare("SELECT local_code FROM ObjectFiles WHERE local_code IS NOT NULL")?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            // Records that can't be decoded can't be used anyway.
+            if let Ok(local_code) = decode_record::<ObjectLocalCodeRecordRaw>(row.get_ref(0)?) {
+                files.insert(
+                    ObjectLocalCodeRecord::from_raw(&local_code, &self.data_dir).local_code_file,
+                );
+            }
+        }
+        let mut statement = conn.prepare("SELECT result FROM PreprocessResults")?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            if let Ok(result) = decode_record::<PreprocessResultRecordRaw>(row.get_ref(0)?) {
+                files.insert(
+                    PreprocessResultRecord::from_raw(&result, &self.data_dir)
+                        .local_code
+                        .local_code_file,
+                );
+            }
+        }
+        Ok(files)
+    }
+
+    /// Writes a consistent copy of the database, even while it's being used. With `since`, the
+    /// copy only contains the object files that were built after that time. Preprocess results
+    /// don't know when they were used, so they are left out in that case.
+    pub fn write_snapshot(&self, path: &Path, since: Option<chrono::DateTime<Utc>>) -> Result<()> {
+        self.conn
+            .lock()
+            .execute("VACUUM INTO ?1", rusqlite::params![path.to_string_lossy()])?;
+        if let Some(since) = since {
+            let snapshot = rusqlite::Connection::open(path)?;
+            snapshot.execute(
+                "DELETE FROM ObjectFiles WHERE last_build <= ?1",
+                rusqlite::params![since.to_rfc3339()],
+            )?;
+            snapshot.execute("DELETE FROM PreprocessResults", [])?;
+        }
+        Ok(())
+    }
+
+    /// Adds the records of another database, e.g. of an imported cache. Existing records are
+    /// kept, unless the other database has a more recent build of the same object file.
+    pub fn merge_database(path: &Path, other_path: &Path) -> Result<()> {
+        // Both databases are migrated first, so that rows can be copied as they are.
+        drop(Self::open_connection(other_path)?);
+        let conn = Self::open_connection(path)?;
+        conn.execute(
+            "ATTACH DATABASE ?1 AS other",
+            rusqlite::params![other_path.to_string_lossy()],
+        )?;
+        conn.execute_batch(
+            "
+            BEGIN;
+            DELETE FROM main.ObjectFiles WHERE path IN (
+                SELECT o.path FROM other.ObjectFiles o JOIN main.ObjectFiles m ON o.path = m.path
+                WHERE o.last_build > m.last_build
+            );
+            INSERT OR IGNORE INTO main.ObjectFiles SELECT * FROM other.ObjectFiles;
+            INSERT OR IGNORE INTO main.ArchiveFiles SELECT * FROM other.ArchiveFiles;
+            INSERT OR IGNORE INTO main.LinkedFiles SELECT * FROM other.LinkedFiles;
+            INSERT OR IGNORE INTO main.PreprocessResults SELECT * FROM other.PreprocessResults;
+            INSERT OR IGNORE INTO main.Builds SELECT * FROM other.Builds;
+            COMMIT;
+            ",
+        )?;
+        conn.execute("DETACH DATABASE other", [])?;
+        Ok(())
+    }
+
+    /// Paths of all object files that have not been built since the given time.
+    // Garbage collection of old objects is the intended caller.
+    #[allow(dead_code)]
+    pub fn objects_older_than(&self, cutoff: chrono::DateTime<Utc>) -> Result<Vec<PathBuf>> {
+        let conn = self.conn.lock();
+        // The timestamps are stored in the same format, so they can be compared as text which
+        // allows using the index.
+        let mut statement = conn.prepare("SELECT path FROM ObjectFiles WHERE last_build < ?")?;
+        let rows = statement.query_map(rusqlite::params![cutoff.to_rfc3339()], |row| {
+            let path: String = row.get(0)?;
+            Ok(PathBuf::from(path))
+        })?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
     pub fn get_archive_file(&self, path: &Path) -> Option<CreateArchiveRecord> {
         self.conn
             .lock()
@@ -166,6 +595,22 @@ impl PersistentState {
             .ok()
     }
 
+    pub fn get_linked_file(&self, path: &Path) -> Option<FinalLinkRecord> {
+        self.conn
+            .lock()
+            .query_row(
+                "SELECT build FROM LinkedFiles WHERE path = ?",
+                rusqlite::params!(path.to_string_lossy()),
+                |row| {
+                    let build: String = row.get(0)?;
+                    let build = serde_json::from_str::<FinalLinkRecordRaw>(&build)
+                        .map_err(|_| rusqlite::Error::InvalidQuery)?;
+                    Ok(FinalLinkRecord::from_raw(&build))
+                },
+            )
+            .ok()
+    }
+
     fn _store_flat_table(
         &self,
         table_name: &str,
@@ -191,17 +636,67 @@ impl PersistentState {
     }
 }
 
+/// Records that are only read by ccelerate are stored in a compact binary format. The debug
+/// columns contain the same data as JSON.
+fn encode_record(record: &impl serde::Serialize) -> Result<Vec<u8>> {
+    Ok(postcard::to_stdvec(record)?)
+}
+
+fn decode_record<T: serde::de::DeserializeOwned>(value: rusqlite::types::ValueRef) -> Result<T> {
+    match value {
+        rusqlite::types::ValueRef::Blob(data) => Ok(postcard::from_bytes(data)?),
+        // Rows written by older versions contain JSON.
+        rusqlite::types::ValueRef::Text(data) => Ok(serde_json::from_slice(data)?),
+        _ => Err(anyhow::anyhow!(
+            "Unexpected record type: {:?}",
+            value.data_type()
+        )),
+    }
+}
+
+/// Files that ccelerate writes into the data directory are stored relative to it. That way the
+/// cache stays valid when the data directory is moved or copied to another machine. Project
+/// paths like the working directory and includes are stored as is, even if they happen to be in
+/// the data directory.
+fn store_path(path: &Path, data_dir: &Path) -> OsString {
+    path.strip_prefix(data_dir).unwrap_or(path).into()
+}
+
+/// Inverse of [`store_path`]. Older records contain absolute paths which are kept as is.
+fn load_path(stored: &OsStr, data_dir: &Path) -> PathBuf {
+    data_dir.join(stored)
+}
+
 #[derive(Debug, Clone)]
 pub struct ObjectData {
     pub path: PathBuf,
     pub create: CompileObjectRecord,
     pub local_code: ObjectLocalCodeRecord,
     pub last_build: chrono::DateTime<chrono::FixedOffset>,
+    // Name of the source file the local code was extracted from. Only used for display.
+    pub source_name: Option<String>,
+    // Set with the `pin-individual` command or a `materialize` config rule to never compile the
+    // object together with others.
+    pub pinned_individual: bool,
+    // Files that the local code was extracted from, at the time they were preprocessed.
+    pub input_stamps: Option<Vec<InputFileStamp>>,
+}
+
+/// Used to detect whether a file changed without reading it.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct InputFileStamp {
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified_nanos: u128,
+    // Allows detecting that a file is unchanged even if it was touched.
+    pub content_hash: Option<u128>,
 }
 
 #[derive(Debug, Clone)]
 pub struct CompileObjectRecord {
     pub binary: WrappedBinary,
+    // Stored in a separate column, see [`Executable`].
+    pub resolved_binary: Option<PathBuf>,
     pub cwd: PathBuf,
     pub args: Vec<OsString>,
 }
@@ -214,14 +709,29 @@ struct CompileObjectRecordRaw {
 #[derive(serde::Serialize)]
 struct CompileObjectRecordDebug {
     binary: WrappedBinary,
+    resolved_binary: Option<String>,
     cwd: String,
     args: Vec<String>,
 }
 
+impl ObjectData {
+    /// Source file that the object is compiled from, if it can still be determined.
+    pub fn source_path(&self) -> Option<PathBuf> {
+        args_processing::BuildObjectFileInfo::from_args(
+            self.create.binary,
+            &self.create.cwd,
+            &self.create.args,
+        )
+        .ok()
+        .map(|info| info.source_path)
+    }
+}
+
 impl CompileObjectRecord {
-    fn from_raw(raw: &CompileObjectRecordRaw) -> Self {
+    fn from_raw(raw: &CompileObjectRecordRaw, resolved_binary: Option<PathBuf>) -> Self {
         Self {
             binary: raw.binary,
+            resolved_binary,
             cwd: raw.cwd.clone().into(),
             args: raw.args.clone(),
         }
@@ -235,9 +745,17 @@ impl CompileObjectRecord {
         }
     }
 
+    pub fn executable(&self) -> Executable {
+        Executable::new(self.binary, self.resolved_binary.clone())
+    }
+
     fn to_debug(&self) -> CompileObjectRecordDebug {
         CompileObjectRecordDebug {
             binary: self.binary,
+            resolved_binary: self
+                .resolved_binary
+                .as_ref()
+                .map(|path| path.to_string_lossy().to_string()),
             cwd: self.cwd.to_string_lossy().to_string(),
             args: self
                 .args
@@ -268,9 +786,9 @@ struct ObjectLocalCodeRecordDebug {
 }
 
 impl ObjectLocalCodeRecord {
-    fn from_raw(raw: &ObjectLocalCodeRecordRaw) -> Self {
+    fn from_raw(raw: &ObjectLocalCodeRecordRaw, data_dir: &Path) -> Self {
         Self {
-            local_code_file: raw.local_code_file.clone().into(),
+            local_code_file: load_path(&raw.local_code_file, data_dir),
             direct_includes: raw
                 .direct_includes
                 .iter()
@@ -280,9 +798,9 @@ impl ObjectLocalCodeRecord {
         }
     }
 
-    fn to_raw(&self) -> ObjectLocalCodeRecordRaw {
+    fn to_raw(&self, data_dir: &Path) -> ObjectLocalCodeRecordRaw {
         ObjectLocalCodeRecordRaw {
-            local_code_file: self.local_code_file.clone().into(),
+            local_code_file: store_path(&self.local_code_file, data_dir),
             direct_includes: self
                 .direct_includes
                 .iter()
@@ -305,6 +823,45 @@ impl ObjectLocalCodeRecord {
     }
 }
 
+/// Everything that is extracted from preprocessing a source, independent of the object file.
+#[derive(Debug, Clone)]
+pub struct PreprocessResultRecord {
+    pub local_code: ObjectLocalCodeRecord,
+    // Headers that were included, at the time they were preprocessed. The source itself is
+    // identified by its content hash in the key.
+    pub include_stamps: Vec<InputFileStamp>,
+    pub preprocessor_stderr: Vec<u8>,
+    // Content of the depfile written by the preprocessor, if requested.
+    pub depfile: Option<Vec<u8>>,
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+struct PreprocessResultRecordRaw {
+    local_code: ObjectLocalCodeRecordRaw,
+    include_stamps: Vec<InputFileStamp>,
+    preprocessor_stderr: Vec<u8>,
+    depfile: Option<Vec<u8>>,
+}
+
+impl PreprocessResultRecord {
+    fn from_raw(raw: &PreprocessResultRecordRaw, data_dir: &Path) -> Self {
+        Self {
+            local_code: ObjectLocalCodeRecord::from_raw(&raw.local_code, data_dir),
+            include_stamps: raw.include_stamps.clone(),
+            preprocessor_stderr: raw.preprocessor_stderr.clone(),
+            depfile: raw.depfile.clone(),
+        }
+    }
+
+    fn to_raw(&self, data_dir: &Path) -> PreprocessResultRecordRaw {
+        PreprocessResultRecordRaw {
+            local_code: self.local_code.to_raw(data_dir),
+            include_stamps: self.include_stamps.clone(),
+            preprocessor_stderr: self.preprocessor_stderr.clone(),
+            depfile: self.depfile.clone(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct CreateArchiveRecord {
     pub cwd: PathBuf,
@@ -353,3 +910,302 @@ impl CreateArchiveRecord {
         }
     }
 }
+
+#[derive(Debug, Clone)]
+pub struct FinalLinkRecord {
+    pub cwd: PathBuf,
+    pub binary: WrappedBinary,
+    pub args: Vec<OsString>,
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+struct FinalLinkRecordRaw {
+    cwd: OsString,
+    binary: WrappedBinary,
+    args: Vec<OsString>,
+}
+#[derive(serde::Serialize)]
+struct FinalLinkRecordDebug {
+    cwd: String,
+    binary: WrappedBinary,
+    args: Vec<String>,
+}
+
+impl FinalLinkRecord {
+    fn from_raw(raw: &FinalLinkRecordRaw) -> Self {
+        Self {
+            cwd: raw.cwd.clone().into(),
+            binary: raw.binary,
+            args: raw.args.clone(),
+        }
+    }
+
+    fn to_raw(&self) -> FinalLinkRecordRaw {
+        FinalLinkRecordRaw {
+            cwd: self.cwd.clone().into(),
+            binary: self.binary,
+            args: self.args.clone(),
+        }
+    }
+
+    fn to_debug(&self) -> FinalLinkRecordDebug {
+        FinalLinkRecordDebug {
+            cwd: self.cwd.to_string_lossy().to_string(),
+            binary: self.binary,
+            args: self
+                .args
+                .iter()
+                .map(|s| s.to_string_lossy().to_string())
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TestEnv;
+
+    #[tokio::test]
+    async fn objects_older_than_cutoff() {
+        let env = TestEnv::new(&[]).await;
+        let persistent = &env.state.persistent;
+        let now = Utc::now();
+        let ages_in_days = [0, 1, 5, 10];
+        for days in ages_in_days {
+            let path = env.path(&format!("{}.o", days));
+            persistent
+                .update_object_file(
+                    &path,
+                    &Executable::new(WrappedBinary::Gcc, None),
+                    &env.path(""),
+                    ["-c", "foo.c", "-o", "foo.o"],
+                )
+                .expect("record can be stored");
+            persistent
+                .conn
+                .lock()
+                .execute(
+                    "UPDATE ObjectFiles SET last_build = ?1 WHERE path = ?2",
+                    rusqlite::params![
+                        (now - chrono::Duration::days(days)).to_rfc3339(),
+                        path.to_string_lossy()
+                    ],
+                )
+                .expect("record can be updated");
+        }
+        let older_than = |days: i64| {
+            let mut paths = persistent
+                .objects_older_than(now - chrono::Duration::days(days))
+                .expect("query succeeds");
+            paths.sort();
+            paths
+        };
+        assert_eq!(older_than(2), vec![env.path("10.o"), env.path("5.o")]);
+        assert_eq!(older_than(7), vec![env.path("10.o")]);
+        assert!(older_than(30).is_empty());
+        assert_eq!(older_than(-1).len(), ages_in_days.len());
+    }
+
+    #[tokio::test]
+    async fn truncated_database_is_moved_aside() {
+        let dir = tempfile::tempdir().expect("temp dir can be created");
+        let db_path = dir.path().join("ccelerate.db");
+        let cache_size = NonZeroUsize::MIN;
+        {
+            let persistent = PersistentState::new(&db_path, dir.path(), cache_size, false)
+                .await
+                .expect("database can be created");
+            persistent
+                .update_object_file(
+                    &dir.path().join("foo.o"),
+                    &Executable::new(WrappedBinary::Gcc, None),
+                    dir.path(),
+                    ["-c", "foo.c"],
+                )
+                .expect("record can be stored");
+            // Move everything from the WAL into the database file.
+            persistent
+                .conn
+                .lock()
+                .execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")
+                .expect("checkpoint succeeds");
+        }
+        let data = std::fs::read(&db_path).expect("database can be read");
+        std::fs::write(&db_path, &data[..data.len() / 3]).expect("database can be truncated");
+
+        // Without the flag, nothing is changed.
+        assert!(
+            PersistentState::new(&db_path, dir.path(), cache_size, false)
+                .await
+                .is_err()
+        );
+        assert_eq!(
+            std::fs::read(&db_path).expect("database can be read").len(),
+            data.len() / 3
+        );
+
+        let persistent = PersistentState::new(&db_path, dir.path(), cache_size, true)
+            .await
+            .expect("database is reset");
+        assert!(
+            persistent
+                .get_object_file(&dir.path().join("foo.o"))
+                .is_none()
+        );
+        let moved_files = std::fs::read_dir(dir.path())
+            .expect("directory can be read")
+            .map(|entry| {
+                entry
+                    .expect("entry can be read")
+                    .file_name()
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .filter(|name| name.contains(".corrupt-"))
+            .collect::<Vec<_>>();
+        // The WAL file is kept next to the database, because it may contain the latest changes.
+        assert!(
+            moved_files.iter().any(|name| name.ends_with("-wal")),
+            "{:?}",
+            moved_files
+        );
+        assert!(
+            moved_files
+                .iter()
+                .any(|name| !name.ends_with("-wal") && !name.ends_with("-shm")),
+            "{:?}",
+            moved_files
+        );
+    }
+
+    #[tokio::test]
+    async fn other_open_errors_dont_reset_the_database() {
+        let dir = tempfile::tempdir().expect("temp dir can be created");
+        // A directory can't be opened as database, but it's not corrupted either.
+        let db_path = dir.path().join("ccelerate.db");
+        std::fs::create_dir(&db_path).expect("directory can be created");
+        assert!(
+            PersistentState::new(&db_path, dir.path(), NonZeroUsize::MIN, true)
+                .await
+                .is_err()
+        );
+        assert!(db_path.is_dir());
+    }
+
+    #[tokio::test]
+    async fn records_stay_valid_when_data_dir_moves() {
+        let dir = tempfile::tempdir().expect("temp dir can be created");
+        let old_data_dir = dir.path().join("old");
+        let new_data_dir = dir.path().join("new");
+        let object_path = dir.path().join("project/foo.o");
+        let cache_size = NonZeroUsize::MIN;
+        {
+            let persistent = PersistentState::new(
+                &old_data_dir.join("ccelerate.db"),
+                &old_data_dir,
+                cache_size,
+                false,
+            )
+            .await
+            .expect("database can be created");
+            persistent
+                .update_object_file(
+                    &object_path,
+                    &Executable::new(WrappedBinary::Gcc, None),
+                    &dir.path().join("project"),
+                    ["-c", "foo.c", "-o", "foo.o"],
+                )
+                .expect("record can be stored");
+            persistent
+                .update_object_file_local_code(
+                    &object_path,
+                    &old_data_dir.join("local_code/ab/foo.i"),
+                    "foo.c",
+                    [dir.path().join("project/foo.h")],
+                    Vec::<&BStr>::new(),
+                    None,
+                )
+                .expect("local code can be stored");
+        }
+        std::fs::rename(&old_data_dir, &new_data_dir).expect("data dir can be moved");
+
+        let persistent = PersistentState::new(
+            &new_data_dir.join("ccelerate.db"),
+            &new_data_dir,
+            cache_size,
+            false,
+        )
+        .await
+        .expect("moved database can be opened");
+        let object = persistent
+            .get_object_file(&object_path)
+            .expect("record is still found");
+        assert_eq!(
+            object.local_code.local_code_file,
+            new_data_dir.join("local_code/ab/foo.i")
+        );
+        assert_eq!(object.create.cwd, dir.path().join("project"));
+        assert_eq!(
+            object.local_code.direct_includes,
+            vec![dir.path().join("project/foo.h")]
+        );
+    }
+
+    #[tokio::test]
+    async fn only_data_dir_files_are_stored_relative() {
+        let env = TestEnv::new(&[]).await;
+        let persistent = &env.state.persistent;
+        let data_dir = &env.state.data_dir;
+        // A project in the data directory must still be stored with absolute paths.
+        let project_dir = data_dir.join("project");
+        let object_path = project_dir.join("foo.o");
+        let local_code_file = data_dir.join("local_code/ab/foo.i");
+        persistent
+            .update_object_file(
+                &object_path,
+                &Executable::new(WrappedBinary::Gcc, None),
+                &project_dir,
+                ["-c", "foo.c", "-o", "foo.o"],
+            )
+            .expect("record can be stored");
+        persistent
+            .update_object_file_local_code(
+                &object_path,
+                &local_code_file,
+                "foo.c",
+                [project_dir.join("foo.h")],
+                Vec::<&BStr>::new(),
+                None,
+            )
+            .expect("local code can be stored");
+
+        let (build, local_code) = persistent
+            .conn
+            .lock()
+            .query_row(
+                "SELECT build, local_code FROM ObjectFiles WHERE path = ?1",
+                rusqlite::params![object_path.to_string_lossy()],
+                |row| {
+                    Ok((
+                        decode_record::<CompileObjectRecordRaw>(row.get_ref(0)?)
+                            .expect("build can be decoded"),
+                        decode_record::<ObjectLocalCodeRecordRaw>(row.get_ref(1)?)
+                            .expect("local code can be decoded"),
+                    ))
+                },
+            )
+            .expect("record exists");
+        assert_eq!(build.cwd, project_dir.as_os_str());
+        assert_eq!(local_code.local_code_file, "local_code/ab/foo.i");
+        assert_eq!(
+            local_code.direct_includes,
+            vec![project_dir.join("foo.h").into_os_string()]
+        );
+
+        let object = persistent
+            .get_object_file(&object_path)
+            .expect("record can be loaded");
+        assert_eq!(object.local_code.local_code_file, local_code_file);
+        assert_eq!(object.create.cwd, project_dir);
+    }
+}
@@ -1,14 +1,26 @@
 #![deny(clippy::unwrap_used)]
 
 use std::{
-    sync::Arc,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
     time::{Duration, Instant},
 };
 
 use parking_lot::Mutex;
 
+use crate::{CommandOutput, path_utils::ProjectRoots, request_id::RequestId};
+
+/// Outputs of failed tasks are kept in memory, so they are truncated if they are very large.
+const MAX_TASK_OUTPUT_SIZE: usize = 64 * 1024;
+
 pub struct TaskPeriods {
     tasks: Arc<Mutex<TaskPeriodsVec>>,
+    created: Instant,
+    events: tokio::sync::broadcast::Sender<TaskEvent>,
+    next_event_id: AtomicUsize,
+    project_roots: Arc<ProjectRoots>,
 }
 
 struct TaskPeriodsVec {
@@ -18,14 +30,21 @@ struct TaskPeriodsVec {
 
 struct TaskPeriodStorage {
     info: Box<dyn TaskPeriodInfo>,
-    start_time: Instant,
+    request_id: Option<RequestId>,
+    queue_time: Instant,
+    /// Not set while the task is still waiting for its turn.
+    start_time: Arc<Mutex<Option<Instant>>>,
     end_time: Arc<Mutex<Option<Instant>>>,
     finished_successfully: Arc<Mutex<bool>>,
+    output: Arc<Mutex<Option<Arc<TaskOutput>>>>,
 }
 
 pub trait TaskPeriodInfo: Send + Sync {
     fn category(&self) -> String;
-    fn terminal_one_liner(&self) -> String;
+    /// Paths should be shortened with the given roots.
+    fn terminal_one_liner(&self, project_roots: &ProjectRoots) -> String;
+    /// Only called when debug logging is enabled. Otherwise, just the category and one-liner
+    /// are logged.
     fn log_detailed(&self);
 }
 
@@ -33,44 +52,148 @@ pub trait TaskPeriodInfo: Send + Sync {
 pub struct TaskPeriod {
     pub category: String,
     pub name: String,
+    pub request_id: Option<RequestId>,
     pub start: Instant,
     pub duration: Duration,
     pub active: bool,
+    pub queued: bool,
     pub finished_successfully: bool,
+    pub output: Option<Arc<TaskOutput>>,
+}
+
+/// Output of a command that failed as part of a task.
+#[derive(Debug)]
+pub struct TaskOutput {
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Published when a task starts or finishes. It's serialized as JSON like this:
+///
+/// ```json
+/// {
+///   "kind": "start" | "finish",
+///   "id": 42,                    // Same for the start and finish event of a task.
+///   "category": "Compile",
+///   "name": "foo.c bar.c",
+///   "request_id": "..." | null,  // Request the task belongs to.
+///   "start": 1.25,               // Seconds since the server started.
+///   "duration": 0.5 | null,      // Seconds, only set when finished.
+///   "success": true | null       // Only set when finished.
+/// }
+/// ```
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct TaskEvent {
+    pub kind: TaskEventKind,
+    pub id: usize,
+    pub category: String,
+    pub name: String,
+    pub request_id: Option<String>,
+    pub start: f64,
+    pub duration: Option<f64>,
+    pub success: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskEventKind {
+    Start,
+    Finish,
 }
 
 pub struct TaskPeriodScope {
     end_time: Arc<Mutex<Option<Instant>>>,
     finished_successfully: Arc<Mutex<bool>>,
+    output: Arc<Mutex<Option<Arc<TaskOutput>>>>,
+    // Only set once the task started.
+    start_event: Option<TaskEvent>,
+    events: tokio::sync::broadcast::Sender<TaskEvent>,
+    created: Instant,
+}
+
+/// A task that is known already but waits for e.g. a pool permit before it can start.
+pub struct QueuedTaskPeriod {
+    start_time: Arc<Mutex<Option<Instant>>>,
+    start_event: TaskEvent,
+    scope: TaskPeriodScope,
 }
 
 impl TaskPeriods {
-    pub fn new() -> Self {
+    pub fn new(project_roots: Arc<ProjectRoots>) -> Self {
         Self {
             tasks: Arc::new(Mutex::new(TaskPeriodsVec {
                 tasks: vec![],
                 final_sorted_num: 0,
             })),
+            created: Instant::now(),
+            // Slow subscribers miss events instead of slowing down the server.
+            events: tokio::sync::broadcast::channel(1024).0,
+            next_event_id: AtomicUsize::new(0),
+            project_roots,
         }
     }
 
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<TaskEvent> {
+        self.events.subscribe()
+    }
+
     pub fn start<Info: TaskPeriodInfo + 'static + Send + Sync>(
         &self,
         info: Info,
     ) -> TaskPeriodScope {
+        self.queue(info).start()
+    }
+
+    /// Records the task without starting it yet. It's started with [`QueuedTaskPeriod::start`].
+    pub fn queue<Info: TaskPeriodInfo + 'static + Send + Sync>(
+        &self,
+        info: Info,
+    ) -> QueuedTaskPeriod {
+        let start_time = Arc::new(Mutex::new(None));
         let end_time = Arc::new(Mutex::new(None));
         let finished_successfully = Arc::new(Mutex::new(false));
-        info.log_detailed();
+        let output = Arc::new(Mutex::new(None));
+        if log::log_enabled!(log::Level::Debug) {
+            info.log_detailed();
+        } else {
+            log::info!(
+                "{}: {}",
+                info.category(),
+                info.terminal_one_liner(&self.project_roots)
+            );
+        }
+        let request_id = RequestId::current();
+        let start_event = TaskEvent {
+            kind: TaskEventKind::Start,
+            id: self.next_event_id.fetch_add(1, Ordering::Relaxed),
+            category: info.category(),
+            name: info.terminal_one_liner(&self.project_roots),
+            request_id: request_id.map(|id| id.to_string()),
+            start: 0.0,
+            duration: None,
+            success: None,
+        };
         let task = TaskPeriodStorage {
             info: Box::new(info),
-            start_time: Instant::now(),
+            request_id,
+            queue_time: Instant::now(),
+            start_time: start_time.clone(),
             end_time: end_time.clone(),
             finished_successfully: finished_successfully.clone(),
+            output: output.clone(),
         };
         self.tasks.lock().tasks.push(task);
-        TaskPeriodScope {
-            end_time,
-            finished_successfully,
+        QueuedTaskPeriod {
+            start_time,
+            start_event,
+            scope: TaskPeriodScope {
+                end_time,
+                finished_successfully,
+                output,
+                start_event: None,
+                events: self.events.clone(),
+                created: self.created,
+            },
         }
     }
 
@@ -83,6 +206,7 @@ impl TaskPeriods {
             let duration = t.duration();
             (
                 is_running,
+                !t.is_queued(),
                 if is_running { duration } else { Duration::ZERO },
             )
         });
@@ -95,11 +219,14 @@ impl TaskPeriods {
             .iter()
             .map(|t| TaskPeriod {
                 category: t.info.category(),
-                name: t.info.terminal_one_liner(),
-                start: t.start_time,
+                name: t.info.terminal_one_liner(&self.project_roots),
+                request_id: t.request_id,
+                start: t.start_time(),
                 duration: t.duration(),
-                active: t.is_running(),
+                active: t.is_running() && !t.is_queued(),
+                queued: t.is_queued(),
                 finished_successfully: *t.finished_successfully.lock(),
+                output: t.output.lock().clone(),
             })
             .collect()
     }
@@ -114,11 +241,33 @@ impl TaskPeriodStorage {
         self.end_time.lock().is_none()
     }
 
+    fn is_queued(&self) -> bool {
+        self.start_time.lock().is_none() && self.is_running()
+    }
+
+    /// Tasks that are still queued (or never started) use the time they were queued.
+    fn start_time(&self) -> Instant {
+        self.start_time.lock().unwrap_or(self.queue_time)
+    }
+
     fn duration(&self) -> Duration {
         self.end_time
             .lock()
             .unwrap_or_else(Instant::now)
-            .duration_since(self.start_time)
+            .duration_since(self.start_time())
+    }
+}
+
+impl QueuedTaskPeriod {
+    pub fn start(mut self) -> TaskPeriodScope {
+        let now = Instant::now();
+        *self.start_time.lock() = Some(now);
+        let mut start_event = self.start_event;
+        start_event.start = now.duration_since(self.scope.created).as_secs_f64();
+        // Sending only fails when there are no subscribers.
+        let _ = self.scope.events.send(start_event.clone());
+        self.scope.start_event = Some(start_event);
+        self.scope
     }
 }
 
@@ -126,10 +275,117 @@ impl TaskPeriodScope {
     pub fn finished_successfully(&self) {
         *self.finished_successfully.lock() = true;
     }
+
+    /// Keeps the output of a failed command so that it can be inspected in the terminal ui.
+    pub fn failed_with_output(&self, output: &CommandOutput) {
+        *self.output.lock() = Some(Arc::new(TaskOutput {
+            stdout: truncated_output(&output.stdout),
+            stderr: truncated_output(&output.stderr),
+        }));
+    }
+}
+
+fn truncated_output(output: &[u8]) -> String {
+    if output.len() <= MAX_TASK_OUTPUT_SIZE {
+        return String::from_utf8_lossy(output).to_string();
+    }
+    format!(
+        "{}\n[{} more bytes truncated]",
+        String::from_utf8_lossy(&output[..MAX_TASK_OUTPUT_SIZE]),
+        output.len() - MAX_TASK_OUTPUT_SIZE
+    )
 }
 
 impl Drop for TaskPeriodScope {
     fn drop(&mut self) {
-        *self.end_time.lock() = Some(Instant::now());
+        let now = Instant::now();
+        *self.end_time.lock() = Some(now);
+        if let Some(start_event) = self.start_event.take() {
+            let success = *self.finished_successfully.lock();
+            if !success {
+                log::warn!("{} failed: {}", start_event.category, start_event.name);
+            }
+            let duration = now.duration_since(self.created).as_secs_f64() - start_event.start;
+            let _ = self.events.send(TaskEvent {
+                kind: TaskEventKind::Finish,
+                duration: Some(duration),
+                success: Some(success),
+                ..start_event
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Keeps all log messages, so that tests can check what was logged.
+    struct CapturingLogger {
+        records: Mutex<Vec<(log::Level, String)>>,
+    }
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            self.records
+                .lock()
+                .push((record.level(), record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CapturingLogger = CapturingLogger {
+        records: Mutex::new(Vec::new()),
+    };
+
+    struct NamedTaskInfo {
+        name: &'static str,
+    }
+
+    impl TaskPeriodInfo for NamedTaskInfo {
+        fn category(&self) -> String {
+            "Test".to_string()
+        }
+
+        fn terminal_one_liner(&self, _project_roots: &ProjectRoots) -> String {
+            self.name.to_string()
+        }
+
+        fn log_detailed(&self) {}
+    }
+
+    #[test]
+    fn failures_are_logged_at_warn() {
+        // Other tests may log at the same time, so only messages about these tasks are checked.
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Warn);
+        let periods = TaskPeriods::new(Arc::new(ProjectRoots::default()));
+        periods
+            .start(NamedTaskInfo {
+                name: "warn-test-success",
+            })
+            .finished_successfully();
+        drop(periods.start(NamedTaskInfo {
+            name: "warn-test-failure",
+        }));
+        let records = LOGGER
+            .records
+            .lock()
+            .iter()
+            .filter(|(_, message)| message.contains("warn-test-"))
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            records,
+            [(
+                log::Level::Warn,
+                "Test failed: warn-test-failure".to_string()
+            )]
+        );
     }
 }
@@ -0,0 +1,107 @@
+#![deny(clippy::unwrap_used)]
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use ccelerate_shared::{RunRequestData, WrappedBinary};
+
+use crate::{Cli, CommandOutput, state::State};
+
+/// A server state with its own data directory and a project directory that requests run in.
+pub struct TestEnv {
+    pub dir: tempfile::TempDir,
+    pub state: Arc<State>,
+}
+
+impl TestEnv {
+    /// `args` are passed to the server like on the command line.
+    pub async fn new(args: &[&str]) -> Self {
+        let dir = tempfile::tempdir().expect("temp dir can be created");
+        let state = Self::create_state(dir.path(), args).await;
+        Self { dir, state }
+    }
+
+    /// Replaces the state with a new one that uses the same directories, like when the server
+    /// is restarted.
+    pub async fn restart(&mut self, args: &[&str]) {
+        self.state = Self::create_state(self.dir.path(), args).await;
+    }
+
+    async fn create_state(dir: &Path, args: &[&str]) -> Arc<State> {
+        let data_dir = dir.join("data");
+        let cli: Cli = clap::Parser::parse_from(
+            ["ccelerate_server", "--no-tui", "--data-dir"]
+                .into_iter()
+                .map(Into::into)
+                .chain([data_dir.clone().into_os_string()])
+                .chain(args.iter().map(Into::into)),
+        );
+        Arc::new(
+            State::new(cli, data_dir)
+                .await
+                .expect("state can be created"),
+        )
+    }
+
+    pub fn path(&self, relative: &str) -> PathBuf {
+        self.dir.path().join("project").join(relative)
+    }
+
+    pub fn write(&self, relative: &str, content: &str) -> PathBuf {
+        let path = self.path(relative);
+        std::fs::create_dir_all(path.parent().expect("path has a parent"))
+            .expect("directory can be created");
+        std::fs::write(&path, content).expect("file can be written");
+        path
+    }
+
+    /// Writes a script that can be run like a compiler.
+    #[cfg(unix)]
+    pub fn write_executable(&self, relative: &str, content: &str) -> PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = self.write(relative, content);
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))
+            .expect("permissions can be set");
+        path
+    }
+
+    pub fn request(&self, binary: WrappedBinary, args: &[&str]) -> RunRequestData {
+        let cwd = self.path("");
+        std::fs::create_dir_all(&cwd).expect("directory can be created");
+        RunRequestData {
+            binary,
+            args: args.iter().map(Into::into).collect(),
+            cwd,
+            accepts_compression: false,
+            client_is_tty: false,
+            make_jobs: None,
+            resolved_binary: None,
+        }
+    }
+
+    /// Handles a request like the server does, but without sending it over the network.
+    pub async fn run(&self, binary: WrappedBinary, args: &[&str]) -> CommandOutput {
+        CommandOutput::from_result(
+            crate::handle_request(&self.request(binary, args), &self.state).await,
+        )
+    }
+
+    /// Like [`Self::run`] but fails the test if the command fails.
+    pub async fn run_ok(&self, binary: WrappedBinary, args: &[&str]) -> CommandOutput {
+        let output = self.run(binary, args).await;
+        assert_eq!(output.status, 0, "{:?} failed: {}", args, output);
+        output
+    }
+
+    /// Runs an executable that was built in the project directory.
+    pub fn run_program(&self, relative: &str) -> String {
+        let output = std::process::Command::new(self.path(relative))
+            .output()
+            .expect("program can be started");
+        assert!(output.status.success(), "{} failed", relative);
+        String::from_utf8_lossy(&output.stdout).to_string()
+    }
+}
@@ -1,32 +1,58 @@
 #![deny(clippy::unwrap_used)]
 
-use std::sync::Arc;
+use std::sync::{Arc, atomic::Ordering};
 
 use anyhow::Result;
 use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers};
 use ratatui::{
-    layout::Layout,
+    layout::{Layout, Margin},
     style::{Color, Style, Stylize},
+    widgets::{Block, Clear, Paragraph},
 };
 
 use crate::{State, export_trace};
 
+/// Shows the captured output of a failed task.
+struct OutputPopup {
+    title: String,
+    text: String,
+    scroll: u16,
+}
+
 pub async fn run_tui(state: &Arc<State>) -> Result<()> {
     let mut terminal = ratatui::init();
 
     let start_instant = std::time::Instant::now();
+    let mut output_popup: Option<OutputPopup> = None;
 
     loop {
+        if state.server_stopped.load(Ordering::Relaxed) {
+            break;
+        }
         if *state.auto_scroll.lock() {
             state.tasks_table_state.lock().select_last();
         }
         terminal
             .draw(|frame| {
-                draw_terminal(frame, state);
+                draw_terminal(frame, state, output_popup.as_ref());
             })
             .expect("failed to draw terminal");
         if crossterm::event::poll(std::time::Duration::from_millis(100))? {
-            match crossterm::event::read()? {
+            let event = crossterm::event::read()?;
+            if let Some(popup) = &mut output_popup {
+                if let Event::Key(key) = event {
+                    match key.code {
+                        KeyCode::Up => popup.scroll = popup.scroll.saturating_sub(1),
+                        KeyCode::Down => popup.scroll = popup.scroll.saturating_add(1),
+                        KeyCode::PageUp => popup.scroll = popup.scroll.saturating_sub(20),
+                        KeyCode::PageDown => popup.scroll = popup.scroll.saturating_add(20),
+                        KeyCode::Esc | KeyCode::Enter | KeyCode::Char('q') => output_popup = None,
+                        _ => {}
+                    }
+                }
+                continue;
+            }
+            match event {
                 Event::Key(KeyEvent {
                     code: KeyCode::Char('q'),
                     ..
@@ -76,6 +102,12 @@ pub async fn run_tui(state: &Arc<State>) -> Result<()> {
                     let save_path = state.data_dir.join("tasks.json");
                     export_trace::export(&save_path, &state.task_periods, start_instant).await?;
                 }
+                Event::Key(KeyEvent {
+                    code: KeyCode::Enter,
+                    ..
+                }) => {
+                    output_popup = selected_task_output(state);
+                }
                 _ => {}
             }
         }
@@ -84,7 +116,29 @@ pub async fn run_tui(state: &Arc<State>) -> Result<()> {
     Ok(())
 }
 
-fn draw_terminal(frame: &mut ratatui::Frame, state: &Arc<State>) {
+fn selected_task_output(state: &State) -> Option<OutputPopup> {
+    let selected = state.tasks_table_state.lock().selected()?;
+    let tasks = state.task_periods.get_sorted_periods();
+    let task = tasks.get(selected)?;
+    let output = task.output.as_ref()?;
+    let mut text = String::new();
+    for (name, content) in [("stderr", &output.stderr), ("stdout", &output.stdout)] {
+        if !content.is_empty() {
+            text.push_str(&format!("{}:\n{}\n", name, content));
+        }
+    }
+    Some(OutputPopup {
+        title: format!(" {}: {} ", task.category, task.name),
+        text,
+        scroll: 0,
+    })
+}
+
+fn draw_terminal(
+    frame: &mut ratatui::Frame,
+    state: &Arc<State>,
+    output_popup: Option<&OutputPopup>,
+) {
     use ratatui::layout::Constraint::*;
 
     let tasks: Vec<crate::task_periods::TaskPeriod> = state.task_periods.get_sorted_periods();
@@ -93,12 +147,21 @@ fn draw_terminal(frame: &mut ratatui::Frame, state: &Arc<State>) {
 
     let vertical = Layout::vertical([Length(1), Min(0)]);
     let [title_area, main_area] = vertical.areas(frame.area());
-    let text = ratatui::text::Text::raw(format!("ccelerate_server at http://{}", state.address));
+    let pool_stats = state.pool.stats();
+    let text = ratatui::text::Text::raw(format!(
+        "ccelerate_server at http://{} | {} | {}/{} jobs running, {} waiting",
+        state.address,
+        state.build_progress.snapshot().summary(),
+        pool_stats.in_use,
+        pool_stats.limit,
+        pool_stats.waiting
+    ));
     frame.render_widget(text, title_area);
 
     let success_style = Style::new().fg(Color::Green);
     let fail_style = Style::new().fg(Color::Red);
     let not_done_style = Style::new().fg(Color::Blue);
+    let queued_style = Style::new().fg(Color::DarkGray);
 
     let mut table = ratatui::widgets::Table::new(
         tasks.iter().map(|t| {
@@ -107,7 +170,9 @@ fn draw_terminal(frame: &mut ratatui::Frame, state: &Arc<State>) {
                 ratatui::text::Text::raw(&t.category),
                 ratatui::text::Text::raw(&t.name),
             ])
-            .style(if t.active {
+            .style(if t.queued {
+                queued_style
+            } else if t.active {
                 not_done_style
             } else if t.finished_successfully {
                 success_style
@@ -122,4 +187,18 @@ fn draw_terminal(frame: &mut ratatui::Frame, state: &Arc<State>) {
     }
 
     frame.render_stateful_widget(table, main_area, &mut tasks_table_state);
+
+    if let Some(popup) = output_popup {
+        let popup_area = main_area.inner(Margin {
+            horizontal: 4,
+            vertical: 2,
+        });
+        frame.render_widget(Clear, popup_area);
+        frame.render_widget(
+            Paragraph::new(popup.text.as_str())
+                .block(Block::bordered().title(popup.title.as_str()))
+                .scroll((popup.scroll, 0)),
+            popup_area,
+        );
+    }
 }
@@ -1,71 +1,529 @@
 #![deny(clippy::unwrap_used)]
 
-use anyhow::Result;
-use bstr::{BString, ByteSlice};
-use ccelerate_shared::WrappedBinary;
+use anyhow::{Result, anyhow};
+use bstr::{BString, ByteSlice, ByteVec};
 use std::{
-    ffi::OsStr,
+    ffi::{OsStr, OsString},
     path::{Path, PathBuf},
     sync::Arc,
+    time::SystemTime,
 };
 
 use crate::{
-    CommandOutput, State, args_processing,
+    CommandOutput, State, args_processing, child_process,
+    compile_commands::CompileCommand,
     config::Config,
-    local_code::LocalCode,
-    path_utils::{self, shorten_path},
-    task_periods::TaskPeriodInfo,
+    defined_symbols,
+    executable::Executable,
+    local_code::{LinemarkerFlavor, LocalCode},
+    path_utils::{self, ProjectRoots},
+    placeholders,
+    state_persistent::{InputFileStamp, ObjectData, PreprocessResultRecord},
+    task_periods::{TaskPeriodInfo, TaskPeriodScope},
 };
 
 pub async fn wrap_compile_object_file(
-    binary: WrappedBinary,
+    executable: &Executable,
     args: &[impl AsRef<OsStr>],
     cwd: &Path,
     state: &Arc<State>,
     config: &Arc<Config>,
 ) -> Result<CommandOutput> {
-    state
+    let args_info = args_processing::BuildObjectFileInfo::from_args(executable.binary, cwd, args)?;
+    if let Some(object) = get_unchanged_object(executable, args, cwd, state, &args_info).await {
+        record_preprocess_cache_hit(state, &args_info, &object.local_code.local_code_file).await;
+        let task_period = state.task_periods.start(ReuseLocalCodeTaskInfo {
+            dst_object_file: args_info.object_path.clone(),
+        });
+        // The object file is written again because the build system expects it to be newer
+        // than the source. Preprocessor warnings are not reported again.
+        write_dummy_object_file(&args_info.object_path).await?;
+        if let Some(compilation_database_path) = &args_info.compilation_database_path {
+            CompileCommand::from_build(executable.binary, cwd, args, &args_info)?
+                .write_fragment(compilation_database_path)
+                .await?;
+        }
+        task_period.finished_successfully();
+        return Ok(CommandOutput::new_ok());
+    }
+    // Without a key (e.g. for unsupported arguments), the result is just not cached.
+    let preprocess_key =
+        get_preprocess_result_key(executable, args, cwd, state, config, &args_info)
+            .await
+            .ok();
+    if let Some(preprocess_key) = &preprocess_key
+        && let Some(result) = get_valid_preprocess_result(&preprocess_key.key, state).await
+    {
+        record_preprocess_cache_hit(state, &args_info, &result.local_code.local_code_file).await;
+        let task_period = state.task_periods.start(ReusePreprocessResultTaskInfo {
+            dst_object_file: args_info.object_path.clone(),
+        });
+        let output = reuse_preprocess_result(
+            executable,
+            args,
+            cwd,
+            state,
+            &args_info,
+            preprocess_key,
+            result,
+        )
+        .await?;
+        task_period.finished_successfully();
+        return Ok(output);
+    }
+    state.preprocess_cache_stats.record_miss();
+    let preprocess_period = state.task_periods.queue(PreprocessTranslationUnitTaskInfo {
+        dst_object_file: args_info.object_path.clone(),
+    });
+    let output = state
         .pool
-        .run_local(async move || {
-            wrap_compile_object_file_impl(binary, args, cwd, state, config).await
+        .run_local(async || {
+            wrap_compile_object_file_impl(
+                executable,
+                args,
+                cwd,
+                state,
+                config,
+                &args_info,
+                preprocess_period.start(),
+            )
+            .await
         })
+        .await?;
+    if let Some(preprocess_key) = &preprocess_key {
+        store_preprocess_result(state, &args_info, preprocess_key, &output).await?;
+    }
+    Ok(output)
+}
+
+/// Handles commands that compile multiple sources at once by compiling every source on its
+/// own. The outputs are combined like the compiler would report them.
+pub async fn wrap_compile_object_files(
+    executable: &Executable,
+    args: &[impl AsRef<OsStr>],
+    cwd: &Path,
+    state: &Arc<State>,
+    config: &Arc<Config>,
+) -> Result<CommandOutput> {
+    let info = args_processing::BuildObjectFilesInfo::from_args(executable.binary, args)?;
+    let outputs = futures::future::join_all(
+        info.single_source_args
+            .iter()
+            .map(|args| wrap_compile_object_file(executable, args, cwd, state, config)),
+    )
+    .await;
+    let mut result = CommandOutput::new_ok();
+    for output in outputs {
+        let output = CommandOutput::from_result(output);
+        result.stdout.extend(output.stdout);
+        result.stderr.extend(output.stderr);
+        if result.status == 0 {
+            result.status = output.status;
+        }
+    }
+    Ok(result)
+}
+
+/// Extracts the local code of an object file again from its recorded command. The caller is
+/// expected to already run in the pool.
+pub async fn recompute_local_code(
+    object: &ObjectData,
+    state: &Arc<State>,
+    config: &Config,
+) -> Result<()> {
+    let create = &object.create;
+    let args_info =
+        args_processing::BuildObjectFileInfo::from_args(create.binary, &create.cwd, &create.args)?;
+    let preprocess_period = state.task_periods.start(PreprocessTranslationUnitTaskInfo {
+        dst_object_file: args_info.object_path.clone(),
+    });
+    wrap_compile_object_file_impl(
+        &create.executable(),
+        &create.args,
+        &create.cwd,
+        state,
+        config,
+        &args_info,
+        preprocess_period,
+    )
+    .await?;
+    Ok(())
+}
+
+async fn record_preprocess_cache_hit(
+    state: &Arc<State>,
+    args_info: &args_processing::BuildObjectFileInfo,
+    local_code_file: &Path,
+) {
+    let size = tokio::fs::metadata(local_code_file)
+        .await
+        .map(|metadata| metadata.len())
+        .unwrap_or(0);
+    state.preprocess_cache_stats.record_hit(size);
+    log::debug!(
+        "Preprocess cache hit: {}",
+        args_info.source_path.to_string_lossy()
+    );
+}
+
+/// Returns the object record if the object file was recorded with the same command before and
+/// none of the files that were preprocessed changed since then. The existing local code can be
+/// reused then.
+async fn get_unchanged_object(
+    executable: &Executable,
+    args: &[impl AsRef<OsStr>],
+    cwd: &Path,
+    state: &Arc<State>,
+    args_info: &args_processing::BuildObjectFileInfo,
+) -> Option<Arc<ObjectData>> {
+    let object = state.persistent.get_object_file(&args_info.object_path)?;
+    let input_stamps = object.input_stamps.as_ref()?;
+    if let Some(depfile) = &args_info.depfile
+        && !depfile.path.exists()
+    {
+        // The depfile is only written by the preprocessor. Build systems like Ninja remove it
+        // after reading it.
+        return None;
+    }
+    if object.create.executable() != *executable
+        || object.create.cwd != cwd
+        || !object
+            .create
+            .args
+            .iter()
+            .map(|arg| arg.as_os_str())
+            .eq(args.iter().map(|arg| arg.as_ref()))
+    {
+        return None;
+    }
+    let mut current_stamps = Vec::with_capacity(input_stamps.len());
+    let mut touched = false;
+    for stamp in input_stamps {
+        let mut current = get_input_stamp(&stamp.path).await.ok()?;
+        if current.size != stamp.size {
+            return None;
+        }
+        if current.modified_nanos != stamp.modified_nanos {
+            // Files are often touched without being changed, e.g. when switching git branches.
+            let content_hash = stamp.content_hash?;
+            let content = tokio::fs::read(&stamp.path).await.ok()?;
+            if twox_hash::XxHash3_128::oneshot(&content) != content_hash {
+                return None;
+            }
+            touched = true;
+        }
+        current.content_hash = stamp.content_hash;
+        current_stamps.push(current);
+    }
+    if !local_code_file_is_intact(&object.local_code.local_code_file)
+        .await
+        .unwrap_or(false)
+    {
+        return None;
+    }
+    if touched {
+        // Avoid reading the touched files again next time.
+        let _ = state
+            .persistent
+            .update_object_file_input_stamps(&object.path, &current_stamps);
+    }
+    Some(object)
+}
+
+struct PreprocessResultKey {
+    key: String,
+    source_hash: u128,
+    source_hashed_at: SystemTime,
+}
+
+/// Preprocessing gives the same result if the compiler, config, source content, included
+/// headers and relevant arguments are the same. Headers are checked with their stamps that are
+/// stored in the result.
+async fn get_preprocess_result_key(
+    executable: &Executable,
+    args: &[impl AsRef<OsStr>],
+    cwd: &Path,
+    state: &Arc<State>,
+    config: &Config,
+    args_info: &args_processing::BuildObjectFileInfo,
+) -> Result<PreprocessResultKey> {
+    let source_hashed_at = SystemTime::now();
+    let source_content = tokio::fs::read(&args_info.source_path).await?;
+    let source_hash = twox_hash::XxHash3_128::oneshot(&source_content);
+    let mut key = BString::default();
+    for part in [
+        executable
+            .binary
+            .to_standard_binary_name()
+            .as_encoded_bytes(),
+        state
+            .compiler_versions
+            .get(&preprocess_executable(executable, config, args_info).program(config))
+            .await?
+            .as_bytes(),
+        format!("{:032x}", config.fingerprint()).as_bytes(),
+        cwd.as_os_str().as_encoded_bytes(),
+        args_info.source_path.as_os_str().as_encoded_bytes(),
+        format!("{:032x}", source_hash).as_bytes(),
+        // The target is part of the depfile content.
+        args_info
+            .depfile
+            .as_ref()
+            .map(|depfile| depfile.target.as_encoded_bytes())
+            .unwrap_or_default(),
+    ] {
+        key.push_str(part);
+        key.push(0);
+    }
+    args_processing::add_preprocess_args_to_key(executable.binary, args, &mut key)?;
+    Ok(PreprocessResultKey {
+        key: content_hash(&key),
+        source_hash,
+        source_hashed_at,
+    })
+}
+
+/// Stores what preprocessing the source resulted in, based on the object file record.
+async fn store_preprocess_result(
+    state: &Arc<State>,
+    args_info: &args_processing::BuildObjectFileInfo,
+    preprocess_key: &PreprocessResultKey,
+    output: &CommandOutput,
+) -> Result<()> {
+    let Some(object) = state.persistent.get_object_file(&args_info.object_path) else {
+        return Ok(());
+    };
+    let Some(input_stamps) = &object.input_stamps else {
+        return Ok(());
+    };
+    if input_stamps[0].content_hash != Some(preprocess_key.source_hash) {
+        // The source changed since the key was created.
+        return Ok(());
+    }
+    let depfile = match &args_info.depfile {
+        Some(depfile) => Some(tokio::fs::read(&depfile.path).await?),
+        None => None,
+    };
+    state.persistent.update_preprocess_result(
+        &preprocess_key.key,
+        &PreprocessResultRecord {
+            local_code: object.local_code.clone(),
+            include_stamps: input_stamps[1..].to_vec(),
+            preprocessor_stderr: output.stderr.clone(),
+            depfile,
+        },
+    )
+}
+
+async fn get_valid_preprocess_result(
+    key: &str,
+    state: &Arc<State>,
+) -> Option<PreprocessResultRecord> {
+    let result = state.persistent.get_preprocess_result(key)?;
+    for stamp in &result.include_stamps {
+        let current = get_input_stamp(&stamp.path).await.ok()?;
+        if current.size != stamp.size || current.modified_nanos != stamp.modified_nanos {
+            return None;
+        }
+    }
+    if !local_code_file_is_intact(&result.local_code.local_code_file)
         .await
+        .unwrap_or(false)
+    {
+        return None;
+    }
+    Some(result)
+}
+
+/// Does everything that preprocessing the source would do, but with the stored result.
+async fn reuse_preprocess_result(
+    executable: &Executable,
+    args: &[impl AsRef<OsStr>],
+    cwd: &Path,
+    state: &Arc<State>,
+    args_info: &args_processing::BuildObjectFileInfo,
+    preprocess_key: &PreprocessResultKey,
+    result: PreprocessResultRecord,
+) -> Result<CommandOutput> {
+    if let Some(depfile) = &args_info.depfile {
+        let content = result
+            .depfile
+            .as_ref()
+            .ok_or_else(|| anyhow!("The stored preprocess result has no depfile"))?;
+        path_utils::ensure_directory_and_write(&depfile.path, content).await?;
+    }
+    let local_code_path = &result.local_code.local_code_file;
+    if state.cli.verify_chunks {
+        defined_symbols::record_defined_symbols(
+            executable.binary,
+            &executable.program(&state.config_manager.current()),
+            args,
+            cwd,
+            &args_info.source_path,
+            local_code_path,
+            &state.cli.compiler_launcher,
+        )
+        .await?;
+    }
+    write_dummy_object_file(&args_info.object_path).await?;
+    if let Some(compilation_database_path) = &args_info.compilation_database_path {
+        CompileCommand::from_build(executable.binary, cwd, args, args_info)?
+            .write_fragment(compilation_database_path)
+            .await?;
+    }
+
+    let mut source_stamp = get_input_stamp(&args_info.source_path).await?;
+    source_stamp.content_hash = Some(preprocess_key.source_hash);
+    let source_hashed_at_nanos = preprocess_key
+        .source_hashed_at
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_nanos();
+    // The source may have changed after it was hashed for the key.
+    let input_stamps = (source_stamp.modified_nanos < source_hashed_at_nanos).then(|| {
+        std::iter::once(source_stamp)
+            .chain(result.include_stamps.iter().cloned())
+            .collect::<Vec<_>>()
+    });
+    state
+        .persistent
+        .update_object_file(&args_info.object_path, executable, cwd, args)?;
+    state.persistent.update_object_file_local_code(
+        &args_info.object_path,
+        local_code_path,
+        &source_name(args_info),
+        &result.local_code.direct_includes,
+        &result.local_code.include_defines,
+        input_stamps.as_deref(),
+    )?;
+    Ok(CommandOutput {
+        stderr: result.preprocessor_stderr,
+        ..CommandOutput::new_ok()
+    })
+}
+
+pub async fn get_input_stamp(path: &Path) -> Result<InputFileStamp> {
+    let metadata = tokio::fs::metadata(path).await?;
+    Ok(InputFileStamp {
+        path: path.to_owned(),
+        size: metadata.len(),
+        modified_nanos: metadata
+            .modified()?
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_nanos(),
+        content_hash: None,
+    })
+}
+
+/// Returns `None` if any of the files changed after preprocessing started, because it's unknown
+/// which version was preprocessed then.
+async fn get_input_stamps(
+    args_info: &args_processing::BuildObjectFileInfo,
+    local_code: &LocalCode,
+    preprocess_start: SystemTime,
+) -> Result<Option<Vec<InputFileStamp>>> {
+    let preprocess_start_nanos = preprocess_start
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_nanos();
+    // Only the source is hashed, because reading all headers again would be too costly. It's
+    // read before the stamps are created, so that a change in between is detected below.
+    let source_content = tokio::fs::read(&args_info.source_path).await?;
+    let source_hash = twox_hash::XxHash3_128::oneshot(&source_content);
+    let mut stamps = vec![];
+    for path in std::iter::once(&args_info.source_path).chain(local_code.all_includes.iter()) {
+        let stamp = get_input_stamp(path).await?;
+        if stamp.modified_nanos >= preprocess_start_nanos {
+            return Ok(None);
+        }
+        stamps.push(stamp);
+    }
+    stamps[0].content_hash = Some(source_hash);
+    Ok(Some(stamps))
 }
 
 async fn wrap_compile_object_file_impl(
-    binary: WrappedBinary,
+    executable: &Executable,
     args: &[impl AsRef<OsStr>],
     cwd: &Path,
     state: &Arc<State>,
-    config: &Arc<Config>,
+    config: &Config,
+    args_info: &args_processing::BuildObjectFileInfo,
+    preprocess_period: TaskPeriodScope,
 ) -> Result<CommandOutput> {
-    let args_info = args_processing::BuildObjectFileInfo::from_args(binary, cwd, args)?;
-    let local_code = extract_local_code(binary, args, cwd, state, config, &args_info).await?;
-    let local_code_path = write_local_code_file(&args_info, &local_code, state).await?;
+    let preprocess_start = SystemTime::now();
+    let (local_code, preprocessor_stderr) = extract_local_code(
+        executable,
+        args,
+        cwd,
+        state,
+        config,
+        args_info,
+        preprocess_period,
+    )
+    .await?;
+    let local_code_path = write_local_code_file(args_info, &local_code, state).await?;
+    if state.cli.verify_chunks {
+        defined_symbols::record_defined_symbols(
+            executable.binary,
+            &executable.program(config),
+            args,
+            cwd,
+            &args_info.source_path,
+            &local_code_path,
+            &state.cli.compiler_launcher,
+        )
+        .await?;
+    }
     write_dummy_object_file(&args_info.object_path).await?;
+    if let Some(compilation_database_path) = &args_info.compilation_database_path {
+        CompileCommand::from_build(executable.binary, cwd, args, args_info)?
+            .write_fragment(compilation_database_path)
+            .await?;
+    }
 
     state
         .persistent
-        .update_object_file(&args_info.object_path, binary, cwd, args)?;
+        .update_object_file(&args_info.object_path, executable, cwd, args)?;
     state.persistent.update_object_file_local_code(
         &args_info.object_path,
         &local_code_path,
+        &source_name(args_info),
         &local_code.direct_includes,
         &local_code.include_defines,
+        get_input_stamps(args_info, &local_code, preprocess_start)
+            .await
+            // Without stamps, the local code is just never reused.
+            .unwrap_or(None)
+            .as_deref(),
     )?;
 
-    Ok(CommandOutput::new_ok())
+    // Warnings from preprocessing (e.g. `#warning`) would otherwise only show up once the
+    // object is actually compiled, and then only if it has to be compiled on its own.
+    Ok(CommandOutput {
+        stderr: preprocessor_stderr,
+        ..CommandOutput::new_ok()
+    })
 }
 
 async fn extract_local_code(
-    binary: WrappedBinary,
+    executable: &Executable,
     args: &[impl AsRef<OsStr>],
     cwd: &Path,
     state: &Arc<State>,
     config: &Config,
     args_info: &args_processing::BuildObjectFileInfo,
-) -> Result<LocalCode> {
-    let preprocessed_code = extract_preprocessed_code(binary, args, cwd, state, args_info).await?;
+    preprocess_period: TaskPeriodScope,
+) -> Result<(LocalCode, Vec<u8>)> {
+    let preprocess_executable = preprocess_executable(executable, config, args_info);
+    let (preprocessed_code, preprocessor_stderr) = extract_preprocessed_code(
+        &preprocess_executable,
+        args,
+        cwd,
+        config,
+        &state.cli.compiler_launcher,
+        state.cli.child_timeout,
+        preprocess_period,
+    )
+    .await?;
 
     let task_period = state
         .task_periods
@@ -76,36 +534,53 @@ async fn extract_local_code(
         preprocessed_code.as_bstr(),
         &args_info.source_path,
         config,
+        LinemarkerFlavor::from_binary(preprocess_executable.binary),
     )
     .await?;
     task_period.finished_successfully();
-    Ok(analysis)
+    Ok((analysis, preprocessor_stderr))
+}
+
+/// Usually the compiler of the command also preprocesses the source, unless the config
+/// specifies another one for it with `preprocess_with`.
+fn preprocess_executable(
+    executable: &Executable,
+    config: &Config,
+    args_info: &args_processing::BuildObjectFileInfo,
+) -> Executable {
+    match config.preprocess_compiler_for(&args_info.source_path) {
+        Some(binary) if binary != executable.binary => Executable::new(binary, None),
+        _ => executable.clone(),
+    }
 }
 
 async fn extract_preprocessed_code(
-    binary: WrappedBinary,
+    executable: &Executable,
     args: &[impl AsRef<OsStr>],
     cwd: &Path,
-    state: &Arc<State>,
-    args_info: &args_processing::BuildObjectFileInfo,
-) -> Result<BString> {
-    let task_period = state.task_periods.start(PreprocessTranslationUnitTaskInfo {
-        dst_object_file: args_info.object_path.clone(),
-    });
-    let preprocessing_args = args_processing::rewrite_to_extract_local_code(binary, args)?;
-    let child = tokio::process::Command::new(binary.to_standard_binary_name())
-        .args(preprocessing_args)
-        .stdin(std::process::Stdio::null())
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .current_dir(cwd)
-        .spawn()?;
-    let child_result = child.wait_with_output().await?;
-    if !child_result.status.success() {
-        return Err(CommandOutput::from_process_output(child_result).into());
+    config: &Config,
+    launcher: &[OsString],
+    timeout: u64,
+    task_period: TaskPeriodScope,
+) -> Result<(BString, Vec<u8>)> {
+    let preprocessing_args =
+        args_processing::rewrite_to_extract_local_code(executable.binary, args)?;
+    let output = child_process::output_with_timeout(
+        child_process::compiler_command(launcher, executable.program(config))
+            .args(preprocessing_args)
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .current_dir(cwd),
+        timeout,
+    )
+    .await?;
+    if output.status != 0 {
+        task_period.failed_with_output(&output);
+        return Err(output.into());
     }
     task_period.finished_successfully();
-    Ok(BString::new(child_result.stdout))
+    Ok((BString::new(output.stdout), output.stderr))
 }
 
 async fn write_local_code_file(
@@ -113,40 +588,118 @@ async fn write_local_code_file(
     local_code: &LocalCode,
     state: &Arc<State>,
 ) -> Result<PathBuf> {
-    let mut local_code_hash_str = format!(
-        "{:x}",
-        twox_hash::XxHash64::oneshot(0, &local_code.local_code)
-    );
-    local_code_hash_str.truncate(8);
-    let debug_name = args_info
-        .source_path
-        .file_name()
-        .unwrap_or(OsStr::new("unknown"))
-        .to_string_lossy();
-    let local_code_file_name = format!(
-        "{}_{}.{}",
-        local_code_hash_str,
-        debug_name,
-        args_info.source_language.to_preprocessed()?.valid_ext()
-    );
+    let extension = args_info.source_language.to_preprocessed()?.valid_ext();
+    let (local_code_hash_str, local_code_file_name) = if state.cli.content_addressed_local_code {
+        // The name has to be unique across everything in a shared cache, so the full hash is
+        // used. The source name is only stored in the database in this case.
+        let hash_str = content_hash(&local_code.local_code);
+        let file_name = format!("{}.{}", hash_str, extension);
+        (hash_str, file_name)
+    } else {
+        let mut hash_str = format!(
+            "{:x}",
+            twox_hash::XxHash64::oneshot(0, &local_code.local_code)
+        );
+        hash_str.truncate(8);
+        let file_name = format!("{}_{}.{}", hash_str, source_name(args_info), extension);
+        (hash_str, file_name)
+    };
 
     let preprocess_file_path = state
         .data_dir
         .join("preprocessed")
         .join(&local_code_hash_str[..2])
         .join(local_code_file_name);
-    path_utils::ensure_directory_and_write(&preprocess_file_path, &local_code.local_code).await?;
+    // The same file may be written by multiple requests at the same time, and it may already be
+    // in use by a chunk that is compiled.
+    path_utils::ensure_directory_and_write_atomically(
+        &preprocess_file_path,
+        &local_code.local_code,
+    )
+    .await?;
     Ok(preprocess_file_path)
 }
 
+/// Hash used to name content-addressed local code files.
+pub fn content_hash(data: &[u8]) -> String {
+    format!("{:032x}", twox_hash::XxHash3_128::oneshot(data))
+}
+
+/// Content-addressed local code files are named by the hash of their content. This detects
+/// files that don't match their name anymore, e.g. because the server crashed while writing
+/// them. Other files are only checked for existence.
+pub async fn local_code_file_is_intact(path: &Path) -> Result<bool> {
+    let expected_hash = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .filter(|s| s.len() == 32 && s.bytes().all(|c| c.is_ascii_hexdigit()));
+    let Some(expected_hash) = expected_hash else {
+        return Ok(tokio::fs::try_exists(path).await?);
+    };
+    let data = match tokio::fs::read(path).await {
+        Ok(data) => data,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err.into()),
+    };
+    Ok(content_hash(&data) == expected_hash)
+}
+
+fn source_name(args_info: &args_processing::BuildObjectFileInfo) -> String {
+    args_info
+        .source_path
+        .file_name()
+        .unwrap_or(OsStr::new("unknown"))
+        .to_string_lossy()
+        .to_string()
+}
+
 async fn write_dummy_object_file(object_path: &Path) -> Result<()> {
-    let dummy_object = crate::ASSETS_DIR
-        .get_file("dummy_object.o")
-        .expect("file should exist");
-    tokio::fs::write(&object_path, dummy_object.contents()).await?;
+    tokio::fs::write(&object_path, placeholders::dummy_object()).await?;
     Ok(())
 }
 
+struct ReuseLocalCodeTaskInfo {
+    dst_object_file: PathBuf,
+}
+
+impl TaskPeriodInfo for ReuseLocalCodeTaskInfo {
+    fn category(&self) -> String {
+        "Unchanged".to_string()
+    }
+
+    fn terminal_one_liner(&self, project_roots: &ProjectRoots) -> String {
+        project_roots.shorten(&self.dst_object_file)
+    }
+
+    fn log_detailed(&self) {
+        log::debug!(
+            "Reuse local code: {}",
+            self.dst_object_file.to_string_lossy()
+        );
+    }
+}
+
+struct ReusePreprocessResultTaskInfo {
+    dst_object_file: PathBuf,
+}
+
+impl TaskPeriodInfo for ReusePreprocessResultTaskInfo {
+    fn category(&self) -> String {
+        "Cached".to_string()
+    }
+
+    fn terminal_one_liner(&self, project_roots: &ProjectRoots) -> String {
+        project_roots.shorten(&self.dst_object_file)
+    }
+
+    fn log_detailed(&self) {
+        log::debug!(
+            "Reuse preprocess result: {}",
+            self.dst_object_file.to_string_lossy()
+        );
+    }
+}
+
 struct PreprocessTranslationUnitTaskInfo {
     dst_object_file: PathBuf,
 }
@@ -156,12 +709,12 @@ impl TaskPeriodInfo for PreprocessTranslationUnitTaskInfo {
         "Preprocess".to_string()
     }
 
-    fn terminal_one_liner(&self) -> String {
-        shorten_path(&self.dst_object_file)
+    fn terminal_one_liner(&self, project_roots: &ProjectRoots) -> String {
+        project_roots.shorten(&self.dst_object_file)
     }
 
     fn log_detailed(&self) {
-        log::info!("Preprocess: {}", self.dst_object_file.to_string_lossy());
+        log::debug!("Preprocess: {}", self.dst_object_file.to_string_lossy());
     }
 }
 
@@ -174,14 +727,284 @@ impl TaskPeriodInfo for HandlePreprocessedTranslationUnitTaskInfo {
         "Local Code".to_string()
     }
 
-    fn terminal_one_liner(&self) -> String {
-        shorten_path(&self.dst_object_file)
+    fn terminal_one_liner(&self, project_roots: &ProjectRoots) -> String {
+        project_roots.shorten(&self.dst_object_file)
     }
 
     fn log_detailed(&self) {
-        log::info!(
+        log::debug!(
             "Handle preprocessed: {}",
             self.dst_object_file.to_string_lossy()
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ccelerate_shared::WrappedBinary;
+
+    use crate::test_utils::TestEnv;
+
+    #[tokio::test]
+    async fn identical_local_code_is_stored_once() {
+        let env = TestEnv::new(&["--content-addressed-local-code"]).await;
+        env.write("a.c", "int twice(void) { return 2; }\n");
+        std::fs::create_dir_all(env.path("other")).expect("dir can be created");
+        // E.g. the same source built for two targets with the same flags.
+        for object in ["a.o", "other/a_copy.o"] {
+            env.run_ok(WrappedBinary::Gcc, &["-c", "a.c", "-o", object])
+                .await;
+        }
+        let object = |name: &str| {
+            env.state
+                .persistent
+                .get_object_file(&env.path(name))
+                .expect("object is recorded")
+        };
+        let local_code_file = object("a.o").local_code.local_code_file.clone();
+        assert_eq!(
+            object("other/a_copy.o").local_code.local_code_file,
+            local_code_file
+        );
+        // The source name is only stored in the database.
+        let file_name = local_code_file
+            .file_name()
+            .expect("file name")
+            .to_string_lossy()
+            .to_string();
+        assert!(!file_name.contains("a.c"), "{}", file_name);
+        assert_eq!(object("a.o").source_name.as_deref(), Some("a.c"));
+
+        let mut files = vec![];
+        for dir in std::fs::read_dir(env.state.data_dir.join("preprocessed")).expect("dir exists") {
+            for file in std::fs::read_dir(dir.expect("entry").path()).expect("dir exists") {
+                files.push(file.expect("entry").path());
+            }
+        }
+        // Also, no temporary files are left behind.
+        assert_eq!(files, vec![local_code_file]);
+    }
+
+    #[tokio::test]
+    async fn unchanged_source_reuses_local_code() {
+        let env = TestEnv::new(&[]).await;
+        env.write("a.h", "#define VALUE 1\n");
+        env.write("a.c", "#include \"a.h\"\nint a(void) { return VALUE; }\n");
+        let args = ["-c", "a.c", "-o", "a.o"];
+        let local_code_file = || {
+            env.state
+                .persistent
+                .get_object_file(&env.path("a.o"))
+                .expect("object is recorded")
+                .local_code
+                .local_code_file
+                .clone()
+        };
+        env.run_ok(WrappedBinary::Gcc, &args).await;
+        let first_local_code_file = local_code_file();
+        assert_eq!(env.state.preprocess_cache_stats.snapshot().misses, 1);
+
+        env.run_ok(WrappedBinary::Gcc, &args).await;
+        let stats = env.state.preprocess_cache_stats.snapshot();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+        assert_eq!(local_code_file(), first_local_code_file);
+
+        // A changed header is detected even though the source is unchanged.
+        env.write("a.h", "#define VALUE 22\n");
+        env.run_ok(WrappedBinary::Gcc, &args).await;
+        let stats = env.state.preprocess_cache_stats.snapshot();
+        assert_eq!((stats.hits, stats.misses), (1, 2));
+        assert_ne!(local_code_file(), first_local_code_file);
+    }
+
+    #[tokio::test]
+    async fn touched_or_reformatted_source_reuses_local_code() {
+        let env = TestEnv::new(&["--content-addressed-local-code"]).await;
+        let source = "int a(void) { return 1; }\n";
+        env.write("a.c", source);
+        let args = ["-c", "a.c", "-o", "a.o"];
+        let local_code_file = || {
+            env.state
+                .persistent
+                .get_object_file(&env.path("a.o"))
+                .expect("object is recorded")
+                .local_code
+                .local_code_file
+                .clone()
+        };
+        env.run_ok(WrappedBinary::Gcc, &args).await;
+        let first_local_code_file = local_code_file();
+
+        // Writing the same content again only changes the modification time.
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        env.write("a.c", source);
+        env.run_ok(WrappedBinary::Gcc, &args).await;
+        let stats = env.state.preprocess_cache_stats.snapshot();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+        assert_eq!(local_code_file(), first_local_code_file);
+
+        // Whitespace within a line does not change the preprocessed code, so the existing
+        // local code is used even though the source has to be preprocessed again.
+        env.write("a.c", "int a(void) {   return 1;   }   \n");
+        env.run_ok(WrappedBinary::Gcc, &args).await;
+        let stats = env.state.preprocess_cache_stats.snapshot();
+        assert_eq!((stats.hits, stats.misses), (1, 2));
+        assert_eq!(local_code_file(), first_local_code_file);
+    }
+
+    #[tokio::test]
+    async fn identical_preprocessing_runs_once() {
+        let env = TestEnv::new(&[]).await;
+        env.write(
+            "a.c",
+            "#warning \"preprocessed\"\nint a(void) { return 1; }\n",
+        );
+        env.write(
+            "main.c",
+            "int a(void);\nint main(void) { return a() - 1; }\n",
+        );
+        let first = env
+            .run_ok(WrappedBinary::Gcc, &["-O1", "-c", "a.c", "-o", "a.o"])
+            .await;
+        let second = env
+            .run_ok(WrappedBinary::Gcc, &["-O1", "-c", "a.c", "-o", "a_copy.o"])
+            .await;
+        let stats = env.state.preprocess_cache_stats.snapshot();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+        // The reused result looks the same to the caller.
+        assert_eq!(second.stderr, first.stderr);
+        let object = |name: &str| {
+            env.state
+                .persistent
+                .get_object_file(&env.path(name))
+                .expect("object is recorded")
+        };
+        assert_eq!(
+            object("a_copy.o").local_code.local_code_file,
+            object("a.o").local_code.local_code_file
+        );
+
+        // Different arguments that affect preprocessing are not reused.
+        env.run_ok(
+            WrappedBinary::Gcc,
+            &["-O1", "-DOTHER", "-c", "a.c", "-o", "a_other.o"],
+        )
+        .await;
+        assert_eq!(env.state.preprocess_cache_stats.snapshot().misses, 2);
+
+        env.run_ok(WrappedBinary::Gcc, &["main.c", "a_copy.o", "-o", "main"])
+            .await;
+        let status = std::process::Command::new(env.path("main"))
+            .status()
+            .expect("binary runs");
+        assert!(status.success());
+    }
+
+    /// Runs gcc directly to get the depfile a normal build would write, then checks that the
+    /// same depfile is written when the command is handled lazily.
+    async fn check_depfile_matches_gcc(args: &[&str], depfile: &str) {
+        let env = TestEnv::new(&[]).await;
+        env.write("inc/foo.h", "#define FOO 1\n");
+        env.write(
+            "foo.c",
+            "#include \"foo.h\"\nint foo(void) { return FOO; }\n",
+        );
+        let status = std::process::Command::new("gcc")
+            .args(args)
+            .current_dir(env.path(""))
+            .status()
+            .expect("gcc can be started");
+        assert!(status.success());
+        let expected = std::fs::read_to_string(env.path(depfile)).expect("depfile exists");
+        std::fs::remove_file(env.path(depfile)).expect("depfile can be removed");
+
+        env.run_ok(WrappedBinary::Gcc, args).await;
+        let depfile = std::fs::read_to_string(env.path(depfile)).expect("depfile exists");
+        assert_eq!(depfile, expected);
+    }
+
+    #[tokio::test]
+    async fn depfile_matches_gcc() {
+        check_depfile_matches_gcc(&["-Iinc", "-MD", "-c", "foo.c", "-o", "foo.o"], "foo.d").await;
+        check_depfile_matches_gcc(
+            &[
+                "-Iinc", "-MMD", "-MF", "deps.d", "-MT", "target", "-c", "foo.c", "-o", "foo.o",
+            ],
+            "deps.d",
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn depfile_without_output() {
+        check_depfile_matches_gcc(&["-Iinc", "-MMD", "-c", "foo.c"], "foo.d").await;
+    }
+
+    #[tokio::test]
+    async fn multiple_sources_without_output() {
+        let env = TestEnv::new(&[]).await;
+        env.write("a.c", "int a(void) { return 1; }\n");
+        env.write("b.c", "int b(void) { return 2; }\n");
+        env.write(
+            "main.c",
+            "int a(void); int b(void);\nint main(void) { return a() + b() - 3; }\n",
+        );
+        env.run_ok(WrappedBinary::Gcc, &["-c", "a.c", "b.c"]).await;
+        for name in ["a", "b"] {
+            let object = env
+                .state
+                .persistent
+                .get_object_file(&env.path(&format!("{}.o", name)))
+                .expect("object is recorded");
+            assert_eq!(object.source_path(), Some(env.path(&format!("{}.c", name))));
+        }
+        env.run_ok(WrappedBinary::Gcc, &["main.c", "a.o", "b.o", "-o", "main"])
+            .await;
+        let status = std::process::Command::new(env.path("main"))
+            .status()
+            .expect("binary runs");
+        assert!(status.success());
+    }
+
+    #[tokio::test]
+    async fn preprocessor_warnings_are_forwarded() {
+        let env = TestEnv::new(&[]).await;
+        env.write(
+            "foo.c",
+            "#warning \"foo is deprecated\"\nint foo(void) { return 1; }\n",
+        );
+        let output = env
+            .run_ok(WrappedBinary::Gcc, &["-c", "foo.c", "-o", "foo.o"])
+            .await;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("foo is deprecated"), "{}", stderr);
+    }
+
+    #[tokio::test]
+    async fn content_addressed_names_use_the_full_hash() {
+        let env = TestEnv::new(&["--content-addressed-local-code"]).await;
+        env.write("foo.c", "int foo(void) { return 1; }\n");
+        env.run_ok(WrappedBinary::Gcc, &["-c", "foo.c", "-o", "foo.o"])
+            .await;
+        let local_code_file = env
+            .state
+            .persistent
+            .get_object_file(&env.path("foo.o"))
+            .expect("object is recorded")
+            .local_code
+            .local_code_file
+            .clone();
+        let stem = local_code_file
+            .file_stem()
+            .expect("file has a name")
+            .to_string_lossy()
+            .to_string();
+        assert_eq!(stem.len(), 32, "{}", stem);
+        let content = std::fs::read(&local_code_file).expect("file can be read");
+        assert_eq!(stem, super::content_hash(&content));
+        assert_eq!(
+            local_code_file.parent().and_then(|dir| dir.file_name()),
+            Some(std::ffi::OsStr::new(&stem[..2]))
+        );
+    }
+}
@@ -5,7 +5,10 @@ use std::{ffi::OsStr, path::Path, sync::Arc};
 use anyhow::Result;
 use ccelerate_shared::WrappedBinary;
 
-use crate::{CommandOutput, State, ar_args, task_periods::TaskPeriodInfo};
+use crate::{
+    CommandOutput, State, ar_args, path_utils::ProjectRoots, placeholders,
+    task_periods::TaskPeriodInfo,
+};
 
 pub async fn wrap_create_static_archive(
     binary: WrappedBinary,
@@ -20,10 +23,7 @@ pub async fn wrap_create_static_archive(
     state
         .persistent
         .update_archive_file(&ar_args.archive_path, binary, cwd, args)?;
-    let dummy_archive = crate::ASSETS_DIR
-        .get_file("dummy_archive.a")
-        .expect("file should exist");
-    tokio::fs::write(ar_args.archive_path, dummy_archive.contents()).await?;
+    tokio::fs::write(ar_args.archive_path, placeholders::dummy_archive()).await?;
     task_period.finished_successfully();
     Ok(CommandOutput::new_ok())
 }
@@ -37,11 +37,11 @@ impl TaskPeriodInfo for BuildStaticArchiveInfo {
         "Ar".to_string()
     }
 
-    fn terminal_one_liner(&self) -> String {
+    fn terminal_one_liner(&self, _project_roots: &ProjectRoots) -> String {
         self.archive_name.clone()
     }
 
     fn log_detailed(&self) {
-        log::info!("Prepare: {}", self.archive_name);
+        log::debug!("Prepare: {}", self.archive_name);
     }
 }
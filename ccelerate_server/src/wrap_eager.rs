@@ -8,45 +8,99 @@ use std::{
 use anyhow::Result;
 use ccelerate_shared::WrappedBinary;
 
-use crate::{CommandOutput, State, task_periods::TaskPeriodInfo};
+use crate::{
+    CommandOutput, State, child_process, executable::Executable, path_utils::ProjectRoots,
+    task_periods::TaskPeriodInfo,
+};
 
 pub async fn wrap_eager(
-    binary: WrappedBinary,
+    executable: &Executable,
+    args: &[impl AsRef<OsStr>],
+    cwd: &Path,
+    state: &State,
+) -> Result<CommandOutput> {
+    run_unchanged(executable, args, cwd, state, "Eager").await
+}
+
+/// Probes like `--version` or `-###` only print information about the toolchain. They are run
+/// unchanged like eager commands but are labeled separately.
+pub async fn wrap_probe(
+    executable: &Executable,
+    args: &[impl AsRef<OsStr>],
+    cwd: &Path,
+    state: &State,
+) -> Result<CommandOutput> {
+    run_unchanged(executable, args, cwd, state, "Probe").await
+}
+
+/// Assembly sources are always assembled as is. They are labeled separately so that they are
+/// not mistaken for commands that could not be handled lazily.
+pub async fn wrap_assemble(
+    executable: &Executable,
     args: &[impl AsRef<OsStr>],
     cwd: &Path,
     state: &State,
+) -> Result<CommandOutput> {
+    run_unchanged(executable, args, cwd, state, "Assemble").await
+}
+
+/// Commands that write their output to stdout (`-o -`) are run unchanged as well.
+pub async fn wrap_output_to_stdout(
+    executable: &Executable,
+    args: &[impl AsRef<OsStr>],
+    cwd: &Path,
+    state: &State,
+) -> Result<CommandOutput> {
+    run_unchanged(executable, args, cwd, state, "Stdout").await
+}
+
+async fn run_unchanged(
+    executable: &Executable,
+    args: &[impl AsRef<OsStr>],
+    cwd: &Path,
+    state: &State,
+    category: &'static str,
 ) -> Result<CommandOutput> {
     let task_period = state.task_periods.start(EagerGccTaskInfo {
-        binary,
+        category,
+        binary: executable.binary,
         args: args.iter().map(|s| s.as_ref().to_owned()).collect(),
     });
-    let child = tokio::process::Command::new(binary.to_standard_binary_name())
-        .args(args)
-        .current_dir(cwd)
-        .stdin(std::process::Stdio::null())
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .spawn()?;
-    let child_result = child.wait_with_output().await?;
-    task_period.finished_successfully();
-    Ok(CommandOutput::from_process_output(child_result))
+    let program = executable.program(&state.config_manager.current());
+    let output = child_process::output_with_timeout(
+        child_process::compiler_command(&state.cli.compiler_launcher, program)
+            .args(args)
+            .current_dir(cwd)
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped()),
+        state.cli.child_timeout,
+    )
+    .await?;
+    if output.status == 0 {
+        task_period.finished_successfully();
+    } else {
+        task_period.failed_with_output(&output);
+    }
+    Ok(output)
 }
 
 struct EagerGccTaskInfo {
+    category: &'static str,
     binary: WrappedBinary,
     args: Vec<OsString>,
 }
 
 impl TaskPeriodInfo for EagerGccTaskInfo {
     fn category(&self) -> String {
-        "Eager".to_string()
+        self.category.to_string()
     }
 
-    fn terminal_one_liner(&self) -> String {
+    fn terminal_one_liner(&self, _project_roots: &ProjectRoots) -> String {
         format!("{} {:?}", self.binary, self.args)
     }
 
     fn log_detailed(&self) {
-        log::info!("{} {:?}", self.binary, self.args);
+        log::debug!("{} {:?}", self.binary, self.args);
     }
 }
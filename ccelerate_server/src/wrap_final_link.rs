@@ -1,69 +1,219 @@
 #![deny(clippy::unwrap_used)]
 
 use std::{
-    ffi::OsStr,
+    ffi::{OsStr, OsString},
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
 };
 
 use anyhow::Result;
-use ccelerate_shared::WrappedBinary;
+use bstr::{BStr, ByteSlice};
+use ccelerate_shared::{CompileChunkRequestData, WrappedBinary};
 use futures::stream::FuturesUnordered;
 use nunny::NonEmpty;
 
 use crate::{
-    CommandOutput, ar_args, args_processing,
+    CommandOutput, ar_args, args_processing, child_process,
     code_language::CodeLanguage,
     config::Config,
-    gcc_args,
+    defined_symbols, diagnostics,
+    executable::Executable,
+    final_link_cache::{self, LinkedOutput},
     group_compatible_objects::group_compatible_objects,
-    link_sources::find_link_sources,
-    path_utils::{self, shorten_path},
+    link_sources::{OrderedLinkSource, find_link_sources},
+    manifest::Manifest,
+    path_utils::{self, ProjectRoots},
     preprocess_headers::get_preprocessed_headers,
+    remote_workers::RemoteWorkerGuard,
+    request_id,
+    source_file::SourceFile,
     state::State,
     state_persistent::ObjectData,
-    task_periods::TaskPeriodInfo,
+    task_periods::{TaskPeriodInfo, TaskPeriodScope},
+    wrap_compile_object_file::{self, get_input_stamp},
 };
 
 pub async fn wrap_final_link(
-    binary: WrappedBinary,
+    executable: &Executable,
     original_args: &[impl AsRef<OsStr>],
     cwd: &Path,
     state: &Arc<State>,
     config: &Arc<Config>,
 ) -> Result<CommandOutput> {
-    let args_info = args_processing::LinkFileInfo::from_args(binary, cwd, original_args)?;
-    let link_sources = find_link_sources(&args_info, state)?;
-    let object_paths =
+    let args_info =
+        args_processing::LinkFileInfo::from_args(executable.binary, cwd, original_args)?;
+    state.persistent.update_linked_file(
+        &args_info.output,
+        executable.binary,
+        cwd,
+        original_args,
+    )?;
+    let link_sources = find_link_sources(&args_info, state, config)?;
+    let linked_output_slot =
+        match final_link_cache::create_key(executable, original_args, cwd, &link_sources).await? {
+            Some((key, time)) => Some(state.final_link_cache.get(&key, time).await),
+            None => None,
+        };
+    let linked_output = linked_output_slot
+        .as_ref()
+        .and_then(|slot| slot.lock().clone());
+    if let Some(slot) = &linked_output_slot
+        && let Some(linked_output) = linked_output
+        && let Some(reused_output) =
+            final_link_cache::reuse_linked_output(&linked_output, &args_info.output).await?
+    {
+        let task_period = state.task_periods.start(ReuseFinalLinkTaskInfo {
+            output: args_info.output.clone(),
+        });
+        if let Some(manifest_dir) = &state.cli.write_manifest {
+            Manifest::new(
+                &reused_output.path,
+                &reused_output.archive_paths,
+                &reused_output.chunk_object_paths,
+            )
+            .await?
+            .write_to_dir(manifest_dir)
+            .await?;
+        }
+        *slot.lock() = Some(reused_output);
+        task_period.finished_successfully();
+        return Ok(CommandOutput::new_ok());
+    }
+    let mut compiled_chunks =
         compile_objects_smart(&link_sources.known_object_files, state, config).await?;
-    let archive_path = create_thin_archive_for_objects(&object_paths, state).await?;
+    let archive_path =
+        create_archive_for_objects(&chunk_object_paths(&compiled_chunks), state).await?;
+    let mut archive_paths = vec![archive_path.clone()];
 
-    let mut all_link_sources = vec![archive_path];
-    all_link_sources.extend(link_sources.unknown_sources.into_iter());
+    // Unknown sources also contain source files that are compiled and linked in one step.
+    // Those are passed through to the compiler driver which compiles them as part of the link.
+    let all_link_sources = link_sources
+        .ordered_sources
+        .iter()
+        .map(|sources| {
+            sources
+                .iter()
+                .map(|source| match source {
+                    OrderedLinkSource::KnownObjects => args_info.to_link_source(&archive_path),
+                    OrderedLinkSource::Unknown(path) => args_info.to_link_source(path),
+                })
+                .collect()
+        })
+        .collect::<Vec<_>>();
 
-    final_link(
-        binary,
+    let mut whole_archive_link_sources = vec![];
+    if !link_sources.whole_archive_known_object_files.is_empty() {
+        // These are kept in a separate archive, because only they must be linked entirely.
+        let whole_archive_chunks = compile_objects_smart(
+            &link_sources.whole_archive_known_object_files,
+            state,
+            config,
+        )
+        .await?;
+        let archive_path =
+            create_archive_for_objects(&chunk_object_paths(&whole_archive_chunks), state).await?;
+        compiled_chunks.extend(whole_archive_chunks);
+        whole_archive_link_sources.push(args_info.to_link_source(&archive_path));
+        archive_paths.push(archive_path);
+    }
+    whole_archive_link_sources.extend(
+        link_sources
+            .whole_archive_unknown_sources
+            .iter()
+            .map(|path| args_info.to_link_source(path)),
+    );
+
+    if !state.cli.regular_archives {
+        // Thin archives only reference the objects, so the linker would fail with a less clear
+        // error if one was removed in the meantime.
+        check_thin_archive_members_exist(&compiled_chunks)?;
+    }
+    let mut output = final_link(
+        executable,
         original_args,
         &args_info,
         cwd,
         state,
         &all_link_sources,
+        &whole_archive_link_sources,
     )
-    .await
+    .await?;
+    // Warnings would be shown when compiling the objects in a normal build. Here they are only
+    // known once the chunks are compiled.
+    // The same warning may be reported by multiple chunks, e.g. when it's in a shared header.
+    let mut stderr = diagnostics::dedup_diagnostics(
+        compiled_chunks
+            .iter()
+            .flat_map(|c| c.warnings.iter().copied())
+            .collect(),
+    );
+    stderr.extend(output.stderr);
+    output.stderr = stderr;
+    if let Some(manifest_dir) = &state.cli.write_manifest {
+        Manifest::new(
+            &args_info.output,
+            &archive_paths,
+            &chunk_object_paths(&compiled_chunks),
+        )
+        .await?
+        .write_to_dir(manifest_dir)
+        .await?;
+    }
+    if let Some(slot) = linked_output_slot {
+        *slot.lock() = Some(LinkedOutput {
+            path: args_info.output.clone(),
+            stamp: get_input_stamp(&args_info.output).await?,
+            archive_paths,
+            chunk_object_paths: chunk_object_paths(&compiled_chunks),
+        });
+    }
+    Ok(output)
+}
+
+/// Object file that was compiled from a chunk of compatible local code.
+pub struct CompiledChunk {
+    pub object_path: PathBuf,
+    // Compiler output of a successful compilation.
+    pub warnings: Vec<u8>,
+}
+
+fn chunk_object_paths(chunks: &[CompiledChunk]) -> Vec<PathBuf> {
+    chunks.iter().map(|c| c.object_path.clone()).collect()
+}
+
+fn check_thin_archive_members_exist(chunks: &[CompiledChunk]) -> Result<()> {
+    for chunk in chunks {
+        if !chunk.object_path.exists() {
+            return Err(anyhow::anyhow!(
+                "Compiled object was removed before the final link: {}",
+                chunk.object_path.display()
+            ));
+        }
+    }
+    Ok(())
 }
 
 async fn compile_objects_smart(
     objects: &[Arc<ObjectData>],
     state: &Arc<State>,
     config: &Arc<Config>,
-) -> Result<Vec<PathBuf>> {
+) -> Result<Vec<CompiledChunk>> {
     let compatible_objects_groups = group_compatible_objects(objects, state)?;
     let handles = FuturesUnordered::new();
     for compatible_objects in compatible_objects_groups {
         let state = state.clone();
         let config = config.clone();
-        let handle = tokio::task::spawn(async move {
-            compile_compatible_objects_in_chunks(&compatible_objects.objects, &state, &config).await
+        let handle = request_id::spawn(async move {
+            compile_compatible_objects_in_chunks(
+                compatible_objects.key.as_bstr(),
+                &compatible_objects.objects,
+                &state,
+                &config,
+            )
+            .await
         });
         handles.push(handle);
     }
@@ -76,15 +226,16 @@ async fn compile_objects_smart(
 
 #[async_recursion::async_recursion]
 async fn compile_compatible_objects_in_chunks(
+    key: &BStr,
     compatible_objects: &NonEmpty<[Arc<ObjectData>]>,
     state: &Arc<State>,
     config: &Arc<Config>,
-) -> Result<Vec<PathBuf>> {
+) -> Result<Vec<CompiledChunk>> {
     if compatible_objects.is_empty() {
         return Ok(vec![]);
     }
-    if compatible_objects.len() <= 10 {
-        let key = compatible_objects
+    if compatible_objects.len() <= state.chunk_sizes.get(key) {
+        let cache_key = compatible_objects
             .iter()
             .map(|o| o.path.as_path())
             .collect::<Vec<_>>();
@@ -93,21 +244,46 @@ async fn compile_compatible_objects_in_chunks(
             .map(|o| o.last_build)
             .max()
             .expect("never empty");
-        let result = state
-            .objects_cache
-            .get(&key, latest_build, async || {
-                compile_compatible_objects_in_pool(state, compatible_objects, config).await
-            })
-            .await;
+        // Results from the cache don't say anything about how well the current chunk size works.
+        let compiled = AtomicBool::new(false);
+        let get_chunk = async || {
+            state
+                .objects_cache
+                .get(&cache_key, latest_build, async || {
+                    compiled.store(true, Ordering::Relaxed);
+                    compile_compatible_objects_in_pool(state, compatible_objects, config).await
+                })
+                .await
+        };
+        let mut result = get_chunk().await;
+        if let Ok(chunk) = result.as_ref()
+            && !chunk.object_path.exists()
+        {
+            // The object was removed since it was compiled, so it's compiled again.
+            state.objects_cache.remove(&cache_key, latest_build);
+            result = get_chunk().await;
+        }
         match result.as_ref() {
-            Ok(object_path) => {
-                let object_path = object_path.clone();
-                return Ok(vec![object_path]);
+            Ok(chunk) => {
+                if compiled.load(Ordering::Relaxed) {
+                    state
+                        .chunk_sizes
+                        .compiled_successfully(key, compatible_objects.len());
+                }
+                return Ok(vec![CompiledChunk {
+                    object_path: chunk.object_path.clone(),
+                    warnings: chunk.warnings.clone(),
+                }]);
             }
             Err(e) => {
                 if compatible_objects.len() == 1 {
                     return Err(anyhow::anyhow!("{}", e));
                 }
+                if compiled.load(Ordering::Relaxed) {
+                    state
+                        .chunk_sizes
+                        .compile_failed(key, compatible_objects.len());
+                }
             }
         }
     }
@@ -115,8 +291,8 @@ async fn compile_compatible_objects_in_chunks(
     let left = NonEmpty::<[_]>::new(left).expect("empty");
     let right = NonEmpty::<[_]>::new(right).expect("empty");
     let (left, right) = tokio::try_join!(
-        compile_compatible_objects_in_chunks(left, state, config),
-        compile_compatible_objects_in_chunks(right, state, config)
+        compile_compatible_objects_in_chunks(key, left, state, config),
+        compile_compatible_objects_in_chunks(key, right, state, config)
     )?;
     Ok(left.into_iter().chain(right).collect())
 }
@@ -125,23 +301,106 @@ async fn compile_compatible_objects_in_pool(
     state: &Arc<State>,
     objects: &NonEmpty<[Arc<ObjectData>]>,
     config: &Arc<Config>,
-) -> Result<PathBuf> {
+) -> Result<CompiledChunk> {
+    // The budget is acquired first so that tasks waiting for memory don't hold pool permits
+    // that other, smaller chunks could use. This also covers chunks compiled remotely, because
+    // their preprocessed headers and code are still prepared here.
+    let _memory_permit = state
+        .memory_budget
+        .acquire(chunk_input_size(objects).await)
+        .await;
+    let task = {
+        let state = state.clone();
+        let objects = nunny::Vec::new(objects.to_vec()).expect("empty");
+        let config = config.clone();
+        async move || {
+            let task_period = state
+                .task_periods
+                .start(CompileChunkTaskInfo::new(&objects));
+            compile_compatible_objects(&state, &objects, &config, None, task_period).await
+        }
+    };
+    if let Ok(handle) = state.pool.try_run_spawned(task) {
+        return handle.await?;
+    }
+    // Remote workers are only used when the local machine is busy already.
+    if let Some(remote_worker) = state.remote_workers.acquire() {
+        let task_period = state.task_periods.start(CompileChunkTaskInfo::new(objects));
+        return compile_compatible_objects(
+            state,
+            objects,
+            config,
+            Some(&remote_worker),
+            task_period,
+        )
+        .await;
+    }
+    // The task shows up as queued while it's waiting for the pool.
+    let queued_period = state.task_periods.queue(CompileChunkTaskInfo::new(objects));
     let state_clone = state.clone();
-    let objects = nunny::Vec::new(objects.to_vec()).expect("empty");
-    let config = config.clone();
+    let objects_clone = nunny::Vec::new(objects.to_vec()).expect("empty");
+    let config_clone = config.clone();
     state
         .pool
         .run_spawned(async move || {
-            compile_compatible_objects(&state_clone, &objects, &config).await
+            compile_compatible_objects(
+                &state_clone,
+                &objects_clone,
+                &config_clone,
+                None,
+                queued_period.start(),
+            )
+            .await
         })
+        .await
         .await?
 }
 
+/// Estimates how much memory compiling the chunk needs from the size of the local code and of
+/// the headers that are preprocessed for it. Included headers are not taken into account.
+async fn chunk_input_size(objects: &NonEmpty<[Arc<ObjectData>]>) -> u64 {
+    let mut paths: Vec<&Path> = vec![];
+    for object in objects {
+        paths.push(&object.local_code.local_code_file);
+        for include in &object.local_code.direct_includes {
+            if !paths.contains(&include.as_path()) {
+                paths.push(include);
+            }
+        }
+    }
+    let mut size = 0;
+    for path in paths {
+        // Missing files are handled when compiling the chunk.
+        if let Ok(metadata) = tokio::fs::metadata(path).await {
+            size += metadata.len();
+        }
+    }
+    size
+}
+
 async fn compile_compatible_objects(
     state: &Arc<State>,
     objects: &NonEmpty<[Arc<ObjectData>]>,
     config: &Config,
-) -> Result<PathBuf> {
+    remote_worker: Option<&RemoteWorkerGuard<'_>>,
+    task_period: TaskPeriodScope,
+) -> Result<CompiledChunk> {
+    if state.cli.verify_chunks && objects.len() > 1 {
+        let duplicates =
+            defined_symbols::find_duplicate_symbols(objects, &state.project_roots).await?;
+        if !duplicates.is_empty() {
+            // This makes the caller split up the chunk.
+            return Err(anyhow::anyhow!(
+                "Symbols are defined in multiple sources of the chunk: {}",
+                duplicates.join(", ")
+            ));
+        }
+    }
+
+    for object in objects {
+        ensure_local_code_is_intact(object, state, config).await?;
+    }
+
     let any_object = objects.first();
     let preprocessed_language = CodeLanguage::from_path(&any_object.local_code.local_code_file)?;
 
@@ -161,13 +420,6 @@ async fn compile_compatible_objects(
         .open(preprocessed_source_file.path())
         .await?;
 
-    let task_period = state.task_periods.start(CompileChunkTaskInfo {
-        sources: objects
-            .iter()
-            .map(|r| r.local_code.local_code_file.clone())
-            .collect(),
-    });
-
     for object in objects {
         tokio::io::copy(
             &mut tokio::fs::File::open(&object.local_code.local_code_file).await?,
@@ -176,32 +428,219 @@ async fn compile_compatible_objects(
         .await?;
     }
 
-    let build_args = gcc_args::update_to_build_object_from_stdin(
+    let output = match remote_worker {
+        Some(remote_worker) => {
+            match compile_remotely(
+                remote_worker,
+                any_object,
+                preprocessed_source_file.path(),
+                preprocessed_language,
+                &object_path,
+            )
+            .await
+            {
+                Ok(output) => output,
+                Err(err) => {
+                    log::warn!(
+                        "Compiling on {} failed, compiling locally instead: {}",
+                        remote_worker.url(),
+                        err
+                    );
+                    state
+                        .pool
+                        .run_local(async || {
+                            compile_locally(
+                                any_object,
+                                config,
+                                preprocessed_source_file.path(),
+                                &object_path,
+                                &state.cli.compiler_launcher,
+                                state.cli.child_timeout,
+                            )
+                            .await
+                        })
+                        .await?
+                }
+            }
+        }
+        None => {
+            compile_locally(
+                any_object,
+                config,
+                preprocessed_source_file.path(),
+                &object_path,
+                &state.cli.compiler_launcher,
+                state.cli.child_timeout,
+            )
+            .await?
+        }
+    };
+    if output.status != 0 {
+        let output = output.with_replaced_path(
+            preprocessed_source_file.path(),
+            &chunk_label(objects, &state.project_roots),
+        );
+        task_period.failed_with_output(&output);
+        return Err(output.into());
+    }
+    task_period.finished_successfully();
+    // The user chose not to see warnings for these objects.
+    let warnings =
+        if args_processing::suppresses_warnings(any_object.create.binary, &any_object.create.args)?
+        {
+            Vec::new()
+        } else {
+            output.stderr
+        };
+    Ok(CompiledChunk {
+        object_path,
+        warnings,
+    })
+}
+
+/// Compiling a corrupted local code file could silently produce a broken object. Such files
+/// are extracted again. That only helps if the source still results in the same code though.
+async fn ensure_local_code_is_intact(
+    object: &ObjectData,
+    state: &Arc<State>,
+    config: &Config,
+) -> Result<()> {
+    let local_code_file = &object.local_code.local_code_file;
+    if wrap_compile_object_file::local_code_file_is_intact(local_code_file).await? {
+        return Ok(());
+    }
+    log::warn!(
+        "Local code file {} is corrupted, recomputing it for {}",
+        local_code_file.display(),
+        object.path.display()
+    );
+    wrap_compile_object_file::recompute_local_code(object, state, config).await?;
+    if !wrap_compile_object_file::local_code_file_is_intact(local_code_file).await? {
+        return Err(anyhow::anyhow!(
+            "Local code file {} is corrupted and could not be recomputed for {}",
+            local_code_file.display(),
+            object.path.display()
+        ));
+    }
+    Ok(())
+}
+
+async fn compile_locally(
+    any_object: &ObjectData,
+    config: &Config,
+    preprocessed_source_path: &Path,
+    object_path: &Path,
+    launcher: &[OsString],
+    timeout: u64,
+) -> Result<CommandOutput> {
+    let build_args = args_processing::update_to_build_object_from_stdin(
+        any_object.create.binary,
         &any_object.create.args,
-        preprocessed_source_file.path(),
-        &object_path,
+        preprocessed_source_path,
+        object_path,
     )?;
-
-    let child_output =
-        tokio::process::Command::new(any_object.create.binary.to_standard_binary_name())
+    child_process::output_with_timeout(
+        child_process::compiler_command(launcher, any_object.create.executable().program(config))
             .args(build_args)
             .stdout(std::process::Stdio::piped())
-            .stderr(std::process::Stdio::piped())
-            .spawn()?
-            .wait_with_output()
-            .await?;
-    if !child_output.status.success() {
-        return Err(CommandOutput::from_process_output(child_output).into());
+            .stderr(std::process::Stdio::piped()),
+        timeout,
+    )
+    .await
+}
+
+/// Sends the preprocessed code to another ccelerate server and writes the object file it
+/// returns. Errors are only returned if the remote worker could not be used.
+async fn compile_remotely(
+    remote_worker: &RemoteWorkerGuard<'_>,
+    any_object: &ObjectData,
+    preprocessed_source_path: &Path,
+    preprocessed_language: CodeLanguage,
+    object_path: &Path,
+) -> Result<CommandOutput> {
+    let source = tokio::fs::read(preprocessed_source_path).await?;
+    let response = remote_worker
+        .compile(
+            any_object.create.binary,
+            &any_object.create.args,
+            preprocessed_language.valid_ext(),
+            source,
+        )
+        .await?;
+    if response.status == 0 {
+        tokio::fs::write(object_path, &response.stdout).await?;
     }
-    task_period.finished_successfully();
-    Ok(object_path)
+    Ok(CommandOutput {
+        stdout: Vec::new(),
+        stderr: response.stderr,
+        status: response.status,
+    })
+}
+
+/// Compiles preprocessed code for another ccelerate server. On success, the object file is
+/// returned as stdout.
+pub async fn compile_chunk_for_remote(
+    request: &CompileChunkRequestData,
+    state: &Arc<State>,
+) -> Result<CommandOutput> {
+    let queued_period = state.task_periods.queue(CompileRemoteChunkTaskInfo {
+        size: request.source.len(),
+    });
+    state
+        .pool
+        .run_local(async || {
+            let task_period = queued_period.start();
+            let source_file =
+                tempfile::NamedTempFile::with_suffix(format!(".{}", request.source_extension))?;
+            tokio::fs::write(source_file.path(), &request.source).await?;
+            let object_file = tempfile::NamedTempFile::with_suffix(".o")?;
+            let build_args = args_processing::update_to_build_object_from_stdin(
+                request.binary,
+                &request.args,
+                source_file.path(),
+                object_file.path(),
+            )?;
+            let program = state.config_manager.current().binary_path(request.binary);
+            let output = child_process::output_with_timeout(
+                child_process::compiler_command(&state.cli.compiler_launcher, program)
+                    .args(build_args)
+                    .stdout(std::process::Stdio::piped())
+                    .stderr(std::process::Stdio::piped()),
+                state.cli.child_timeout,
+            )
+            .await?;
+            let mut output = output.with_replaced_path(source_file.path(), "<remote chunk>");
+            if output.status == 0 {
+                output.stdout = tokio::fs::read(object_file.path()).await?;
+                task_period.finished_successfully();
+            } else {
+                task_period.failed_with_output(&output);
+            }
+            Ok(output)
+        })
+        .await
+}
+
+/// Label for the code of multiple objects that are compiled together. It refers to the original
+/// sources, because the local code files may only be named by their hash.
+pub fn chunk_label(objects: &[Arc<ObjectData>], project_roots: &ProjectRoots) -> String {
+    let names = objects
+        .iter()
+        .map(|object| match object.source_path() {
+            Some(source_path) => project_roots.shorten(&source_path),
+            None => project_roots.shorten(&object.local_code.local_code_file),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!("<chunk: {}>", names)
 }
 
-pub async fn create_thin_archive_for_objects(
+pub async fn create_archive_for_objects(
     objects: &[PathBuf],
     state: &Arc<State>,
 ) -> Result<PathBuf> {
-    let task_period = state.task_periods.start(CreateThinArchiveTaskInfo {});
+    let thin = !state.cli.regular_archives;
+    let task_period = state.task_periods.start(CreateArchiveTaskInfo { thin });
 
     let archive_name = format!("{}.a", uuid::Uuid::new_v4());
     let archive_path = state
@@ -211,17 +650,26 @@ pub async fn create_thin_archive_for_objects(
         .join(archive_name);
     path_utils::ensure_directory_for_file(&archive_path).await?;
 
-    let child = tokio::process::Command::new(WrappedBinary::Ar.to_standard_binary_name())
-        .args(ar_args::make_args_to_build_thin_static_archive(
-            &archive_path,
-            objects,
-        ))
-        .spawn()?;
-    let child_output = child.wait_with_output().await?;
-    if !child_output.status.success() {
+    let program = state
+        .config_manager
+        .current()
+        .binary_path(WrappedBinary::Ar);
+    let output = child_process::output_with_timeout(
+        tokio::process::Command::new(program)
+            .args(ar_args::make_args_to_build_static_archive(
+                &archive_path,
+                objects,
+                thin,
+            ))
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped()),
+        state.cli.child_timeout,
+    )
+    .await?;
+    if output.status != 0 {
         return Err(anyhow::anyhow!(
             "Archive creation failed: {}",
-            String::from_utf8_lossy(&child_output.stderr)
+            String::from_utf8_lossy(&output.stderr)
         ));
     }
 
@@ -230,35 +678,59 @@ pub async fn create_thin_archive_for_objects(
 }
 
 pub async fn final_link(
-    binary: WrappedBinary,
+    executable: &Executable,
     original_args: &[impl AsRef<OsStr>],
     args_info: &args_processing::LinkFileInfo,
     cwd: &Path,
     state: &Arc<State>,
-    sources: &[PathBuf],
+    sources: &[Vec<SourceFile>],
+    whole_archive_sources: &[SourceFile],
 ) -> Result<CommandOutput> {
     let task_period = state.task_periods.start(FinalLinkTaskInfo {
         output: args_info.output.clone(),
     });
 
-    let link_args = args_processing::rewrite_to_link_sources(binary, original_args, sources)?;
-    let child_output = tokio::process::Command::new(binary.to_standard_binary_name())
-        .args(link_args)
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .current_dir(cwd)
-        .spawn()?
-        .wait_with_output()
-        .await?;
-    if !child_output.status.success() {
-        return Err(CommandOutput::from_process_output(child_output).into());
+    let link_args = args_processing::rewrite_to_link_sources(
+        executable.binary,
+        original_args,
+        sources,
+        whole_archive_sources,
+        state.cli.link_group.use_link_group(),
+    )?;
+    let program = executable.program(&state.config_manager.current());
+    let output = child_process::output_with_timeout(
+        child_process::compiler_command(&state.cli.compiler_launcher, program)
+            .args(link_args)
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .current_dir(cwd),
+        state.cli.child_timeout,
+    )
+    .await?;
+    if output.status != 0 {
+        task_period.failed_with_output(&output);
+        return Err(output.into());
     }
     task_period.finished_successfully();
-    Ok(CommandOutput::from_process_output(child_output))
+    Ok(output)
 }
 
 struct CompileChunkTaskInfo {
     sources: Vec<PathBuf>,
+    // Not known for objects that were recorded by older versions.
+    names: Vec<Option<String>>,
+}
+
+impl CompileChunkTaskInfo {
+    fn new(objects: &[Arc<ObjectData>]) -> Self {
+        Self {
+            sources: objects
+                .iter()
+                .map(|r| r.local_code.local_code_file.clone())
+                .collect(),
+            names: objects.iter().map(|r| r.source_name.clone()).collect(),
+        }
+    }
 }
 
 impl TaskPeriodInfo for CompileChunkTaskInfo {
@@ -266,10 +738,14 @@ impl TaskPeriodInfo for CompileChunkTaskInfo {
         "Compile".to_string()
     }
 
-    fn terminal_one_liner(&self) -> String {
-        self.sources
+    fn terminal_one_liner(&self, project_roots: &ProjectRoots) -> String {
+        self.names
             .iter()
-            .map(|p| shorten_path(p))
+            .zip(&self.sources)
+            .map(|(name, source)| {
+                name.clone()
+                    .unwrap_or_else(|| project_roots.shorten(source))
+            })
             .collect::<Vec<_>>()
             .join(" ")
     }
@@ -278,10 +754,28 @@ impl TaskPeriodInfo for CompileChunkTaskInfo {
         let mut msg = "Compile chunk: ".to_string();
         for source in &self.sources {
             msg.push_str("  ");
-            msg.push_str(&shorten_path(source));
+            msg.push_str(&source.to_string_lossy());
             msg.push('\n');
         }
-        log::info!("{}", msg);
+        log::debug!("{}", msg);
+    }
+}
+
+struct CompileRemoteChunkTaskInfo {
+    size: usize,
+}
+
+impl TaskPeriodInfo for CompileRemoteChunkTaskInfo {
+    fn category(&self) -> String {
+        "Compile".to_string()
+    }
+
+    fn terminal_one_liner(&self, _project_roots: &ProjectRoots) -> String {
+        format!("Remote chunk ({} bytes)", self.size)
+    }
+
+    fn log_detailed(&self) {
+        log::debug!("Compile remote chunk with {} bytes", self.size);
     }
 }
 
@@ -294,27 +788,607 @@ impl TaskPeriodInfo for FinalLinkTaskInfo {
         "Link".to_string()
     }
 
-    fn terminal_one_liner(&self) -> String {
-        shorten_path(&self.output)
+    fn terminal_one_liner(&self, project_roots: &ProjectRoots) -> String {
+        project_roots.shorten(&self.output)
+    }
+
+    fn log_detailed(&self) {
+        log::debug!("Final link for {}", self.output.to_string_lossy());
+    }
+}
+
+struct ReuseFinalLinkTaskInfo {
+    output: PathBuf,
+}
+
+impl TaskPeriodInfo for ReuseFinalLinkTaskInfo {
+    fn category(&self) -> String {
+        "Unchanged".to_string()
+    }
+
+    fn terminal_one_liner(&self, project_roots: &ProjectRoots) -> String {
+        project_roots.shorten(&self.output)
     }
 
     fn log_detailed(&self) {
-        log::info!("Final link for {}", self.output.to_string_lossy());
+        log::debug!("Reuse final link for {}", self.output.to_string_lossy());
     }
 }
 
-struct CreateThinArchiveTaskInfo {}
+struct CreateArchiveTaskInfo {
+    thin: bool,
+}
 
-impl TaskPeriodInfo for CreateThinArchiveTaskInfo {
+impl TaskPeriodInfo for CreateArchiveTaskInfo {
     fn category(&self) -> String {
         "Archive".to_string()
     }
 
-    fn terminal_one_liner(&self) -> String {
-        "Create thin archive".to_string()
+    fn terminal_one_liner(&self, _project_roots: &ProjectRoots) -> String {
+        if self.thin {
+            "Create thin archive".to_string()
+        } else {
+            "Create archive".to_string()
+        }
     }
 
     fn log_detailed(&self) {
-        log::info!("Create thin archive");
+        log::debug!("Create {}archive", if self.thin { "thin " } else { "" });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ccelerate_shared::WrappedBinary;
+
+    use super::{CompiledChunk, check_thin_archive_members_exist, create_archive_for_objects};
+    use crate::{test_utils::TestEnv, wrap_compile_object_file};
+
+    #[tokio::test]
+    async fn chunk_errors_refer_to_sources() {
+        let env = TestEnv::new(&["--content-addressed-local-code"]).await;
+        env.write("broken.h", "int broken(void) { return }\n");
+        env.write("a.c", "#include \"broken.h\"\nint a(void) { return 1; }");
+        env.write("b.c", "int b(void) { return 2; }");
+        env.write("main.c", "int main(void) { return 0; }");
+        for name in ["a", "b"] {
+            env.run_ok(
+                WrappedBinary::Gcc,
+                &["-c", &format!("{}.c", name), "-o", &format!("{}.o", name)],
+            )
+            .await;
+        }
+        let output = env
+            .run(WrappedBinary::Gcc, &["main.c", "a.o", "b.o", "-o", "main"])
+            .await;
+        assert_ne!(output.status, 0);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("In file included from <chunk: a.c"),
+            "{}",
+            stderr
+        );
+        assert!(stderr.contains(&*env.path("broken.h").to_string_lossy()));
+        // Neither temporary files nor the hashed local code files are mentioned.
+        let stderr = stderr.replace(&*env.path("").to_string_lossy(), "");
+        assert!(!stderr.contains(".tmp"), "{}", stderr);
+    }
+
+    #[tokio::test]
+    async fn chunks_larger_than_the_memory_budget() {
+        let env = TestEnv::new(&["--memory-budget", "1"]).await;
+        // Each source alone is larger than the budget.
+        let padding = "x".repeat(2 * 1024 * 1024);
+        for name in ["a", "b", "c"] {
+            env.write(
+                &format!("{}.c", name),
+                &format!(
+                    "const char *{name}_padding = \"{padding}\";\n\
+                     int {name}(void) {{ return 1; }}\n"
+                ),
+            );
+        }
+        env.write(
+            "main.c",
+            "int a(void); int b(void); int c(void);\n\
+             int main(void) { return a() + b() + c() - 3; }",
+        );
+        for name in ["a", "b", "c"] {
+            env.run_ok(
+                WrappedBinary::Gcc,
+                &["-c", &format!("{}.c", name), "-o", &format!("{}.o", name)],
+            )
+            .await;
+        }
+        let output = tokio::time::timeout(
+            std::time::Duration::from_secs(60),
+            env.run(
+                WrappedBinary::Gcc,
+                &["main.c", "a.o", "b.o", "c.o", "-o", "main"],
+            ),
+        )
+        .await
+        .expect("link must not wait for memory forever");
+        assert_eq!(
+            output.status,
+            0,
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let status = std::process::Command::new(env.path("main"))
+            .status()
+            .expect("binary runs");
+        assert!(status.success());
+    }
+
+    #[tokio::test]
+    async fn corrupted_local_code_is_detected() {
+        let env = TestEnv::new(&["--content-addressed-local-code"]).await;
+        env.write("a.c", "int a(void) { return 1; }\n");
+        env.write("b.c", "int b(void) { return 2; }\n");
+        env.write(
+            "main.c",
+            "int a(void); int b(void);\nint main(void) { return a() + b() - 3; }\n",
+        );
+        for name in ["a", "b"] {
+            env.run_ok(
+                WrappedBinary::Gcc,
+                &["-c", &format!("{}.c", name), "-o", &format!("{}.o", name)],
+            )
+            .await;
+        }
+        let local_code_file = env
+            .state
+            .persistent
+            .get_object_file(&env.path("a.o"))
+            .expect("object is recorded")
+            .local_code
+            .local_code_file
+            .clone();
+        let content = std::fs::read(&local_code_file).expect("file can be read");
+        // E.g. the server crashed while writing the file.
+        std::fs::write(&local_code_file, &content[..content.len() / 2])
+            .expect("file can be written");
+        assert!(
+            !wrap_compile_object_file::local_code_file_is_intact(&local_code_file)
+                .await
+                .expect("file can be checked")
+        );
+
+        env.run_ok(WrappedBinary::Gcc, &["main.c", "a.o", "b.o", "-o", "main"])
+            .await;
+        assert_eq!(
+            std::fs::read(&local_code_file).expect("file can be read"),
+            content
+        );
+        let status = std::process::Command::new(env.path("main"))
+            .status()
+            .expect("binary runs");
+        assert!(status.success());
+    }
+
+    #[tokio::test]
+    async fn chunk_warnings_are_reported_once() {
+        let env = TestEnv::new(&[]).await;
+        env.write("a.c", "int a(void) { int unused_in_a = 0; return 1; }\n");
+        env.write("b.c", "int b(void) { return 2; }\n");
+        env.write(
+            "main.c",
+            "int a(void); int b(void);\nint main(void) { return a() + b() - 3; }\n",
+        );
+        for name in ["a", "b"] {
+            env.run_ok(
+                WrappedBinary::Gcc,
+                &[
+                    "-Wall",
+                    "-c",
+                    &format!("{}.c", name),
+                    "-o",
+                    &format!("{}.o", name),
+                ],
+            )
+            .await;
+        }
+        let output = env
+            .run_ok(WrappedBinary::Gcc, &["main.c", "a.o", "b.o", "-o", "main"])
+            .await;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("unused_in_a"), "{}", stderr);
+        assert_eq!(
+            stderr.matches("[-Wunused-variable]").count(),
+            1,
+            "{}",
+            stderr
+        );
+    }
+
+    #[tokio::test]
+    async fn shared_library_keeps_soname_and_version_script() {
+        let env = TestEnv::new(&[]).await;
+        let cwd = env.path("");
+        env.write("a.c", "int exported(void) { return 1; }\n");
+        env.write("b.c", "int hidden(void) { return 2; }\n");
+        env.write("foo.map", "{ global: exported; local: *; };\n");
+        for name in ["a", "b"] {
+            env.run_ok(
+                WrappedBinary::Gcc,
+                &[
+                    "-fPIC",
+                    "-c",
+                    &format!("{}.c", name),
+                    "-o",
+                    &format!("{}.o", name),
+                ],
+            )
+            .await;
+        }
+        env.run_ok(
+            WrappedBinary::Gcc,
+            &[
+                "-shared",
+                "-Wl,-soname,libfoo.so.1",
+                "a.o",
+                "-Wl,--version-script=foo.map",
+                "b.o",
+                "-o",
+                "libfoo.so.1.0",
+            ],
+        )
+        .await;
+
+        let inspect = |program: &str, args: &[&str]| {
+            let output = std::process::Command::new(program)
+                .args(args)
+                .arg("libfoo.so.1.0")
+                .current_dir(&cwd)
+                .output()
+                .expect("tool can be started");
+            assert!(output.status.success());
+            String::from_utf8_lossy(&output.stdout).into_owned()
+        };
+        assert!(inspect("readelf", &["-d"]).contains("Library soname: [libfoo.so.1]"));
+        let symbols = inspect("nm", &["-D", "--defined-only"]);
+        assert!(symbols.contains(" exported"), "{}", symbols);
+        assert!(!symbols.contains(" hidden"), "{}", symbols);
+    }
+
+    #[tokio::test]
+    async fn libraries_between_known_objects_keep_their_position() {
+        let env = TestEnv::new(&["--link-group", "never"]).await;
+        let cwd = env.path("");
+        // `libext.a` is built without ccelerate. It needs a symbol from `util.o`, so the known
+        // objects have to be linked again after it.
+        env.write(
+            "ext.c",
+            "int util(void);\nint ext(void) { return util() + 1; }\n",
+        );
+        env.write("util.c", "int util(void) { return 1; }\n");
+        env.write(
+            "main.c",
+            "#include <stdio.h>\nint ext(void);\nint main(void) { printf(\"%d\", ext()); return 0; }\n",
+        );
+        let status = std::process::Command::new("gcc")
+            .args(["-c", "ext.c"])
+            .current_dir(&cwd)
+            .status()
+            .expect("gcc can be started");
+        assert!(status.success());
+        let status = std::process::Command::new("ar")
+            .args(["rcs", "libext.a", "ext.o"])
+            .current_dir(&cwd)
+            .status()
+            .expect("ar can be started");
+        assert!(status.success());
+        for name in ["main", "util"] {
+            env.run_ok(
+                WrappedBinary::Gcc,
+                &["-c", &format!("{}.c", name), "-o", &format!("{}.o", name)],
+            )
+            .await;
+        }
+        env.run_ok(
+            WrappedBinary::Gcc,
+            &["main.o", "-L.", "-lext", "util.o", "-o", "main"],
+        )
+        .await;
+        assert_eq!(env.run_program("main"), "2");
+    }
+
+    #[tokio::test]
+    async fn pthread_objects_are_linked() {
+        let env = TestEnv::new(&[]).await;
+        env.write(
+            "worker.c",
+            "#include <pthread.h>\n\
+             static void *work(void *arg) { return arg; }\n\
+             int run_worker(void) {\n\
+               pthread_t thread;\n\
+               if (pthread_create(&thread, 0, work, 0)) return 1;\n\
+               return pthread_join(thread, 0);\n\
+             }\n",
+        );
+        env.write(
+            "main.c",
+            "int run_worker(void);\nint main(void) { return run_worker(); }\n",
+        );
+        env.run_ok(
+            WrappedBinary::Gcc,
+            &["-pthread", "-c", "worker.c", "-o", "worker.o"],
+        )
+        .await;
+        env.run_ok(
+            WrappedBinary::Gcc,
+            &["-pthread", "main.c", "worker.o", "-o", "main"],
+        )
+        .await;
+        let status = std::process::Command::new(env.path("main"))
+            .status()
+            .expect("binary runs");
+        assert!(status.success());
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn identical_link_is_served_from_cache() {
+        use std::os::unix::fs::MetadataExt;
+
+        let manifest_dir = tempfile::tempdir().expect("temp dir can be created");
+        let manifest_dir_arg = manifest_dir.path().to_string_lossy().to_string();
+        let env = TestEnv::new(&["--write-manifest", &manifest_dir_arg]).await;
+        env.write("a.c", "int a(void) { return 1; }\n");
+        env.write(
+            "main.c",
+            "int a(void);\nint main(void) { return a() - 1; }\n",
+        );
+        env.run_ok(WrappedBinary::Gcc, &["-c", "a.c", "-o", "a.o"])
+            .await;
+        let link_args = ["main.c", "a.o", "-o", "main"];
+        env.run_ok(WrappedBinary::Gcc, &link_args).await;
+        let first = std::fs::metadata(env.path("main")).expect("output exists");
+
+        std::fs::remove_dir_all(manifest_dir.path()).expect("manifests can be removed");
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        env.run_ok(WrappedBinary::Gcc, &link_args).await;
+        let second = std::fs::metadata(env.path("main")).expect("output exists");
+        // The linker would have created a new file.
+        assert_eq!(second.ino(), first.ino());
+        assert!(second.modified().expect("has mtime") > first.modified().expect("has mtime"));
+        assert_eq!(
+            std::fs::read_dir(manifest_dir.path())
+                .expect("manifest is written again")
+                .count(),
+            1
+        );
+
+        // The touched output is still recognized as the cached one.
+        env.run_ok(WrappedBinary::Gcc, &link_args).await;
+        let third = std::fs::metadata(env.path("main")).expect("output exists");
+        assert_eq!(third.ino(), first.ino());
+
+        // A changed output is not reused.
+        std::fs::write(env.path("main"), "broken").expect("output can be overwritten");
+        env.run_ok(WrappedBinary::Gcc, &link_args).await;
+        let status = std::process::Command::new(env.path("main"))
+            .status()
+            .expect("binary runs");
+        assert!(status.success());
+    }
+
+    #[tokio::test]
+    async fn shared_c_header_is_preprocessed_per_language() {
+        let env = TestEnv::new(&[]).await;
+        // The header is preprocessed separately for the C and the C++ chunk, because it has
+        // different content in C++.
+        env.write(
+            "shared.h",
+            "#ifdef __cplusplus\nextern \"C\" {\n#endif\nint shared(void);\n#ifdef __cplusplus\n}\n#endif\n\
+             static inline int lang(void) {\n#ifdef __cplusplus\n  return 2;\n#else\n  return 1;\n#endif\n}\n",
+        );
+        env.write(
+            "c_part.c",
+            "#include \"shared.h\"\nint shared(void) { return 5; }\nint c_lang(void) { return lang(); }\n",
+        );
+        env.write(
+            "cxx_part.cpp",
+            "#include \"shared.h\"\nextern \"C\" int cxx_lang(void) { return lang() + shared(); }\n",
+        );
+        env.write(
+            "main.c",
+            "int c_lang(void); int cxx_lang(void);\n\
+             int main(void) { return c_lang() == 1 && cxx_lang() == 7 ? 0 : 1; }\n",
+        );
+        env.run_ok(WrappedBinary::Gcc, &["-c", "c_part.c", "-o", "c_part.o"])
+            .await;
+        env.run_ok(
+            WrappedBinary::Gxx,
+            &["-c", "cxx_part.cpp", "-o", "cxx_part.o"],
+        )
+        .await;
+        env.run_ok(
+            WrappedBinary::Gcc,
+            &["main.c", "c_part.o", "cxx_part.o", "-o", "main"],
+        )
+        .await;
+        let status = std::process::Command::new(env.path("main"))
+            .status()
+            .expect("binary runs");
+        assert!(status.success());
+    }
+
+    #[tokio::test]
+    async fn archiver_failures_are_reported() {
+        let env = TestEnv::new(&["--child-timeout", "1"]).await;
+        let hang_marker = env.path("hang");
+        env.write_executable(
+            "toolchain/ar",
+            &format!(
+                "#!/bin/sh\nif [ -e '{}' ]; then sleep 10; fi\necho 'ar exploded' >&2\nexit 1\n",
+                hang_marker.display()
+            ),
+        );
+        env.write(
+            "ccelerate.toml",
+            "eager_patterns = []\nlocal_header_patterns = []\ninclude_defines = []\npure_c_header_patterns = []\n[toolchain_paths]\nar = \"toolchain/ar\"\n",
+        );
+        env.state
+            .config_manager
+            .config_for_paths(&[env.path("")])
+            .expect("config can be loaded");
+        let objects = [env.path("a.o")];
+
+        let err = create_archive_for_objects(&objects, &env.state)
+            .await
+            .expect_err("archiver fails");
+        assert!(err.to_string().contains("ar exploded"), "{}", err);
+
+        env.write("hang", "");
+        let err = create_archive_for_objects(&objects, &env.state)
+            .await
+            .expect_err("archiver is killed");
+        assert!(
+            err.to_string().contains("did not finish within 1 seconds"),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn regular_archive_is_self_contained() {
+        let env = TestEnv::new(&["--regular-archives"]).await;
+        let mut objects = vec![];
+        for name in ["a", "b"] {
+            env.write(
+                &format!("{}.c", name),
+                &format!("int {name}(void) {{ return 1; }}\n"),
+            );
+            let status = std::process::Command::new("gcc")
+                .args(["-c", &format!("{}.c", name), "-o", &format!("{}.o", name)])
+                .current_dir(env.path(""))
+                .status()
+                .expect("gcc can be started");
+            assert!(status.success());
+            objects.push(env.path(&format!("{}.o", name)));
+        }
+        let archive = create_archive_for_objects(&objects, &env.state)
+            .await
+            .expect("archive can be created");
+        let content = std::fs::read(&archive).expect("archive exists");
+        assert!(content.starts_with(b"!<arch>\n"));
+        for object in &objects {
+            std::fs::remove_file(object).expect("object can be removed");
+        }
+
+        let extract_dir = env.path("extracted");
+        std::fs::create_dir_all(&extract_dir).expect("dir can be created");
+        let status = std::process::Command::new("ar")
+            .arg("x")
+            .arg(&archive)
+            .current_dir(&extract_dir)
+            .status()
+            .expect("ar can be started");
+        assert!(status.success());
+        for name in ["a.o", "b.o"] {
+            assert!(extract_dir.join(name).exists(), "{} is missing", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn suppressed_warnings_are_not_reported() {
+        let env = TestEnv::new(&[]).await;
+        env.write("a.c", "int a(void) { int unused_in_a = 0; return 1; }\n");
+        env.write(
+            "main.c",
+            "int a(void);\nint main(void) { return a() - 1; }\n",
+        );
+        env.run_ok(
+            WrappedBinary::Gcc,
+            &["-w", "-Wall", "-c", "a.c", "-o", "a.o"],
+        )
+        .await;
+        let output = env
+            .run_ok(WrappedBinary::Gcc, &["main.c", "a.o", "-o", "main"])
+            .await;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(!stderr.contains("unused_in_a"), "{}", stderr);
+    }
+
+    #[tokio::test]
+    async fn header_warnings_from_multiple_chunks_are_reported_once() {
+        let env = TestEnv::new(&["--chunk-size", "1"]).await;
+        env.write("shared.h", "static int helper(void) { return 0; }\n");
+        let names = ["a", "b", "c"];
+        for name in names {
+            env.write(
+                &format!("{}.c", name),
+                &format!("#include \"shared.h\"\nint {name}(void) {{ return 1; }}\n"),
+            );
+            env.run_ok(
+                WrappedBinary::Gcc,
+                &[
+                    "-Wall",
+                    "-c",
+                    &format!("{}.c", name),
+                    "-o",
+                    &format!("{}.o", name),
+                ],
+            )
+            .await;
+        }
+        env.write(
+            "main.c",
+            "int a(void); int b(void); int c(void);\n\
+             int main(void) { return a() + b() + c() - 3; }\n",
+        );
+        let output = env
+            .run_ok(
+                WrappedBinary::Gcc,
+                &["main.c", "a.o", "b.o", "c.o", "-o", "main"],
+            )
+            .await;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(
+            stderr.matches("[-Wunused-function]").count(),
+            1,
+            "{}",
+            stderr
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_thin_archive_member_is_detected() {
+        let env = TestEnv::new(&[]).await;
+        let existing = env.write("existing.o", "");
+        let missing = env.path("missing.o");
+        let chunk = |object_path| CompiledChunk {
+            object_path,
+            warnings: vec![],
+        };
+        check_thin_archive_members_exist(&[chunk(existing.clone())])
+            .expect("existing objects are accepted");
+        let error = check_thin_archive_members_exist(&[chunk(existing), chunk(missing.clone())])
+            .expect_err("missing objects are detected");
+        assert!(error.to_string().contains(&missing.display().to_string()));
+    }
+
+    #[tokio::test]
+    async fn removed_chunk_objects_are_compiled_again() {
+        let env = TestEnv::new(&[]).await;
+        env.write("main.c", "int main(void) { return 0; }\n");
+        env.run_ok(WrappedBinary::Gcc, &["-c", "main.c", "-o", "main.o"])
+            .await;
+        env.run_ok(WrappedBinary::Gcc, &["main.o", "-o", "main"])
+            .await;
+
+        // The cached chunk still references its object, e.g. after the data directory was
+        // cleaned up.
+        std::fs::remove_dir_all(env.state.data_dir.join("objects"))
+            .expect("objects can be removed");
+        std::fs::remove_file(env.path("main")).expect("output can be removed");
+        env.run_ok(WrappedBinary::Gcc, &["main.o", "-o", "main"])
+            .await;
+        let status = std::process::Command::new(env.path("main"))
+            .status()
+            .expect("binary runs");
+        assert!(status.success());
     }
 }
@@ -0,0 +1,184 @@
+#![deny(clippy::unwrap_used)]
+
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Result;
+use ccelerate_shared::WrappedBinary;
+
+use crate::{
+    CommandOutput, State,
+    executable::Executable,
+    object_tool_args::{self, ObjectToolFile},
+    path_utils::ProjectRoots,
+    placeholders,
+    task_periods::TaskPeriodInfo,
+    wrap_eager,
+};
+
+/// `objcopy` and `strip` can't do anything useful with the placeholder object files and
+/// archives that ccelerate creates, and the placeholders are replaced by the final link anyway.
+/// So the tool is skipped for them, while other files are still processed as usual.
+pub async fn wrap_object_tool(
+    executable: &Executable,
+    args: &[impl AsRef<OsStr>],
+    cwd: &Path,
+    state: &Arc<State>,
+) -> Result<CommandOutput> {
+    let info = object_tool_args::ObjectToolInfo::from_args(executable.binary, cwd, args)?;
+    let (placeholders, real_files): (Vec<&ObjectToolFile>, Vec<&ObjectToolFile>) = info
+        .files
+        .iter()
+        .partition(|file| is_placeholder(&file.input, state));
+    if placeholders.is_empty() {
+        return wrap_eager::wrap_eager(executable, args, cwd, state).await;
+    }
+
+    let task_info = SkippedObjectToolTaskInfo {
+        binary: executable.binary,
+        files: placeholders.iter().map(|file| file.input.clone()).collect(),
+    };
+    // The result differs from running the tool, so this is logged at every log level.
+    log::warn!(
+        "Skipped {} for placeholder files: {}",
+        executable,
+        task_info.terminal_one_liner(&state.project_roots)
+    );
+    let task_period = state.task_periods.start(task_info);
+    for file in &placeholders {
+        if file.output == file.input {
+            continue;
+        }
+        // The output has to be a placeholder for the same build as well.
+        tokio::fs::copy(&file.input, &file.output).await?;
+        if state.persistent.get_object_file(&file.input).is_some() {
+            state
+                .persistent
+                .copy_object_file(&file.input, &file.output)?;
+        } else {
+            state
+                .persistent
+                .copy_archive_file(&file.input, &file.output)?;
+        }
+    }
+    let output = if real_files.is_empty() {
+        CommandOutput::new_ok()
+    } else {
+        let args = object_tool_args::remove_files_from_args(args, placeholders);
+        wrap_eager::wrap_eager(executable, &args, cwd, state).await?
+    };
+    task_period.finished_successfully();
+    Ok(output)
+}
+
+fn is_placeholder(path: &Path, state: &State) -> bool {
+    let placeholder = if state.persistent.get_object_file(path).is_some() {
+        placeholders::dummy_object()
+    } else if state.persistent.get_archive_file(path).is_some() {
+        placeholders::dummy_archive()
+    } else {
+        return false;
+    };
+    !state.cli.hybrid || placeholders::is_placeholder_file(path, placeholder)
+}
+
+struct SkippedObjectToolTaskInfo {
+    binary: WrappedBinary,
+    files: Vec<PathBuf>,
+}
+
+impl TaskPeriodInfo for SkippedObjectToolTaskInfo {
+    fn category(&self) -> String {
+        format!("{} (skipped)", self.binary)
+    }
+
+    fn terminal_one_liner(&self, project_roots: &ProjectRoots) -> String {
+        self.files
+            .iter()
+            .map(|p| project_roots.shorten(p))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn log_detailed(&self) {
+        log::debug!(
+            "Skipped {} for placeholder files: {:?}",
+            self.binary,
+            self.files
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ccelerate_shared::WrappedBinary;
+
+    use crate::test_utils::TestEnv;
+
+    #[tokio::test]
+    async fn placeholders_are_skipped() {
+        let env = TestEnv::new(&[]).await;
+        env.write("a.c", "int a(void) { return 1; }\n");
+        env.write(
+            "main.c",
+            "int a(void);\nint main(void) { return a() - 1; }\n",
+        );
+        env.run_ok(WrappedBinary::Gcc, &["-c", "a.c", "-o", "a.o"])
+            .await;
+        let placeholder = std::fs::read(env.path("a.o")).expect("object exists");
+
+        env.run_ok(WrappedBinary::Strip, &["--strip-debug", "a.o"])
+            .await;
+        assert_eq!(
+            std::fs::read(env.path("a.o")).expect("object exists"),
+            placeholder
+        );
+        // The copy is deferred to the final link, which compiles the recorded build.
+        env.run_ok(WrappedBinary::Objcopy, &["-g", "a.o", "b.o"])
+            .await;
+        assert!(
+            env.state
+                .persistent
+                .get_object_file(&env.path("b.o"))
+                .is_some()
+        );
+        env.run_ok(WrappedBinary::Gcc, &["main.c", "b.o", "-o", "main"])
+            .await;
+        env.run_program("main");
+    }
+
+    #[tokio::test]
+    async fn real_files_are_still_processed() {
+        let env = TestEnv::new(&[]).await;
+        env.write("a.c", "int a(void) { return 1; }\n");
+        env.write("real.c", "int real(void) { return 2; }\n");
+        env.run_ok(WrappedBinary::Gcc, &["-c", "a.c", "-o", "a.o"])
+            .await;
+        let status = std::process::Command::new("gcc")
+            .args(["-g", "-c", "real.c", "-o", "real.o"])
+            .current_dir(env.path(""))
+            .status()
+            .expect("gcc can be started");
+        assert!(status.success());
+        let placeholder = std::fs::read(env.path("a.o")).expect("object exists");
+        let real_size = std::fs::metadata(env.path("real.o"))
+            .expect("object exists")
+            .len();
+
+        env.run_ok(WrappedBinary::Strip, &["--strip-debug", "a.o", "real.o"])
+            .await;
+        assert_eq!(
+            std::fs::read(env.path("a.o")).expect("object exists"),
+            placeholder
+        );
+        assert!(
+            std::fs::metadata(env.path("real.o"))
+                .expect("object exists")
+                .len()
+                < real_size
+        );
+    }
+}
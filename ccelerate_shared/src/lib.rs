@@ -10,6 +10,20 @@ pub struct RunRequestDataWire {
     pub binary: WrappedBinary,
     pub args: Vec<String>,
     pub cwd: String,
+    // Older clients don't send this, so they never get compressed responses.
+    #[serde(default)]
+    pub accepts_compression: bool,
+    // Whether the client shows the output in a terminal, in which case diagnostics can be
+    // colored.
+    #[serde(default)]
+    pub client_is_tty: bool,
+    // Number of parallel jobs of the make process that runs the command, if any.
+    #[serde(default)]
+    pub make_jobs: Option<usize>,
+    // Absolute path of the executable that the wrapper stands in for, found on the `PATH` of
+    // the caller.
+    #[serde(default)]
+    pub resolved_binary: Option<String>,
 }
 
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize, Default)]
@@ -17,15 +31,77 @@ pub struct RunResponseDataWire {
     pub stdout: String,
     pub stderr: String,
     pub status: i32,
+    // If true, every payload starts with a header byte that indicates whether the remaining
+    // bytes are compressed. See [`PAYLOAD_HEADER_RAW`] and [`PAYLOAD_HEADER_ZSTD`].
+    #[serde(default)]
+    pub payload_headers: bool,
+}
+
+/// Sent with status 503 when the server is too busy to handle a request. The client runs the
+/// base64 encoded program with the original arguments itself instead.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct RunLocallyResponseWire {
+    pub program: String,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct RunBatchRequestWire {
+    pub requests: Vec<RunRequestDataWire>,
+}
+
+/// Asks another ccelerate server to compile preprocessed code. The response is a
+/// [`RunResponseDataWire`] whose stdout contains the object file on success.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct CompileChunkRequestWire {
+    pub binary: WrappedBinary,
+    pub args: Vec<String>,
+    pub source_extension: String,
+    pub source: String,
 }
 
 pub const DEFAULT_PORT: u16 = 6235;
 
+/// HTTP header that contains the token which other servers need to compile chunks on a server.
+pub const WORKER_TOKEN_HEADER: &str = "x-ccelerate-worker-token";
+
+pub const PAYLOAD_HEADER_RAW: u8 = 0;
+pub const PAYLOAD_HEADER_ZSTD: u8 = 1;
+
+/// Smaller payloads are not compressed because it's not worth the overhead.
+const COMPRESSION_THRESHOLD: usize = 4096;
+
+#[derive(Debug)]
+pub enum WireDecodeError {
+    Base64(base64::DecodeError),
+    Payload(String),
+}
+
+impl fmt::Display for WireDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WireDecodeError::Base64(err) => write!(f, "Invalid base64: {}", err),
+            WireDecodeError::Payload(msg) => write!(f, "Invalid payload: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for WireDecodeError {}
+
+impl From<base64::DecodeError> for WireDecodeError {
+    fn from(err: base64::DecodeError) -> Self {
+        WireDecodeError::Base64(err)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct RunRequestData {
     pub binary: WrappedBinary,
     pub args: Vec<OsString>,
     pub cwd: PathBuf,
+    pub accepts_compression: bool,
+    pub client_is_tty: bool,
+    pub make_jobs: Option<usize>,
+    pub resolved_binary: Option<PathBuf>,
 }
 
 #[derive(Debug, Clone)]
@@ -35,13 +111,32 @@ pub struct RunResponseData {
     pub status: i32,
 }
 
-#[derive(Debug, Clone, Copy, serde::Deserialize, serde::Serialize, PartialEq, Eq)]
+#[derive(Debug, Clone)]
+pub struct RunLocallyResponseData {
+    pub program: OsString,
+}
+
+#[derive(Debug, Clone)]
+pub struct CompileChunkRequestData {
+    pub binary: WrappedBinary,
+    // Arguments of the original compile command. The receiver replaces the inputs and outputs.
+    pub args: Vec<OsString>,
+    // Extension of the preprocessed source which determines its language.
+    pub source_extension: String,
+    pub source: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, serde::Deserialize, serde::Serialize, PartialEq, Eq, Hash)]
 pub enum WrappedBinary {
     Gcc,
     Gxx,
     Clang,
     Clangxx,
+    // Clang with MSVC compatible arguments.
+    ClangCl,
     Ar,
+    Objcopy,
+    Strip,
 }
 
 impl WrappedBinary {
@@ -51,7 +146,10 @@ impl WrappedBinary {
             WrappedBinary::Gxx => "g++".into(),
             WrappedBinary::Clang => "clang".into(),
             WrappedBinary::Clangxx => "clang++".into(),
+            WrappedBinary::ClangCl => "clang-cl".into(),
             WrappedBinary::Ar => "ar".into(),
+            WrappedBinary::Objcopy => "objcopy".into(),
+            WrappedBinary::Strip => "strip".into(),
         }
     }
 
@@ -61,7 +159,10 @@ impl WrappedBinary {
             Some("g++") => Some(WrappedBinary::Gxx),
             Some("clang") => Some(WrappedBinary::Clang),
             Some("clang++") => Some(WrappedBinary::Clangxx),
+            Some("clang-cl") => Some(WrappedBinary::ClangCl),
             Some("ar") => Some(WrappedBinary::Ar),
+            Some("objcopy") => Some(WrappedBinary::Objcopy),
+            Some("strip") => Some(WrappedBinary::Strip),
             _ => None,
         }
     }
@@ -90,10 +191,16 @@ impl RunRequestData {
             binary: self.binary,
             cwd: encode_osstr(self.cwd.into_os_string()),
             args: self.args.into_iter().map(encode_osstr).collect(),
+            accepts_compression: self.accepts_compression,
+            client_is_tty: self.client_is_tty,
+            make_jobs: self.make_jobs,
+            resolved_binary: self
+                .resolved_binary
+                .map(|path| encode_osstr(path.into_os_string())),
         }
     }
 
-    pub fn from_wire(wire: &RunRequestDataWire) -> Result<Self, base64::DecodeError> {
+    pub fn from_wire(wire: &RunRequestDataWire) -> Result<Self, WireDecodeError> {
         Ok(Self {
             binary: wire.binary,
             cwd: decode_osstr(&wire.cwd)?.into(),
@@ -102,33 +209,250 @@ impl RunRequestData {
                 .iter()
                 .map(|s| decode_osstr(s))
                 .collect::<Result<_, _>>()?,
+            accepts_compression: wire.accepts_compression,
+            client_is_tty: wire.client_is_tty,
+            make_jobs: wire.make_jobs,
+            resolved_binary: wire
+                .resolved_binary
+                .as_deref()
+                .map(decode_osstr)
+                .transpose()?
+                .map(PathBuf::from),
+        })
+    }
+}
+
+impl CompileChunkRequestData {
+    pub fn to_wire(self) -> CompileChunkRequestWire {
+        CompileChunkRequestWire {
+            binary: self.binary,
+            args: self.args.into_iter().map(encode_osstr).collect(),
+            source_extension: self.source_extension,
+            // Both sides are ccelerate servers, so the payload can always be compressed.
+            source: BASE64_STANDARD.encode(encode_payload(&self.source)),
+        }
+    }
+
+    pub fn from_wire(wire: &CompileChunkRequestWire) -> Result<Self, WireDecodeError> {
+        Ok(Self {
+            binary: wire.binary,
+            args: wire
+                .args
+                .iter()
+                .map(|s| decode_osstr(s))
+                .collect::<Result<_, _>>()?,
+            source_extension: wire.source_extension.clone(),
+            source: decode_payload(&BASE64_STANDARD.decode(&wire.source)?)?,
+        })
+    }
+}
+
+impl RunLocallyResponseData {
+    pub fn to_wire(self) -> RunLocallyResponseWire {
+        RunLocallyResponseWire {
+            program: encode_osstr(self.program),
+        }
+    }
+
+    pub fn from_wire(wire: &RunLocallyResponseWire) -> Result<Self, WireDecodeError> {
+        Ok(Self {
+            program: decode_osstr(&wire.program)?,
         })
     }
 }
 
 impl RunResponseData {
-    pub fn to_wire(self) -> RunResponseDataWire {
+    /// Large payloads are compressed if `allow_compression` is true. That should only be done
+    /// if the receiver indicated that it can decompress them.
+    pub fn to_wire(self, allow_compression: bool) -> RunResponseDataWire {
+        let encode = |data: &[u8]| match allow_compression {
+            true => BASE64_STANDARD.encode(encode_payload(data)),
+            false => BASE64_STANDARD.encode(data),
+        };
         RunResponseDataWire {
-            stdout: BASE64_STANDARD.encode(&self.stdout),
-            stderr: BASE64_STANDARD.encode(&self.stderr),
+            stdout: encode(&self.stdout),
+            stderr: encode(&self.stderr),
             status: self.status,
+            payload_headers: allow_compression,
         }
     }
 
-    pub fn from_wire(wire: RunResponseDataWire) -> Result<Self, base64::DecodeError> {
+    pub fn from_wire(wire: RunResponseDataWire) -> Result<Self, WireDecodeError> {
+        let decode = |data: &str| -> Result<Vec<u8>, WireDecodeError> {
+            let data = BASE64_STANDARD.decode(data)?;
+            match wire.payload_headers {
+                true => decode_payload(&data),
+                false => Ok(data),
+            }
+        };
         Ok(Self {
-            stdout: BASE64_STANDARD.decode(wire.stdout)?,
-            stderr: BASE64_STANDARD.decode(wire.stderr)?,
+            stdout: decode(&wire.stdout)?,
+            stderr: decode(&wire.stderr)?,
             status: wire.status,
         })
     }
 }
 
+fn encode_payload(data: &[u8]) -> Vec<u8> {
+    if data.len() >= COMPRESSION_THRESHOLD
+        && let Ok(compressed) = zstd::bulk::compress(data, 0)
+        && compressed.len() < data.len()
+    {
+        let mut result = Vec::with_capacity(compressed.len() + 1);
+        result.push(PAYLOAD_HEADER_ZSTD);
+        result.extend_from_slice(&compressed);
+        return result;
+    }
+    let mut result = Vec::with_capacity(data.len() + 1);
+    result.push(PAYLOAD_HEADER_RAW);
+    result.extend_from_slice(data);
+    result
+}
+
+fn decode_payload(data: &[u8]) -> Result<Vec<u8>, WireDecodeError> {
+    match data.split_first() {
+        Some((&PAYLOAD_HEADER_RAW, payload)) => Ok(payload.to_vec()),
+        Some((&PAYLOAD_HEADER_ZSTD, payload)) => {
+            zstd::decode_all(payload).map_err(|err| WireDecodeError::Payload(err.to_string()))
+        }
+        Some((header, _)) => Err(WireDecodeError::Payload(format!(
+            "Unknown payload header: {}",
+            header
+        ))),
+        None => Err(WireDecodeError::Payload("Missing payload header".into())),
+    }
+}
+
 fn encode_osstr(s: OsString) -> String {
     BASE64_STANDARD.encode(s.as_encoded_bytes())
 }
 
-fn decode_osstr(s: &str) -> Result<OsString, base64::DecodeError> {
-    // SAFETY: It is expected that the string had been encoded on the same system.
-    Ok(unsafe { OsString::from_encoded_bytes_unchecked(BASE64_STANDARD.decode(s)?) })
+fn decode_osstr(s: &str) -> Result<OsString, WireDecodeError> {
+    bytes_to_osstring(BASE64_STANDARD.decode(s)?)
+}
+
+#[cfg(unix)]
+fn bytes_to_osstring(bytes: Vec<u8>) -> Result<OsString, WireDecodeError> {
+    use std::os::unix::ffi::OsStringExt;
+    // Any byte sequence is a valid OsString on unix.
+    Ok(OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+fn bytes_to_osstring(bytes: Vec<u8>) -> Result<OsString, WireDecodeError> {
+    // The encoding of OsString is unspecified on other platforms, so the bytes may come from a
+    // different system or be corrupted. Only UTF-8 is accepted because that is always valid.
+    String::from_utf8(bytes)
+        .map(OsString::from)
+        .map_err(|err| WireDecodeError::Payload(format!("Invalid string encoding: {}", err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(data: &[u8], allow_compression: bool) -> RunResponseDataWire {
+        let wire = RunResponseData {
+            stdout: data.to_vec(),
+            stderr: data.to_vec(),
+            status: 3,
+        }
+        .to_wire(allow_compression);
+        // Also goes through JSON like on the network.
+        let json = serde_json::to_string(&wire).expect("wire data can be serialized");
+        let decoded = RunResponseData::from_wire(
+            serde_json::from_str(&json).expect("wire data can be deserialized"),
+        )
+        .expect("wire data can be decoded");
+        assert_eq!(decoded.stdout, data);
+        assert_eq!(decoded.stderr, data);
+        assert_eq!(decoded.status, 3);
+        wire
+    }
+
+    #[test]
+    fn response_round_trip() {
+        let large = b"warning: unused variable\n".repeat(1000);
+        for data in [&b""[..], b"small output", &large] {
+            round_trip(data, false);
+            round_trip(data, true);
+        }
+    }
+
+    #[test]
+    fn only_large_payloads_are_compressed() {
+        let large = b"warning: unused variable\n".repeat(1000);
+        let wire = round_trip(&large, true);
+        let payload = BASE64_STANDARD
+            .decode(&wire.stdout)
+            .expect("payload is base64");
+        assert_eq!(payload[0], PAYLOAD_HEADER_ZSTD);
+        assert!(payload.len() < large.len() / 10);
+
+        let wire = round_trip(b"small output", true);
+        let payload = BASE64_STANDARD
+            .decode(&wire.stdout)
+            .expect("payload is base64");
+        assert_eq!(payload[0], PAYLOAD_HEADER_RAW);
+
+        // Old clients that don't know about the header get the raw data.
+        let wire = round_trip(&large, false);
+        assert_eq!(
+            BASE64_STANDARD
+                .decode(&wire.stdout)
+                .expect("payload is base64"),
+            large
+        );
+    }
+
+    #[test]
+    fn invalid_base64_is_an_error() {
+        for input in ["!!!", "abc", "YWJj=", "\u{0}"] {
+            assert!(matches!(
+                decode_osstr(input),
+                Err(WireDecodeError::Base64(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn non_utf8_strings() {
+        // Lone continuation byte, overlong encoding and an encoded surrogate.
+        let inputs: [&[u8]; 3] = [b"\x80", b"\xc0\xaf", b"\xed\xa0\x80"];
+        for bytes in inputs {
+            let decoded = decode_osstr(&BASE64_STANDARD.encode(bytes));
+            #[cfg(unix)]
+            assert_eq!(
+                decoded
+                    .expect("any bytes are valid on unix")
+                    .as_encoded_bytes(),
+                bytes
+            );
+            #[cfg(not(unix))]
+            assert!(matches!(decoded, Err(WireDecodeError::Payload(_))));
+        }
+    }
+
+    #[test]
+    fn invalid_request_is_an_error() {
+        let wire = RunRequestData {
+            binary: WrappedBinary::Gcc,
+            args: vec!["-c".into(), "foo.c".into()],
+            cwd: "/tmp".into(),
+            accepts_compression: false,
+            client_is_tty: false,
+            make_jobs: None,
+            resolved_binary: None,
+        }
+        .to_wire();
+        assert!(RunRequestData::from_wire(&wire).is_ok());
+
+        let mut broken = wire.clone();
+        broken.args[1] = "not base64".into();
+        assert!(RunRequestData::from_wire(&broken).is_err());
+
+        let mut broken = wire;
+        broken.resolved_binary = Some("%".into());
+        assert!(RunRequestData::from_wire(&broken).is_err());
+    }
 }
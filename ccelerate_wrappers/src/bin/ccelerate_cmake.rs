@@ -12,17 +12,26 @@ fn main() {
     let ccelerate_ar_path = current_dir.join("ccelerate_ar");
     let ccelerate_gcc_path = current_dir.join("ccelerate_gcc");
     let ccelerate_gxx_path = current_dir.join("ccelerate_gxx");
+    let ccelerate_objcopy_path = current_dir.join("ccelerate_objcopy");
+    let ccelerate_strip_path = current_dir.join("ccelerate_strip");
 
     print!(
-        "ccelerate overrides:\n  {}\n  {}\n  {}\n\n",
+        "ccelerate overrides:\n  {}\n  {}\n  {}\n  {}\n  {}\n\n",
         ccelerate_ar_path.display(),
         ccelerate_gcc_path.display(),
-        ccelerate_gxx_path.display()
+        ccelerate_gxx_path.display(),
+        ccelerate_objcopy_path.display(),
+        ccelerate_strip_path.display()
     );
 
     let child = std::process::Command::new("cmake")
         .args(std::env::args_os().skip(1))
         .arg(format!("-DCMAKE_AR={}", ccelerate_ar_path.display()))
+        .arg(format!(
+            "-DCMAKE_OBJCOPY={}",
+            ccelerate_objcopy_path.display()
+        ))
+        .arg(format!("-DCMAKE_STRIP={}", ccelerate_strip_path.display()))
         .env("CC", ccelerate_gcc_path)
         .env("CXX", ccelerate_gxx_path)
         .spawn();
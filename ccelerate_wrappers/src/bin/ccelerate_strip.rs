@@ -0,0 +1,3 @@
+fn main() {
+    ccelerate_wrappers::wrap_command(ccelerate_shared::WrappedBinary::Strip);
+}
@@ -1,6 +1,11 @@
 #![deny(clippy::unwrap_used)]
 
-use std::{io::Write, process::exit};
+use std::{
+    ffi::{OsStr, OsString},
+    io::{IsTerminal, Write},
+    path::{Path, PathBuf},
+    process::exit,
+};
 
 pub fn wrap_command(binary: ccelerate_shared::WrappedBinary) {
     let args = std::env::args_os().skip(1).collect::<Vec<_>>();
@@ -13,48 +18,209 @@ pub fn wrap_command(binary: ccelerate_shared::WrappedBinary) {
         exit(1);
     };
 
-    let request = ccelerate_shared::RunRequestData { binary, args, cwd };
-    let response = client
-        .post(format!(
-            "http://127.0.0.1:{}/run",
-            ccelerate_shared::DEFAULT_PORT
-        ))
-        .json(&request.to_wire())
-        .send();
+    let resolved_binary = resolve_binary(binary, &cwd);
+    let request = ccelerate_shared::RunRequestData {
+        binary,
+        args: args.clone(),
+        cwd,
+        accepts_compression: true,
+        // Diagnostics are written to stderr.
+        client_is_tty: std::io::stderr().is_terminal(),
+        make_jobs: std::env::var("MAKEFLAGS")
+            .ok()
+            .and_then(|flags| parse_make_jobs(&flags)),
+        resolved_binary,
+    };
+    exit(send_request(
+        &client,
+        &format!("http://127.0.0.1:{}/run", ccelerate_shared::DEFAULT_PORT),
+        request,
+        &args,
+    ));
+}
+
+/// Sends the request to the server and forwards its output. Returns the exit code of the
+/// command.
+fn send_request(
+    client: &reqwest::blocking::Client,
+    url: &str,
+    request: ccelerate_shared::RunRequestData,
+    args: &[OsString],
+) -> i32 {
+    let response = client.post(url).json(&request.to_wire()).send();
     match response {
         Ok(response) => {
+            if response.status() == reqwest::StatusCode::SERVICE_UNAVAILABLE {
+                let Some(data) = response
+                    .json::<ccelerate_shared::RunLocallyResponseWire>()
+                    .ok()
+                    .and_then(|wire| {
+                        ccelerate_shared::RunLocallyResponseData::from_wire(&wire).ok()
+                    })
+                else {
+                    eprintln!("ccelerate_server is busy");
+                    return 1;
+                };
+                return run_locally(&data.program, args);
+            }
             if !response.status().is_success() {
                 eprintln!(
                     "Failed to run command (status: {}): {}",
                     response.status(),
                     response.text().unwrap_or("Unknown error".to_string()),
                 );
-                exit(1);
+                return 1;
             }
             let Ok(data) = response.json::<ccelerate_shared::RunResponseDataWire>() else {
                 eprintln!("Failed to decode response");
-                exit(1);
+                return 1;
             };
             let Ok(data) = ccelerate_shared::RunResponseData::from_wire(data) else {
                 eprintln!("Failed to decode response");
-                exit(1);
+                return 1;
             };
             std::io::stdout().write_all(&data.stdout).ok();
             std::io::stderr().write_all(&data.stderr).ok();
-            exit(data.status);
+            data.status
         }
         Err(err) => {
             if err.is_connect() {
                 eprintln!(
-                    "Cannot connect to ccelerate_server on port {}, is it running?",
-                    ccelerate_shared::DEFAULT_PORT
+                    "Cannot connect to ccelerate_server at {}, is it running?",
+                    url
                 );
             } else if err.is_timeout() {
                 eprintln!("Connection to ccelerate_server timed out");
             } else {
                 eprintln!("Failed: {}", err);
             }
-            exit(1);
+            1
+        }
+    }
+}
+
+/// The server is too busy, so the command runs here instead. Its output goes to the terminal
+/// directly.
+fn run_locally(program: &OsStr, args: &[OsString]) -> i32 {
+    match std::process::Command::new(program).args(args).status() {
+        Ok(status) => status.code().unwrap_or(1),
+        Err(err) => {
+            eprintln!("Failed to run {}: {}", program.to_string_lossy(), err);
+            1
         }
     }
 }
+
+/// Finds the executable that would have been run if this wrapper was not on the `PATH`, i.e.
+/// the next one with the same name that is not the wrapper itself.
+fn resolve_binary(binary: ccelerate_shared::WrappedBinary, cwd: &Path) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    let current_exe = std::env::current_exe().and_then(std::fs::canonicalize).ok();
+    let name = binary.to_standard_binary_name();
+    std::env::split_paths(&path)
+        .map(|dir| cwd.join(dir).join(&name))
+        .filter(|candidate| candidate.is_file())
+        .find(|candidate| {
+            let canonical = std::fs::canonicalize(candidate).ok();
+            canonical.is_some() && canonical != current_exe
+        })
+}
+
+/// Finds the job count in `MAKEFLAGS`, e.g. `-j8` in ` -j8 --jobserver-auth=fifo:/tmp/x`. Make
+/// passes `-j` without a number when the job count is unlimited.
+fn parse_make_jobs(makeflags: &str) -> Option<usize> {
+    makeflags.split_whitespace().find_map(|flag| {
+        flag.strip_prefix("-j")
+            .or_else(|| flag.strip_prefix("--jobs="))
+            .and_then(|jobs| jobs.parse().ok())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+    };
+
+    use super::send_request;
+
+    /// Answers a single request like a server that is too busy to handle it.
+    fn start_busy_server(program: &std::path::Path) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("listener can be bound");
+        let address = listener.local_addr().expect("listener has an address");
+        let body = serde_json::to_string(
+            &ccelerate_shared::RunLocallyResponseData {
+                program: program.into(),
+            }
+            .to_wire(),
+        )
+        .expect("response can be encoded");
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().expect("connection can be accepted");
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+            // Read the headers and the body before answering.
+            while let Ok(read) = stream.read(&mut buffer) {
+                request.extend_from_slice(&buffer[..read]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some((headers, body)) = text.split_once("\r\n\r\n") {
+                    let length = headers
+                        .lines()
+                        .find_map(|line| {
+                            line.to_ascii_lowercase()
+                                .strip_prefix("content-length:")
+                                .and_then(|length| length.trim().parse::<usize>().ok())
+                        })
+                        .unwrap_or(0);
+                    if body.len() >= length {
+                        break;
+                    }
+                }
+                if read == 0 {
+                    break;
+                }
+            }
+            write!(
+                stream,
+                "HTTP/1.1 503 Service Unavailable\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .ok();
+        });
+        format!("http://{}/run", address)
+    }
+
+    #[test]
+    fn busy_server_lets_wrapper_compile_locally() {
+        let dir = tempfile::tempdir().expect("temporary directory can be created");
+        let source = dir.path().join("a.c");
+        let object = dir.path().join("a.o");
+        std::fs::write(&source, "int a(void) { return 1; }\n").expect("source can be written");
+        let args = vec![
+            "-c".into(),
+            source.into_os_string(),
+            "-o".into(),
+            object.clone().into(),
+        ];
+        let request = ccelerate_shared::RunRequestData {
+            binary: ccelerate_shared::WrappedBinary::Gcc,
+            args: args.clone(),
+            cwd: dir.path().to_owned(),
+            accepts_compression: true,
+            client_is_tty: false,
+            make_jobs: None,
+            resolved_binary: None,
+        };
+        let compiler = std::env::split_paths(&std::env::var_os("PATH").unwrap_or_default())
+            .map(|dir| dir.join("gcc"))
+            .find(|path| path.is_file())
+            .expect("gcc is installed");
+        let url = start_busy_server(&compiler);
+
+        let client = reqwest::blocking::Client::new();
+        assert_eq!(send_request(&client, &url, request, &args), 0);
+        assert!(object.is_file());
+    }
+}